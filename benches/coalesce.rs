@@ -0,0 +1,74 @@
+//! Small allocations under each coalescing policy, once dominated by frees
+//! of blocks whose size is asked for again straight away, and once by
+//! batches of varied sizes that keep needing fresh chunks.
+
+#![feature(test)]
+
+extern crate test;
+
+use disk_dlmalloc::{CoalescePolicy, DiskDlmalloc};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use tempfile::NamedTempFile;
+use test::Bencher;
+
+const SLOTS: usize = 1024;
+
+fn arena(temp_file: &NamedTempFile, policy: CoalescePolicy) -> DiskDlmalloc {
+    DiskDlmalloc::builder(temp_file.path(), 256 << 20)
+        .coalesce_policy(policy)
+        .build()
+        .unwrap()
+}
+
+/// Frees a random block and allocates another of the same size in its place.
+fn free_heavy(b: &mut Bencher, policy: CoalescePolicy) {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = arena(&temp_file, policy);
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut slots: Vec<_> = (0..SLOTS).map(|_| unsafe { a.malloc(64, 8) }).collect();
+    b.iter(|| unsafe {
+        let slot = &mut slots[rng.gen_range(0..SLOTS)];
+        a.free(*slot, 64, 8);
+        *slot = a.malloc(64, 8);
+    });
+    for ptr in slots {
+        unsafe { a.free(ptr, 64, 8) };
+    }
+}
+
+/// Allocates a batch of blocks of random sizes, then frees them all.
+fn alloc_heavy(b: &mut Bencher, policy: CoalescePolicy) {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = arena(&temp_file, policy);
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut batch = Vec::with_capacity(SLOTS);
+    b.iter(|| unsafe {
+        for _ in 0..SLOTS {
+            let size = rng.gen_range(16..232);
+            batch.push((a.malloc(size, 8), size));
+        }
+        for (ptr, size) in batch.drain(..) {
+            a.free(ptr, size, 8);
+        }
+    });
+}
+
+#[bench]
+fn free_heavy_eager(b: &mut Bencher) {
+    free_heavy(b, CoalescePolicy::Eager);
+}
+
+#[bench]
+fn free_heavy_deferred(b: &mut Bencher) {
+    free_heavy(b, CoalescePolicy::Deferred);
+}
+
+#[bench]
+fn alloc_heavy_eager(b: &mut Bencher) {
+    alloc_heavy(b, CoalescePolicy::Eager);
+}
+
+#[bench]
+fn alloc_heavy_deferred(b: &mut Bencher) {
+    alloc_heavy(b, CoalescePolicy::Deferred);
+}
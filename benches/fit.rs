@@ -0,0 +1,50 @@
+//! Random large allocations and frees, about half of them live at a time,
+//! under each fit policy. The high-water mark of the arena after the run is
+//! printed as a rough measure of fragmentation.
+
+#![feature(test)]
+
+extern crate test;
+
+use disk_dlmalloc::{DiskDlmalloc, FitPolicy};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use tempfile::NamedTempFile;
+use test::Bencher;
+
+const SLOTS: usize = 1024;
+
+fn random_large(b: &mut Bencher, fit_policy: FitPolicy) {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 30)
+        .fit_policy(fit_policy)
+        .build()
+        .unwrap();
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut slots = vec![(std::ptr::null_mut::<u8>(), 0); SLOTS];
+    b.iter(|| unsafe {
+        let slot = &mut slots[rng.gen_range(0..SLOTS)];
+        if slot.0.is_null() {
+            let size = rng.gen_range(256..64 << 10);
+            *slot = (a.malloc(size, 8), size);
+        } else {
+            a.free(slot.0, slot.1, 8);
+            slot.0 = std::ptr::null_mut();
+        }
+    });
+    eprintln!("{:?}: high-water mark {} KiB", fit_policy, a.offset() >> 10);
+    for (ptr, size) in slots {
+        if !ptr.is_null() {
+            unsafe { a.free(ptr, size, 8) };
+        }
+    }
+}
+
+#[bench]
+fn best_fit(b: &mut Bencher) {
+    random_large(b, FitPolicy::BestFit);
+}
+
+#[bench]
+fn first_fit(b: &mut Bencher) {
+    random_large(b, FitPolicy::FirstFit);
+}
@@ -0,0 +1,50 @@
+//! Random allocations and frees of sizes just above the default small bin
+//! threshold, about half of them live at a time, with the threshold left
+//! alone and raised to cover them.
+
+#![feature(test)]
+
+extern crate test;
+
+use disk_dlmalloc::DiskDlmalloc;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use tempfile::NamedTempFile;
+use test::Bencher;
+
+const SLOTS: usize = 1024;
+
+fn just_above_default(b: &mut Bencher, threshold: Option<usize>) {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut builder = DiskDlmalloc::builder(temp_file.path(), 1 << 30);
+    if let Some(threshold) = threshold {
+        builder = builder.small_bin_threshold(threshold);
+    }
+    let a = builder.build().unwrap();
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut slots = vec![(std::ptr::null_mut::<u8>(), 0); SLOTS];
+    b.iter(|| unsafe {
+        let slot = &mut slots[rng.gen_range(0..SLOTS)];
+        if slot.0.is_null() {
+            let size = rng.gen_range(240..400);
+            *slot = (a.malloc(size, 8), size);
+        } else {
+            a.free(slot.0, slot.1, 8);
+            slot.0 = std::ptr::null_mut();
+        }
+    });
+    for (ptr, size) in slots {
+        if !ptr.is_null() {
+            unsafe { a.free(ptr, size, 8) };
+        }
+    }
+}
+
+#[bench]
+fn default_threshold(b: &mut Bencher) {
+    just_above_default(b, None);
+}
+
+#[bench]
+fn raised_threshold(b: &mut Bencher) {
+    just_above_default(b, Some(400));
+}
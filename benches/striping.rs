@@ -0,0 +1,51 @@
+//! Small allocations on one thread while another thread churns through 1 MB
+//! zeroed allocations (which clear memory with the lock held), with and
+//! without lock striping.
+
+#![feature(test)]
+
+extern crate test;
+
+use disk_dlmalloc::DiskDlmalloc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tempfile::NamedTempFile;
+use test::Bencher;
+
+fn small_allocs_with_large_churn(b: &mut Bencher, lock_striping: bool) {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 256 << 20)
+        .lock_striping(lock_striping)
+        .build()
+        .unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let churn = {
+        let a = a.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                unsafe {
+                    let ptr = a.calloc(1 << 20, 8);
+                    a.free(ptr, 1 << 20, 8);
+                }
+            }
+        })
+    };
+    b.iter(|| unsafe {
+        let ptr = a.malloc(32, 8);
+        a.free(test::black_box(ptr), 32, 8);
+    });
+    done.store(true, Ordering::Relaxed);
+    churn.join().unwrap();
+}
+
+#[bench]
+fn single_lock(b: &mut Bencher) {
+    small_allocs_with_large_churn(b, false);
+}
+
+#[bench]
+fn striped(b: &mut Bencher) {
+    small_allocs_with_large_churn(b, true);
+}
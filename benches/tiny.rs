@@ -0,0 +1,20 @@
+//! Tiny short-lived allocations on a single thread, where the cost of the
+//! allocator's locking is most visible.
+
+#![feature(test)]
+
+extern crate test;
+
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+use test::Bencher;
+
+#[bench]
+fn tiny_malloc_free(b: &mut Bencher) {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    b.iter(|| unsafe {
+        let ptr = a.malloc(16, 8);
+        a.free(test::black_box(ptr), 16, 8);
+    });
+}
@@ -0,0 +1,614 @@
+use crate::dlmalloc;
+use crate::sys::System;
+#[cfg(target_os = "linux")]
+use crate::uffd::PageHandler;
+use crate::{DiskDlmalloc, Error, Observer, ReloadableConfig, MALLOC_ALIGNMENT};
+use memmap2::Advice;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Configures and creates a [`DiskDlmalloc`].
+///
+/// Obtained from [`DiskDlmalloc::builder`]. Every option has a default
+/// matching what [`DiskDlmalloc::new`] does.
+pub struct Builder {
+    pub(crate) file_path: PathBuf,
+    pub(crate) total_size: usize,
+    pub(crate) config: ReloadableConfig,
+    pub(crate) lock_striping: bool,
+    pub(crate) lazy_free: bool,
+    pub(crate) observer: Option<Arc<dyn Observer>>,
+    pub(crate) base_address: Option<usize>,
+    pub(crate) fill_on_alloc: Option<u8>,
+    pub(crate) fill_on_free: Option<u8>,
+    pub(crate) shared: bool,
+    pub(crate) catch_sigbus: bool,
+    pub(crate) fit_policy: FitPolicy,
+    pub(crate) coalesce_policy: CoalescePolicy,
+    pub(crate) account_disk_space: bool,
+    pub(crate) pre_zero: bool,
+    pub(crate) mmap_threshold: usize,
+    pub(crate) calloc_punch_threshold: usize,
+    pub(crate) max_segments: usize,
+    pub(crate) small_bin_threshold: usize,
+    pub(crate) warn_on_leak: bool,
+    pub(crate) panic_on_leak: bool,
+    pub(crate) realloc_growth_factor: f64,
+    pub(crate) flush_on_free: bool,
+    pub(crate) retry_with_trim: bool,
+    pub(crate) writeback_limit: Option<usize>,
+    pub(crate) align_to_size_class: bool,
+    pub(crate) initial_align: usize,
+    pub(crate) file_lock: FileLock,
+    #[cfg(target_os = "linux")]
+    pub(crate) page_fault_handler: Option<Arc<PageHandler>>,
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fail_after_n_allocs: Option<usize>,
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fail_with_probability: Option<f64>,
+}
+
+/// How `dlmalloc` picks a free chunk for requests too large for its small
+/// bins (more than 232 bytes on 64-bit targets, unless raised with
+/// [`Builder::small_bin_threshold`]).
+///
+/// Small requests are always served from exact-size bins, so the policy only
+/// matters for workloads with many large allocations of varying sizes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// Search the size-sorted tree for the chunk that leaves the least
+    /// behind. Keeps fragmentation lowest and is what C `dlmalloc` does.
+    #[default]
+    BestFit,
+    /// Take the first chunk found on the way down the tree that's large
+    /// enough. Searches are shorter, but chunks get split more than they
+    /// need to be, which tends to scatter small free remainders around the
+    /// heap over time.
+    FirstFit,
+}
+
+/// When `dlmalloc` merges a freed chunk with free neighbours.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoalescePolicy {
+    /// Merge on every `free`, as C `dlmalloc` does, so free memory is
+    /// always in as few chunks as possible.
+    #[default]
+    Eager,
+    /// Set the chunks of small requests (see [`Builder::small_bin_threshold`])
+    /// aside on `free`, still marked in use, and hand them straight back to
+    /// the next request of the same size. They're merged with their
+    /// neighbours only when an allocation would otherwise need more of the
+    /// arena, or by `trim`. Larger chunks are merged right away as usual.
+    ///
+    /// Frees, and allocations of sizes recently freed, get cheaper, but
+    /// memory set aside can't serve requests of other sizes until it's
+    /// merged, so the heap tends to run closer to its limit before reusing
+    /// it.
+    Deferred,
+}
+
+/// The advisory lock [`Builder::file_lock`] takes on the backing file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileLock {
+    /// Take no lock, so nothing stops another allocator from opening, and
+    /// truncating, the same file.
+    #[default]
+    None,
+    /// Take a shared lock, which any number of holders can have at once but
+    /// not alongside an exclusive one.
+    Shared,
+    /// Take an exclusive lock, held by this allocator alone.
+    Exclusive,
+}
+
+impl Builder {
+    pub(crate) fn new<P: AsRef<Path>>(file_path: P, total_size: usize) -> Builder {
+        Builder {
+            file_path: file_path.as_ref().to_path_buf(),
+            total_size,
+            config: ReloadableConfig::default(),
+            lock_striping: false,
+            lazy_free: false,
+            observer: None,
+            base_address: None,
+            fill_on_alloc: None,
+            fill_on_free: None,
+            shared: true,
+            catch_sigbus: false,
+            fit_policy: FitPolicy::BestFit,
+            coalesce_policy: CoalescePolicy::Eager,
+            account_disk_space: false,
+            pre_zero: false,
+            mmap_threshold: 32 * 1024 * 1024,
+            calloc_punch_threshold: 1024 * 1024,
+            max_segments: usize::MAX,
+            small_bin_threshold: dlmalloc::DEFAULT_SMALL_THRESHOLD,
+            warn_on_leak: false,
+            panic_on_leak: false,
+            realloc_growth_factor: 2.0,
+            flush_on_free: false,
+            retry_with_trim: false,
+            writeback_limit: None,
+            align_to_size_class: false,
+            initial_align: MALLOC_ALIGNMENT,
+            file_lock: FileLock::None,
+            #[cfg(target_os = "linux")]
+            page_fault_handler: None,
+            #[cfg(feature = "fault-injection")]
+            fail_after_n_allocs: None,
+            #[cfg(feature = "fault-injection")]
+            fail_with_probability: None,
+        }
+    }
+
+    /// Sets the advice passed to `madvise` for the whole mapping. Defaults to
+    /// `Advice::Normal`.
+    pub fn mem_advise(mut self, mem_advise: Advice) -> Builder {
+        self.config.advice = mem_advise;
+        self
+    }
+
+    /// Maps the file with `MAP_SHARED` rather than `MAP_PRIVATE`. Defaults to
+    /// `true`.
+    ///
+    /// With a private mapping writes are copy-on-write and never reach the
+    /// file, which suits a scratch arena: the file only provides zeroed pages
+    /// to start from. That gives up everything that relies on the file, so
+    /// nothing persists past the allocator, other processes mapping the file
+    /// don't see the arena's contents, and `swap_backing` only carries over
+    /// what's been allocated.
+    pub fn shared(mut self, shared: bool) -> Builder {
+        self.shared = shared;
+        self
+    }
+
+    /// Limits [`DiskDlmalloc::available`] by the free space on the
+    /// filesystem holding the backing file. Defaults to `false`.
+    ///
+    /// The backing file is created sparse, so the space it claims is only
+    /// allocated on disk as pages are first written back. On a filling
+    /// filesystem, or with overcommit disabled, that can fail long before
+    /// `total_size` is reached, and the failure shows up as a `SIGBUS` when
+    /// the page is touched rather than as a failed allocation. With this
+    /// set, `available` counts at most what the filesystem can still hold,
+    /// so callers checking it first can back off in time. Allocation itself
+    /// isn't limited. Has no effect on private mappings, which don't write to
+    /// the file.
+    pub fn account_disk_space(mut self, enabled: bool) -> Builder {
+        self.account_disk_space = enabled;
+        self
+    }
+
+    /// Writes zeros over the whole backing file when it's created, and reads
+    /// them back to check, rather than trusting the filesystem to read the
+    /// newly extended file as zeros. Defaults to `false`.
+    ///
+    /// Local filesystems guarantee those zeros, but some network and overlay
+    /// filesystems have been known not to. On Linux this uses
+    /// `fallocate(FALLOC_FL_ZERO_RANGE)` where the filesystem supports it and
+    /// plain writes elsewhere. Either way the file is no longer sparse, so
+    /// it takes up its full size on disk, and building takes as long as
+    /// writing and reading the whole file. `build` fails if the zeros don't
+    /// read back.
+    ///
+    /// `calloc` clears the memory it returns regardless, as memory handed
+    /// out before may come back with its old contents; this only makes sure
+    /// the rest of the file starts out in a known state.
+    pub fn pre_zero(mut self, enabled: bool) -> Builder {
+        self.pre_zero = enabled;
+        self
+    }
+
+    /// Chooses how large requests are matched to free chunks. Defaults to
+    /// [`FitPolicy::BestFit`].
+    ///
+    /// On the `fit` benchmark (random sizes between 256 bytes and 64 KiB,
+    /// about half of them live at any time) first fit was around 5% faster
+    /// per operation and left a 2-3% higher high-water mark, so it's only
+    /// worth it where allocator speed matters more than arena size.
+    pub fn fit_policy(mut self, fit_policy: FitPolicy) -> Builder {
+        self.fit_policy = fit_policy;
+        self
+    }
+
+    /// Chooses when freed chunks are merged with their neighbours. Defaults
+    /// to [`CoalescePolicy::Eager`].
+    pub fn coalesce_policy(mut self, coalesce_policy: CoalescePolicy) -> Builder {
+        self.coalesce_policy = coalesce_policy;
+        self
+    }
+
+    /// Installs a `SIGBUS` handler that reports faults in the arena before
+    /// aborting. Defaults to `false`.
+    ///
+    /// Pages of a file mapping that no longer have file behind them, because
+    /// the file was truncated or its storage failed, raise `SIGBUS` when
+    /// touched, which kills the process without explanation. With this set,
+    /// such a fault prints the arena file and the offset that couldn't be
+    /// accessed to stderr and then aborts, so the crash can be diagnosed.
+    /// There's no way to safely resume after the fault, so it's still fatal.
+    ///
+    /// The handler is process-wide and installed the first time it's asked
+    /// for. `SIGBUS` outside any arena goes to whatever handler was there
+    /// before. Up to 64 mappings can be watched across all arenas; building
+    /// fails beyond that.
+    pub fn catch_sigbus(mut self, enabled: bool) -> Builder {
+        self.catch_sigbus = enabled;
+        self
+    }
+
+    /// Stripes the allocator lock by size class. Defaults to `false`.
+    ///
+    /// When enabled, small requests (those `dlmalloc` serves from its small
+    /// bins, see [`small_bin_threshold`](Builder::small_bin_threshold)) are
+    /// handled by a second,
+    /// independently locked `dlmalloc` instance, so threads allocating small
+    /// objects never wait behind a thread doing a slow large allocation. The
+    /// two instances carve their memory out of the same file.
+    ///
+    /// The cost is a second set of allocator state (about 1 KiB) and, once
+    /// the first small request arrives, a second segment in the arena of at
+    /// least 64 KiB that only small allocations can use. Free memory isn't
+    /// shared between the two halves either, so fragmentation can be
+    /// somewhat higher for mixed workloads.
+    pub fn lock_striping(mut self, enabled: bool) -> Builder {
+        self.lock_striping = enabled;
+        self
+    }
+
+    /// Releases memory given back by `trim` (or by a `free` that triggers an
+    /// automatic trim) with `MADV_FREE` rather than `MADV_DONTNEED`. Defaults
+    /// to `false`.
+    ///
+    /// `MADV_FREE` lets the kernel reclaim the pages lazily, only once it's
+    /// short on memory, which makes releasing memory much cheaper. The flip
+    /// side is that released pages may keep their old contents until they're
+    /// reclaimed or reused, so this is a poor fit if freed data must not
+    /// linger in memory. Only macOS accepts `MADV_FREE` for a file mapping,
+    /// and only a private one (see [`shared`](Builder::shared)); Linux takes
+    /// it for anonymous memory alone. Elsewhere `MADV_DONTNEED` is used
+    /// instead, as [`DiskDlmalloc::capabilities`] reports.
+    ///
+    /// In a private mapping, released pages lose the pattern written by
+    /// [`fill_on_free`](Builder::fill_on_free): right away with
+    /// `MADV_DONTNEED`, but with `MADV_FREE` only once the kernel reclaims
+    /// them, so until then such a page may read either way.
+    pub fn lazy_free(mut self, enabled: bool) -> Builder {
+        self.lazy_free = enabled;
+        self
+    }
+
+    /// Installs an [`Observer`] to be notified of allocator events.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> Builder {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Fills the bytes of every `malloc` result with `pattern` (`0xAB`, say)
+    /// so reads of uninitialized memory stand out. Defaults to `None`.
+    ///
+    /// This is a debugging aid and costs a pass over every allocation. It
+    /// doesn't apply to `calloc`, and when `realloc` grows a block only the
+    /// new bytes are filled.
+    pub fn fill_on_alloc(mut self, pattern: Option<u8>) -> Builder {
+        self.fill_on_alloc = pattern;
+        self
+    }
+
+    /// Fills the bytes of every freed block with `pattern` (`0xDD`, say) so
+    /// use-after-free reads stand out, like the MSVC debug heap does. Defaults
+    /// to `None`.
+    ///
+    /// `dlmalloc` keeps its free lists in the first few words of a free block,
+    /// so those bytes won't keep the pattern. Bytes released by a `realloc`
+    /// that shrinks in place aren't filled. In a private mapping, whole pages
+    /// that `trim` gives back lose the pattern too, right away or, with
+    /// [`lazy_free`](Builder::lazy_free), whenever the kernel reclaims them.
+    pub fn fill_on_free(mut self, pattern: Option<u8>) -> Builder {
+        self.fill_on_free = pattern;
+        self
+    }
+
+    /// Asks for the file to be mapped at `addr`, which must be page aligned.
+    /// Defaults to letting the kernel choose.
+    ///
+    /// The mapping never replaces one that's already there: if anything is
+    /// mapped in the range, `build` fails with [`Error::MappingOverlaps`].
+    /// If the kernel refuses for any other reason the file is mapped
+    /// wherever the kernel likes instead, and on platforms without
+    /// `MAP_FIXED_NOREPLACE` the kernel is free to treat `addr` as a mere
+    /// hint. Check [`DiskDlmalloc::to_ptr`] with offset 0 if you need to know
+    /// where the arena ended up.
+    pub fn base_address(mut self, addr: usize) -> Builder {
+        self.base_address = Some(addr);
+        self
+    }
+
+    /// Sets the size from which an allocation gets a region of the file to
+    /// itself instead of being carved out of the heap. Defaults to 32 MiB;
+    /// `usize::MAX` turns this off.
+    ///
+    /// Freeing such an allocation gives its whole region back at once, to be
+    /// reused by later allocations of any size, so a short-lived huge buffer
+    /// can't leave the heap fragmented or bloated. The price is rounding
+    /// each one up to whole pages.
+    pub fn mmap_threshold(mut self, bytes: usize) -> Builder {
+        self.mmap_threshold = bytes;
+        self
+    }
+
+    /// Fills each page of the arena on first access by calling `handler`
+    /// with its file offset and a zeroed buffer of one page to fill, for
+    /// arenas whose contents come lazily from somewhere else, such as the
+    /// network. Linux only.
+    ///
+    /// This registers the mapping with `userfaultfd` and serves its faults
+    /// on a thread of its own, so a thread touching a page for the first
+    /// time waits while `handler` runs. Only pages missing from the page
+    /// cache fault, so pages that already have contents in the file, like
+    /// those `swap_backing` copies over, don't go through `handler`; and
+    /// whatever `handler` fills in becomes part of the file. `handler` mustn't
+    /// touch the arena itself, and if it panics the process aborts.
+    ///
+    /// The kernel only supports this for files in `tmpfs` (such as under
+    /// `/dev/shm`) or `hugetlbfs`; with any other file `build` fails. Without
+    /// `CAP_SYS_PTRACE`, or `vm.unprivileged_userfaultfd` set, only faults
+    /// from user space are handled, so system calls reading or writing
+    /// arena pages that haven't been touched yet fail with `EFAULT`.
+    #[cfg(target_os = "linux")]
+    pub fn page_fault_handler(
+        mut self,
+        handler: impl Fn(usize, &mut [u8]) + Send + Sync + 'static,
+    ) -> Builder {
+        self.page_fault_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Pads the start of the arena so the first allocation it serves is
+    /// aligned to `align`, for callers that allocate one dominant buffer up
+    /// front and want it on a cache line or page boundary without asking
+    /// every allocation for that alignment. Defaults to twice the size of a
+    /// pointer, what every allocation is aligned to anyway, so no padding.
+    ///
+    /// The padding comes out of the arena and is never handed out, so it's
+    /// up to `align` bytes, and offsets start after it. Only the first
+    /// allocation after creation or `reset_keep_mapping` is affected, and
+    /// for alignments over the page size only as long as the arena isn't
+    /// moved by `swap_backing`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` isn't a power of two.
+    pub fn initial_align(mut self, align: usize) -> Builder {
+        assert!(align.is_power_of_two(), "initial_align must be a power of two");
+        self.initial_align = align;
+        self
+    }
+
+    /// Locks the backing file with `flock` when it's opened, before it's
+    /// truncated, and holds the lock until the allocator is dropped.
+    /// Defaults to [`FileLock::None`].
+    ///
+    /// If the lock is held incompatibly, by another allocator or anyone
+    /// else using `flock` on the file, `build` fails with
+    /// [`Error::Locked`] and leaves the file untouched. The lock is
+    /// advisory: it only keeps out those who ask for one, and it belongs to
+    /// the open file, so a second allocator in the same process conflicts
+    /// like one in another process would. `swap_backing` releases the lock
+    /// along with the old file and doesn't lock the new one.
+    pub fn file_lock(mut self, lock: FileLock) -> Builder {
+        self.file_lock = lock;
+        self
+    }
+
+    /// Sets the size from which `calloc` zeroes memory by punching it out of
+    /// the backing file rather than writing zeros over it. Defaults to 1 MiB;
+    /// `usize::MAX` turns this off.
+    ///
+    /// The punched pages read back as zeros from the now sparse file, and
+    /// only take up memory, or disk space, once touched, so a huge `calloc`
+    /// costs next to nothing until it's used. Bytes sharing a page with
+    /// something else are still zeroed by hand. Punching needs a shared
+    /// mapping on Linux, on a filesystem that supports it, and no
+    /// [`page_fault_handler`](Builder::page_fault_handler); anywhere else
+    /// `calloc` writes zeros as usual.
+    pub fn calloc_punch_threshold(mut self, bytes: usize) -> Builder {
+        self.calloc_punch_threshold = bytes;
+        self
+    }
+
+    /// Caps how many segments the heap may be made of. Defaults to
+    /// `usize::MAX`, no limit.
+    ///
+    /// Memory the heap gets that doesn't continue one of its segments, such
+    /// as a region mapped by `refresh_mapping` or automatic growth, starts a
+    /// new one, and every segment costs a little space and a step in every
+    /// walk of the heap. Once the cap is reached such memory is given back
+    /// and the allocation that wanted it fails, though growth of the file
+    /// that already happened isn't undone; memory continuing the newest
+    /// segment still extends it. With lock striping each heap has its own
+    /// cap, and allocations with a region of their own (see
+    /// [`mmap_threshold`](Builder::mmap_threshold)) don't count.
+    pub fn max_segments(mut self, max: usize) -> Builder {
+        self.max_segments = max;
+        self
+    }
+
+    /// Sets the largest request `dlmalloc` serves from its exact-size small
+    /// bins rather than its size-sorted trees. Defaults to 232 bytes on
+    /// 64-bit targets (116 on 32-bit ones), as in C `dlmalloc`.
+    ///
+    /// Small bins are a quicker path, both to allocate from and to free to,
+    /// so raising the cutoff helps workloads whose objects mostly sit a
+    /// little above it. Past the default each bin holds a single chunk size
+    /// that a tree would otherwise have served with a best fit, so freed
+    /// memory of those sizes is reused less flexibly. The request is rounded
+    /// up to the next chunk size, and only requests up to it are affected by
+    /// [`CoalescePolicy::Deferred`] and
+    /// [`lock_striping`](Builder::lock_striping).
+    ///
+    /// The bins past the default are there whether or not this is raised,
+    /// costing each heap under a kilobyte but no measurable time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is below the default, the smallest chunks the trees
+    /// can hold, or above 488 bytes on 64-bit targets (244 on 32-bit ones),
+    /// the largest the small bins can.
+    pub fn small_bin_threshold(mut self, size: usize) -> Builder {
+        assert!(
+            (dlmalloc::DEFAULT_SMALL_THRESHOLD..=dlmalloc::MAX_SMALL_THRESHOLD).contains(&size),
+            "small_bin_threshold must be between {} and {} bytes",
+            dlmalloc::DEFAULT_SMALL_THRESHOLD,
+            dlmalloc::MAX_SMALL_THRESHOLD
+        );
+        self.small_bin_threshold = size;
+        self
+    }
+
+    /// Sets how much [`DiskDlmalloc::realloc_amortized`] grows an allocation
+    /// by, as a multiple of its usable size, when asked for more than it
+    /// holds. Defaults to 2.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `factor` is less than 1.
+    pub fn realloc_growth_factor(mut self, factor: f64) -> Builder {
+        assert!(factor >= 1.0, "realloc_growth_factor must be at least 1");
+        self.realloc_growth_factor = factor;
+        self
+    }
+
+    /// Makes `free` and `deallocate` write the freed allocation's pages back
+    /// to the file with `msync` before giving the memory back, for records
+    /// that must be durable once the caller is done with them. Defaults to
+    /// `false`.
+    ///
+    /// This covers `free_all` and `release` too. Every free then waits on the
+    /// disk, though not while holding the heap lock. If the pages can't be
+    /// written back the memory is still freed, and the error goes to
+    /// [`Observer::on_flush_error`]. Without an observer the first such error
+    /// is returned by [`DiskDlmalloc::close`].
+    pub fn flush_on_free(mut self, flush: bool) -> Builder {
+        self.flush_on_free = flush;
+        self
+    }
+
+    /// Makes an allocation that finds no room trim every heap and try once
+    /// more before failing, or going to the overflow allocator. Defaults to
+    /// `false`. Applies to every way of allocating except
+    /// `try_malloc_timeout`, where a trim could hold it up past its
+    /// deadline.
+    ///
+    /// This is what callers would otherwise do by hand on running out of
+    /// memory: with [`lock_striping`](Builder::lock_striping), or with
+    /// automatic trimming turned off, free memory can sit at the end of one
+    /// heap where another can't use it until it's trimmed. A failing
+    /// allocation then costs a trim of every heap.
+    pub fn retry_with_trim(mut self, retry: bool) -> Builder {
+        self.retry_with_trim = retry;
+        self
+    }
+
+    /// Has the allocator write freshly allocated memory back to the file
+    /// itself, `limit` bytes at a time, instead of leaving it all to the
+    /// kernel. Defaults to `None`, which leaves it to the kernel.
+    ///
+    /// A burst of allocations can dirty more pages than the disk keeps up
+    /// with, until the kernel stalls every writer at once to catch up. With
+    /// a limit, memory counts as dirty once it's allocated, and each time
+    /// another `limit` bytes have been, the allocating thread starts
+    /// writeback of those with `sync_file_range` and waits for the batch
+    /// before them to finish. That keeps at most about twice `limit` bytes
+    /// waiting on the disk, spreading the cost over the burst. Failed
+    /// writeback is ignored, as the kernel writes the pages back anyway.
+    /// Private mappings and in-memory arenas have nothing to write back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is `Some(0)`.
+    pub fn writeback_limit(mut self, limit: Option<usize>) -> Builder {
+        assert!(limit != Some(0), "writeback_limit must be positive");
+        self.writeback_limit = limit;
+        self
+    }
+
+    /// Aligns every allocation to its size rounded up to a power of two, up
+    /// to the page size, whatever alignment was asked for, so that objects
+    /// of one size class never straddle more cache lines or pages than they
+    /// must. Defaults to `false`.
+    ///
+    /// A `malloc(64, 1)` then returns a 64-byte aligned pointer. Requests
+    /// that end up aligned past what `dlmalloc` guarantees anyway go through
+    /// its slower `memalign` path and waste more space. With the `redzones`
+    /// feature the canary in front of each allocation is widened to keep
+    /// the alignment. [`DiskDlmalloc::relocate`] leaves such allocations
+    /// where they are.
+    pub fn align_to_size_class(mut self, align: bool) -> Builder {
+        self.align_to_size_class = align;
+        self
+    }
+
+    /// Reports allocations still live when the last handle to the allocator
+    /// is dropped to [`Observer::on_leak`]. Defaults to `false`. Needs an
+    /// [`observer`](Builder::observer): without one, `build` fails.
+    ///
+    /// Dropping the allocator walks the whole heap when this or
+    /// [`panic_on_leak`](Builder::panic_on_leak) is set. Everything
+    /// `dlmalloc` handed out counts, finished streams and magic rings
+    /// included, but `malloc_high`'s allocations aren't tracked and so are
+    /// never reported.
+    pub fn warn_on_leak(mut self, enabled: bool) -> Builder {
+        self.warn_on_leak = enabled;
+        self
+    }
+
+    /// Panics when the last handle to the allocator is dropped with
+    /// allocations still live, with a message giving their count and total
+    /// size. Defaults to `false`. Meant for test suites.
+    ///
+    /// Nothing is checked when the handle is dropped by a panic unwinding,
+    /// as a second panic would abort. See also
+    /// [`warn_on_leak`](Builder::warn_on_leak), which is reported first if
+    /// both are set.
+    pub fn panic_on_leak(mut self, enabled: bool) -> Builder {
+        self.panic_on_leak = enabled;
+        self
+    }
+
+    /// Makes every allocation after the first `n` fail, as if the arena were
+    /// full. Defaults to `None`. Requires the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    pub fn fail_after_n_allocs(mut self, n: Option<usize>) -> Builder {
+        self.fail_after_n_allocs = n;
+        self
+    }
+
+    /// Makes each allocation fail with probability `p`, as if the arena were
+    /// full. Defaults to `None`. The random sequence is the same on every
+    /// run, so failures are reproducible for a given order of allocations.
+    /// Requires the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    pub fn fail_with_probability(mut self, p: Option<f64>) -> Builder {
+        self.fail_with_probability = p;
+        self
+    }
+
+    /// Creates the backing file and maps it, returning the allocator.
+    ///
+    /// Any existing file at the path is truncated. Fails with
+    /// [`io::ErrorKind::InvalidInput`] if
+    /// [`warn_on_leak`](Builder::warn_on_leak) is set without an observer.
+    pub fn build(self) -> Result<DiskDlmalloc, Error> {
+        if self.warn_on_leak && self.observer.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "warn_on_leak needs an observer to report to",
+            )
+            .into());
+        }
+        let system = System::new(&self)?;
+        Ok(DiskDlmalloc::from_system(system, &self))
+    }
+}
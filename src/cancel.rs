@@ -0,0 +1,31 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Asks a long-running operation such as
+/// [`DiskDlmalloc::migrate_to_cancellable`](crate::DiskDlmalloc::migrate_to_cancellable)
+/// or
+/// [`DiskDlmalloc::compact_preserving_order_cancellable`](crate::DiskDlmalloc::compact_preserving_order_cancellable)
+/// to stop early, from any thread.
+///
+/// Clones share the same flag, so keep one and hand another to the
+/// operation. Cancelling is sticky: a token stays cancelled once it is.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token that isn't cancelled.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Makes operations checking this token, or a clone of it, return
+    /// [`Error::Cancelled`](crate::Error::Cancelled) at their next check.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `cancel` was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
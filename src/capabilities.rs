@@ -0,0 +1,60 @@
+use crate::DiskDlmalloc;
+
+/// What an arena is backed by, as reported by
+/// [`DiskDlmalloc::capabilities`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Backend {
+    /// A memory-mapped file, from [`DiskDlmalloc::new`] or
+    /// [`DiskDlmalloc::builder`].
+    File,
+    /// Ordinary heap memory, from [`DiskDlmalloc::new_in_memory`].
+    Memory,
+}
+
+/// What the arena can do on this platform and build, as returned by
+/// [`DiskDlmalloc::capabilities`], for portable code that wants to skip
+/// what isn't there rather than handle the errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// What the arena is backed by. Parts from
+    /// [`DiskDlmalloc::split`] report their parent's.
+    pub backend: Backend,
+    /// Whether `dlmalloc` can move or resize its directly mapped chunks in
+    /// place, `mremap` style, when they're reallocated. Never, for now:
+    /// they're copied instead.
+    pub can_remap: bool,
+    /// Whether freed memory can be punched out of the file with
+    /// `fallocate`, as [`DiskDlmalloc::release`] and `calloc` of large
+    /// sizes do. Only on Linux, for a shared file mapping; the filesystem
+    /// may still refuse.
+    pub can_punch_hole: bool,
+    /// Whether [`Builder::lazy_free`](crate::Builder::lazy_free) can use
+    /// `MADV_FREE`, which takes a private mapping on macOS. Linux only
+    /// accepts it for anonymous memory, never a file mapping. It falls back
+    /// to `MADV_DONTNEED` otherwise.
+    pub can_madv_free: bool,
+    /// Whether pages can be locked into memory, as
+    /// [`DiskDlmalloc::lock_metadata`] does. Not for heap memory, which
+    /// isn't mapped by the arena.
+    pub supports_mlock: bool,
+}
+
+impl DiskDlmalloc {
+    /// Reports what this arena can do, given the platform it was compiled
+    /// for and how it was created. These are what the build supports, not
+    /// a promise: the kernel or filesystem can still refuse at run time.
+    pub fn capabilities(&self) -> Capabilities {
+        let system = &self.0.system;
+        let file = !system.is_in_memory();
+        let shared = file && system.is_shared();
+        Capabilities {
+            backend: if file { Backend::File } else { Backend::Memory },
+            can_remap: false,
+            can_punch_hole: cfg!(target_os = "linux") && shared,
+            can_madv_free: cfg!(target_os = "macos") && file && !shared,
+            supports_mlock: file,
+        }
+    }
+}
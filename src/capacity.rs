@@ -0,0 +1,67 @@
+use crate::DiskDlmalloc;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{self, Ordering};
+use std::task::{Context, Poll};
+
+/// Lets async code wait for space in the arena instead of polling for it.
+///
+/// Obtained from [`DiskDlmalloc::capacity_watcher`].
+#[derive(Clone)]
+pub struct CapacityWatcher {
+    alloc: DiskDlmalloc,
+}
+
+impl DiskDlmalloc {
+    /// Returns a [`CapacityWatcher`] for this arena.
+    pub fn capacity_watcher(&self) -> CapacityWatcher {
+        CapacityWatcher {
+            alloc: self.clone(),
+        }
+    }
+}
+
+impl CapacityWatcher {
+    /// Returns a future that resolves once at least `bytes` are
+    /// [`available`](DiskDlmalloc::available).
+    ///
+    /// The future is woken whenever memory is freed, so it works with any
+    /// executor and never blocks a thread. Space isn't reserved, so another
+    /// allocation can still claim it before the waiting task gets to run;
+    /// callers should be prepared to wait again if their allocation fails.
+    pub fn until_available(&self, bytes: usize) -> UntilAvailable<'_> {
+        UntilAvailable {
+            watcher: self,
+            bytes,
+        }
+    }
+}
+
+/// Future returned by [`CapacityWatcher::until_available`].
+pub struct UntilAvailable<'a> {
+    watcher: &'a CapacityWatcher,
+    bytes: usize,
+}
+
+impl Future for UntilAvailable<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let shared = &self.watcher.alloc.0;
+        // Register before checking so a free landing in between can't be
+        // missed.
+        {
+            let mut waiters = shared.waiters.lock().unwrap();
+            if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+            shared.has_waiters.store(true, Ordering::Relaxed);
+        }
+        atomic::fence(Ordering::SeqCst);
+        if shared.available() >= self.bytes {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{CancellationToken, DiskDlmalloc, Error};
+use core::mem;
+
+impl DiskDlmalloc {
+    /// Removes the free gaps between live allocations by sliding each one
+    /// down over the free space in front of it, keeping them all in the
+    /// order they were in, for append-heavy logs whose readers rely on
+    /// entries being laid out in the order they were written. Reports where
+    /// each moved allocation went by calling `fixup` with its old and new
+    /// offset, once everything has moved, with the allocator unlocked.
+    ///
+    /// The free space of the heap ends up in one piece at its end, which
+    /// [`trim`](DiskDlmalloc::trim) can then give back. Offsets are those of
+    /// the start of each allocation as in [`migrate_to`]. Allocations only
+    /// keep their order within their heap: with lock striping, the two heaps
+    /// are compacted separately. Allocations aligned beyond
+    /// [`malloc_alignment`], such as those from `alloc_thp` or backing an
+    /// `alloc_magic_ring`, don't move, and neither do those that have a
+    /// segment of their own, from `malloc_high` or from an overflow
+    /// allocator.
+    ///
+    /// [`migrate_to`]: DiskDlmalloc::migrate_to
+    /// [`malloc_alignment`]: DiskDlmalloc::malloc_alignment
+    ///
+    /// # Safety
+    ///
+    /// Nothing may allocate, free or use any allocation of this allocator
+    /// while this runs, and pointers into moved allocations are dangling
+    /// once it returns.
+    pub unsafe fn compact_preserving_order(&self, fixup: impl FnMut(usize, usize)) {
+        // Nothing else holds the token, so this can't fail.
+        let _ = self.compact_preserving_order_cancellable(fixup, &CancellationToken::new());
+    }
+
+    /// Like `compact_preserving_order`, but checks `cancel` before moving
+    /// each allocation and returns [`Error::Cancelled`] once it's cancelled,
+    /// so a service shutting down needn't wait for a large heap to be
+    /// compacted.
+    ///
+    /// A cancelled compaction leaves the heap consistent, with the
+    /// allocations moved so far where they went and the rest where they
+    /// were. `fixup` is still called for each one that moved, as those
+    /// pointers are dangling either way.
+    ///
+    /// # Safety
+    ///
+    /// As for `compact_preserving_order`.
+    pub unsafe fn compact_preserving_order_cancellable(
+        &self,
+        mut fixup: impl FnMut(usize, usize),
+        cancel: &CancellationToken,
+    ) -> Result<(), Error> {
+        let mut moved = Vec::new();
+        let mut finished = true;
+        for heap in self.0.heaps() {
+            let mut heap = self.0.lock(heap);
+            let heap = &mut *heap;
+            let sys = &self.0.system;
+            finished = heap.dl.slide_down(
+                || cancel.is_cancelled(),
+                |old, new, usable| {
+                    if cfg!(feature = "shadow") {
+                        // Moves only go down, and in address order, so
+                        // poisoning where a chunk was can't hide one that
+                        // already moved.
+                        let header = mem::size_of::<usize>();
+                        heap.shadow.poison(sys, old.sub(header), old.add(usable));
+                        heap.shadow.unpoison(sys, new, new.add(usable));
+                    }
+                    moved.push((sys.to_offset(old).unwrap(), sys.to_offset(new).unwrap()));
+                },
+            );
+            if !finished {
+                break;
+            }
+        }
+        for (old, new) in moved {
+            fixup(old, new);
+        }
+        if finished {
+            Ok(())
+        } else {
+            Err(Error::Cancelled)
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{Builder, DiskDlmalloc};
+use memmap2::Advice;
+use std::io;
+
+/// The allocator settings that can be changed while it's running, with
+/// [`DiskDlmalloc::reload`], e.g. from a `SIGHUP` handler thread.
+///
+/// Pass one to [`Builder::config`] for the initial values. The defaults
+/// match [`DiskDlmalloc::new`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReloadableConfig {
+    /// The advice passed to `madvise` for the whole mapping, see
+    /// [`Builder::mem_advise`].
+    pub advice: Advice,
+    /// How much free memory has to collect at the end of the heap before
+    /// `free` gives it back to the file. Defaults to 2 MiB.
+    pub trim_threshold: usize,
+    /// Whether `free` trims the heap at all. Defaults to `true`. When
+    /// disabled memory is only given back by explicit calls to
+    /// [`DiskDlmalloc::trim`].
+    pub auto_trim: bool,
+}
+
+impl Default for ReloadableConfig {
+    fn default() -> ReloadableConfig {
+        ReloadableConfig {
+            advice: Advice::Normal,
+            trim_threshold: 2 * 1024 * 1024,
+            auto_trim: true,
+        }
+    }
+}
+
+impl ReloadableConfig {
+    /// The threshold `dlmalloc` should use.
+    pub(crate) fn effective_trim_threshold(&self) -> usize {
+        if self.auto_trim {
+            self.trim_threshold
+        } else {
+            usize::MAX
+        }
+    }
+}
+
+impl Builder {
+    /// Sets the initial [`ReloadableConfig`]. This replaces any advice set
+    /// with [`mem_advise`](Builder::mem_advise).
+    pub fn config(mut self, config: ReloadableConfig) -> Builder {
+        self.config = config;
+        self
+    }
+}
+
+impl DiskDlmalloc {
+    /// Applies `config` to the running allocator.
+    ///
+    /// The update is atomic: every heap is locked while it's applied, so no
+    /// allocation sees a mix of old and new settings. If the new advice can't
+    /// be applied nothing changes and the error is returned.
+    pub fn reload(&self, config: ReloadableConfig) -> io::Result<()> {
+        let mut current = self.0.config.lock().unwrap();
+        let mut heaps: Vec<_> = self.0.heaps().map(|heap| self.0.lock(heap)).collect();
+        if config.advice != current.advice {
+            self.0.system.set_advice(config.advice)?;
+        }
+        for heap in &mut heaps {
+            heap.dl
+                .set_trim_threshold(config.effective_trim_threshold());
+        }
+        *current = config;
+        Ok(())
+    }
+}
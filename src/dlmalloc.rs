@@ -22,11 +22,12 @@ macro_rules! debug_assert_eq {
 use core::cmp;
 use core::mem;
 use core::ptr;
+use std::collections::BTreeSet;
 
 use crate::SystemAllocator;
 
 pub struct Dlmalloc<A> {
-    smallmap: u32,
+    smallmap: u64,
     treemap: u32,
     smallbins: [*mut Chunk; (NSMALLBINS + 1) * 2],
     treebins: [*mut TreeChunk; NTREEBINS],
@@ -38,21 +39,62 @@ pub struct Dlmalloc<A> {
     max_footprint: usize,
     seg: Segment,
     trim_check: usize,
+    trim_threshold: usize,
+    mmap_threshold: usize,
+    max_segments: usize,
+    strict_shrink: bool,
+    // Free chunks up to this size go in the small bins, larger ones in the
+    // trees. C `dlmalloc` fixes it at `min_large_size() - 1`; here it can be
+    // raised to use the small bins past that, which start out empty.
+    max_small_size: usize,
+    // Chunks with a segment of their own, see `mmap_alloc`. The C version
+    // doesn't keep track of them, but `chunk_containing` needs to.
+    mmapped: Vec<*mut Chunk>,
+    // In-use chunks from `memalign`, whose alignment `slide_down` has to
+    // keep and, as nothing records it, keeps by not moving them.
+    aligned: BTreeSet<*mut Chunk>,
     least_addr: *mut u8,
     release_checks: usize,
+    first_fit: bool,
+    // With deferred coalescing, small chunks `free` set aside without
+    // merging them with their neighbours, one LIFO list per small bin linked
+    // through `prev`. They stay marked in use, with `FLAG4` set, until
+    // `coalesce_deferred` frees them for real. `deferred_map` has a bit set
+    // for each non-empty list, like `smallmap`.
+    defer_coalescing: bool,
+    deferred: [*mut Chunk; NSMALLBINS],
+    deferred_map: u64,
     system_allocator: A,
 }
 unsafe impl<A: Send> Send for Dlmalloc<A> {}
 
-// TODO: document this
-const NSMALLBINS: usize = 32;
-const NTREEBINS: usize = 32;
+// Twice C `dlmalloc`'s 32, so that `set_small_threshold` has empty bins to
+// raise the cutoff into. Every heap carries them whether or not it does:
+// 776 more bytes of bin headers, deferred lists and maps on 64-bit targets,
+// with `smallmap` and `deferred_map` widened to 64 bits. Churning requests
+// below, across and well past the default cutoff measured the same speed
+// either way.
+pub const NSMALLBINS: usize = 64;
+pub const NTREEBINS: usize = 32;
 const SMALLBIN_SHIFT: usize = 3;
 const TREEBIN_SHIFT: usize = 8;
 
 const NSMALLBINS_U32: u32 = NSMALLBINS as u32;
 const NTREEBINS_U32: u32 = NTREEBINS as u32;
 
+/// The largest request served from the small bins unless raised with
+/// `set_small_threshold`.
+pub const DEFAULT_SMALL_THRESHOLD: usize = small_threshold((1 << TREEBIN_SHIFT) - 1);
+/// The largest request `set_small_threshold` accepts, the last small bin
+/// being the limit.
+pub const MAX_SMALL_THRESHOLD: usize = small_threshold((NSMALLBINS << SMALLBIN_SHIFT) - 1);
+
+/// The largest request whose chunk is at most `max_small_size`.
+const fn small_threshold(max_small_size: usize) -> usize {
+    let word = mem::size_of::<usize>();
+    max_small_size - (2 * word - 1) - word
+}
+
 // TODO: runtime configurable? documentation?
 const DEFAULT_GRANULARITY: usize = 64 * 1024;
 const DEFAULT_TRIM_THRESHOLD: usize = 2 * 1024 * 1024;
@@ -96,6 +138,14 @@ fn least_bit(x: u32) -> u32 {
     x & (!x + 1)
 }
 
+fn left_bits64(x: u64) -> u64 {
+    (x << 1) | (!(x << 1)).wrapping_add(1)
+}
+
+fn least_bit64(x: u64) -> u64 {
+    x & (!x + 1)
+}
+
 fn leftshift_for_tree_index(x: u32) -> u32 {
     let x = usize::try_from(x).unwrap();
     if x == NTREEBINS - 1 {
@@ -125,8 +175,19 @@ impl<A> Dlmalloc<A> {
                 flags: 0,
             },
             trim_check: 0,
+            trim_threshold: DEFAULT_TRIM_THRESHOLD,
+            mmap_threshold: usize::MAX,
+            max_segments: usize::MAX,
+            strict_shrink: false,
+            max_small_size: (1 << TREEBIN_SHIFT) - 1,
+            mmapped: Vec::new(),
+            aligned: BTreeSet::new(),
             least_addr: ptr::null_mut(),
             release_checks: 0,
+            first_fit: false,
+            defer_coalescing: false,
+            deferred: [ptr::null_mut(); NSMALLBINS],
+            deferred_map: 0,
             system_allocator,
         }
     }
@@ -138,6 +199,94 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         mem::size_of::<usize>() * 2
     }
 
+    /// Makes large requests take the first chunk found in the tree bins
+    /// that fits instead of searching for the best fit.
+    pub fn set_first_fit(&mut self, first_fit: bool) {
+        self.first_fit = first_fit;
+    }
+
+    /// Makes `free` set small chunks aside for reuse by requests of the same
+    /// size instead of merging them with their neighbours, leaving that to
+    /// when the heap would otherwise have to grow, or to `trim`.
+    pub unsafe fn set_defer_coalescing(&mut self, defer: bool) {
+        if !defer {
+            self.coalesce_deferred();
+        }
+        self.defer_coalescing = defer;
+    }
+
+    /// Frees the chunks set aside with deferred coalescing, merging them
+    /// with their neighbours. Returns how many bytes they held.
+    pub unsafe fn coalesce_deferred(&mut self) -> usize {
+        if self.deferred_map == 0 {
+            return 0;
+        }
+        let mut bytes = 0;
+        for idx in 0..NSMALLBINS {
+            let mut p = mem::replace(&mut self.deferred[idx], ptr::null_mut());
+            while !p.is_null() {
+                let next = (*p).prev;
+                (*p).head &= !FLAG4;
+                bytes += Chunk::size(p);
+                self.free_chunk(p);
+                p = next;
+            }
+        }
+        self.deferred_map = 0;
+        bytes
+    }
+
+    /// Sets how large the top chunk has to grow before `free` trims it,
+    /// `mallopt(M_TRIM_THRESHOLD)` in C.
+    pub fn set_trim_threshold(&mut self, threshold: usize) {
+        self.trim_threshold = threshold;
+        self.trim_check = threshold;
+    }
+
+    /// Sets the request size from which chunks get a segment of their own,
+    /// `mallopt(M_MMAP_THRESHOLD)` in C. Defaults to `usize::MAX`, never.
+    pub fn set_mmap_threshold(&mut self, threshold: usize) {
+        self.mmap_threshold = threshold;
+    }
+
+    /// Sets how many segments the heap may have, beyond which requests that
+    /// would need another fail. Defaults to `usize::MAX`, no limit.
+    pub fn set_max_segments(&mut self, max: usize) {
+        self.max_segments = max;
+    }
+
+    /// Makes `realloc` give back the surplus of a shrunk chunk with a segment
+    /// of its own as soon as it's a page, rather than only past twice the
+    /// granularity. Such chunks can't be split, so they're moved to a
+    /// smaller one. Off by default.
+    pub fn set_strict_shrink(&mut self, strict: bool) {
+        self.strict_shrink = strict;
+    }
+
+    /// Serves requests up to `size` bytes, rounded up to the next chunk
+    /// size, from the small bins rather than the trees. Must be called
+    /// before the first allocation. Defaults to `DEFAULT_SMALL_THRESHOLD`.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `size` is between `DEFAULT_SMALL_THRESHOLD` and
+    /// `MAX_SMALL_THRESHOLD`: the trees can't hold chunks below
+    /// `min_large_size`, nor the small bins ones past the last of them.
+    pub fn set_small_threshold(&mut self, size: usize) {
+        assert!(
+            (DEFAULT_SMALL_THRESHOLD..=MAX_SMALL_THRESHOLD).contains(&size),
+            "small threshold must be between {} and {} bytes",
+            DEFAULT_SMALL_THRESHOLD,
+            MAX_SMALL_THRESHOLD
+        );
+        debug_assert!(self.seg.base.is_null());
+        self.max_small_size = self.pad_request(size) + self.malloc_alignment() - 1;
+    }
+
+    pub fn system_allocator(&self) -> &A {
+        &self.system_allocator
+    }
+
     // TODO: dox
     fn chunk_overhead(&self) -> usize {
         mem::size_of::<usize>()
@@ -154,11 +303,11 @@ impl<A: SystemAllocator> Dlmalloc<A> {
 
     // TODO: dox
     fn max_small_size(&self) -> usize {
-        self.min_large_size() - 1
+        self.max_small_size
     }
 
     // TODO: dox
-    fn max_small_request(&self) -> usize {
+    pub fn max_small_request(&self) -> usize {
         self.max_small_size() - (self.malloc_alignment() - 1) - self.chunk_overhead()
     }
 
@@ -204,7 +353,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
     }
 
     fn is_small(&self, s: usize) -> bool {
-        s >> SMALLBIN_SHIFT < NSMALLBINS
+        s <= self.max_small_size
     }
 
     fn is_aligned(&self, a: usize) -> bool {
@@ -252,6 +401,13 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         }
     }
 
+    /// Returns how many bytes of the in-use chunk at `mem` can be used,
+    /// like C's `malloc_usable_size`.
+    pub unsafe fn usable_size(&self, mem: *mut u8) -> usize {
+        let p = Chunk::from_mem(mem);
+        Chunk::size(p) - self.overhead_for(p)
+    }
+
     pub unsafe fn calloc_must_clear(&self, ptr: *mut u8) -> bool {
         !self.system_allocator.allocates_zeros() || !Chunk::mmapped(Chunk::from_mem(ptr))
     }
@@ -263,6 +419,17 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         if size <= self.max_small_request() {
             nb = self.request2size(size);
             let mut idx = self.small_index(nb);
+            if self.deferred_map & (1 << idx) != 0 {
+                let p = self.deferred[idx as usize];
+                self.deferred[idx as usize] = (*p).prev;
+                if (*p).prev.is_null() {
+                    self.deferred_map &= !(1 << idx);
+                }
+                (*p).head &= !FLAG4;
+                let ret = Chunk::to_mem(p);
+                self.check_malloced_chunk(ret, nb);
+                return ret;
+            }
             let smallbits = self.smallmap >> idx;
 
             // Check the bin for `idx` (the lowest bit) but also check the next
@@ -270,7 +437,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
             if smallbits & 0b11 != 0 {
                 // If our the lowest bit, our `idx`, is unset then bump up the
                 // index as we'll be using the next bucket up.
-                idx += !smallbits & 1;
+                idx += (!smallbits & 1) as u32;
 
                 let b = self.smallbin_at(idx);
                 let p = (*b).prev;
@@ -286,8 +453,8 @@ impl<A: SystemAllocator> Dlmalloc<A> {
                 // If there's some other bin with some memory, then we just use
                 // the next smallest bin
                 if smallbits != 0 {
-                    let leftbits = (smallbits << idx) & left_bits(1 << idx);
-                    let leastbit = least_bit(leftbits);
+                    let leftbits = (smallbits << idx) & left_bits64(1 << idx);
+                    let leastbit = least_bit64(leftbits);
                     let i = leastbit.trailing_zeros();
                     let b = self.smallbin_at(i);
                     let p = (*b).prev;
@@ -355,18 +522,13 @@ impl<A: SystemAllocator> Dlmalloc<A> {
 
         // Split the top node if we can
         if nb < self.topsize {
-            self.topsize -= nb;
-            let rsize = self.topsize;
-            let p = self.top;
-            self.top = Chunk::plus_offset(p, nb);
-            let r = self.top;
-            (*r).head = rsize | PINUSE;
-            Chunk::set_size_and_pinuse_of_inuse_chunk(p, nb);
-            self.check_top_chunk(self.top);
-            let ret = Chunk::to_mem(p);
-            self.check_malloced_chunk(ret, nb);
-            self.check_malloc_state();
-            return ret;
+            return self.split_top(nb);
+        }
+
+        // Chunks set aside may merge into enough space, so try again with
+        // them freed before asking for more.
+        if self.coalesce_deferred() > 0 {
+            return self.malloc(size);
         }
 
         self.sys_alloc(nb)
@@ -375,6 +537,13 @@ impl<A: SystemAllocator> Dlmalloc<A> {
     /// allocates system resources
     unsafe fn sys_alloc(&mut self, size: usize) -> *mut u8 {
         self.check_malloc_state();
+        // Directly map large chunks, so they can be given back whole
+        if size >= self.mmap_threshold {
+            let mem = self.mmap_alloc(size);
+            if !mem.is_null() {
+                return mem;
+            }
+        }
         // keep in sync with max_request
         let asize = align_up(
             size + self.top_foot_size() + self.malloc_alignment(),
@@ -428,6 +597,10 @@ impl<A: SystemAllocator> Dlmalloc<A> {
                     (*sp).base = tbase;
                     (*sp).size += tsize;
                     return self.prepend_alloc(tbase, oldbase, size);
+                } else if self.segment_count() >= self.max_segments {
+                    self.footprint -= tsize;
+                    self.system_allocator.free(tbase, tsize);
+                    return ptr::null_mut();
                 } else {
                     self.add_segment(tbase, tsize, flags);
                 }
@@ -449,7 +622,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
             return ret;
         }
 
-        return ptr::null_mut();
+        ptr::null_mut()
     }
 
     pub unsafe fn realloc(&mut self, oldmem: *mut u8, bytes: usize) -> *mut u8 {
@@ -469,7 +642,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
             ptr::copy_nonoverlapping(oldmem, ptr, cmp::min(oc, bytes));
             self.free(oldmem);
         }
-        return ptr;
+        ptr
     }
 
     unsafe fn try_realloc_chunk(&mut self, p: *mut Chunk, nb: usize, can_move: bool) -> *mut Chunk {
@@ -522,7 +695,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
                 self.dvsize = 0;
                 self.dv = ptr::null_mut();
             }
-            return p;
+            p
         } else if !Chunk::cinuse(next) {
             // extend into the next free chunk
             let nextsize = Chunk::size(next);
@@ -554,7 +727,12 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         }
 
         // Keep the old chunk if it's big enough but not too big
-        if oldsize >= nb + mem::size_of::<usize>() && (oldsize - nb) <= (DEFAULT_GRANULARITY << 1) {
+        let max_surplus = if self.strict_shrink {
+            self.system_allocator.page_size() - 1
+        } else {
+            DEFAULT_GRANULARITY << 1
+        };
+        if oldsize >= nb + mem::size_of::<usize>() && (oldsize - nb) <= max_surplus {
             return oldp;
         }
 
@@ -580,7 +758,49 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         self.footprint = self.footprint + newmmsize - oldmmsize;
         self.max_footprint = cmp::max(self.max_footprint, self.footprint);
         self.check_mmapped_chunk(newp);
-        return newp;
+        newp
+    }
+
+    /// Allocates a chunk of `nb` bytes in a segment of its own, which `free`
+    /// hands straight back to the system allocator.
+    unsafe fn mmap_alloc(&mut self, nb: usize) -> *mut u8 {
+        let mmsize = self.mmap_align(nb + 6 * mem::size_of::<usize>() + self.malloc_alignment() - 1);
+        if mmsize <= nb {
+            return ptr::null_mut();
+        }
+        let (mm, mmsize, _) = self.system_allocator.alloc(mmsize);
+        if mm.is_null() {
+            return ptr::null_mut();
+        }
+        let offset = self.align_offset(Chunk::to_mem(mm.cast()));
+        let psize = mmsize - offset - self.mmap_foot_pad();
+        let p = mm.add(offset).cast::<Chunk>();
+        (*p).prev_foot = offset;
+        (*p).head = psize;
+        (*Chunk::plus_offset(p, psize)).head = Chunk::fencepost_head();
+        (*Chunk::plus_offset(p, psize + mem::size_of::<usize>())).head = 0;
+        if self.least_addr.is_null() || mm < self.least_addr {
+            self.least_addr = mm;
+        }
+        self.footprint += mmsize;
+        self.max_footprint = cmp::max(self.max_footprint, self.footprint);
+        self.mmapped.push(p);
+        self.check_mmapped_chunk(p);
+        Chunk::to_mem(p)
+    }
+
+    /// Like `malloc`, but always gives the chunk a segment of its own.
+    pub unsafe fn malloc_mapped(&mut self, size: usize) -> *mut u8 {
+        if size >= self.max_request() {
+            return ptr::null_mut();
+        }
+        let nb = self.pad_request(size);
+        self.mmap_alloc(nb)
+    }
+
+    /// Whether the chunk at `mem` has a segment of its own.
+    pub unsafe fn is_mapped(&self, mem: *mut u8) -> bool {
+        Chunk::mmapped(Chunk::from_mem(mem))
     }
 
     fn mmap_align(&self, a: usize) -> usize {
@@ -624,6 +844,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
             if Chunk::mmapped(p) {
                 (*newp).prev_foot = (*p).prev_foot + leadsize;
                 (*newp).head = newsize;
+                self.replace_mmapped(p, newp);
             } else {
                 // give back the leader, use the rest
                 Chunk::set_inuse(newp, newsize);
@@ -645,11 +866,14 @@ impl<A: SystemAllocator> Dlmalloc<A> {
             }
         }
 
+        if !Chunk::mmapped(p) {
+            self.aligned.insert(p);
+        }
         let mem = Chunk::to_mem(p);
         debug_assert!(Chunk::size(p) >= nb);
         debug_assert_eq!(align_up(mem as usize, alignment), mem as usize);
         self.check_inuse_chunk(p);
-        return mem;
+        mem
     }
 
     // consolidate and bin a chunk, differs from exported versions of free
@@ -723,7 +947,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         self.topsize = size;
         (*p).head = size | PINUSE;
         (*Chunk::plus_offset(p, size)).head = self.top_foot_size();
-        self.trim_check = DEFAULT_TRIM_THRESHOLD;
+        self.trim_check = self.trim_threshold;
     }
 
     unsafe fn init_bins(&mut self) {
@@ -773,7 +997,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         let ret = Chunk::to_mem(p);
         self.check_malloced_chunk(ret, size);
         self.check_malloc_state();
-        return ret;
+        ret
     }
 
     // add a segment to hold a new noncontiguous region
@@ -839,6 +1063,16 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         self.check_malloc_state();
     }
 
+    unsafe fn segment_count(&self) -> usize {
+        let mut count = 0;
+        let mut sp = &self.seg as *const Segment;
+        while !sp.is_null() {
+            count += 1;
+            sp = (*sp).next;
+        }
+        count
+    }
+
     unsafe fn segment_holding(&self, ptr: *mut u8) -> *mut Segment {
         let mut sp = &self.seg as *const Segment as *mut Segment;
         while !sp.is_null() {
@@ -903,6 +1137,10 @@ impl<A: SystemAllocator> Dlmalloc<A> {
                     if rsize == 0 {
                         break;
                     }
+                    if self.first_fit {
+                        t = ptr::null_mut();
+                        break;
+                    }
                 }
                 let rt = (*t).child[1];
                 t = (*t).child[(sizebits >> (mem::size_of::<usize>() * 8 - 1)) & 1];
@@ -929,7 +1167,13 @@ impl<A: SystemAllocator> Dlmalloc<A> {
             }
         }
 
-        // Find the smallest of this tree or subtree
+        // Find the smallest of this tree or subtree. With first fit, whatever
+        // is at the root of the next bin up fits, so take that.
+        if self.first_fit && v.is_null() && !t.is_null() {
+            v = t;
+            rsize = Chunk::size(TreeChunk::chunk(t)) - size;
+            t = ptr::null_mut();
+        }
         while !t.is_null() {
             let csize = Chunk::size(TreeChunk::chunk(t));
             if csize >= size && csize - size < rsize {
@@ -940,7 +1184,9 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         }
 
         // If dv is a better fit, then return null so malloc will use it
-        if v.is_null() || (self.dvsize >= size && !(rsize < self.dvsize - size)) {
+        if v.is_null()
+            || (!self.first_fit && self.dvsize >= size && !(rsize < self.dvsize - size))
+        {
             return ptr::null_mut();
         }
 
@@ -1049,7 +1295,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
             let mut k = size << leftshift_for_tree_index(idx);
             loop {
                 if Chunk::size(TreeChunk::chunk(t)) != size {
-                    let c = &mut (*t).child[(k >> mem::size_of::<usize>() * 8 - 1) & 1];
+                    let c = &mut (*t).child[(k >> (mem::size_of::<usize>() * 8 - 1)) & 1];
                     k <<= 1;
                     if !c.is_null() {
                         t = *c;
@@ -1184,6 +1430,19 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         }
     }
 
+    /// The part of the chunk at `mem` that holds no allocator metadata once
+    /// it's freed, whatever it gets merged with: everything past the links
+    /// a free chunk keeps at its start. Empty for directly mapped chunks,
+    /// which `free` gives back whole anyway.
+    pub unsafe fn free_interior(&self, mem: *mut u8) -> (*mut u8, usize) {
+        let p = Chunk::from_mem(mem);
+        if Chunk::mmapped(p) {
+            return (mem, 0);
+        }
+        let links = mem::size_of::<TreeChunk>();
+        (p.cast::<u8>().add(links), Chunk::size(p).saturating_sub(links))
+    }
+
     pub unsafe fn validate_size(&mut self, ptr: *mut u8, size: usize) {
         let p = Chunk::from_mem(ptr);
         let psize = Chunk::size(p);
@@ -1202,13 +1461,30 @@ impl<A: SystemAllocator> Dlmalloc<A> {
     pub unsafe fn free(&mut self, mem: *mut u8) {
         self.check_malloc_state();
 
-        let mut p = Chunk::from_mem(mem);
+        let p = Chunk::from_mem(mem);
+        let psize = Chunk::size(p);
+        if !self.aligned.is_empty() {
+            self.aligned.remove(&p);
+        }
+        if self.defer_coalescing && !Chunk::mmapped(p) && self.is_small(psize) {
+            let idx = self.small_index(psize);
+            (*p).head |= FLAG4;
+            (*p).prev = self.deferred[idx as usize];
+            self.deferred[idx as usize] = p;
+            self.deferred_map |= 1 << idx;
+            return;
+        }
+        self.free_chunk(p);
+    }
+
+    unsafe fn free_chunk(&mut self, mut p: *mut Chunk) {
         let mut psize = Chunk::size(p);
         let next = Chunk::plus_offset(p, psize);
         if !Chunk::pinuse(p) {
             let prevsize = (*p).prev_foot;
 
             if Chunk::mmapped(p) {
+                self.replace_mmapped(p, ptr::null_mut());
                 psize += prevsize + self.mmap_foot_pad();
                 if self
                     .system_allocator
@@ -1289,21 +1565,21 @@ impl<A: SystemAllocator> Dlmalloc<A> {
             pad += self.top_foot_size();
             if self.topsize > pad {
                 let unit = DEFAULT_GRANULARITY;
-                let extra = ((self.topsize - pad + unit - 1) / unit - 1) * unit;
+                let extra = ((self.topsize - pad).div_ceil(unit) - 1) * unit;
                 let sp = self.segment_holding(self.top.cast());
                 debug_assert!(!sp.is_null());
 
-                if !Segment::is_extern(sp) {
-                    if Segment::can_release_part(&self.system_allocator, sp) {
-                        if (*sp).size >= extra && !self.has_segment_link(sp) {
-                            let newsize = (*sp).size - extra;
-                            if self
-                                .system_allocator
-                                .free_part((*sp).base, (*sp).size, newsize)
-                            {
-                                released = extra;
-                            }
-                        }
+                if !Segment::is_extern(sp)
+                    && Segment::can_release_part(&self.system_allocator, sp)
+                    && (*sp).size >= extra
+                    && !self.has_segment_link(sp)
+                {
+                    let newsize = (*sp).size - extra;
+                    if self
+                        .system_allocator
+                        .free_part((*sp).base, (*sp).size, newsize)
+                    {
+                        released = extra;
                     }
                 }
 
@@ -1320,7 +1596,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
             released += self.release_unused_segments();
 
             if released == 0 && self.topsize > self.trim_check {
-                self.trim_check = usize::max_value();
+                self.trim_check = usize::MAX;
             }
         }
 
@@ -1386,7 +1662,7 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         } else {
             MAX_RELEASE_CHECK_RATE
         };
-        return released;
+        released
     }
 
     // Sanity checks
@@ -1681,10 +1957,437 @@ impl<A: SystemAllocator> Dlmalloc<A> {
         0
     }
 
+    /// Calls `f` with the base, size and flags of every segment, the most
+    /// recently added first.
+    pub unsafe fn for_each_segment(&self, mut f: impl FnMut(*mut u8, usize, u32)) {
+        let mut sp = &self.seg as *const Segment as *mut Segment;
+        while !sp.is_null() {
+            if !(*sp).base.is_null() {
+                f((*sp).base, (*sp).size, (*sp).flags);
+            }
+            sp = (*sp).next;
+        }
+    }
+
+    /// Like `malloc`, but carves the chunk out of the lowest-addressed free
+    /// chunk that fits rather than the best fit, falling back to `malloc`
+    /// when no free chunk below the top is big enough.
+    pub unsafe fn malloc_lowest(&mut self, size: usize) -> *mut u8 {
+        if size >= self.max_request() {
+            return ptr::null_mut();
+        }
+        let nb = self.request2size(size);
+        let mut best: *mut Chunk = ptr::null_mut();
+        let mut sp = &self.seg as *const Segment as *mut Segment;
+        while !sp.is_null() {
+            let mut q = self.align_as_chunk((*sp).base);
+            while Segment::holds(sp, q.cast())
+                && q != self.top
+                && (*q).head != Chunk::fencepost_head()
+            {
+                if !Chunk::inuse(q) && Chunk::size(q) >= nb {
+                    if best.is_null() || q < best {
+                        best = q;
+                    }
+                    // Later chunks of this segment are all higher.
+                    break;
+                }
+                q = Chunk::next(q);
+            }
+            sp = (*sp).next;
+        }
+        if best.is_null() {
+            return self.malloc(size);
+        }
+        self.take_free_chunk(best, nb)
+    }
+
+    /// Like `malloc`, but prefers a chunk whose memory starts in
+    /// `[lo, hi)`, either a free one or the top, falling back to `malloc`
+    /// when there's none big enough. This walks the segment holding `lo`
+    /// chunk by chunk.
+    pub unsafe fn malloc_near(&mut self, size: usize, lo: *mut u8, hi: *mut u8) -> *mut u8 {
+        if size >= self.max_request() {
+            return ptr::null_mut();
+        }
+        let nb = self.request2size(size);
+        let sp = self.segment_holding(lo);
+        if !sp.is_null() {
+            let mut q = self.align_as_chunk((*sp).base);
+            while Segment::holds(sp, q.cast()) && (*q).head != Chunk::fencepost_head() {
+                let mem = Chunk::to_mem(q);
+                if mem >= hi {
+                    break;
+                }
+                if q == self.top {
+                    if mem >= lo && nb < self.topsize {
+                        return self.split_top(nb);
+                    }
+                    break;
+                }
+                if mem >= lo && !Chunk::inuse(q) && Chunk::size(q) >= nb {
+                    return self.take_free_chunk(q, nb);
+                }
+                q = Chunk::next(q);
+            }
+        }
+        self.malloc(size)
+    }
+
+    /// Slides every in-use chunk down over the free chunk in front of it,
+    /// segment by segment in address order, so that the free space of each
+    /// segment ends up in one chunk at its end, merged with the top in the
+    /// top segment. Calls `f` with the old and new memory and the usable
+    /// size of each chunk moved. Checks `stop` before each move and returns
+    /// false, with the heap consistent, once it says to stop.
+    ///
+    /// Chunks keep their order. Those from `memalign`, which would lose
+    /// their alignment, and those holding the records of older segments
+    /// stay where they are.
+    pub unsafe fn slide_down(
+        &mut self,
+        stop: impl Fn() -> bool,
+        mut f: impl FnMut(*mut u8, *mut u8, usize),
+    ) -> bool {
+        if self.top.is_null() {
+            return true;
+        }
+        self.coalesce_deferred();
+        let mut records = Vec::new();
+        let mut sp = self.seg.next;
+        while !sp.is_null() {
+            records.push(sp.cast::<u8>());
+            sp = (*sp).next;
+        }
+        let mut sp = &self.seg as *const Segment as *mut Segment;
+        while !sp.is_null() {
+            let mut q = self.align_as_chunk((*sp).base);
+            while Segment::holds(sp, q.cast())
+                && q != self.top
+                && (*q).head != Chunk::fencepost_head()
+            {
+                let mem = Chunk::to_mem(q);
+                if !Chunk::inuse(q)
+                    || Chunk::pinuse(q)
+                    || records.contains(&mem)
+                    || self.aligned.contains(&q)
+                {
+                    q = Chunk::next(q);
+                    continue;
+                }
+                if stop() {
+                    self.check_malloc_state();
+                    return false;
+                }
+                // The free chunk in front takes the data, and what's left of
+                // the two after it is freed like any other chunk, merging
+                // with whatever follows.
+                let gap = (*q).prev_foot;
+                let p = Chunk::minus_offset(q, gap);
+                if p == self.dv {
+                    self.dv = ptr::null_mut();
+                    self.dvsize = 0;
+                } else {
+                    self.unlink_chunk(p, gap);
+                }
+                let size = Chunk::size(q);
+                let usable = size - self.overhead_for(q);
+                ptr::copy(mem, Chunk::to_mem(p), usable);
+                Chunk::set_size_and_pinuse_of_inuse_chunk(p, size);
+                let r = Chunk::plus_offset(p, size);
+                (*r).head = gap | PINUSE;
+                self.dispose_chunk(r, gap);
+                f(mem, Chunk::to_mem(p), usable);
+                q = r;
+            }
+            sp = (*sp).next;
+        }
+        self.check_malloc_state();
+        true
+    }
+
+    /// Updates the list of directly mapped chunks after `old` moved to `new`
+    /// or, for a null `new`, was freed.
+    fn replace_mmapped(&mut self, old: *mut Chunk, new: *mut Chunk) {
+        let i = self.mmapped.iter().position(|&p| p == old).unwrap();
+        if new.is_null() {
+            self.mmapped.swap_remove(i);
+        } else {
+            self.mmapped[i] = new;
+        }
+    }
+
+    /// Carves `nb` bytes off the start of the top chunk, which must be
+    /// bigger than that.
+    unsafe fn split_top(&mut self, nb: usize) -> *mut u8 {
+        self.topsize -= nb;
+        let rsize = self.topsize;
+        let p = self.top;
+        self.top = Chunk::plus_offset(p, nb);
+        let r = self.top;
+        (*r).head = rsize | PINUSE;
+        Chunk::set_size_and_pinuse_of_inuse_chunk(p, nb);
+        self.check_top_chunk(self.top);
+        let ret = Chunk::to_mem(p);
+        self.check_malloced_chunk(ret, nb);
+        self.check_malloc_state();
+        ret
+    }
+
+    /// Allocates `nb` bytes from the start of free chunk `p`, which may be
+    /// in a bin or the designated victim, returning the rest to the bins.
+    unsafe fn take_free_chunk(&mut self, p: *mut Chunk, nb: usize) -> *mut u8 {
+        let psize = Chunk::size(p);
+        let rsize = psize - nb;
+        if p == self.dv {
+            self.dv = ptr::null_mut();
+            self.dvsize = 0;
+        } else {
+            self.unlink_chunk(p, psize);
+        }
+        if rsize < self.min_chunk_size() {
+            Chunk::set_inuse_and_pinuse(p, psize);
+        } else {
+            Chunk::set_size_and_pinuse_of_inuse_chunk(p, nb);
+            let r = Chunk::plus_offset(p, nb);
+            Chunk::set_size_and_pinuse_of_free_chunk(r, rsize);
+            self.insert_chunk(r, rsize);
+        }
+        let ret = Chunk::to_mem(p);
+        self.check_malloced_chunk(ret, nb);
+        self.check_malloc_state();
+        ret
+    }
+
+    /// Finds the in-use chunk whose memory contains `addr`, returning its
+    /// memory and usable size. Returns `None` if `addr` is in a free chunk,
+    /// chunk header or outside the heap.
+    pub unsafe fn chunk_containing(&self, addr: *mut u8) -> Option<(*mut u8, usize)> {
+        for &p in &self.mmapped {
+            let mem = Chunk::to_mem(p);
+            let usable = Chunk::size(p) - self.mmap_chunk_overhead();
+            if mem <= addr && addr < mem.add(usable) {
+                return Some((mem, usable));
+            }
+        }
+        if self.top.is_null() {
+            return None;
+        }
+        let mut sp = &self.seg as *const Segment as *mut Segment;
+        while !sp.is_null() {
+            if Segment::holds(sp, addr) {
+                let mut q = self.align_as_chunk((*sp).base);
+                while Segment::holds(sp, q.cast())
+                    && q != self.top
+                    && (*q).head != Chunk::fencepost_head()
+                {
+                    // An in-use chunk's memory runs into the `prev_foot` of
+                    // the next one, so go by the usable size rather than
+                    // the chunk boundary.
+                    let mem = Chunk::to_mem(q);
+                    if addr < mem {
+                        return None;
+                    }
+                    let next = Chunk::next(q);
+                    if Chunk::live(q) {
+                        let usable = Chunk::size(q) - self.overhead_for(q);
+                        if addr < mem.add(usable) {
+                            return Some((mem, usable));
+                        }
+                    } else if addr < next.cast() {
+                        return None;
+                    }
+                    q = next;
+                }
+                return None;
+            }
+            sp = (*sp).next;
+        }
+        None
+    }
+
+    /// Calls `f` with the address and length of each piece of the heap's
+    /// own bookkeeping that lives in the arena rather than in `self`: the
+    /// records of older segments, and the header and links of every free
+    /// chunk, top included, which is what finding a chunk for a request
+    /// reads.
+    pub unsafe fn for_each_metadata(&self, mut f: impl FnMut(*mut u8, usize)) {
+        let mut sp = self.seg.next;
+        while !sp.is_null() {
+            f(sp.cast(), mem::size_of::<Segment>());
+            sp = (*sp).next;
+        }
+        self.for_each_chunk(|p, _, inuse| {
+            if !inuse {
+                f(p, mem::size_of::<TreeChunk>());
+            }
+        });
+    }
+
+    /// Calls `f` with the address, size and in-use flag of every chunk,
+    /// segment by segment and then the directly mapped ones. The top chunk
+    /// counts as free; fenceposts are skipped.
+    pub unsafe fn for_each_chunk(&self, mut f: impl FnMut(*mut u8, usize, bool)) {
+        if !self.top.is_null() {
+            let mut sp = &self.seg as *const Segment as *mut Segment;
+            while !sp.is_null() {
+                let mut q = self.align_as_chunk((*sp).base);
+                while Segment::holds(sp, q.cast()) && (*q).head != Chunk::fencepost_head() {
+                    f(q.cast(), Chunk::size(q), q != self.top && Chunk::live(q));
+                    if q == self.top {
+                        break;
+                    }
+                    q = Chunk::next(q);
+                }
+                sp = (*sp).next;
+            }
+        }
+        for &p in &self.mmapped {
+            f(p.cast(), Chunk::size(p), true);
+        }
+    }
+
+    /// Calls `f` with the memory and usable size of every in-use chunk, in
+    /// the same order as `for_each_chunk`, leaving out the chunks
+    /// `add_segment` keeps the records of older segments in.
+    pub unsafe fn for_each_allocation(&self, mut f: impl FnMut(*mut u8, usize)) {
+        let mut records = Vec::new();
+        let mut sp = self.seg.next;
+        while !sp.is_null() {
+            records.push(sp.cast::<u8>());
+            sp = (*sp).next;
+        }
+        self.for_each_chunk(|p, _, inuse| {
+            let p = p.cast::<Chunk>();
+            let mem = Chunk::to_mem(p);
+            if inuse && !records.contains(&mem) {
+                f(mem, Chunk::size(p) - self.overhead_for(p));
+            }
+        });
+    }
+
+    /// Calls `f` with the bin index and size of every chunk in the small
+    /// bins, then does the same for the tree bins. The designated victim and
+    /// top chunk aren't in any bin, so they're skipped.
+    pub unsafe fn for_each_binned_chunk(
+        &mut self,
+        mut small: impl FnMut(u32, usize),
+        mut tree: impl FnMut(u32, usize),
+    ) {
+        for idx in 0..NSMALLBINS_U32 {
+            // Emptying a bin leaves its links as they were, so only the map
+            // says whether it's empty.
+            if !self.smallmap_is_marked(idx) {
+                continue;
+            }
+            let b = self.smallbin_at(idx);
+            let mut p = (*b).next;
+            while p != b {
+                small(idx, Chunk::size(p));
+                p = (*p).next;
+            }
+        }
+        for idx in 0..NTREEBINS_U32 {
+            let mut nodes = vec![*self.treebin_at(idx)];
+            while let Some(t) = nodes.pop() {
+                if t.is_null() {
+                    continue;
+                }
+                // Chunks of the same size hang off the tree node in a ring.
+                let mut u = t;
+                loop {
+                    tree(idx, Chunk::size(TreeChunk::chunk(u)));
+                    u = TreeChunk::prev(u);
+                    if u == t {
+                        break;
+                    }
+                }
+                nodes.extend((*t).child);
+            }
+        }
+    }
+
+    /// The size of the chunks in small bin `idx`.
+    pub fn small_bin_size(&self, idx: u32) -> usize {
+        self.small_index2size(idx)
+    }
+
+    /// The smallest chunk tree bin `idx` can hold.
+    pub fn tree_bin_min_size(&self, idx: u32) -> usize {
+        self.min_size_for_tree_index(idx)
+    }
+
+    /// Walks every segment summarizing how its memory is used, like
+    /// `mallinfo` in the C version.
+    pub unsafe fn mallinfo(&self) -> MallInfo {
+        let mut info = MallInfo::default();
+        if self.top.is_null() {
+            return info;
+        }
+        let mut nfree = 1; // top is always free
+        let mut mfree = self.topsize + self.top_foot_size();
+        let mut sum = mfree;
+        let mut sp = &self.seg as *const Segment as *mut Segment;
+        while !sp.is_null() {
+            let mut q = self.align_as_chunk((*sp).base);
+            while Segment::holds(sp, q.cast())
+                && q != self.top
+                && (*q).head != Chunk::fencepost_head()
+            {
+                let sz = Chunk::size(q);
+                sum += sz;
+                // Chunks set aside by deferred coalescing count as free.
+                if !Chunk::live(q) {
+                    mfree += sz;
+                    nfree += 1;
+                }
+                q = Chunk::next(q);
+            }
+            sp = (*sp).next;
+        }
+        info.arena = sum;
+        info.ordblks = nfree;
+        info.hblkhd = self.footprint - sum;
+        info.usmblks = self.max_footprint;
+        info.uordblks = self.footprint - mfree;
+        info.fordblks = mfree;
+        info.keepcost = self.topsize;
+        info
+    }
+
     pub unsafe fn trim(&mut self, pad: usize) -> bool {
+        self.coalesce_deferred();
         self.sys_trim(pad)
     }
 
+    /// Forgets every chunk and segment, keeping the settings, as if freshly
+    /// created. Nothing is given back to the system allocator.
+    pub fn reset(&mut self) {
+        self.smallmap = 0;
+        self.treemap = 0;
+        self.smallbins = [ptr::null_mut(); (NSMALLBINS + 1) * 2];
+        self.treebins = [ptr::null_mut(); NTREEBINS];
+        self.dvsize = 0;
+        self.topsize = 0;
+        self.dv = ptr::null_mut();
+        self.top = ptr::null_mut();
+        self.footprint = 0;
+        self.max_footprint = 0;
+        self.seg = Segment {
+            base: ptr::null_mut(),
+            size: 0,
+            next: ptr::null_mut(),
+            flags: 0,
+        };
+        self.trim_check = self.trim_threshold;
+        self.mmapped.clear();
+        self.aligned.clear();
+        self.least_addr = ptr::null_mut();
+        self.release_checks = 0;
+        self.deferred = [ptr::null_mut(); NSMALLBINS];
+        self.deferred_map = 0;
+    }
+
     pub unsafe fn destroy(mut self) -> usize {
         let mut freed = 0;
         let mut sp: *mut Segment = &mut self.seg;
@@ -1702,6 +2405,25 @@ impl<A: SystemAllocator> Dlmalloc<A> {
     }
 }
 
+/// Summary of heap usage, with the same fields as C's `struct mallinfo`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MallInfo {
+    /// non-mmapped space allocated from system
+    pub arena: usize,
+    /// number of free chunks
+    pub ordblks: usize,
+    /// space in mmapped regions
+    pub hblkhd: usize,
+    /// maximum total allocated space
+    pub usmblks: usize,
+    /// total allocated space
+    pub uordblks: usize,
+    /// total free space
+    pub fordblks: usize,
+    /// releasable (via malloc_trim) space
+    pub keepcost: usize,
+}
+
 const PINUSE: usize = 1 << 0;
 const CINUSE: usize = 1 << 1;
 const FLAG4: usize = 1 << 2;
@@ -1741,6 +2463,11 @@ impl Chunk {
         (*me).head & INUSE != PINUSE
     }
 
+    /// In use and not just set aside by deferred coalescing.
+    unsafe fn live(me: *mut Chunk) -> bool {
+        Chunk::inuse(me) && (*me).head & FLAG4 == 0
+    }
+
     unsafe fn mmapped(me: *mut Chunk) -> bool {
         (*me).head & INUSE == 0
     }
@@ -1820,7 +2547,7 @@ impl TreeChunk {
     }
 }
 
-const EXTERN: u32 = 1 << 0;
+pub const EXTERN: u32 = 1 << 0;
 
 impl Segment {
     unsafe fn is_extern(seg: *mut Segment) -> bool {
@@ -0,0 +1,75 @@
+use crate::DiskDlmalloc;
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+/// A cheaply cloneable, type-erased allocator handle.
+///
+/// `Allocator` is implemented for references to trait objects but not for
+/// `Arc<dyn Allocator>`, so collections can't own a shared dynamic allocator
+/// without a wrapper like this one. Using it as the allocator parameter of
+/// `Vec`, `Box` and friends means they all have the same type whatever
+/// allocator is behind them, at the cost of a virtual call per operation.
+#[derive(Clone)]
+pub struct DynAllocator(Arc<dyn Allocator + Send + Sync>);
+
+impl DynAllocator {
+    /// Wraps `alloc` for dynamic dispatch.
+    pub fn new<A: Allocator + Send + Sync + 'static>(alloc: A) -> DynAllocator {
+        DynAllocator(Arc::new(alloc))
+    }
+}
+
+impl From<Arc<dyn Allocator + Send + Sync>> for DynAllocator {
+    fn from(alloc: Arc<dyn Allocator + Send + Sync>) -> DynAllocator {
+        DynAllocator(alloc)
+    }
+}
+
+impl DiskDlmalloc {
+    /// Returns a [`DynAllocator`] handle to this arena.
+    pub fn to_dyn(&self) -> DynAllocator {
+        DynAllocator::new(self.clone())
+    }
+}
+
+unsafe impl Allocator for DynAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.deallocate(ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.grow(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.shrink(ptr, old_layout, new_layout)
+    }
+}
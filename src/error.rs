@@ -0,0 +1,144 @@
+use std::fmt;
+use std::io;
+
+/// Errors returned by arena-wide operations.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// An operation on the backing file or its mapping failed.
+    Io(io::Error),
+    /// Shrinking the arena would cut off memory that's still in use.
+    WouldTruncateLive {
+        /// File offset up to which memory is in use.
+        in_use: usize,
+        /// The size that was asked for.
+        requested: usize,
+    },
+    /// The fixed base address asked for with
+    /// [`Builder::base_address`](crate::Builder::base_address) overlaps
+    /// something already mapped.
+    MappingOverlaps {
+        /// The requested base address.
+        addr: usize,
+        /// Length of the mapping that didn't fit there.
+        len: usize,
+    },
+    /// [`DiskDlmalloc::close`](crate::DiskDlmalloc::close) was called while
+    /// other handles to the allocator were still alive.
+    StillInUse {
+        /// How many other handles there were.
+        handles: usize,
+    },
+    /// [`DiskDlmalloc::migrate_to`](crate::DiskDlmalloc::migrate_to) ran out
+    /// of room in the destination arena.
+    DestinationFull {
+        /// Offset in the source arena of the allocation that didn't fit.
+        offset: usize,
+        /// Its usable size.
+        size: usize,
+    },
+    /// The operation was stopped through its
+    /// [`CancellationToken`](crate::CancellationToken).
+    Cancelled,
+    /// The backing file is locked by someone else in a way that conflicts
+    /// with the lock asked for with
+    /// [`Builder::file_lock`](crate::Builder::file_lock).
+    Locked,
+    /// [`DiskDlmalloc::split`](crate::DiskDlmalloc::split) couldn't take a
+    /// part from the arena.
+    NoRoom {
+        /// The size of the part that didn't fit, rounded up to a page.
+        requested: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => err.fmt(f),
+            Error::WouldTruncateLive { in_use, requested } => write!(
+                f,
+                "cannot shrink the arena to {} bytes, the first {} are in use",
+                requested, in_use
+            ),
+            Error::MappingOverlaps { addr, len } => write!(
+                f,
+                "cannot map {} bytes at {:#x}, the range overlaps an existing mapping",
+                len, addr
+            ),
+            Error::StillInUse { handles } => write!(
+                f,
+                "cannot close the allocator, {} other handles to it are alive",
+                handles
+            ),
+            Error::DestinationFull { offset, size } => write!(
+                f,
+                "no room in the destination for the {} byte allocation at offset {}",
+                size, offset
+            ),
+            Error::Cancelled => f.write_str("the operation was cancelled"),
+            Error::Locked => f.write_str("the backing file is locked by another user"),
+            Error::NoRoom { requested } => {
+                write!(f, "no room in the arena for a {} byte part", requested)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+/// Why [`DiskDlmalloc::try_malloc_timeout`](crate::DiskDlmalloc::try_malloc_timeout)
+/// didn't return an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TryAllocError {
+    /// The allocator lock couldn't be acquired before the timeout.
+    LockTimeout,
+    /// The lock was acquired but the arena couldn't satisfy the request.
+    ///
+    /// The figures are taken right after the failure and tell exhaustion
+    /// apart from fragmentation: if `total_free` covers the request but
+    /// `largest_free_block` doesn't, compacting may help where only growing
+    /// the arena would otherwise. Both count chunk headers, so a request
+    /// needs a block a little bigger than itself.
+    OutOfMemory {
+        /// The largest free chunk in the heap or contiguous run of the arena
+        /// not yet handed out, in bytes.
+        largest_free_block: usize,
+        /// All free memory in the heap plus what the arena can still hand
+        /// out, as [`DiskDlmalloc::available`](crate::DiskDlmalloc::available)
+        /// counts it.
+        total_free: usize,
+    },
+}
+
+impl fmt::Display for TryAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryAllocError::LockTimeout => f.write_str("timed out waiting for the allocator lock"),
+            TryAllocError::OutOfMemory {
+                largest_free_block,
+                total_free,
+            } => write!(
+                f,
+                "out of memory, {} bytes free, the largest free block is {} bytes",
+                total_free, largest_free_block
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TryAllocError {}
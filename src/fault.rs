@@ -0,0 +1,82 @@
+//! Allocation failure injection, enabled with the `fault-injection`
+//! feature, so applications can exercise their out-of-memory handling
+//! without filling an arena.
+//!
+//! Injected failures apply to everything that can return null for lack of
+//! memory: `malloc`, `calloc`, `realloc` and the `Allocator` methods built
+//! on them. Without the feature the check isn't compiled at all.
+
+use crate::Shared;
+
+impl Shared {
+    /// Whether the next allocation should fail regardless of free space.
+    #[inline(always)]
+    pub(crate) fn inject_fault(&self) -> bool {
+        #[cfg(feature = "fault-injection")]
+        {
+            self.faults.should_fail()
+        }
+        #[cfg(not(feature = "fault-injection"))]
+        {
+            false
+        }
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+pub(crate) use imp::*;
+
+#[cfg(feature = "fault-injection")]
+mod imp {
+    use crate::Builder;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    /// Seed for `fail_with_probability`, fixed so failing runs can be
+    /// reproduced.
+    const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+    pub(crate) struct Faults {
+        fail_after: Option<usize>,
+        allocs: AtomicUsize,
+        probability: Option<f64>,
+        rng: AtomicU64,
+    }
+
+    impl Faults {
+        pub(crate) fn new(builder: &Builder) -> Faults {
+            Faults {
+                fail_after: builder.fail_after_n_allocs,
+                allocs: AtomicUsize::new(0),
+                probability: builder.fail_with_probability,
+                rng: AtomicU64::new(SEED),
+            }
+        }
+
+        pub(crate) fn should_fail(&self) -> bool {
+            if let Some(n) = self.fail_after {
+                if self.allocs.fetch_add(1, Ordering::Relaxed) >= n {
+                    return true;
+                }
+            }
+            match self.probability {
+                Some(p) => self.next_unit() < p,
+                None => false,
+            }
+        }
+
+        /// Steps the xorshift generator, returning a value in `0.0..1.0`.
+        fn next_unit(&self) -> f64 {
+            let step = |mut x: u64| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x
+            };
+            let prev = self
+                .rng
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x)))
+                .unwrap();
+            (step(prev) >> 11) as f64 / (1u64 << 53) as f64
+        }
+    }
+}
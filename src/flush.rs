@@ -0,0 +1,73 @@
+use crate::{DiskDlmalloc, SystemAllocator};
+use core::cmp;
+use std::io;
+use std::sync::atomic::{self, Ordering};
+
+/// How many bytes [`DiskDlmalloc::flush_range`] syncs with each `msync`,
+/// which is how precisely it can tell how far it got.
+const STEP: usize = 1 << 20;
+
+/// How far [`DiskDlmalloc::flush_range`] got.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct FlushOutcome {
+    /// How many bytes from the start of the range are known to be on stable
+    /// storage. The whole length unless `error` is set.
+    pub durable_up_to: usize,
+    /// What stopped the flush, if anything.
+    pub error: Option<io::Error>,
+}
+
+impl FlushOutcome {
+    /// Whether the whole range was flushed.
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Turns the outcome into a plain `io::Result`, for callers that don't
+    /// care how far a failed flush got.
+    pub fn into_result(self) -> io::Result<()> {
+        self.error.map_or(Ok(()), Err)
+    }
+}
+
+impl DiskDlmalloc {
+    /// Like [`fence_and_flush`](DiskDlmalloc::fence_and_flush), but reports
+    /// how much of `[ptr, ptr + len)` was made durable before an error, so
+    /// callers committing a log, say, can mark that prefix as committed and
+    /// retry from where it stopped.
+    ///
+    /// The range is synced 1 MiB at a time, stopping at the first failure,
+    /// and `durable_up_to` counts up to the start of the step that failed,
+    /// even though some of its pages may have made it too. A range running
+    /// past the end of the arena fails at the step that would cross it.
+    pub fn flush_range(&self, ptr: *mut u8, len: usize) -> FlushOutcome {
+        atomic::fence(Ordering::SeqCst);
+        let (start, end) = (ptr as usize, (ptr as usize).saturating_add(len));
+        // Steps start on page boundaries, as `msync` syncs whole pages.
+        let base = start & !(self.0.system.page_size() - 1);
+        let mut pos = start;
+        while pos < end {
+            let next = cmp::min(base + ((pos - base) / STEP + 1) * STEP, end);
+            let res = if self.0.system.to_offset((next - 1) as *const u8).is_some() {
+                self.0.system.sync(pos as *mut u8, next - pos)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "range runs past the end of the arena",
+                ))
+            };
+            if let Err(err) = res {
+                return FlushOutcome {
+                    durable_up_to: pos - start,
+                    error: Some(err),
+                };
+            }
+            pos = next;
+        }
+        FlushOutcome {
+            durable_up_to: len,
+            error: None,
+        }
+    }
+}
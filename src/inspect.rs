@@ -0,0 +1,443 @@
+//! Read-only views of the allocator's internal state.
+
+use crate::dlmalloc::{self, Dlmalloc};
+use crate::sys::System;
+use crate::{DiskDlmalloc, ReloadableConfig, Shared};
+use core::{cmp, slice};
+use std::fmt;
+use std::sync::atomic::Ordering;
+
+/// One contiguous region of memory `dlmalloc` manages, as returned by
+/// [`DiskDlmalloc::segments`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// Offset of the start of the segment within the backing file.
+    pub base_offset: usize,
+    /// Length of the segment in bytes.
+    pub len: usize,
+    /// The flags `SystemAllocator::alloc` returned for the segment. Bit 0 is
+    /// `EXTERN_BIT`, see [`SegmentInfo::is_extern`].
+    pub flags: u32,
+}
+
+impl SegmentInfo {
+    /// Whether the segment wasn't allocated by `dlmalloc` itself, in which
+    /// case it's never released or merged with others.
+    pub fn is_extern(&self) -> bool {
+        self.flags & dlmalloc::EXTERN != 0
+    }
+}
+
+/// A snapshot of how much of the arena is in use, as returned by
+/// [`DiskDlmalloc::stats`].
+///
+/// The arena has two capacities: the logical one, the size of the backing
+/// file, and what the filesystem can actually hold, as the file is sparse
+/// and its pages only take up disk space once written back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Size of the arena: `total_size`, rounded up to the filesystem's
+    /// block size, plus any growth picked up by `refresh_mapping`, less any
+    /// `shrink_arena`.
+    pub logical_capacity: usize,
+    /// Free space on the filesystem holding the backing file, or `None` for
+    /// private mappings or when the filesystem won't say.
+    pub disk_free: Option<usize>,
+    /// How far into the file memory has been handed out, see
+    /// [`DiskDlmalloc::offset`].
+    pub offset: usize,
+    /// Free memory inside the heap, below `offset`.
+    pub heap_free: usize,
+    /// What [`DiskDlmalloc::available`] returns.
+    pub available: usize,
+    /// The settings in effect, see [`DiskDlmalloc::reload`].
+    pub config: ReloadableConfig,
+}
+
+/// Free chunks in one of `dlmalloc`'s bins, see [`BinStats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BinInfo {
+    /// Chunk size the bin starts at. Small bins hold only chunks of exactly
+    /// this size, tree bins everything up to the next bin's `min_size`.
+    pub min_size: usize,
+    /// Number of free chunks in the bin.
+    pub chunks: usize,
+    /// Their combined size, chunk headers included.
+    pub bytes: usize,
+}
+
+/// How `dlmalloc`'s free lists are populated, as returned by
+/// [`DiskDlmalloc::bin_stats`].
+///
+/// Free chunks under 256 bytes, or under 512 with
+/// [`Builder::small_bin_threshold`](crate::Builder::small_bin_threshold)
+/// raised, live in exact-size small bins, larger ones in tree bins covering a
+/// range of sizes each. The chunk at the end of the
+/// heap and the one most recently split aren't in any bin, so the totals
+/// here are less than `Stats::heap_free`. With lock striping both heaps are
+/// counted together.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BinStats {
+    /// The small bins, indexed by chunk size divided by 8. The first few
+    /// are always empty, as no chunk is that small, and so are those past
+    /// the small bin threshold.
+    pub small: Vec<BinInfo>,
+    /// The tree bins, in increasing size order.
+    pub tree: Vec<BinInfo>,
+}
+
+/// Everything the individual diagnostics report, taken at a single instant,
+/// as returned by [`DiskDlmalloc::health`].
+///
+/// Below `stats.offset` every byte is either `used` or `free`, so the two
+/// add up to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HealthReport {
+    /// What [`DiskDlmalloc::stats`] returns.
+    pub stats: Stats,
+    /// What [`DiskDlmalloc::bin_stats`] returns.
+    pub bins: BinStats,
+    /// What [`DiskDlmalloc::segments`] returns.
+    pub segments: Vec<SegmentInfo>,
+    /// What [`DiskDlmalloc::high_water_mark`] returns.
+    pub high_water_mark: usize,
+    /// Bytes below `stats.offset` taken up by live allocations, their
+    /// headers and `dlmalloc`'s own bookkeeping.
+    pub used: usize,
+    /// Bytes below `stats.offset` that are free: `stats.heap_free` plus what
+    /// was given back to the arena and not handed out again.
+    pub free: usize,
+    /// The largest free chunk in the heap, headers included.
+    pub largest_free_block: usize,
+}
+
+/// Allocations still live when the last handle to an allocator was dropped,
+/// reported with [`Builder::warn_on_leak`](crate::Builder::warn_on_leak) or
+/// [`Builder::panic_on_leak`](crate::Builder::panic_on_leak).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LeakReport {
+    /// How many allocations were never freed.
+    pub allocations: usize,
+    /// Their combined usable size, which can be a little more than was asked
+    /// for.
+    pub bytes: usize,
+    /// Bytes still charged to each [`ScopedAllocator`](crate::ScopedAllocator)
+    /// token with any outstanding, in token order.
+    pub by_token: Vec<(u64, usize)>,
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} allocations totalling {} bytes were never freed",
+            self.allocations, self.bytes
+        )?;
+        for (token, bytes) in &self.by_token {
+            write!(f, ", {} bytes under token {}", bytes, token)?;
+        }
+        Ok(())
+    }
+}
+
+impl LeakReport {
+    /// Walks the heaps of an allocator nobody else holds a handle to.
+    pub(crate) fn of(shared: &mut Shared) -> LeakReport {
+        let (mut allocations, mut bytes) = (0, 0);
+        for heap in core::iter::once(&mut shared.heap).chain(&mut shared.small) {
+            let heap = heap.get_mut().unwrap_or_else(|err| err.into_inner());
+            unsafe {
+                heap.dl.for_each_allocation(|_, size| {
+                    allocations += 1;
+                    bytes += size;
+                })
+            };
+        }
+        let usage = shared
+            .usage
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner());
+        let mut by_token: Vec<_> = usage
+            .iter()
+            .filter(|&(_, &bytes)| bytes != 0)
+            .map(|(&token, &bytes)| (token, bytes))
+            .collect();
+        by_token.sort_unstable();
+        LeakReport {
+            allocations,
+            bytes,
+            by_token,
+        }
+    }
+}
+
+impl HealthReport {
+    /// How much of the heap's free memory is in pieces other than the
+    /// largest, from 0 when it's all one block (or there is none) to close
+    /// to 1 when it's scattered.
+    pub fn fragmentation(&self) -> f64 {
+        if self.stats.heap_free == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free_block as f64 / self.stats.heap_free as f64
+    }
+}
+
+impl BinStats {
+    fn empty() -> BinStats {
+        BinStats {
+            small: vec![BinInfo::default(); dlmalloc::NSMALLBINS],
+            tree: vec![BinInfo::default(); dlmalloc::NTREEBINS],
+        }
+    }
+}
+
+/// Adds the free chunks of one heap to `stats`.
+unsafe fn add_bin_stats(dl: &mut Dlmalloc<System>, stats: &mut BinStats) {
+    for (idx, bin) in stats.small.iter_mut().enumerate() {
+        bin.min_size = dl.small_bin_size(idx as u32);
+    }
+    for (idx, bin) in stats.tree.iter_mut().enumerate() {
+        bin.min_size = dl.tree_bin_min_size(idx as u32);
+    }
+    let count = |bin: &mut BinInfo, size| {
+        bin.chunks += 1;
+        bin.bytes += size;
+    };
+    let BinStats { small, tree } = stats;
+    dl.for_each_binned_chunk(
+        |idx, size| count(&mut small[idx as usize], size),
+        |idx, size| count(&mut tree[idx as usize], size),
+    );
+}
+
+impl DiskDlmalloc {
+    /// Returns a [`HealthReport`] combining `stats`, `bin_stats`,
+    /// `segments` and `high_water_mark`.
+    ///
+    /// Unlike calling those one by one, every heap is locked once for the
+    /// whole report, so all of it describes the same instant. It walks the
+    /// heap a few times over, so it's no cheaper than they are together.
+    pub fn health(&self) -> HealthReport {
+        let mut heaps: Vec<_> = self.0.heaps().map(|heap| self.0.lock(heap)).collect();
+        let mut bins = BinStats::empty();
+        let mut segments = Vec::new();
+        let (mut used, mut heap_free, mut largest_free_block) = (0, 0, 0);
+        for heap in &mut heaps {
+            let dl = &mut heap.dl;
+            unsafe {
+                add_bin_stats(dl, &mut bins);
+                self.add_segments(dl, &mut segments);
+                let info = dl.mallinfo();
+                used += info.uordblks;
+                heap_free += info.fordblks;
+                dl.for_each_chunk(|_, size, inuse| {
+                    if !inuse {
+                        largest_free_block = cmp::max(largest_free_block, size);
+                    }
+                });
+            }
+        }
+        segments.sort_by_key(|s| s.base_offset);
+        let system = &self.0.system;
+        let report = HealthReport {
+            stats: Stats {
+                logical_capacity: system.total_size(),
+                disk_free: system.disk_free(),
+                offset: system.offset(),
+                heap_free,
+                available: system.obtainable() + heap_free,
+                config: *self.0.config.lock().unwrap(),
+            },
+            bins,
+            segments,
+            high_water_mark: system.high_water_mark(),
+            used,
+            free: heap_free + system.hole_bytes(),
+            largest_free_block,
+        };
+        drop(heaps);
+        report
+    }
+
+    /// Returns a [`BinStats`] snapshot of which size bins hold free memory,
+    /// for tuning allocation sizes against `dlmalloc`'s size classes.
+    pub fn bin_stats(&self) -> BinStats {
+        let mut stats = BinStats::empty();
+        for heap in self.0.heaps() {
+            unsafe { add_bin_stats(&mut self.0.lock(heap).dl, &mut stats) };
+        }
+        stats
+    }
+
+    /// Returns a [`Stats`] snapshot. Like `available`, this walks the heap.
+    pub fn stats(&self) -> Stats {
+        let heap_free = self.0.heap_free();
+        Stats {
+            logical_capacity: self.0.system.total_size(),
+            disk_free: self.0.system.disk_free(),
+            offset: self.0.system.offset(),
+            heap_free,
+            available: self.0.system.obtainable() + heap_free,
+            config: *self.0.config.lock().unwrap(),
+        }
+    }
+
+    /// Returns the segments currently making up the heap, sorted by offset.
+    ///
+    /// A segment is created each time `dlmalloc` obtains memory that isn't
+    /// contiguous with what it already has, for example after
+    /// `refresh_mapping` maps a new region, so most arenas have just one.
+    pub fn segments(&self) -> Vec<SegmentInfo> {
+        let mut segments = Vec::new();
+        for heap in self.0.heaps() {
+            unsafe { self.add_segments(&self.0.lock(heap).dl, &mut segments) };
+        }
+        segments.sort_by_key(|s| s.base_offset);
+        segments
+    }
+
+    /// Appends the segments of one heap to `segments`, unsorted.
+    unsafe fn add_segments(&self, dl: &Dlmalloc<System>, segments: &mut Vec<SegmentInfo>) {
+        dl.for_each_segment(|base, len, flags| {
+            segments.push(SegmentInfo {
+                base_offset: self.0.system.to_offset(base).unwrap(),
+                len,
+                flags,
+            })
+        });
+    }
+
+    /// Finds the live allocation containing file offset `offset`, returning
+    /// the offset it starts at and its usable size, which may be a little
+    /// more than was asked for. Returns `None` if `offset` is in free memory,
+    /// allocator metadata or outside the heap.
+    ///
+    /// Meant for tracking down corruption reported at an offset. It walks
+    /// the heap chunk by chunk, so it's slow on large arenas. With the
+    /// `redzones` feature the bounds include the canaries.
+    pub fn find_allocation(&self, offset: usize) -> Option<(usize, usize)> {
+        let addr = self.0.system.to_ptr(offset)?;
+        self.0.heaps().find_map(|heap| {
+            let heap = self.0.lock(heap);
+            let (mem, size) = unsafe { heap.dl.chunk_containing(addr)? };
+            Some((self.0.system.to_offset(mem)?, size))
+        })
+    }
+
+    /// Calls `f` with the address and usable size of every live allocation,
+    /// in ascending address order across all segments, for sweeping over
+    /// live data the way the hardware prefetcher likes.
+    ///
+    /// The allocations are collected with the allocator locked and `f` is
+    /// called afterwards, so it may allocate and free; anything it allocates
+    /// isn't visited. As with `find_allocation`, the bounds include the
+    /// canaries with the `redzones` feature, and allocations served by an
+    /// overflow allocator aren't included.
+    pub fn for_each_allocation_sorted(&self, mut f: impl FnMut(*mut u8, usize)) {
+        let mut allocations = Vec::new();
+        for heap in self.0.heaps() {
+            let heap = self.0.lock(heap);
+            unsafe { heap.dl.for_each_allocation(|ptr, size| allocations.push((ptr, size))) };
+        }
+        allocations.sort_unstable_by_key(|&(ptr, _)| ptr);
+        for (ptr, size) in allocations {
+            f(ptr, size);
+        }
+    }
+
+    /// Returns the file offset and bytes of every live allocation, in
+    /// ascending offset order, for backup tools that want to copy only the
+    /// data in use rather than the whole file, free space and all.
+    ///
+    /// The allocations are collected with the allocator locked, as with
+    /// `for_each_allocation_sorted`, whose caveats apply: each slice is the
+    /// allocation's usable size, canaries included with the `redzones`
+    /// feature, and allocations from `malloc_high` or an overflow allocator
+    /// aren't included.
+    ///
+    /// # Safety
+    ///
+    /// None of the allocations may be freed, reallocated or written to
+    /// while the iterator, or any slice from it, is alive.
+    pub unsafe fn live_regions(&self) -> impl Iterator<Item = (usize, &[u8])> + '_ {
+        let mut allocations = Vec::new();
+        self.for_each_allocation_sorted(|ptr, size| allocations.push((ptr, size)));
+        allocations.into_iter().map(|(ptr, size)| {
+            let offset = self.0.system.to_offset(ptr).unwrap();
+            (offset, slice::from_raw_parts(ptr, size))
+        })
+    }
+
+    /// Returns a hash of the heap's layout: where every chunk starts, how
+    /// big it is and whether it's in use.
+    ///
+    /// Two allocators that went through the same operations from the same
+    /// starting state have the same fingerprint, so this is a cheap way to
+    /// check that runs, or this port and the C `dlmalloc`, agree. It's the
+    /// 64-bit FNV-1a hash of each chunk's file offset, size and in-use flag
+    /// (0 or 1), as little-endian `u64`s, taking chunks in offset order.
+    /// Like `find_allocation` it walks the whole heap.
+    pub fn layout_fingerprint(&self) -> u64 {
+        let mut chunks = Vec::new();
+        for heap in self.0.heaps() {
+            let heap = self.0.lock(heap);
+            unsafe {
+                heap.dl.for_each_chunk(|ptr, size, inuse| {
+                    chunks.push((self.0.system.to_offset(ptr).unwrap(), size, inuse));
+                });
+            }
+        }
+        chunks.sort_unstable_by_key(|&(offset, ..)| offset);
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for (offset, size, inuse) in chunks {
+            for field in [offset as u64, size as u64, inuse as u64] {
+                for byte in field.to_le_bytes() {
+                    hash = (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+                }
+            }
+        }
+        hash
+    }
+
+    /// Returns the size of the arena, the same as `stats().logical_capacity`
+    /// but without walking the heap. This starts out as `total_size` rounded
+    /// up to the filesystem's block size, so it may be more than was asked
+    /// for.
+    pub fn total_size(&self) -> usize {
+        self.0.system.total_size()
+    }
+
+    /// Returns how far into the backing file memory has been handed out to
+    /// `dlmalloc`. Everything past this offset is untouched.
+    ///
+    /// This grows as the heap does and shrinks again when `trim` gives
+    /// memory at the end back.
+    pub fn offset(&self) -> usize {
+        self.0.system.offset()
+    }
+
+    /// Returns the furthest `offset` has ever reached, which is how much of
+    /// the file the arena has needed at its peak.
+    ///
+    /// Unlike `offset` this never goes down when memory is trimmed, so it's
+    /// the number to size `total_size` by.
+    pub fn high_water_mark(&self) -> usize {
+        self.0.system.high_water_mark()
+    }
+
+    /// Returns how many allocations are live: made and not yet freed, for
+    /// leak assertions in tests and quick health checks.
+    ///
+    /// Unlike walking the heap with `for_each_allocation`, this reads a
+    /// counter, without taking any lock. Allocations from `malloc_high`
+    /// count, those served by an overflow allocator or a
+    /// [`scope`](DiskDlmalloc::scope) don't. A `realloc` that moves an
+    /// allocation leaves the count as it was.
+    pub fn live_count(&self) -> usize {
+        self.0.live.load(Ordering::Relaxed)
+    }
+}
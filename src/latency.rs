@@ -0,0 +1,171 @@
+//! Latency histograms for allocator operations, enabled with the
+//! `latency_tracking` feature.
+//!
+//! Every `malloc`, `free` and `realloc` (including `calloc` and the
+//! `Allocator` methods) is timed and counted in a histogram with HDR-style
+//! log-linear buckets, accurate to within about 6%. Counting is a single
+//! atomic increment, so it doesn't add contention. Without the feature the
+//! timing code isn't compiled at all.
+
+use crate::Shared;
+
+/// The kinds of operation that are timed separately.
+#[derive(Clone, Copy)]
+pub(crate) enum Op {
+    Malloc,
+    Free,
+    Realloc,
+}
+
+impl Shared {
+    /// Runs `f`, recording how long it took as an `op`.
+    #[inline(always)]
+    pub(crate) fn timed<T>(&self, op: Op, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "latency_tracking")]
+        {
+            let start = std::time::Instant::now();
+            let res = f();
+            self.latency.record(op, start.elapsed());
+            res
+        }
+        #[cfg(not(feature = "latency_tracking"))]
+        {
+            let _ = op;
+            f()
+        }
+    }
+}
+
+#[cfg(feature = "latency_tracking")]
+pub use imp::*;
+
+#[cfg(feature = "latency_tracking")]
+mod imp {
+    use super::Op;
+    use crate::DiskDlmalloc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    /// Each power of two is split into this many linear sub-buckets.
+    const SUB_BITS: u32 = 4;
+    const SUB_BUCKETS: usize = 1 << SUB_BITS;
+    /// Enough buckets for any `u64` number of nanoseconds.
+    const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+    struct Histogram {
+        counts: Box<[AtomicU64]>,
+    }
+
+    impl Default for Histogram {
+        fn default() -> Histogram {
+            Histogram {
+                counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            }
+        }
+    }
+
+    impl Histogram {
+        fn index(nanos: u64) -> usize {
+            if nanos < SUB_BUCKETS as u64 {
+                return nanos as usize;
+            }
+            let exp = 63 - nanos.leading_zeros();
+            let sub = (nanos >> (exp - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+            (exp - SUB_BITS + 1) as usize * SUB_BUCKETS + sub
+        }
+
+        /// The largest value that lands in bucket `index`.
+        fn highest_equivalent(index: usize) -> u64 {
+            if index < SUB_BUCKETS {
+                return index as u64;
+            }
+            let shift = (index / SUB_BUCKETS - 1) as u32;
+            let sub = (index % SUB_BUCKETS + SUB_BUCKETS) as u64;
+            ((sub + 1) << shift) - 1
+        }
+
+        fn record(&self, elapsed: Duration) {
+            let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+            self.counts[Histogram::index(nanos)].fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn percentiles(&self) -> Percentiles {
+            let counts: Vec<u64> = self
+                .counts
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect();
+            let count: u64 = counts.iter().sum();
+            let at = |quantile: f64| {
+                let rank = ((count as f64 * quantile).ceil() as u64).max(1);
+                let mut seen = 0;
+                for (index, &n) in counts.iter().enumerate() {
+                    seen += n;
+                    if seen >= rank {
+                        return Duration::from_nanos(Histogram::highest_equivalent(index));
+                    }
+                }
+                Duration::ZERO
+            };
+            Percentiles {
+                count,
+                p50: at(0.50),
+                p90: at(0.90),
+                p99: at(0.99),
+            }
+        }
+    }
+
+    /// One histogram per [`Op`].
+    #[derive(Default)]
+    pub(crate) struct Latencies {
+        ops: [Histogram; 3],
+    }
+
+    impl Latencies {
+        pub(crate) fn record(&self, op: Op, elapsed: Duration) {
+            self.ops[op as usize].record(elapsed);
+        }
+    }
+
+    /// Latency percentiles of one kind of operation. All zero if there
+    /// haven't been any.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Percentiles {
+        /// Number of operations recorded.
+        pub count: u64,
+        /// Median latency.
+        pub p50: Duration,
+        /// 90th percentile latency.
+        pub p90: Duration,
+        /// 99th percentile latency.
+        pub p99: Duration,
+    }
+
+    /// Returned by [`DiskDlmalloc::op_latency_percentiles`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct LatencyReport {
+        /// `malloc` and `calloc`, and their `Allocator` counterparts.
+        pub malloc: Percentiles,
+        /// `free` and `Allocator::deallocate`.
+        pub free: Percentiles,
+        /// `realloc` and the `Allocator` methods that grow and shrink.
+        pub realloc: Percentiles,
+    }
+
+    impl DiskDlmalloc {
+        /// Returns latency percentiles for each kind of operation since the
+        /// allocator was created.
+        ///
+        /// Percentiles are reported as the upper end of the histogram bucket
+        /// they fall in, so they may overstate latencies by up to about 6%.
+        pub fn op_latency_percentiles(&self) -> LatencyReport {
+            let ops = &self.0.latency.ops;
+            LatencyReport {
+                malloc: ops[Op::Malloc as usize].percentiles(),
+                free: ops[Op::Free as usize].percentiles(),
+                realloc: ops[Op::Realloc as usize].percentiles(),
+            }
+        }
+    }
+}
@@ -12,20 +12,87 @@
 #![feature(allocator_api)]
 
 use core::cmp;
+use core::mem;
 use core::ptr;
 use std::alloc::{AllocError, Layout};
+use std::collections::HashMap;
+use std::io;
 use std::path::Path;
 use std::ptr::NonNull;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+use latency::Op;
+use std::task::Waker;
+use reentrancy::HeapGuard;
 use sys::System;
 
+mod builder;
+mod cancel;
+mod capabilities;
+mod capacity;
+mod compact;
+mod config;
 mod dlmalloc;
+mod dynamic;
+mod error;
+mod fault;
+mod flush;
+mod inspect;
+mod latency;
+mod migrate;
+mod observer;
+mod overflow;
+mod pool;
+mod redzone;
+mod reentrancy;
+mod scope;
+mod scoped;
+mod shadow;
+mod sigbus;
+mod split;
+mod stream;
 mod sys;
+mod transaction;
+#[cfg(target_os = "linux")]
+mod uffd;
+mod uninit;
+mod watermark;
+pub mod wire;
+mod writeback;
+#[cfg(feature = "trace")]
+pub mod trace;
 
+pub use builder::{Builder, CoalescePolicy, FileLock, FitPolicy};
+pub use cancel::CancellationToken;
+pub use capabilities::{Backend, Capabilities};
+pub use capacity::{CapacityWatcher, UntilAvailable};
+pub use config::ReloadableConfig;
+pub use dynamic::DynAllocator;
+pub use error::{Error, TryAllocError};
+pub use flush::FlushOutcome;
+pub use inspect::{BinInfo, BinStats, HealthReport, LeakReport, SegmentInfo, Stats};
+#[cfg(feature = "latency_tracking")]
+pub use latency::{LatencyReport, Percentiles};
+pub use observer::Observer;
+pub use pool::BufferPool;
+pub use scope::ScopeAllocator;
+pub use scoped::ScopedAllocator;
+pub use stream::{ArenaReader, ArenaWriter};
+pub use transaction::TxnAllocator;
+pub use uninit::ArenaUninit;
+pub use wire::{parse_stats, ParseStatsError};
 pub use memmap2::Advice;
 
 /// In order for this crate to efficiently manage memory, it needs a way to communicate with the
 /// underlying platform. This `Allocator` trait provides an interface for this communication.
+///
+/// # Safety
+///
+/// Implementations must hand out memory that stays valid, and isn't handed out
+/// again, until it's given back through `free`, `free_part` or `remap`, and
+/// must report the size they really mapped.
 pub unsafe trait SystemAllocator: Send {
     /// Allocates system memory region of at least `size` bytes
     /// Returns a triple of `(base, size, flags)` where `base` is a pointer to the beginning of the
@@ -65,7 +132,752 @@ pub unsafe trait SystemAllocator: Send {
 
 /// An allocator instance
 #[derive(Clone)]
-pub struct DiskDlmalloc(Arc<Mutex<dlmalloc::Dlmalloc<System>>>);
+pub struct DiskDlmalloc(Arc<Shared>);
+
+struct Shared {
+    system: System,
+    heap: Mutex<Heap>,
+    /// With lock striping enabled, requests of up to `small_max` bytes are
+    /// served from this separate heap so they never wait on the lock of the
+    /// main heap.
+    small: Option<Mutex<Heap>>,
+    small_max: usize,
+    /// Tasks waiting in `CapacityWatcher::until_available`.
+    waiters: Mutex<Vec<Waker>>,
+    /// Whether `waiters` may be non-empty, so frees can skip locking it.
+    has_waiters: AtomicBool,
+    /// Whether `malloc_high` was ever called, so frees can skip checking for
+    /// its allocations.
+    high_used: AtomicBool,
+    observer: Option<Arc<dyn Observer>>,
+    shadow: Arc<shadow::Shadow>,
+    /// Shared with the heaps, which count what they hand out.
+    live: Arc<AtomicUsize>,
+    /// Rings handed out by `alloc_magic_ring`, as the address of the ring
+    /// and of the allocation backing it.
+    rings: Mutex<Vec<(usize, usize)>>,
+    /// Bytes currently allocated through `ScopedAllocator`s, by token.
+    usage: Mutex<HashMap<u64, usize>>,
+    watermarks: watermark::Watermarks,
+    /// The settings last applied with `reload`.
+    config: Mutex<ReloadableConfig>,
+    /// Where allocations go when the arena can't serve them, set by
+    /// `with_overflow`.
+    overflow: OnceLock<Arc<overflow::Fallback>>,
+    warn_on_leak: bool,
+    panic_on_leak: bool,
+    /// See `Builder::realloc_growth_factor`.
+    realloc_growth_factor: f64,
+    /// See `Builder::flush_on_free`.
+    flush_on_free: bool,
+    /// The first failure to flush a freed allocation, kept for `close` when
+    /// there's no observer to report it to.
+    flush_error: Mutex<Option<io::Error>>,
+    /// See `Builder::retry_with_trim`.
+    retry_with_trim: bool,
+    /// See `Builder::writeback_limit`.
+    writeback: Option<writeback::Writeback>,
+    #[cfg(feature = "latency_tracking")]
+    latency: latency::Latencies,
+    #[cfg(feature = "fault-injection")]
+    faults: fault::Faults,
+    #[cfg(feature = "trace")]
+    trace: Mutex<Option<Box<dyn trace::TraceSink>>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if !self.warn_on_leak && !self.panic_on_leak {
+            return;
+        }
+        let report = LeakReport::of(self);
+        if report.allocations == 0 {
+            return;
+        }
+        if self.warn_on_leak {
+            // `build` won't set `warn_on_leak` without an observer.
+            if let Some(observer) = &self.observer {
+                observer.on_leak(&report);
+            }
+        }
+        // A second panic while unwinding would abort.
+        if self.panic_on_leak && !thread::panicking() {
+            panic!("{}", report);
+        }
+    }
+}
+
+/// State guarded by an allocator lock: the `dlmalloc` instance itself plus
+/// any bookkeeping that has to stay in sync with it.
+struct Heap {
+    dl: dlmalloc::Dlmalloc<System>,
+    observer: Option<Arc<dyn Observer>>,
+    fill_on_alloc: Option<u8>,
+    fill_on_free: Option<u8>,
+    /// See `Builder::calloc_punch_threshold`.
+    calloc_punch_threshold: usize,
+    /// See `Builder::align_to_size_class`.
+    align_to_size_class: bool,
+    shadow: Arc<shadow::Shadow>,
+    /// How many allocations are live across all heaps, see `live_count`.
+    live: Arc<AtomicUsize>,
+}
+
+impl Heap {
+    fn new(
+        system: System,
+        builder: &Builder,
+        shadow: Arc<shadow::Shadow>,
+        live: Arc<AtomicUsize>,
+    ) -> Heap {
+        let mut dl = dlmalloc::Dlmalloc::new(system);
+        dl.set_first_fit(builder.fit_policy == FitPolicy::FirstFit);
+        unsafe { dl.set_defer_coalescing(builder.coalesce_policy == CoalescePolicy::Deferred) };
+        dl.set_trim_threshold(builder.config.effective_trim_threshold());
+        dl.set_mmap_threshold(builder.mmap_threshold);
+        dl.set_max_segments(builder.max_segments);
+        dl.set_small_threshold(builder.small_bin_threshold);
+        Heap {
+            dl,
+            observer: builder.observer.clone(),
+            fill_on_alloc: builder.fill_on_alloc,
+            fill_on_free: builder.fill_on_free,
+            calloc_punch_threshold: builder.calloc_punch_threshold,
+            align_to_size_class: builder.align_to_size_class,
+            shadow,
+            live,
+        }
+    }
+
+    /// The alignment an allocation of `size` bytes asked for with `align`
+    /// actually gets, see `Builder::align_to_size_class`.
+    fn align_for(&self, size: usize, align: usize) -> usize {
+        if !self.align_to_size_class {
+            return align;
+        }
+        let page = self.dl.system_allocator().page_size();
+        cmp::max(align, cmp::min(size.next_power_of_two(), page))
+    }
+
+    unsafe fn malloc_unguarded(&mut self, size: usize, align: usize) -> *mut u8 {
+        let raw = if align <= self.dl.malloc_alignment() {
+            self.dl.malloc(size)
+        } else {
+            self.dl.memalign(align, size)
+        };
+        if !raw.is_null() {
+            self.live.fetch_add(1, Ordering::Relaxed);
+        }
+        raw
+    }
+
+    unsafe fn malloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        let align = self.align_for(size, align);
+        let raw = self.malloc_unguarded(redzone::padded(size, align), align);
+        let ptr = redzone::arm(raw, size, align);
+        self.mark_allocated(raw, ptr, size);
+        fill(ptr, size, self.fill_on_alloc);
+        ptr
+    }
+
+    unsafe fn malloc_near(
+        &mut self,
+        size: usize,
+        align: usize,
+        lo: *mut u8,
+        hi: *mut u8,
+    ) -> *mut u8 {
+        let align = self.align_for(size, align);
+        if align > self.dl.malloc_alignment() {
+            return self.malloc(size, align);
+        }
+        let raw = self.dl.malloc_near(redzone::padded(size, align), lo, hi);
+        if !raw.is_null() {
+            self.live.fetch_add(1, Ordering::Relaxed);
+        }
+        let ptr = redzone::arm(raw, size, align);
+        self.mark_allocated(raw, ptr, size);
+        fill(ptr, size, self.fill_on_alloc);
+        ptr
+    }
+
+    /// Returns the part of the allocation at `ptr` that can be discarded once
+    /// it's freed, see `Dlmalloc::free_interior`.
+    unsafe fn free_interior(&self, ptr: *mut u8, size: usize, align: usize) -> (*mut u8, usize) {
+        let align = self.align_for(size, align);
+        let (raw, _) = redzone::disarm(ptr, size, align);
+        self.dl.free_interior(raw)
+    }
+
+    /// Returns how many bytes of the allocation at `ptr` the caller may use.
+    unsafe fn usable_size(&self, ptr: *mut u8, size: usize, align: usize) -> usize {
+        let align = self.align_for(size, align);
+        let (raw, _) = redzone::disarm(ptr, size, align);
+        redzone::usable(size, self.dl.usable_size(raw))
+    }
+
+    unsafe fn calloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        let align = self.align_for(size, align);
+        let padded = redzone::padded(size, align);
+        let raw = self.malloc_unguarded(padded, align);
+        if !raw.is_null() && self.dl.calloc_must_clear(raw) {
+            let punched = padded >= self.calloc_punch_threshold
+                && self.dl.system_allocator().zero_sparse(raw, padded);
+            if !punched {
+                ptr::write_bytes(raw, 0, padded);
+            }
+        }
+        let ptr = redzone::arm(raw, size, align);
+        self.mark_allocated(raw, ptr, size);
+        ptr
+    }
+
+    unsafe fn free(&mut self, ptr: *mut u8, size: usize, align: usize) {
+        let align = self.align_for(size, align);
+        let raw = self.check_redzone(ptr, size, align);
+        self.dl.validate_size(raw, redzone::padded(size, align));
+        fill(ptr, size, self.fill_on_free);
+        self.mark_freed(raw);
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        self.dl.free(raw)
+    }
+
+    unsafe fn realloc(
+        &mut self,
+        ptr: *mut u8,
+        old_size: usize,
+        old_align: usize,
+        new_size: usize,
+        new_align: usize,
+    ) -> *mut u8 {
+        let old_align = self.align_for(old_size, old_align);
+        let new_align = self.align_for(new_size, new_align);
+        let raw = self.check_redzone(ptr, old_size, old_align);
+        self.dl
+            .validate_size(raw, redzone::padded(old_size, old_align));
+
+        if old_align <= self.dl.malloc_alignment() && new_align <= self.dl.malloc_alignment() {
+            self.mark_freed(raw);
+            let new_raw = self.dl.realloc(raw, redzone::padded(new_size, new_align));
+            let res = redzone::arm(new_raw, new_size, new_align);
+            if new_raw.is_null() {
+                self.mark_allocated(raw, ptr, old_size);
+            } else {
+                self.mark_allocated(new_raw, res, new_size);
+            }
+            if new_size > old_size {
+                fill(res.wrapping_add(old_size), new_size - old_size, self.fill_on_alloc);
+            }
+            res
+        } else {
+            let res = self.malloc(new_size, new_align);
+            if !res.is_null() {
+                let size = cmp::min(old_size, new_size);
+                ptr::copy_nonoverlapping(ptr, res, size);
+                fill(ptr, old_size, self.fill_on_free);
+                self.mark_freed(raw);
+                self.live.fetch_sub(1, Ordering::Relaxed);
+                self.dl.free(raw);
+            }
+            res
+        }
+    }
+
+    /// Like `realloc` to a smaller size, but with `dlmalloc` giving back any
+    /// surplus of at least a page, see `Dlmalloc::set_strict_shrink`.
+    unsafe fn realloc_shrink_strict(
+        &mut self,
+        ptr: *mut u8,
+        size: usize,
+        align: usize,
+        new_size: usize,
+    ) -> *mut u8 {
+        self.dl.set_strict_shrink(true);
+        let res = self.realloc(ptr, size, align, new_size, align);
+        self.dl.set_strict_shrink(false);
+        res
+    }
+
+    /// Moves a naturally aligned allocation to the lowest free spot that fits
+    /// it, returning the new pointer, or `ptr` itself if there's nothing
+    /// lower. Returns null, leaving `ptr` alone, only if allocating failed.
+    unsafe fn relocate(&mut self, ptr: *mut u8, size: usize) -> *mut u8 {
+        let align = MALLOC_ALIGNMENT;
+        // Moving it would lose the alignment of its size class.
+        if self.align_for(size, align) > align {
+            return ptr;
+        }
+        let raw = self.check_redzone(ptr, size, align);
+        let padded = redzone::padded(size, align);
+        self.dl.validate_size(raw, padded);
+        let new_raw = self.dl.malloc_lowest(padded);
+        if new_raw.is_null() {
+            redzone::arm(raw, size, align);
+            return ptr::null_mut();
+        }
+        if new_raw > raw {
+            self.dl.free(new_raw);
+            return redzone::arm(raw, size, align);
+        }
+        let res = redzone::arm(new_raw, size, align);
+        self.mark_allocated(new_raw, res, size);
+        ptr::copy_nonoverlapping(ptr, res, size);
+        fill(ptr, size, self.fill_on_free);
+        self.mark_freed(raw);
+        self.dl.free(raw);
+        res
+    }
+
+    /// Records in the shadow map that the chunk at `raw` now holds the
+    /// allocation of `size` bytes at `ptr`: its usable part is addressable,
+    /// the chunk header and any redzones aren't.
+    unsafe fn mark_allocated(&self, raw: *mut u8, ptr: *mut u8, size: usize) {
+        if cfg!(feature = "shadow") && !raw.is_null() {
+            let chunk = self.dl.usable_size(raw);
+            let sys = self.dl.system_allocator();
+            self.shadow
+                .poison(sys, raw.sub(mem::size_of::<usize>()), raw.add(chunk));
+            let usable = redzone::usable(size, chunk);
+            self.shadow.unpoison(sys, ptr, ptr.add(usable));
+        }
+    }
+
+    /// Records in the shadow map that the chunk at `raw` is about to be
+    /// freed.
+    unsafe fn mark_freed(&self, raw: *mut u8) {
+        if cfg!(feature = "shadow") {
+            let chunk = self.dl.usable_size(raw);
+            self.shadow.poison(
+                self.dl.system_allocator(),
+                raw.sub(mem::size_of::<usize>()),
+                raw.add(chunk),
+            );
+        }
+    }
+
+    /// Verifies the redzone around an allocation that's about to be freed or
+    /// reallocated, returning the start of the underlying chunk's memory.
+    unsafe fn check_redzone(&self, ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
+        let (raw, intact) = redzone::disarm(ptr, size, align);
+        if !intact {
+            match &self.observer {
+                Some(observer) => observer.on_corruption(ptr, size),
+                None => panic!(
+                    "redzone around the {} byte allocation at {:p} was overwritten",
+                    size, ptr
+                ),
+            }
+        }
+        raw
+    }
+}
+
+/// Overwrites `size` bytes at `ptr` with `pattern`, if there is one.
+unsafe fn fill(ptr: *mut u8, size: usize, pattern: Option<u8>) {
+    if let Some(pattern) = pattern {
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, pattern, size);
+        }
+    }
+}
+
+impl Shared {
+    /// Returns the heap responsible for allocations of `size` bytes aligned
+    /// to `align`. Frees are routed by the same rule, which is why callers
+    /// have to hand back the original size.
+    fn heap_for(&self, size: usize, align: usize) -> &Mutex<Heap> {
+        match &self.small {
+            Some(small) if size <= self.small_max && align <= MALLOC_ALIGNMENT => small,
+            _ => &self.heap,
+        }
+    }
+
+    fn heaps(&self) -> impl Iterator<Item = &Mutex<Heap>> {
+        core::iter::once(&self.heap).chain(&self.small)
+    }
+
+    /// Locks one of our heaps. All heap locking goes through here so the
+    /// observer sees every acquisition.
+    ///
+    /// Panics if this thread already holds the lock, say because an observer
+    /// called back into the allocator, rather than deadlocking.
+    fn lock<'a>(&self, heap: &'a Mutex<Heap>) -> HeapGuard<'a> {
+        match self.lock_unless_held(heap) {
+            Some(guard) => guard,
+            None => panic!("disk-dlmalloc: allocator re-entered while locked by the same thread"),
+        }
+    }
+
+    /// Like `lock`, but returns `None` if this thread already holds the
+    /// lock. Allocation paths use this so code running under the lock can
+    /// still call them and just fail. A nested free has no way to fail, so
+    /// it goes through `lock` and panics instead of leaking.
+    fn lock_unless_held<'a>(&self, heap: &'a Mutex<Heap>) -> Option<HeapGuard<'a>> {
+        if reentrancy::held(heap) {
+            return None;
+        }
+        let guard = HeapGuard::new(heap.lock().unwrap(), heap);
+        self.locked();
+        Some(guard)
+    }
+
+    /// Like `lock`, but gives up at `deadline`. `std`'s mutex can't wait
+    /// with a timeout, so this polls with exponential backoff, which adds up
+    /// to a millisecond of latency once the lock is contended.
+    fn lock_until<'a>(
+        &self,
+        heap: &'a Mutex<Heap>,
+        deadline: Instant,
+    ) -> Option<HeapGuard<'a>> {
+        if reentrancy::held(heap) {
+            return None;
+        }
+        let mut backoff = Duration::from_micros(1);
+        loop {
+            match heap.try_lock() {
+                Ok(guard) => {
+                    let guard = HeapGuard::new(guard, heap);
+                    self.locked();
+                    return Some(guard);
+                }
+                Err(TryLockError::Poisoned(err)) => panic!("{}", err),
+                Err(TryLockError::WouldBlock) => {}
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            thread::sleep(cmp::min(backoff, deadline - now));
+            backoff = cmp::min(backoff * 2, Duration::from_millis(1));
+        }
+    }
+
+    fn locked(&self) {
+        if let Some(observer) = &self.observer {
+            observer.on_lock();
+        }
+    }
+
+    unsafe fn malloc(&self, size: usize, align: usize) -> *mut u8 {
+        if self.inject_fault() {
+            return ptr::null_mut();
+        }
+        let attempt = || {
+            self.timed(Op::Malloc, || {
+                match self.lock_unless_held(self.heap_for(size, align)) {
+                    Some(mut heap) => heap.malloc(size, align),
+                    None => ptr::null_mut(),
+                }
+            })
+        };
+        self.alloc_or_overflow(size, align, false, attempt)
+    }
+
+    /// Makes an allocation with `attempt`, trying it once more after a trim
+    /// if it fails and `retry_with_trim` is set, and going to the overflow
+    /// allocator if that fails too.
+    unsafe fn alloc_or_overflow(
+        &self,
+        size: usize,
+        align: usize,
+        zeroed: bool,
+        attempt: impl Fn() -> *mut u8,
+    ) -> *mut u8 {
+        let mut ptr = attempt();
+        if ptr.is_null() && self.retry_with_trim && self.trim_for_retry() {
+            ptr = attempt();
+        }
+        self.check_watermarks();
+        if ptr.is_null() {
+            return self.overflow_alloc(size, align, zeroed);
+        }
+        self.dirtied(ptr, size);
+        ptr
+    }
+
+    unsafe fn malloc_with_usable(&self, size: usize, align: usize) -> (*mut u8, usize) {
+        if self.inject_fault() {
+            return (ptr::null_mut(), 0);
+        }
+        let attempt = || {
+            self.timed(Op::Malloc, || {
+                match self.lock_unless_held(self.heap_for(size, align)) {
+                    Some(mut heap) => {
+                        let ptr = heap.malloc(size, align);
+                        if ptr.is_null() {
+                            (ptr, 0)
+                        } else {
+                            (ptr, heap.usable_size(ptr, size, align))
+                        }
+                    }
+                    None => (ptr::null_mut(), 0),
+                }
+            })
+        };
+        let (mut ptr, mut usable) = attempt();
+        if ptr.is_null() && self.retry_with_trim && self.trim_for_retry() {
+            (ptr, usable) = attempt();
+        }
+        self.check_watermarks();
+        if ptr.is_null() {
+            let ptr = self.overflow_alloc(size, align, false);
+            return (ptr, if ptr.is_null() { 0 } else { size });
+        }
+        self.dirtied(ptr, size);
+        (ptr, self.cap_usable(usable, size, align))
+    }
+
+    /// Trims every heap this thread isn't already in, so that free memory
+    /// one of them holds at its end can go to another. Returns whether any
+    /// was given back.
+    fn trim_for_retry(&self) -> bool {
+        let mut released = false;
+        for heap in self.heaps() {
+            if let Some(mut heap) = self.lock_unless_held(heap) {
+                released |= unsafe { heap.dl.trim(0) };
+            }
+        }
+        released
+    }
+
+    /// Caps the usable size of an allocation of `size` bytes so that freeing
+    /// it with the capped size still finds the heap it came from.
+    fn cap_usable(&self, usable: usize, size: usize, align: usize) -> usize {
+        if ptr::eq(self.heap_for(size, align), &self.heap) {
+            usable
+        } else {
+            cmp::min(usable, self.small_max)
+        }
+    }
+
+    unsafe fn calloc(&self, size: usize, align: usize) -> *mut u8 {
+        if self.inject_fault() {
+            return ptr::null_mut();
+        }
+        let attempt = || {
+            self.timed(Op::Malloc, || {
+                match self.lock_unless_held(self.heap_for(size, align)) {
+                    Some(mut heap) => heap.calloc(size, align),
+                    None => ptr::null_mut(),
+                }
+            })
+        };
+        self.alloc_or_overflow(size, align, true, attempt)
+    }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        old_size: usize,
+        old_align: usize,
+        new_size: usize,
+        new_align: usize,
+    ) -> *mut u8 {
+        if self.inject_fault() {
+            return ptr::null_mut();
+        }
+        let res = self.timed(Op::Realloc, || {
+            self.realloc_untimed(ptr, old_size, old_align, new_size, new_align)
+        });
+        self.check_watermarks();
+        self.dirtied(res, new_size);
+        res
+    }
+
+    unsafe fn realloc_shrink_strict(
+        &self,
+        ptr: *mut u8,
+        size: usize,
+        align: usize,
+        new_size: usize,
+    ) -> *mut u8 {
+        if self.overflow_owner(ptr).is_none() {
+            let heap = self.heap_for(size, align);
+            if ptr::eq(heap, self.heap_for(new_size, align)) {
+                let res = match self.lock_unless_held(heap) {
+                    Some(mut heap) => heap.realloc_shrink_strict(ptr, size, align, new_size),
+                    None => ptr::null_mut(),
+                };
+                if !res.is_null() {
+                    self.freed();
+                    return res;
+                }
+            }
+        }
+        self.realloc_untimed(ptr, size, align, new_size, align)
+    }
+
+    unsafe fn realloc_untimed(
+        &self,
+        ptr: *mut u8,
+        old_size: usize,
+        old_align: usize,
+        new_size: usize,
+        new_align: usize,
+    ) -> *mut u8 {
+        if let Some(fallback) = self.overflow_owner(ptr) {
+            return Self::overflow_realloc(fallback, ptr, old_size, old_align, new_size, new_align);
+        }
+        let from = self.heap_for(old_size, old_align);
+        let to = self.heap_for(new_size, new_align);
+        let res = if ptr::eq(from, to) {
+            match self.lock_unless_held(from) {
+                Some(mut me) => me.realloc(ptr, old_size, old_align, new_size, new_align),
+                None => ptr::null_mut(),
+            }
+        } else {
+            // Moving between stripes: take the locks one after the other
+            // rather than nesting them. Fail up front if the old one can't be
+            // taken, rather than copying and leaking `ptr`.
+            let res = match self.lock_unless_held(to) {
+                Some(mut to) if !reentrancy::held(from) => to.malloc(new_size, new_align),
+                _ => ptr::null_mut(),
+            };
+            if !res.is_null() {
+                ptr::copy_nonoverlapping(ptr, res, cmp::min(old_size, new_size));
+                self.lock(from).free(ptr, old_size, old_align);
+            }
+            res
+        };
+        if res.is_null() {
+            return self.realloc_into_overflow(ptr, old_size, old_align, new_size, new_align);
+        }
+        if new_size < old_size {
+            self.freed();
+        }
+        res
+    }
+
+    unsafe fn free(&self, ptr: *mut u8, size: usize, align: usize) {
+        if self.overflow_free(ptr, size, align) {
+            return;
+        }
+        // Without an overflow allocator to hand it to, a pointer from
+        // elsewhere would corrupt the heap.
+        if cfg!(all(feature = "debug", debug_assertions)) && self.system.to_offset(ptr).is_none() {
+            panic!("disk-dlmalloc: free of {:p}, which isn't in the arena", ptr);
+        }
+        self.flush_freed(ptr, size);
+        if self.free_high(ptr, size) {
+            self.freed();
+            return;
+        }
+        self.timed(Op::Free, || {
+            self.lock(self.heap_for(size, align)).free(ptr, size, align)
+        });
+        self.freed();
+    }
+
+    /// Writes an allocation that's being freed back to the file, with
+    /// `flush_on_free`. A failure goes to the observer, as `free` has no way
+    /// to return it, or without one is kept for `close`.
+    fn flush_freed(&self, ptr: *mut u8, size: usize) {
+        if !self.flush_on_free {
+            return;
+        }
+        if let Err(err) = self.system.sync(ptr, size) {
+            match &self.observer {
+                Some(observer) => observer.on_flush_error(ptr, size, &err),
+                None => {
+                    self.flush_error.lock().unwrap().get_or_insert(err);
+                }
+            }
+        }
+    }
+
+    /// Frees `ptr` if it came from `malloc_high`, returning whether it did.
+    fn free_high(&self, ptr: *mut u8, size: usize) -> bool {
+        let freed = self.high_used.load(Ordering::Relaxed) && self.system.free_high(ptr, size);
+        if freed {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+        }
+        freed
+    }
+
+    /// Wakes anyone waiting for memory to become available. Called after
+    /// every operation that gives memory back.
+    fn freed(&self) {
+        // Pairs with the fence in `UntilAvailable::poll`: either we see its
+        // flag, or it sees the memory we freed when it checks availability.
+        atomic::fence(Ordering::SeqCst);
+        if !self.has_waiters.load(Ordering::Relaxed) {
+            return;
+        }
+        let waiters = {
+            let mut waiters = self.waiters.lock().unwrap();
+            self.has_waiters.store(false, Ordering::Relaxed);
+            mem::take(&mut *waiters)
+        };
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+
+    fn heap_free(&self) -> usize {
+        self.heaps()
+            .map(|heap| unsafe { self.lock(heap).dl.mallinfo().fordblks })
+            .sum()
+    }
+
+    fn available(&self) -> usize {
+        self.system.obtainable() + self.heap_free()
+    }
+
+    /// Reports an `arena_read` or `arena_write` of `len` bytes at `ptr` that
+    /// touches poisoned memory.
+    fn check_access(&self, ptr: *const u8, len: usize) {
+        let Some(poisoned) = self.shadow.first_poisoned(&self.system, ptr, len) else {
+            return;
+        };
+        match &self.observer {
+            Some(observer) => observer.on_poisoned_access(ptr, len, poisoned),
+            None => panic!(
+                "{} byte access at {:p} touches freed or reserved memory at {:p}",
+                len, ptr, poisoned
+            ),
+        }
+    }
+
+    /// Describes how much free memory there is after an allocation failed.
+    /// Heaps that can't be locked by `deadline` are left out.
+    fn out_of_memory(&self, deadline: Option<Instant>) -> TryAllocError {
+        let mut largest_free_block = self.system.largest_obtainable();
+        let mut total_free = self.system.obtainable();
+        for heap in self.heaps() {
+            let heap = match deadline {
+                Some(deadline) => self.lock_until(heap, deadline),
+                None => self.lock_unless_held(heap),
+            };
+            let Some(heap) = heap else { continue };
+            unsafe {
+                heap.dl.for_each_chunk(|_, size, inuse| {
+                    if !inuse {
+                        largest_free_block = cmp::max(largest_free_block, size);
+                        total_free += size;
+                    }
+                });
+            }
+        }
+        TryAllocError::OutOfMemory {
+            largest_free_block,
+            total_free,
+        }
+    }
+}
+
+/// Where `new_deterministic` maps its arena, well away from where the heap,
+/// stacks and shared libraries usually live.
+#[cfg(target_pointer_width = "64")]
+const DETERMINISTIC_BASE: usize = 0x6000_0000_0000;
+#[cfg(not(target_pointer_width = "64"))]
+const DETERMINISTIC_BASE: usize = 0x4000_0000;
+
+/// The size and alignment of what `alloc_thp` hands out: one x86-64 or
+/// aarch64 (4 KiB granule) transparent huge page.
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// The alignment `dlmalloc` guarantees without going through `memalign`.
+const MALLOC_ALIGNMENT: usize = 2 * mem::size_of::<usize>();
 
 impl DiskDlmalloc {
     /// Creates a new instance of an allocator
@@ -74,9 +886,114 @@ impl DiskDlmalloc {
         total_size: usize,
         mem_advise: Option<Advice>,
     ) -> DiskDlmalloc {
-        DiskDlmalloc(Arc::new(Mutex::new(dlmalloc::Dlmalloc::new(System::new(
-            file_path, total_size, mem_advise,
-        )))))
+        let mut builder = DiskDlmalloc::builder(file_path, total_size);
+        if let Some(mem_advise) = mem_advise {
+            builder = builder.mem_advise(mem_advise);
+        }
+        match builder.build() {
+            Ok(a) => a,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates an allocator for tests that wants allocations to land at the
+    /// same addresses every run, so the heap layout can be compared against
+    /// golden files.
+    ///
+    /// The arena is mapped at a fixed base address with
+    /// `MAP_FIXED_NOREPLACE` (see [`Builder::base_address`]). That's
+    /// best-effort, so only offsets are guaranteed to be stable: only one
+    /// such allocator can be at the fixed base at a time, and any others are
+    /// mapped wherever the kernel likes. Panics where `new` would.
+    pub fn new_deterministic<P: AsRef<Path>>(file_path: P, total_size: usize) -> DiskDlmalloc {
+        let file_path = file_path.as_ref();
+        let res = match DiskDlmalloc::builder(file_path, total_size)
+            .base_address(DETERMINISTIC_BASE)
+            .build()
+        {
+            Err(Error::MappingOverlaps { .. }) => {
+                DiskDlmalloc::builder(file_path, total_size).build()
+            }
+            res => res,
+        };
+        match res {
+            Ok(a) => a,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates an allocator over `capacity` bytes of ordinary heap memory
+    /// instead of a file, for tests and for environments without a
+    /// filesystem.
+    ///
+    /// All of the allocator proper runs as usual, but nothing is mapped and
+    /// no system calls are made on the arena, so this also runs under Miri.
+    /// The arena can't grow, and what concerns the file or the mapping either
+    /// does nothing, like flushing or prefetching, or fails with
+    /// [`io::ErrorKind::Unsupported`], like [`commit`](DiskDlmalloc::commit)
+    /// or [`swap_backing`](DiskDlmalloc::swap_backing). The memory is freed
+    /// with the last handle.
+    pub fn new_in_memory(capacity: usize) -> DiskDlmalloc {
+        // The builder's file path goes unused.
+        let builder = DiskDlmalloc::builder("", capacity);
+        DiskDlmalloc::from_system(System::in_memory(&builder), &builder)
+    }
+
+    /// Returns a [`Builder`] for an allocator backed by `file_path`, for when
+    /// the defaults used by `new` aren't what you want.
+    ///
+    /// The whole arena is mapped for the allocator's lifetime, since every
+    /// pointer it hands out has to stay valid until it's freed. `total_size`
+    /// is therefore bounded by the address space rather than by the file, so
+    /// on 32-bit targets it can't get anywhere near 4 GiB. The file is sized
+    /// in whole filesystem blocks (`st_blksize`), so the arena may end up a
+    /// little larger than `total_size`, see
+    /// [`total_size`](DiskDlmalloc::total_size).
+    ///
+    /// There's no per-NUMA-node placement. The arena is page cache, and the
+    /// kernel ignores `mbind` policies on file mappings, placing each page
+    /// by the memory policy of the thread that first faults it in. Pinning
+    /// the allocating thread to a node with `set_mempolicy` is the way to get
+    /// node-local pages.
+    pub fn builder<P: AsRef<Path>>(file_path: P, total_size: usize) -> Builder {
+        Builder::new(file_path, total_size)
+    }
+
+    fn from_system(system: System, builder: &Builder) -> DiskDlmalloc {
+        let shadow = Arc::new(shadow::Shadow::default());
+        let live = Arc::new(AtomicUsize::new(0));
+        let heap = || Heap::new(system.clone(), builder, shadow.clone(), live.clone());
+        let small_max = heap().dl.max_small_request();
+        DiskDlmalloc(Arc::new(Shared {
+            heap: Mutex::new(heap()),
+            small: builder.lock_striping.then(|| Mutex::new(heap())),
+            small_max,
+            system,
+            waiters: Mutex::new(Vec::new()),
+            has_waiters: AtomicBool::new(false),
+            high_used: AtomicBool::new(false),
+            observer: builder.observer.clone(),
+            shadow,
+            live,
+            rings: Mutex::new(Vec::new()),
+            usage: Mutex::new(HashMap::new()),
+            watermarks: watermark::Watermarks::default(),
+            overflow: OnceLock::new(),
+            warn_on_leak: builder.warn_on_leak,
+            panic_on_leak: builder.panic_on_leak,
+            realloc_growth_factor: builder.realloc_growth_factor,
+            flush_on_free: builder.flush_on_free,
+            flush_error: Mutex::new(None),
+            retry_with_trim: builder.retry_with_trim,
+            writeback: builder.writeback_limit.map(writeback::Writeback::new),
+            config: Mutex::new(builder.config),
+            #[cfg(feature = "latency_tracking")]
+            latency: latency::Latencies::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: fault::Faults::new(builder),
+            #[cfg(feature = "trace")]
+            trace: Mutex::new(None),
+        }))
     }
 }
 
@@ -86,41 +1003,330 @@ impl DiskDlmalloc {
     /// Returns a null pointer if allocation fails. Returns a valid pointer
     /// otherwise.
     ///
+    /// A `size` of zero still allocates: the result is a unique, aligned
+    /// pointer that must be passed to `free` like any other.
+    ///
+    /// # Safety
+    ///
     /// Safety and contracts are largely governed by the `GlobalAlloc::alloc`
     /// method contracts.
     #[inline]
     pub unsafe fn malloc(&self, size: usize, align: usize) -> *mut u8 {
-        let mut me = self.0.lock().unwrap();
-        if align <= me.malloc_alignment() {
-            me.malloc(size)
+        let ptr = self.0.malloc(size, align);
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Malloc {
+            size,
+            align,
+            result: sys.to_offset(ptr),
+        });
+        ptr
+    }
+
+    /// Like `malloc`, but also returns how many bytes the allocation can
+    /// really hold, which is at least `size` and often a little more as
+    /// chunk sizes are rounded up. Lets growable containers claim the spare
+    /// room straight away.
+    ///
+    /// Any size from `size` up to the returned one may be passed back to
+    /// `free` or `realloc`. With the `redzones` feature the canary follows
+    /// the requested bytes directly, so the usable size is exactly `size`.
+    ///
+    /// # Safety
+    ///
+    /// As for `malloc`.
+    pub unsafe fn malloc_with_usable(&self, size: usize, align: usize) -> (*mut u8, usize) {
+        let (ptr, usable) = self.0.malloc_with_usable(size, align);
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Malloc {
+            size,
+            align,
+            result: sys.to_offset(ptr),
+        });
+        (ptr, usable)
+    }
+
+    /// Returns how many bytes the live allocation at `ptr`, made with `size`
+    /// and `align`, can hold. The same as what `malloc_with_usable` reports.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation from this allocator made with `size` and
+    /// `align`.
+    pub unsafe fn usable_size(&self, ptr: *mut u8, size: usize, align: usize) -> usize {
+        if self.0.overflow_owner(ptr).is_some() {
+            return size;
+        }
+        let usable = self.0.lock(self.0.heap_for(size, align)).usable_size(ptr, size, align);
+        self.0.cap_usable(usable, size, align)
+    }
+
+    /// Copies `buf.len()` bytes of the arena at `src` into `buf`.
+    ///
+    /// With the `shadow` feature the range is first checked against the
+    /// shadow map, and reading memory that was freed, or the bookkeeping
+    /// around an allocation, is reported to
+    /// [`Observer::on_poisoned_access`], or panics without an observer.
+    /// Without the feature this is a plain copy.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads of `buf.len()` bytes.
+    pub unsafe fn arena_read(&self, src: *const u8, buf: &mut [u8]) {
+        self.0.check_access(src, buf.len());
+        ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len());
+    }
+
+    /// Copies `data` into the arena at `dst`, checked like `arena_read`.
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid for writes of `data.len()` bytes.
+    pub unsafe fn arena_write(&self, dst: *mut u8, data: &[u8]) {
+        self.0.check_access(dst, data.len());
+        ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+    }
+
+    /// Returns the alignment every `malloc` result has regardless of the
+    /// `align` asked for: twice the size of a pointer, so 16 on 64-bit
+    /// targets.
+    ///
+    /// Requests aligned to at most this take `dlmalloc`'s plain allocation
+    /// path; anything stricter goes through `memalign`, which is slower and
+    /// wastes more space.
+    pub fn malloc_alignment(&self) -> usize {
+        MALLOC_ALIGNMENT
+    }
+
+    /// Like `malloc`, but tries to place the allocation on the same page as
+    /// `hint`, for linked structures whose nodes are visited together.
+    ///
+    /// Only free space starting on `hint`'s page is considered; if none of
+    /// it is big enough, or `align` is more than `malloc` guarantees anyway,
+    /// this behaves exactly like `malloc`. Finding that space walks the heap
+    /// from the start of the segment holding `hint`, so this is slower than
+    /// `malloc` on large heaps.
+    ///
+    /// # Safety
+    ///
+    /// As for `malloc`.
+    pub unsafe fn malloc_near(&self, size: usize, align: usize, hint: *const u8) -> *mut u8 {
+        let ptr = if self.0.inject_fault() {
+            self.0.check_watermarks();
+            ptr::null_mut()
+        } else {
+            let page = self.0.system.page_size();
+            let lo = hint.cast_mut().map_addr(|addr| addr & !(page - 1));
+            let hi = lo.wrapping_add(page);
+            let attempt = || {
+                self.0.timed(Op::Malloc, || {
+                    match self.0.lock_unless_held(self.0.heap_for(size, align)) {
+                        Some(mut heap) => heap.malloc_near(size, align, lo, hi),
+                        None => ptr::null_mut(),
+                    }
+                })
+            };
+            self.0.alloc_or_overflow(size, align, false, attempt)
+        };
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::MallocNear {
+            size,
+            align,
+            hint: sys.to_offset(hint.cast_mut()),
+            result: sys.to_offset(ptr),
+        });
+        ptr
+    }
+
+    /// Allocates `size` bytes from the top of the arena down, where `malloc`
+    /// works from the bottom up, so that long-lived data kept at the top
+    /// doesn't pin the end of the heap and stop `trim` from giving memory
+    /// back. The two meet in the middle: this fails once the next
+    /// allocation would run into memory handed out from the bottom, and the
+    /// heap skips over this part of the arena for as long as any of it is
+    /// in use. Failing means what it does for `malloc`, including a retry
+    /// after a trim and the overflow allocator if those are set up.
+    ///
+    /// These allocations bypass `dlmalloc`, and the space between them isn't
+    /// reused until everything below it has been freed, so this is only
+    /// worth it for a few large, long-lived blocks. Free them with `free`
+    /// as usual. `realloc` and `relocate` don't support them.
+    ///
+    /// # Safety
+    ///
+    /// As for `malloc`.
+    pub unsafe fn malloc_high(&self, size: usize, align: usize) -> *mut u8 {
+        let ptr = if self.0.inject_fault() {
+            self.0.check_watermarks();
+            ptr::null_mut()
         } else {
-            me.memalign(align, size)
+            self.0.high_used.store(true, Ordering::Relaxed);
+            // Trimming the heap can hand memory back from its top, which
+            // leaves this more room.
+            let attempt = || {
+                let ptr = self.0.system.alloc_high(size, align);
+                if !ptr.is_null() {
+                    self.0.shadow.unpoison(&self.0.system, ptr, ptr.add(size));
+                    self.0.live.fetch_add(1, Ordering::Relaxed);
+                }
+                ptr
+            };
+            self.0.alloc_or_overflow(size, align, false, attempt)
+        };
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::MallocHigh {
+            size,
+            align,
+            result: sys.to_offset(ptr),
+        });
+        ptr
+    }
+
+    /// Like `malloc`, but says why the allocation failed: the error is always
+    /// [`TryAllocError::OutOfMemory`], with figures to tell a full arena from
+    /// a fragmented one. Working those out walks the heap, which only
+    /// happens on failure.
+    ///
+    /// # Safety
+    ///
+    /// As for `malloc`.
+    pub unsafe fn try_malloc(&self, size: usize, align: usize) -> Result<*mut u8, TryAllocError> {
+        let ptr = self.malloc(size, align);
+        if ptr.is_null() {
+            return Err(self.0.out_of_memory(None));
         }
+        Ok(ptr)
+    }
+
+    /// Like `malloc`, but gives up with [`TryAllocError::LockTimeout`] if the
+    /// allocator lock can't be acquired within `timeout`, for callers that
+    /// mustn't stall behind a slow operation on another thread.
+    ///
+    /// Waiting is done by polling with backoff, so acquiring a contended lock
+    /// may take up to a millisecond longer than with `malloc`. Unlike
+    /// `malloc`, a failed allocation isn't retried after a trim (see
+    /// [`Builder::retry_with_trim`]) or handed to an overflow allocator, as
+    /// either could take longer than `timeout`.
+    ///
+    /// # Safety
+    ///
+    /// As for `malloc`.
+    pub unsafe fn try_malloc_timeout(
+        &self,
+        size: usize,
+        align: usize,
+        timeout: Duration,
+    ) -> Result<*mut u8, TryAllocError> {
+        let deadline = Instant::now() + timeout;
+        let heap = self.0.heap_for(size, align);
+        let ptr = self.0.timed(Op::Malloc, || {
+            let mut heap = self.0.lock_until(heap, deadline)?;
+            if self.0.inject_fault() {
+                return Some(ptr::null_mut());
+            }
+            Some(heap.malloc(size, align))
+        });
+        let ptr = ptr.ok_or(TryAllocError::LockTimeout)?;
+        self.0.check_watermarks();
+        self.0.dirtied(ptr, size);
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Malloc {
+            size,
+            align,
+            result: sys.to_offset(ptr),
+        });
+        if ptr.is_null() {
+            return Err(self.0.out_of_memory(Some(deadline)));
+        }
+        Ok(ptr)
     }
 
     /// Same as `malloc`, except if the allocation succeeds it's guaranteed to
     /// point to `size` bytes of zeros.
+    ///
+    /// # Safety
+    ///
+    /// As for `malloc`.
     #[inline]
     pub unsafe fn calloc(&self, size: usize, align: usize) -> *mut u8 {
-        let ptr = self.malloc(size, align);
-        let me = self.0.lock().unwrap();
-        if !ptr.is_null() && me.calloc_must_clear(ptr) {
-            ptr::write_bytes(ptr, 0, size);
-        }
+        let ptr = self.0.calloc(size, align);
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Calloc {
+            size,
+            align,
+            result: sys.to_offset(ptr),
+        });
         ptr
     }
 
     /// Deallocates a `ptr` with `size` and `align` as the previous request used
     /// to allocate it.
     ///
+    /// A pointer from outside the arena goes to the overflow allocator if
+    /// there is one. Otherwise, with the `debug` feature and debug
+    /// assertions, it panics instead of corrupting the heap.
+    ///
+    /// # Safety
+    ///
     /// Safety and contracts are largely governed by the `GlobalAlloc::dealloc`
     /// method contracts.
     #[inline]
     pub unsafe fn free(&self, ptr: *mut u8, size: usize, align: usize) {
-        let _ = align;
-        let mut me = self.0.lock().unwrap();
-        me.validate_size(ptr, size);
-        me.free(ptr)
+        self.0.free(ptr, size, align);
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Free {
+            offset: sys.to_offset(ptr),
+            size,
+            align,
+        });
+    }
+
+    /// Like `free`, but also gives the pages of the allocation back to the
+    /// operating system right away, rather than leaving that to `trim`,
+    /// which only ever releases memory at the end of the heap. For a large
+    /// block in the middle of the heap that won't be needed again soon.
+    ///
+    /// Whole pages of the freed block are dropped from memory, and for a
+    /// shared mapping punched out of the file as well, so they stop taking
+    /// up page cache and disk space and read as zeros when reused. The first
+    /// few bytes of the block, where `dlmalloc` keeps its free list links,
+    /// are kept, as is any partial page at either end. Returns whether any
+    /// pages were released, which is never the case for blocks smaller than
+    /// a couple of pages, for blocks with their own region (those are given
+    /// back by `free` already), or for allocations from `malloc_high` or an
+    /// overflow allocator, which are freed as usual.
+    ///
+    /// # Safety
+    ///
+    /// The same as for `free`.
+    pub unsafe fn release(&self, ptr: *mut u8, size: usize, align: usize) -> bool {
+        let shared = &self.0;
+        if shared.system.to_offset(ptr).is_none() || shared.system.is_high(ptr) {
+            self.free(ptr, size, align);
+            return false;
+        }
+        shared.flush_freed(ptr, size);
+        let released = shared.timed(Op::Free, || {
+            // Hold every heap until the pages are gone, so neither can hand
+            // the memory out again in the meantime.
+            let mut heaps: Vec<_> = shared.heaps().map(|heap| shared.lock(heap)).collect();
+            let target = shared.heap_for(size, align);
+            let heap = if ptr::eq(target, &shared.heap) {
+                &mut heaps[0]
+            } else {
+                &mut heaps[1]
+            };
+            let (start, len) = heap.free_interior(ptr, size, align);
+            heap.free(ptr, size, align);
+            shared.system.discard(start, len).unwrap_or(false)
+        });
+        shared.freed();
+        #[cfg(feature = "trace")]
+        shared.record(|sys| trace::TraceRecord::Free {
+            offset: sys.to_offset(ptr),
+            size,
+            align,
+        });
+        released
     }
 
     /// Reallocates `ptr`, a previous allocation with `old_size` and
@@ -130,6 +1336,12 @@ impl DiskDlmalloc {
     /// is still valid. Returns a valid pointer and frees `ptr` if the request
     /// is satisfied.
     ///
+    /// As in C, a null `ptr` makes this a `malloc` of `new_size` bytes
+    /// aligned to `old_align` (`old_size` is ignored), and otherwise a
+    /// `new_size` of zero frees `ptr` and always returns a null pointer.
+    ///
+    /// # Safety
+    ///
     /// Safety and contracts are largely governed by the `GlobalAlloc::realloc`
     /// method contracts.
     #[inline]
@@ -140,23 +1352,226 @@ impl DiskDlmalloc {
         old_align: usize,
         new_size: usize,
     ) -> *mut u8 {
-        let mut me = self.0.lock().unwrap();
-        me.validate_size(ptr, old_size);
+        let res = if ptr.is_null() {
+            self.0.malloc(new_size, old_align)
+        } else if new_size == 0 {
+            self.0.free(ptr, old_size, old_align);
+            ptr::null_mut()
+        } else {
+            self.0.realloc(ptr, old_size, old_align, new_size, old_align)
+        };
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Realloc {
+            offset: sys.to_offset(ptr),
+            old_size,
+            old_align,
+            new_size,
+            result: sys.to_offset(res),
+        });
+        res
+    }
 
-        if old_align <= me.malloc_alignment() {
-            me.realloc(ptr, new_size)
+    /// Shrinks the allocation at `ptr` to `new_size` bytes like `realloc`,
+    /// but makes sure the memory it no longer needs goes back to the free
+    /// pool.
+    ///
+    /// `realloc` already splits off the surplus of most allocations. The
+    /// exception is one large enough to have a region of its own (see
+    /// [`Builder::mmap_threshold`]), which can't be split: `realloc` leaves
+    /// it alone unless it would shed more than 128 KiB. This moves it into a
+    /// smaller chunk once a page or more would be freed, at the cost of a
+    /// copy, for memory-constrained callers that need shrinking to actually
+    /// free space. If that fails it falls back to what `realloc` does, so a
+    /// null pointer means the allocation couldn't be made at all, and `ptr`
+    /// is still valid.
+    ///
+    /// # Safety
+    ///
+    /// As for `realloc`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `new_size` is 0 or greater than `size`.
+    pub unsafe fn realloc_shrink_strict(
+        &self,
+        ptr: *mut u8,
+        size: usize,
+        align: usize,
+        new_size: usize,
+    ) -> *mut u8 {
+        assert!(
+            0 < new_size && new_size <= size,
+            "realloc_shrink_strict can only shrink, not resize {} bytes to {}",
+            size,
+            new_size
+        );
+        let res = if self.0.inject_fault() {
+            ptr::null_mut()
         } else {
-            drop(me);
-            let res = self.malloc(new_size, old_align);
-            if !res.is_null() {
-                let size = cmp::min(old_size, new_size);
-                ptr::copy_nonoverlapping(ptr, res, size);
-                self.free(ptr, old_size, old_align);
+            self.0.timed(Op::Realloc, || {
+                self.0.realloc_shrink_strict(ptr, size, align, new_size)
+            })
+        };
+        self.0.check_watermarks();
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::ReallocShrinkStrict {
+            offset: sys.to_offset(ptr),
+            size,
+            align,
+            new_size,
+            result: sys.to_offset(res),
+        });
+        res
+    }
+
+    /// Resizes `ptr`, a previous allocation with `size` and `align`, to hold
+    /// at least `new_size` bytes like `realloc`, but grows it with room to
+    /// spare, returning the new pointer and how many bytes it can really
+    /// hold.
+    ///
+    /// Growing a buffer a little at a time with `realloc` can copy it every
+    /// time. When asked for more than the allocation holds, this asks for
+    /// [`Builder::realloc_growth_factor`] times its usable size instead, or
+    /// `new_size` if that's more, so that the next few calls find the room
+    /// already there and return straight away. A request that fits the
+    /// usable size never moves anything, and shrinking is the same as
+    /// `realloc`. If the larger allocation can't be made it falls back to
+    /// exactly `new_size`, and returns a null pointer and 0, leaving `ptr`
+    /// valid, only if that fails too.
+    ///
+    /// A null `ptr` makes this a `malloc` of `new_size` bytes. Unlike
+    /// `realloc`, a `new_size` of zero isn't a `free`: it's treated as 1, so
+    /// that a null result always means `ptr` is still there.
+    ///
+    /// Any size from `new_size` up to the returned one may be passed back to
+    /// `free` or `realloc`, or here as `size`, which is how a growing buffer
+    /// should use it.
+    ///
+    /// # Safety
+    ///
+    /// As for `realloc`.
+    pub unsafe fn realloc_amortized(
+        &self,
+        ptr: *mut u8,
+        size: usize,
+        align: usize,
+        new_size: usize,
+    ) -> (*mut u8, usize) {
+        if ptr.is_null() {
+            return self.malloc_with_usable(new_size, align);
+        }
+        let new_size = new_size.max(1);
+        if new_size <= size {
+            let res = self.realloc(ptr, size, align, new_size);
+            if res.is_null() {
+                return (res, 0);
             }
-            res
+            return (res, self.usable_size(res, new_size, align));
+        }
+        let usable = self.usable_size(ptr, size, align);
+        if new_size <= usable {
+            return (ptr, usable);
+        }
+        let target = cmp::max(
+            new_size,
+            (usable as f64 * self.0.realloc_growth_factor) as usize,
+        );
+        let mut res = self.realloc(ptr, size, align, target);
+        let mut requested = target;
+        if res.is_null() && target > new_size {
+            res = self.realloc(ptr, size, align, new_size);
+            requested = new_size;
+        }
+        if res.is_null() {
+            return (res, 0);
+        }
+        (res, self.usable_size(res, requested, align))
+    }
+
+    /// Frees every `(ptr, size)` pair in `ptrs`, taking the allocator lock
+    /// only once rather than once per pointer.
+    ///
+    /// The pointers are freed from the highest address down, so runs of
+    /// adjacent allocations next to the top of the heap are merged straight
+    /// into it instead of passing through the free lists one by one, which
+    /// leaves `trim` more to give back.
+    ///
+    /// # Safety
+    ///
+    /// Each pointer must be a live allocation of the paired size whose
+    /// alignment was at most twice the size of a pointer, and appear only
+    /// once.
+    pub unsafe fn free_all(&self, ptrs: &[(*mut u8, usize)]) {
+        let mut sorted: Vec<_> = ptrs
+            .iter()
+            .copied()
+            .filter(|&(ptr, size)| {
+                if self.0.overflow_free(ptr, size, MALLOC_ALIGNMENT) {
+                    return false;
+                }
+                self.0.flush_freed(ptr, size);
+                !self.0.free_high(ptr, size)
+            })
+            .collect();
+        sorted.sort_unstable_by_key(|&(ptr, _)| cmp::Reverse(ptr));
+        for heap in self.0.heaps() {
+            let mut mine = sorted
+                .iter()
+                .filter(|&&(_, size)| ptr::eq(self.0.heap_for(size, MALLOC_ALIGNMENT), heap))
+                .peekable();
+            if mine.peek().is_none() {
+                continue;
+            }
+            let mut heap = self.0.lock(heap);
+            for &(ptr, size) in mine {
+                heap.free(ptr, size, MALLOC_ALIGNMENT);
+            }
+        }
+        self.0.freed();
+        #[cfg(feature = "trace")]
+        for &(ptr, size) in &sorted {
+            self.0.record(|sys| trace::TraceRecord::Free {
+                offset: sys.to_offset(ptr),
+                size,
+                align: MALLOC_ALIGNMENT,
+            });
         }
     }
 
+    /// Moves the allocation at `ptr` as low in the arena as it will go,
+    /// returning its new address.
+    ///
+    /// A new chunk is carved out of the lowest free space that fits, the data
+    /// is copied over and the old chunk freed. Relocating allocations one at
+    /// a time from the top of the arena down leaves the free space in one
+    /// piece at the end, where `trim` can give it back, so callers can
+    /// defragment incrementally, updating one reference at a time.
+    ///
+    /// Returns `ptr` itself if no free space lies below it, and a null
+    /// pointer, leaving `ptr` valid, if the new chunk couldn't be allocated.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation of `size` bytes whose alignment was at
+    /// most twice the size of a pointer. Unless `ptr` is returned, it is
+    /// freed, so every reference to it has to be updated.
+    pub unsafe fn relocate(&self, ptr: *mut u8, size: usize) -> *mut u8 {
+        let res = self
+            .0
+            .lock(self.0.heap_for(size, MALLOC_ALIGNMENT))
+            .relocate(ptr, size);
+        if !res.is_null() && res != ptr {
+            self.0.freed();
+        }
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Relocate {
+            offset: sys.to_offset(ptr),
+            size,
+            result: sys.to_offset(res),
+        });
+        res
+    }
+
     /// If possible, gives memory back to the system if there is unused memory
     /// at the high end of the malloc pool or in unused segments.
     ///
@@ -174,183 +1589,625 @@ impl DiskDlmalloc {
     /// system.
     ///
     /// Returns `true` if it actually released any memory, else `false`.
+    ///
+    /// # Safety
+    ///
+    /// This only gives back free memory, so live allocations stay valid; it's
+    /// `unsafe` to match `Dlmalloc::trim`.
     pub unsafe fn trim(&self, pad: usize) -> bool {
-        let mut me = self.0.lock().unwrap();
-        me.trim(pad)
+        let mut released = false;
+        for heap in self.0.heaps() {
+            released |= self.0.lock(heap).dl.trim(pad);
+        }
+        #[cfg(feature = "trace")]
+        self.0.record(|_| trace::TraceRecord::Trim { pad, released });
+        released
     }
-}
 
-unsafe impl std::alloc::Allocator for DiskDlmalloc {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let size = layout.size();
-        let align = layout.align();
-        let mut me = self.0.lock().unwrap();
-        let ptr = if align <= me.malloc_alignment() {
-            unsafe { me.malloc(size) }
-        } else {
-            unsafe { me.memalign(align, size) }
-        };
-        if ptr.is_null() {
-            Err(AllocError)
-        } else {
-            unsafe {
-                Ok(NonNull::slice_from_raw_parts(
-                    NonNull::new_unchecked(ptr),
-                    size,
-                ))
-            }
+    /// Merges the chunks that [`CoalescePolicy::Deferred`] set aside on
+    /// `free` with their free neighbours, without giving anything back to
+    /// the system as [`trim`](DiskDlmalloc::trim) would. Returns how many
+    /// bytes were set aside, 0 with eager coalescing.
+    ///
+    /// An allocation that finds no room does this by itself before growing
+    /// the heap, so this is for doing it at a time of the caller's choosing,
+    /// say ahead of a burst of large allocations, to take the cost off them.
+    pub fn coalesce_free(&self) -> usize {
+        let mut bytes = 0;
+        for heap in self.0.heaps() {
+            bytes += unsafe { self.0.lock(heap).dl.coalesce_deferred() };
         }
+        #[cfg(feature = "trace")]
+        self.0.record(|_| trace::TraceRecord::CoalesceFree { bytes });
+        bytes
     }
 
-    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let size = layout.size();
-        let align = layout.align();
-        let mut me = self.0.lock().unwrap();
-        let ptr = if align <= me.malloc_alignment() {
-            unsafe { me.malloc(size) }
-        } else {
-            unsafe { me.memalign(align, size) }
-        };
-        if ptr.is_null() {
-            return Err(AllocError);
+    /// Frees everything at once by starting the allocator over on the same
+    /// mapping, so the next allocations land where the first ones after
+    /// `build` did. For benchmarks running many iterations, where building a
+    /// new allocator, with its file and mapping, each time would dominate
+    /// the measurement.
+    ///
+    /// The pages keep their contents and stay mapped, so this costs about
+    /// the same whatever the size of the arena. The high-water mark and the
+    /// settings are kept; scoped allocator usage is cleared. Applies to all
+    /// clones of this allocator.
+    ///
+    /// # Safety
+    ///
+    /// Every allocation made so far becomes invalid, so none may be used,
+    /// freed or reallocated afterwards. Allocations served by an overflow
+    /// allocator are the exception, as they stay with it.
+    pub unsafe fn reset_keep_mapping(&self) {
+        let mut heaps: Vec<_> = self.0.heaps().map(|heap| self.0.lock(heap)).collect();
+        for heap in &mut heaps {
+            heap.dl.reset();
         }
-        unsafe {
-            if me.calloc_must_clear(ptr) {
-                ptr::write_bytes(ptr, 0, size);
-            }
+        self.0.system.reset();
+        self.0.shadow.clear();
+        self.0.live.store(0, Ordering::Relaxed);
+        drop(heaps);
+        self.0.usage.lock().unwrap().clear();
+        self.0.freed();
+        #[cfg(feature = "trace")]
+        self.0.record(|_| trace::TraceRecord::Reset);
+    }
+
+    /// Returns how many bytes could still be allocated: the part of the file
+    /// not yet handed to `dlmalloc` plus everything free inside it.
+    ///
+    /// Free memory may be fragmented, so a single allocation of this size
+    /// isn't guaranteed to succeed. This walks the whole heap, so it isn't
+    /// cheap on large arenas. With [`Builder::account_disk_space`] the part
+    /// not yet handed out is capped by free space on the filesystem.
+    pub fn available(&self) -> usize {
+        self.0.available()
+    }
+
+    /// Lets the arena grow when an allocation doesn't fit, extending the
+    /// backing file by `bytes` at a time, or by as much as the allocation
+    /// needs if that's more. 0, the default, keeps the arena at its size.
+    ///
+    /// A larger increment means fewer `set_len` calls and mappings under
+    /// sustained growth, a smaller one less file allocated but unused, so
+    /// this can be changed at any time as the workload shifts. Each growth
+    /// step maps a new region, just like [`refresh_mapping`], so existing
+    /// pointers stay valid.
+    ///
+    /// [`refresh_mapping`]: DiskDlmalloc::refresh_mapping
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` isn't a multiple of the page size.
+    pub fn set_growth_increment(&self, bytes: usize) {
+        let page = self.0.system.page_size();
+        assert!(
+            bytes.is_multiple_of(page),
+            "growth increment must be a multiple of the {} byte page size, got {}",
+            page,
+            bytes
+        );
+        self.0.system.set_growth_increment(bytes);
+        #[cfg(feature = "trace")]
+        self.0.record(|_| trace::TraceRecord::SetGrowthIncrement { bytes });
+    }
+
+    /// Maps any space added to the backing file since it was last mapped.
+    ///
+    /// When several processes share an arena file, one of them may grow the
+    /// file while the others still have it mapped at the old length. Calling
+    /// this picks up that growth: the new tail of the file is mapped as an
+    /// additional region that subsequent allocations can be served from.
+    /// Existing mappings are never moved, so outstanding pointers stay valid.
+    ///
+    /// Does nothing if the file hasn't grown.
+    pub fn refresh_mapping(&self) -> io::Result<()> {
+        self.0.system.refresh_mapping()
+    }
+
+    /// Shrinks the arena to `new_total_size` bytes, giving the address space
+    /// and disk space past that back to the system.
+    ///
+    /// Free memory at the top of the heap is trimmed first, then the mapping
+    /// is cut down and the file truncated to the new size. Fails with
+    /// [`Error::WouldTruncateLive`], changing nothing, if memory past the new
+    /// bound is still in use, which includes free chunks that can't be
+    /// trimmed because live allocations sit above them.
+    pub fn shrink_arena(&self, new_total_size: usize) -> Result<(), Error> {
+        // Hold every heap lock so nothing is handed out while we shrink.
+        let mut heaps: Vec<_> = self.0.heaps().map(|heap| self.0.lock(heap)).collect();
+        for heap in &mut heaps {
+            unsafe { heap.dl.trim(0) };
         }
-        unsafe {
-            Ok(NonNull::slice_from_raw_parts(
-                NonNull::new_unchecked(ptr),
-                size,
-            ))
+        let res = self.0.system.shrink(new_total_size);
+        drop(heaps);
+        #[cfg(feature = "trace")]
+        self.0.record(|_| trace::TraceRecord::ShrinkArena {
+            new_total_size,
+            shrunk: res.is_ok(),
+        });
+        res
+    }
+
+    /// Starts reading the pages under `[ptr, ptr + len)` into memory, so a
+    /// later scan over a cold allocation doesn't stall on each page fault.
+    ///
+    /// This applies `MADV_WILLNEED` to the page-aligned range and, on Linux,
+    /// also issues `readahead` for the matching range of the backing file.
+    /// Both only start the I/O; this returns without waiting for it. Fails if
+    /// `ptr` isn't in the arena.
+    pub fn prefetch(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        self.0.system.prefetch(ptr, len)
+    }
+
+    /// Copies `buf.len()` bytes of the arena from `ptr` into `buf`, failing
+    /// with an error, rather than killing the process with `SIGBUS`, if any
+    /// of them can't be read, as happens when the file was truncated under
+    /// the mapping or its network filesystem returned an I/O error. For
+    /// reading data that may be gone without betting the process on it.
+    ///
+    /// Only Linux can do this, by having the kernel make the copy, so
+    /// elsewhere this fails with [`io::ErrorKind::Unsupported`]. It's a
+    /// system call per read, so reading through `ptr` is much faster where
+    /// the storage can be trusted. Fails if the range isn't in the arena.
+    pub fn try_read(&self, ptr: *const u8, buf: &mut [u8]) -> io::Result<()> {
+        self.0.system.try_read(ptr, buf)
+    }
+
+    /// Locks the pages holding the allocator's bookkeeping in the arena into
+    /// memory with `mlock`, so that allocating and freeing stay fast while
+    /// the data pages around them are evicted, at the cost of a few pages of
+    /// locked memory.
+    ///
+    /// Those are the pages with the records of the heap's segments and with
+    /// the header of each free chunk, which is what looking for room reads.
+    /// The bins and trees themselves aren't in the arena. Free chunks come
+    /// and go, so this locks them as they are now: call it again once the
+    /// heap has changed, which unlocks the pages the previous call locked
+    /// first. Headers of chunks in use, and free chunks that appear later,
+    /// aren't locked. Fails if the pages can't be locked, typically because
+    /// of `RLIMIT_MEMLOCK`, in which case none stay locked. Does nothing for
+    /// an in-memory arena.
+    pub fn lock_metadata(&self) -> io::Result<()> {
+        let mut ranges = Vec::new();
+        let heaps: Vec<_> = self.0.heaps().map(|heap| self.0.lock(heap)).collect();
+        for heap in &heaps {
+            let add = |ptr, len| ranges.push((ptr, len));
+            unsafe { heap.dl.for_each_metadata(add) };
         }
+        // Held until the pages are locked, so that the chunks stay put.
+        self.0.system.lock_pages(&ranges)
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        if layout.size() == 0 {
-            return;
+    /// Makes the pages under `[ptr, ptr + len)` resident now rather than on
+    /// first touch, for controlling when memory reserved by an allocation
+    /// actually takes up RAM and disk; `decommit` is the reverse.
+    ///
+    /// On Linux 5.14 and later this faults the pages in for writing with
+    /// `MADV_POPULATE_WRITE`, which also allocates their blocks in the file,
+    /// so running out of disk space shows up here as an error rather than
+    /// as `SIGBUS` later. Elsewhere it's `MADV_WILLNEED`, like `prefetch`
+    /// without the readahead, which only starts the I/O. Fails if `ptr`
+    /// isn't in the arena.
+    pub fn commit(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        self.0.system.commit(ptr, len)
+    }
+
+    /// Takes the pages entirely within `[ptr, ptr + len)` out of memory, for
+    /// an allocation that won't be touched for a while but has to stay
+    /// allocated. Their contents are written back to the file first and
+    /// come back from it on the next access, which commits them again.
+    ///
+    /// Pages only partly in the range are left alone, as is the allocation
+    /// itself. On Linux the pages are dropped from the page cache as well,
+    /// so they stop counting against memory altogether; elsewhere the kernel
+    /// may keep them cached. A private mapping never writes back to the
+    /// file, so there this fails with `ErrorKind::Unsupported` rather than
+    /// throw the contents away. Fails too if `ptr` isn't in the arena.
+    pub fn decommit(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        self.0.system.decommit(ptr, len)
+    }
+
+    /// Makes `[ptr, ptr + len)` read-only, so that accidental writes to
+    /// memory that's been finalized, such as a built index, fault with
+    /// `SIGSEGV` instead of corrupting it.
+    ///
+    /// Protection works on whole pages, so `ptr` and `len` must both be
+    /// multiples of the page size; allocate with page alignment and a size
+    /// rounded up to whole pages to protect an allocation and nothing else.
+    /// Call `protect_readwrite` before freeing or reallocating the memory,
+    /// as `dlmalloc` writes to it. Fails if `ptr` isn't in the arena.
+    pub fn protect_readonly(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        self.0.system.protect(ptr, len, libc::PROT_READ)
+    }
+
+    /// Makes `[ptr, ptr + len)` writable again after `protect_readonly`,
+    /// with the same alignment requirements.
+    pub fn protect_readwrite(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        self.0
+            .system
+            .protect(ptr, len, libc::PROT_READ | libc::PROT_WRITE)
+    }
+
+    /// Allocates a buffer of at least `size` bytes meant to be backed by
+    /// transparent huge pages, to cut TLB misses when it's accessed at
+    /// random.
+    ///
+    /// The buffer is aligned to [`HUGE_PAGE_SIZE`] and its size rounded up
+    /// to a multiple of it, and `MADV_HUGEPAGE` is applied to it so the
+    /// kernel will back it with huge pages even when THP is only enabled on
+    /// request. Whether it does is still up to the kernel and filesystem;
+    /// the advice is ignored where it isn't supported.
+    ///
+    /// Returns null if allocation fails. Free the buffer with `free_thp`.
+    ///
+    /// # Safety
+    ///
+    /// As for `malloc`.
+    pub unsafe fn alloc_thp(&self, size: usize) -> *mut u8 {
+        let size = size.max(1).next_multiple_of(HUGE_PAGE_SIZE);
+        let ptr = self.malloc(size, HUGE_PAGE_SIZE);
+        if !ptr.is_null() {
+            let _ = self.0.system.advise_hugepage(ptr, size);
         }
-        let mut me = self.0.lock().unwrap();
-        me.validate_size(ptr.as_ptr(), layout.size());
-        me.free(ptr.as_ptr());
+        ptr
     }
 
-    unsafe fn grow(&self,
-                   ptr: NonNull<u8>,
-                   old_layout: Layout,
-                   new_layout: Layout)
-                   -> Result<NonNull<[u8]>, AllocError> {
-        let old_size = old_layout.size();
-        let old_align = old_layout.align();
-        let new_size = new_layout.size();
-        let new_align = new_layout.align();
-        let mut me = self.0.lock().unwrap();
-        me.validate_size(ptr.as_ptr(), old_size);
-
-        if old_align <= me.malloc_alignment() && new_align <= me.malloc_alignment() {
-            let new_ptr = me.realloc(ptr.as_ptr(), new_size);
-            if new_ptr.is_null() {
-                return Err(AllocError);
+    /// Frees a buffer returned by `alloc_thp(size)`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from `alloc_thp(size)` and not be used afterwards.
+    pub unsafe fn free_thp(&self, ptr: *mut u8, size: usize) {
+        let size = size.max(1).next_multiple_of(HUGE_PAGE_SIZE);
+        self.free(ptr, size, HUGE_PAGE_SIZE)
+    }
+
+    /// Allocates a ring buffer of `size` bytes mapped twice back to back, so
+    /// that `size` bytes read or written from any position inside the first
+    /// copy run on into the start of the buffer instead of off its end.
+    /// Lock-free ring buffers can then hand out contiguous slices across the
+    /// wraparound.
+    ///
+    /// The ring's storage is an ordinary page-aligned allocation of `size`
+    /// bytes from the arena, but the returned pointer is to a separate
+    /// mapping of that part of the file, `2 * size` long, outside the arena.
+    /// Only a shared mapping (see [`Builder::shared`]) can be viewed twice
+    /// like that, and the views keep using the file the arena was created
+    /// with even after `swap_backing`. Returns null if the allocation or
+    /// mapping fails. Free the ring with `free_magic_ring`.
+    ///
+    /// # Safety
+    ///
+    /// As for `malloc`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0 or not a multiple of the page size.
+    pub unsafe fn alloc_magic_ring(&self, size: usize) -> *mut u8 {
+        let page = self.0.system.page_size();
+        assert!(
+            size > 0 && size.is_multiple_of(page),
+            "magic ring size {} isn't a multiple of the page size",
+            size
+        );
+        let buf = self.malloc(size, page);
+        if buf.is_null() {
+            return buf;
+        }
+        match self.0.system.map_ring(buf, size) {
+            Ok(ring) => {
+                self.0.rings.lock().unwrap().push((ring as usize, buf as usize));
+                ring
             }
-            Ok(NonNull::slice_from_raw_parts(
-                NonNull::new_unchecked(new_ptr),
-                new_size,
-            ))
-        } else {
-            drop(me);
-            let res_ptr = self.malloc(new_size, new_align);
-            if res_ptr.is_null() {
-                return Err(AllocError);
+            Err(_) => {
+                self.free(buf, size, page);
+                ptr::null_mut()
             }
-            ptr::copy_nonoverlapping(ptr.as_ptr(), res_ptr, core::cmp::min(old_size, new_size));
-            self.free(ptr.as_ptr(), old_size, old_align);
-            Ok(NonNull::slice_from_raw_parts(
-                NonNull::new_unchecked(res_ptr),
-                new_size,
-            ))
         }
     }
 
-    unsafe fn grow_zeroed(&self,
-                          ptr: NonNull<u8>,
-                          old_layout: Layout,
-                          new_layout: Layout)
-                          -> Result<NonNull<[u8]>, AllocError> {
-        let old_size = old_layout.size();
-        let old_align = old_layout.align();
-        let new_size = new_layout.size();
-        let new_align = new_layout.align();
-        let mut me = self.0.lock().unwrap();
-        me.validate_size(ptr.as_ptr(), old_size);
-
-        if old_align <= me.malloc_alignment() && new_align <= me.malloc_alignment() {
-            let new_ptr = me.realloc(ptr.as_ptr(), new_size);
-            if new_ptr.is_null() {
-                return Err(AllocError);
-            }
-            if new_ptr == ptr.as_ptr() && new_size > old_size {
-                ptr::write_bytes(new_ptr.add(old_size), 0, new_size - old_size);
-            } else if new_ptr != ptr.as_ptr() && new_size > old_size && me.calloc_must_clear(new_ptr) {
-                ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr, old_size);
-                ptr::write_bytes(new_ptr.add(old_size), 0, new_size - old_size);
-            }
-            Ok(NonNull::slice_from_raw_parts(
-                NonNull::new_unchecked(new_ptr),
-                new_size,
-            ))
-        } else {
-            drop(me);
-            let res_ptr = self.malloc(new_size, new_align);
-            if res_ptr.is_null() {
-                return Err(AllocError);
-            }
-            ptr::copy_nonoverlapping(ptr.as_ptr(), res_ptr, core::cmp::min(old_size, new_size));
-            if new_size > old_size {
-                ptr::write_bytes(res_ptr.add(old_size), 0, new_size - old_size);
-            }
-            self.free(ptr.as_ptr(), old_size, old_align);
-            Ok(NonNull::slice_from_raw_parts(
-                NonNull::new_unchecked(res_ptr),
-                new_size,
-            ))
+    /// Unmaps a ring returned by `alloc_magic_ring(size)` and frees its
+    /// storage.
+    ///
+    /// # Safety
+    ///
+    /// `ring` must not be used afterwards, through either view.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ring` didn't come from `alloc_magic_ring`.
+    pub unsafe fn free_magic_ring(&self, ring: *mut u8, size: usize) {
+        let buf = {
+            let mut rings = self.0.rings.lock().unwrap();
+            let i = rings
+                .iter()
+                .position(|&(r, _)| r == ring as usize)
+                .expect("free_magic_ring called with a pointer that isn't a ring");
+            rings.swap_remove(i).1
+        };
+        self.0.system.unmap_ring(ring, size);
+        self.free(buf as *mut u8, size, self.0.system.page_size());
+    }
+
+    /// Makes every write to `[ptr, ptr + len)` made so far durable, returning
+    /// once it's on stable storage.
+    ///
+    /// A sequentially consistent fence comes first, so the compiler and CPU
+    /// can't sink earlier stores to the range past the flush, followed by
+    /// `msync(MS_SYNC)` over the page-aligned range. This is the building
+    /// block for crash-consistent structures in the arena: write the data,
+    /// `fence_and_flush` it, and only then publish whatever points at it.
+    ///
+    /// Unlike an asynchronous flush (`MS_ASYNC`), which merely schedules the
+    /// writeback and returns immediately, this blocks on the I/O, so don't
+    /// call it more often than durability requires. It has no effect beyond
+    /// the fence on private mappings. Fails if `ptr` isn't in the arena.
+    pub fn fence_and_flush(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        atomic::fence(Ordering::SeqCst);
+        self.0.system.sync(ptr, len)
+    }
+
+    /// Flushes the whole arena to disk and unmaps it, rather than leaving
+    /// that to whichever handle happens to be dropped last.
+    ///
+    /// Every clone of an allocator, including those held by a
+    /// [`CapacityWatcher`] or [`ScopedAllocator`], keeps the mapping alive,
+    /// so this only closes the last one. Otherwise it fails with
+    /// [`Error::StillInUse`] and just drops this handle. If flushing fails the
+    /// arena is still unmapped, but what reached the file is unknown.
+    ///
+    /// Without an observer, this is also where a failure to flush a freed
+    /// allocation with [`Builder::flush_on_free`] is reported: the first one
+    /// is returned once the arena has been flushed.
+    pub fn close(self) -> Result<(), Error> {
+        let shared = Arc::try_unwrap(self.0).map_err(|shared| Error::StillInUse {
+            handles: Arc::strong_count(&shared) - 1,
+        })?;
+        shared.system.sync_all()?;
+        let flush_error = shared.flush_error.lock().unwrap().take();
+        match flush_error {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
         }
     }
 
-    unsafe fn shrink(&self,
-                     ptr: NonNull<u8>,
-                     old_layout: Layout,
-                     new_layout: Layout)
-                     -> Result<NonNull<[u8]>, AllocError> {
+    /// Returns whether every page under `[ptr, ptr + len)` is currently in
+    /// memory, as reported by `mincore`, so latency-sensitive code can decide
+    /// whether to [`prefetch`](DiskDlmalloc::prefetch) first.
+    ///
+    /// Any page having been evicted, the range not lying within the arena,
+    /// or `mincore` failing makes this `false`. The answer may be stale by
+    /// the time it's acted on.
+    pub fn is_resident(&self, ptr: *mut u8, len: usize) -> bool {
+        self.0.system.is_resident(ptr, len)
+    }
+
+    /// Applies `advice` to the pages under `[ptr, ptr + len)` with `madvise`,
+    /// for tuning the access pattern of part of the arena, such as
+    /// `Sequential` for a log that's scanned front to back.
+    ///
+    /// The advice is remembered, so [`advice_for`](DiskDlmalloc::advice_for)
+    /// can report it, until another call covers the same pages or
+    /// [`reload`](DiskDlmalloc::reload) changes the advice for the whole
+    /// arena. Fails if the range isn't in the arena.
+    pub fn advise_range(&self, ptr: *mut u8, len: usize, advice: Advice) -> io::Result<()> {
+        self.0.system.advise_range(ptr, len, advice)
+    }
+
+    /// Returns the advice in effect for the page holding `ptr`, as last set
+    /// with [`advise_range`](DiskDlmalloc::advise_range), or else the
+    /// advice for the whole arena, [`Builder::mem_advise`] unless reloaded.
+    pub fn advice_for(&self, ptr: *const u8) -> Advice {
+        self.0.system.advice_for(ptr)
+    }
+
+    /// Moves the arena to a new backing file at `new_path`, for instance to
+    /// get off a failing disk without downtime.
+    ///
+    /// Everything allocated so far is copied to the new file, which is
+    /// flushed to disk before being mapped over the old one with `MAP_FIXED`.
+    /// Virtual addresses don't change, so outstanding pointers stay valid and
+    /// from then on read and write the new file. Allocations block while this
+    /// runs. Fails with [`io::ErrorKind::InvalidInput`] if `new_path` is the
+    /// current backing file, or a link to it.
+    ///
+    /// On Linux a shared arena is copied with `copy_file_range`, which keeps
+    /// the data out of userspace and on btrfs or XFS can share extents
+    /// instead of copying them. Wherever that isn't possible it falls back to
+    /// writing the mapped memory out.
+    ///
+    /// On error the arena keeps using the old file.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may write to memory from this allocator until this returns, as
+    /// the copy could miss those writes.
+    pub unsafe fn swap_backing<P: AsRef<Path>>(&self, new_path: P) -> io::Result<()> {
+        // Hold every heap lock so no chunk headers change under the copy.
+        let _heaps: Vec<_> = self.0.heaps().map(|heap| self.0.lock(heap)).collect();
+        self.0.system.swap_backing(new_path.as_ref())
+    }
+
+    /// Converts a pointer into this arena to its offset within the backing
+    /// file.
+    ///
+    /// Offsets, unlike pointers, are stable across runs and processes, so
+    /// they're what should be stored inside the arena or in logs. Returns
+    /// `None` if `ptr` doesn't point into the arena.
+    pub fn to_offset(&self, ptr: *const u8) -> Option<usize> {
+        self.0.system.to_offset(ptr)
+    }
+
+    /// Converts an offset within the backing file to a pointer into this
+    /// arena, the inverse of `to_offset`.
+    ///
+    /// Returns `None` if `offset` isn't currently mapped.
+    pub fn to_ptr(&self, offset: usize) -> Option<*mut u8> {
+        self.0.system.to_ptr(offset)
+    }
+
+    /// Views `count` values of type `T` starting at `ptr` as a slice, for
+    /// allocations used as arrays of fixed-size records.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `ptr` is aligned for `T` and lies in a live allocation
+    /// with room for all `count` values after it.
+    ///
+    /// # Safety
+    ///
+    /// The memory must hold valid values of `T` (or `T` must be valid for
+    /// any bit pattern, like the integer types) and mustn't be accessed any
+    /// other way while the slice is alive. Freeing the allocation ends its
+    /// lifetime too, which the borrow checker can't see.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn typed_region<T>(&self, ptr: *mut u8, count: usize) -> &mut [T] {
+        assert!(
+            ptr.cast::<T>().is_aligned(),
+            "{:p} isn't aligned for {}",
+            ptr,
+            core::any::type_name::<T>()
+        );
+        let offset = self
+            .to_offset(ptr)
+            .unwrap_or_else(|| panic!("{:p} isn't in the arena", ptr));
+        let (start, usable) = self
+            .find_allocation(offset)
+            .unwrap_or_else(|| panic!("{:p} isn't in a live allocation", ptr));
+        let fits = count
+            .checked_mul(mem::size_of::<T>())
+            .is_some_and(|len| offset + len <= start + usable);
+        assert!(
+            fits,
+            "{} values of {} don't fit in the allocation at {:p}",
+            count,
+            core::any::type_name::<T>(),
+            ptr
+        );
+        core::slice::from_raw_parts_mut(ptr.cast(), count)
+    }
+
+    /// Views the `len` bytes at `ptr` as a slice, for copying a large blob
+    /// into an allocation with `copy_from_slice` rather than raw pointer
+    /// copies. This is `typed_region::<u8>`, with the same checks: `ptr`
+    /// must lie in a live allocation with room for `len` bytes after it,
+    /// bounded by its usable size.
+    ///
+    /// # Safety
+    ///
+    /// The memory mustn't be accessed any other way while the slice is
+    /// alive, and freeing the allocation ends the slice's lifetime.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn chunk_as_mut_slice(&self, ptr: *mut u8, len: usize) -> &mut [u8] {
+        self.typed_region(ptr, len)
+    }
+}
+
+unsafe impl std::alloc::Allocator for DiskDlmalloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let (size, align) = (layout.size(), layout.align());
+        let ptr = unsafe { self.0.malloc(size, align) };
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Malloc {
+            size,
+            align,
+            result: sys.to_offset(ptr),
+        });
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, size))
+            .ok_or(AllocError)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let (size, align) = (layout.size(), layout.align());
+        let ptr = unsafe { self.0.calloc(size, align) };
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Calloc {
+            size,
+            align,
+            result: sys.to_offset(ptr),
+        });
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, size))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.free(ptr.as_ptr(), layout.size(), layout.align());
+        #[cfg(feature = "trace")]
+        self.0.record(|sys| trace::TraceRecord::Free {
+            offset: sys.to_offset(ptr.as_ptr()),
+            size: layout.size(),
+            align: layout.align(),
+        });
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = self.0.realloc(
+            ptr.as_ptr(),
+            old_layout.size(),
+            old_layout.align(),
+            new_layout.size(),
+            new_layout.align(),
+        );
+        #[cfg(feature = "trace")]
+        self.0.record_resize(ptr.as_ptr(), old_layout, new_layout, new_ptr);
+        NonNull::new(new_ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
         let old_size = old_layout.size();
-        let old_align = old_layout.align();
         let new_size = new_layout.size();
-        let new_align = new_layout.align();
-        let mut me = self.0.lock().unwrap();
-        me.validate_size(ptr.as_ptr(), old_size);
-
-        if old_align <= me.malloc_alignment() && new_align <= me.malloc_alignment() {
-            let new_ptr = me.realloc(ptr.as_ptr(), new_size);
-            if new_ptr.is_null() {
-                return Err(AllocError);
-            }
-            Ok(NonNull::slice_from_raw_parts(
-                NonNull::new_unchecked(new_ptr),
-                new_size,
-            ))
-        } else {
-            drop(me);
-            let res_ptr = self.malloc(new_size, new_align);
-            if res_ptr.is_null() {
-                return Err(AllocError);
-            }
-            ptr::copy_nonoverlapping(ptr.as_ptr(), res_ptr, core::cmp::min(old_size, new_size));
-            self.free(ptr.as_ptr(), old_size, old_align);
-            Ok(NonNull::slice_from_raw_parts(
-                NonNull::new_unchecked(res_ptr),
-                new_size,
-            ))
+        let new_ptr = self.0.realloc(
+            ptr.as_ptr(),
+            old_size,
+            old_layout.align(),
+            new_size,
+            new_layout.align(),
+        );
+        #[cfg(feature = "trace")]
+        self.0.record_resize(ptr.as_ptr(), old_layout, new_layout, new_ptr);
+        if new_ptr.is_null() {
+            return Err(AllocError);
         }
+        ptr::write_bytes(new_ptr.add(old_size), 0, new_size - old_size);
+        Ok(NonNull::slice_from_raw_parts(
+            NonNull::new_unchecked(new_ptr),
+            new_size,
+        ))
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = self.0.realloc(
+            ptr.as_ptr(),
+            old_layout.size(),
+            old_layout.align(),
+            new_layout.size(),
+            new_layout.align(),
+        );
+        #[cfg(feature = "trace")]
+        self.0.record_resize(ptr.as_ptr(), old_layout, new_layout, new_ptr);
+        NonNull::new(new_ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+            .ok_or(AllocError)
     }
 
     fn by_ref(&self) -> &Self {
@@ -0,0 +1,115 @@
+use crate::{CancellationToken, DiskDlmalloc, Error};
+use core::ptr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+impl DiskDlmalloc {
+    /// Copies every live allocation into `dest`, for moving a dataset to an
+    /// arena configured differently, and reports where each one ended up
+    /// by calling `fixup` with its old and new offset, so the caller can
+    /// rewrite offsets stored inside the data.
+    ///
+    /// Allocations are copied in address order, each into an allocation of
+    /// the same usable size, and can be freed from `dest` with the size and
+    /// alignment they were allocated with. The offsets are those of the
+    /// start of each allocation as `for_each_allocation_sorted` sees it,
+    /// which with the `redzones` feature is in front of the pointer the
+    /// caller got, so map an offset inside an allocation by where it falls
+    /// relative to the nearest start at or below it. `fixup` is called once
+    /// everything has been copied, with the allocators unlocked.
+    ///
+    /// Allocations from `malloc_high` or an overflow allocator aren't
+    /// copied, and alignments beyond [`malloc_alignment`] aren't kept. If
+    /// `dest` fills up this returns [`Error::DestinationFull`], leaving
+    /// what was copied so far allocated in `dest` and `fixup` uncalled.
+    ///
+    /// [`malloc_alignment`]: DiskDlmalloc::malloc_alignment
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dest` is this allocator or a clone of it, or if `dest`
+    /// uses lock striping and this allocator doesn't, as the copies have to
+    /// be in the heap their size will later be freed to.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may allocate, free or write to this allocator while this
+    /// runs.
+    pub unsafe fn migrate_to(
+        &self,
+        dest: &DiskDlmalloc,
+        fixup: impl FnMut(usize, usize),
+    ) -> Result<(), Error> {
+        self.migrate_to_cancellable(dest, fixup, &CancellationToken::new())
+    }
+
+    /// Like `migrate_to`, but checks `cancel` before copying each allocation
+    /// and returns [`Error::Cancelled`] once it's cancelled, so a service
+    /// shutting down needn't wait for a large arena to be copied.
+    ///
+    /// A cancelled migration stops the same way one that ran out of room
+    /// does: both arenas are intact, what was copied so far stays allocated
+    /// in `dest` and `fixup` isn't called.
+    ///
+    /// # Safety
+    ///
+    /// As for `migrate_to`.
+    pub unsafe fn migrate_to_cancellable(
+        &self,
+        dest: &DiskDlmalloc,
+        mut fixup: impl FnMut(usize, usize),
+        cancel: &CancellationToken,
+    ) -> Result<(), Error> {
+        assert!(
+            !Arc::ptr_eq(&self.0, &dest.0),
+            "disk-dlmalloc: can't migrate an arena into itself"
+        );
+        assert!(
+            dest.0.small.is_none() || self.0.small.is_some(),
+            "disk-dlmalloc: can't migrate into an arena with lock striping from one without"
+        );
+        let mut moved = Vec::new();
+        let targets = [&dest.0.heap, dest.0.small.as_ref().unwrap_or(&dest.0.heap)];
+        for (heap, target) in self.0.heaps().zip(targets) {
+            let mut chunks = Vec::new();
+            {
+                let src = self.0.lock(heap);
+                src.dl.for_each_allocation(|mem, usable| {
+                    chunks.push((mem, usable, src.dl.is_mapped(mem)));
+                });
+            }
+            chunks.sort_unstable_by_key(|&(mem, ..)| mem);
+            let mut dst = dest.0.lock(target);
+            for (mem, usable, mapped) in chunks {
+                if cancel.is_cancelled() {
+                    return Err(Error::Cancelled);
+                }
+                // A chunk that had a segment of its own may be larger than
+                // its size calls for, more than `free` accepts of one in the
+                // heap, so it gets its own segment again.
+                let mut new = ptr::null_mut();
+                if mapped {
+                    new = dst.dl.malloc_mapped(usable);
+                }
+                if new.is_null() {
+                    new = dst.dl.malloc(usable);
+                }
+                let offset = self.0.system.to_offset(mem).unwrap();
+                if new.is_null() {
+                    return Err(Error::DestinationFull {
+                        offset,
+                        size: usable,
+                    });
+                }
+                ptr::copy_nonoverlapping(mem, new, usable);
+                moved.push((offset, dest.0.system.to_offset(new).unwrap()));
+                dest.0.live.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        dest.0.check_watermarks();
+        for (old, new) in moved {
+            fixup(old, new);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use crate::LeakReport;
+use std::io;
+
+/// Receives notifications about events inside the allocator.
+///
+/// Installed with [`Builder::observer`](crate::Builder::observer). Every
+/// method has an empty default implementation, so implementors only need to
+/// override the events they care about.
+///
+/// Methods may be called with allocator locks held. Calling back into the
+/// same allocator from them won't deadlock, but it won't get far either:
+/// allocations fail with a null pointer, and anything else that needs the
+/// lock, frees included, panics.
+pub trait Observer: Send + Sync {
+    /// Called when the redzone around an allocation is found to have been
+    /// overwritten as it's freed or reallocated, which only happens with the
+    /// `redzones` feature enabled. `ptr` and `size` describe the allocation.
+    ///
+    /// Without an observer this is a panic. If the observer returns, the
+    /// allocation is freed as usual.
+    fn on_corruption(&self, ptr: *mut u8, size: usize) {
+        let _ = (ptr, size);
+    }
+
+    /// Called when `arena_read` or `arena_write` is about to touch freed
+    /// memory or the bookkeeping around an allocation, which is only noticed
+    /// with the `shadow` feature enabled. `ptr` and `len` describe the
+    /// access and `poisoned` is the first byte of it that's off limits.
+    ///
+    /// Without an observer this is a panic. If the observer returns, the
+    /// access goes ahead.
+    fn on_poisoned_access(&self, ptr: *const u8, len: usize, poisoned: *const u8) {
+        let _ = (ptr, len, poisoned);
+    }
+
+    /// Called as the last handle to the allocator is dropped with allocations
+    /// still live, if [`Builder::warn_on_leak`](crate::Builder::warn_on_leak)
+    /// is set, which requires an observer.
+    fn on_leak(&self, report: &LeakReport) {
+        let _ = report;
+    }
+
+    /// Called when the allocation at `ptr`, `size` bytes long, couldn't be
+    /// written back to the file as it was freed, with
+    /// [`Builder::flush_on_free`](crate::Builder::flush_on_free) set. The
+    /// memory is freed all the same, so its contents may never reach the
+    /// file.
+    ///
+    /// Without an observer the first such error is returned by
+    /// [`DiskDlmalloc::close`](crate::DiskDlmalloc::close) instead.
+    fn on_flush_error(&self, ptr: *mut u8, size: usize, err: &io::Error) {
+        let _ = (ptr, size, err);
+    }
+
+    /// Called each time an allocator lock is acquired, with the lock held.
+    /// Meant for instrumentation, such as checking that a batch operation
+    /// only locked once; keep it cheap.
+    fn on_lock(&self) {}
+}
@@ -0,0 +1,119 @@
+use crate::{reentrancy, DiskDlmalloc, Shared};
+use core::ptr::{self, NonNull};
+use std::alloc::{Allocator, Layout};
+use std::sync::Arc;
+
+pub(crate) type Fallback = dyn Allocator + Send + Sync;
+
+impl DiskDlmalloc {
+    /// Makes `fallback` serve the allocations this arena can't, say a larger
+    /// but slower arena behind a small fast one, or `std::alloc::Global`.
+    ///
+    /// `malloc`, `calloc`, `malloc_near`, `malloc_high` and `realloc` try
+    /// the arena first and only turn to `fallback` when that fails; an
+    /// allocation outgrowing the arena in `realloc` moves over.
+    /// `try_malloc_timeout` never does, as `fallback` might not honour the
+    /// timeout. `free`, `realloc` and `free_all` tell the two
+    /// apart by whether the pointer lies in the arena, as
+    /// [`owns`](DiskDlmalloc::owns) does. Other methods taking a pointer,
+    /// such as `relocate`, only accept memory from the arena itself.
+    ///
+    /// Applies to every clone of this allocator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an overflow allocator was already set.
+    pub fn with_overflow(self, fallback: Arc<Fallback>) -> DiskDlmalloc {
+        if self.0.overflow.set(fallback).is_err() {
+            panic!("disk-dlmalloc: overflow allocator already set");
+        }
+        self
+    }
+
+    /// Returns whether `ptr` points into the arena rather than, for
+    /// instance, memory from the overflow allocator.
+    pub fn owns(&self, ptr: *const u8) -> bool {
+        self.0.system.to_offset(ptr).is_some()
+    }
+}
+
+impl Shared {
+    /// Returns the overflow allocator if `ptr` came from it.
+    pub(crate) fn overflow_owner(&self, ptr: *mut u8) -> Option<&Fallback> {
+        let fallback = self.overflow.get()?;
+        self.system.to_offset(ptr).is_none().then_some(&**fallback)
+    }
+
+    /// Allocates from the overflow allocator, returning null if there isn't
+    /// one or it fails too.
+    pub(crate) fn overflow_alloc(&self, size: usize, align: usize, zeroed: bool) -> *mut u8 {
+        let (Some(fallback), Ok(layout)) =
+            (self.overflow.get(), Layout::from_size_align(size, align))
+        else {
+            return ptr::null_mut();
+        };
+        let res = if zeroed {
+            fallback.allocate_zeroed(layout)
+        } else {
+            fallback.allocate(layout)
+        };
+        res.map_or(ptr::null_mut(), |ptr| ptr.cast().as_ptr())
+    }
+
+    /// Frees `ptr` to the overflow allocator if it came from there, returning
+    /// whether it did.
+    pub(crate) unsafe fn overflow_free(&self, ptr: *mut u8, size: usize, align: usize) -> bool {
+        let Some(fallback) = self.overflow_owner(ptr) else {
+            return false;
+        };
+        let layout = Layout::from_size_align_unchecked(size, align);
+        fallback.deallocate(NonNull::new_unchecked(ptr), layout);
+        true
+    }
+
+    /// Resizes `ptr`, which `fallback` allocated, leaving it with `fallback`.
+    pub(crate) unsafe fn overflow_realloc(
+        fallback: &Fallback,
+        ptr: *mut u8,
+        old_size: usize,
+        old_align: usize,
+        new_size: usize,
+        new_align: usize,
+    ) -> *mut u8 {
+        let old = Layout::from_size_align_unchecked(old_size, old_align);
+        let Ok(new) = Layout::from_size_align(new_size, new_align) else {
+            return ptr::null_mut();
+        };
+        let ptr = NonNull::new_unchecked(ptr);
+        let res = if new_size >= old_size {
+            fallback.grow(ptr, old, new)
+        } else {
+            fallback.shrink(ptr, old, new)
+        };
+        res.map_or(ptr::null_mut(), |ptr| ptr.cast().as_ptr())
+    }
+
+    /// Moves an arena allocation the arena couldn't resize to the overflow
+    /// allocator, returning null, with `ptr` untouched, if that fails too.
+    pub(crate) unsafe fn realloc_into_overflow(
+        &self,
+        ptr: *mut u8,
+        old_size: usize,
+        old_align: usize,
+        new_size: usize,
+        new_align: usize,
+    ) -> *mut u8 {
+        let heap = self.heap_for(old_size, old_align);
+        // Under the lock `ptr` couldn't be freed after the move.
+        if reentrancy::held(heap) {
+            return ptr::null_mut();
+        }
+        let res = self.overflow_alloc(new_size, new_align, false);
+        if !res.is_null() {
+            ptr::copy_nonoverlapping(ptr, res, old_size.min(new_size));
+            self.lock(heap).free(ptr, old_size, old_align);
+            self.freed();
+        }
+        res
+    }
+}
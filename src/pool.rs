@@ -0,0 +1,84 @@
+use crate::{DiskDlmalloc, MALLOC_ALIGNMENT};
+use std::sync::Mutex;
+
+/// Recycles buffers of one size, such as network I/O buffers, keeping up to
+/// a fixed number of freed ones for the next `get` instead of handing them
+/// back to `dlmalloc` only to split them off again.
+///
+/// Buffers are aligned to [`DiskDlmalloc::malloc_alignment`]. Cached ones
+/// are freed when the pool is dropped; those handed out are the caller's to
+/// `put` back or to free with the arena as allocations of `buffer_size`
+/// bytes.
+pub struct BufferPool {
+    alloc: DiskDlmalloc,
+    buffer_size: usize,
+    max_cached: usize,
+    /// Buffers put back and not handed out again, most recent last.
+    cached: Mutex<Vec<usize>>,
+}
+
+impl BufferPool {
+    /// Creates a pool of `buffer_size` byte buffers from `arena`, caching up
+    /// to `max_cached` of those put back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer_size` is 0.
+    pub fn new(arena: &DiskDlmalloc, buffer_size: usize, max_cached: usize) -> BufferPool {
+        assert!(buffer_size > 0, "buffers must not be empty");
+        BufferPool {
+            alloc: arena.clone(),
+            buffer_size,
+            max_cached,
+            cached: Mutex::new(Vec::with_capacity(max_cached)),
+        }
+    }
+
+    /// Returns a buffer, the one last put back if any, or else a new one
+    /// from the arena, or a null pointer if that's out of room. Its contents
+    /// are whatever was left in it.
+    pub fn get(&self) -> *mut u8 {
+        if let Some(ptr) = self.cached.lock().unwrap().pop() {
+            return ptr as *mut u8;
+        }
+        unsafe { self.alloc.malloc(self.buffer_size, MALLOC_ALIGNMENT) }
+    }
+
+    /// Gives a buffer back to the pool, which keeps it for the next `get`
+    /// unless it already holds `max_cached`, in which case it's freed.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a buffer from this pool's `get`, or an allocation of
+    /// `buffer_size` bytes from the same arena, that isn't used afterwards.
+    pub unsafe fn put(&self, ptr: *mut u8) {
+        let mut cached = self.cached.lock().unwrap();
+        if cached.len() < self.max_cached {
+            cached.push(ptr as usize);
+            return;
+        }
+        drop(cached);
+        self.alloc.free(ptr, self.buffer_size, MALLOC_ALIGNMENT);
+    }
+
+    /// Returns the size of the pool's buffers.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Returns how many buffers the pool holds for reuse.
+    pub fn cached(&self) -> usize {
+        self.cached.lock().unwrap().len()
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        for &ptr in self.cached.get_mut().unwrap().iter() {
+            unsafe {
+                self.alloc
+                    .free(ptr as *mut u8, self.buffer_size, MALLOC_ALIGNMENT)
+            };
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Canary bytes around allocations, enabled with the `redzones` feature.
+//!
+//! Each allocation is padded with a run of known bytes immediately before and
+//! after the memory handed to the caller. They're checked when the
+//! allocation is freed or reallocated, catching small overruns and underruns
+//! far more cheaply than guard pages would. Without the feature every
+//! function here is a no-op.
+
+#[cfg(feature = "redzones")]
+mod imp {
+    use crate::MALLOC_ALIGNMENT;
+    use core::{cmp, ptr, slice};
+
+    const CANARY: u8 = 0xfd;
+
+    /// Bytes of canary after each allocation.
+    const TAIL: usize = MALLOC_ALIGNMENT;
+
+    /// Bytes of canary before an allocation, enough to keep the pointer
+    /// returned to the caller aligned to `align`.
+    pub fn head(align: usize) -> usize {
+        cmp::max(align, MALLOC_ALIGNMENT)
+    }
+
+    /// Size of the chunk backing a request for `size` bytes.
+    pub fn padded(size: usize, align: usize) -> usize {
+        size.saturating_add(head(align) + TAIL)
+    }
+
+    /// Writes the canaries around a freshly allocated chunk, returning the
+    /// pointer to hand to the caller.
+    pub unsafe fn arm(raw: *mut u8, size: usize, align: usize) -> *mut u8 {
+        if raw.is_null() {
+            return raw;
+        }
+        let head = head(align);
+        ptr::write_bytes(raw, CANARY, head);
+        let ptr = raw.add(head);
+        ptr::write_bytes(ptr.add(size), CANARY, TAIL);
+        ptr
+    }
+
+    /// How much of an allocation of `size` bytes the caller may use: no
+    /// more than it asked for, as the tail canary comes right after.
+    pub fn usable(size: usize, _chunk_usable: usize) -> usize {
+        size
+    }
+
+    /// Inverse of `arm`, returning the start of the chunk and whether the
+    /// canaries are intact.
+    pub unsafe fn disarm(ptr: *mut u8, size: usize, align: usize) -> (*mut u8, bool) {
+        let head = head(align);
+        let raw = ptr.sub(head);
+        let before = slice::from_raw_parts(raw, head);
+        let after = slice::from_raw_parts(ptr.add(size), TAIL);
+        let intact = before.iter().chain(after).all(|&b| b == CANARY);
+        (raw, intact)
+    }
+}
+
+#[cfg(not(feature = "redzones"))]
+mod imp {
+    #[inline]
+    pub fn head(_align: usize) -> usize {
+        0
+    }
+
+    #[inline]
+    pub fn padded(size: usize, _align: usize) -> usize {
+        size
+    }
+
+    #[inline]
+    pub unsafe fn arm(raw: *mut u8, _size: usize, _align: usize) -> *mut u8 {
+        raw
+    }
+
+    #[inline]
+    pub fn usable(_size: usize, chunk_usable: usize) -> usize {
+        chunk_usable
+    }
+
+    #[inline]
+    pub unsafe fn disarm(ptr: *mut u8, _size: usize, _align: usize) -> (*mut u8, bool) {
+        (ptr, true)
+    }
+}
+
+pub use imp::*;
@@ -0,0 +1,66 @@
+//! Tracks which heaps the current thread has locked, so a nested attempt to
+//! lock one again, from an observer callback or drop glue running under the
+//! lock, can be refused instead of deadlocking on the non-reentrant mutex.
+
+use crate::Heap;
+use std::cell::Cell;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard};
+
+/// How many heaps one thread can hold at once and still be protected. No
+/// operation takes more than two locks of one allocator, this leaves room
+/// for a few allocators whose callbacks call into each other.
+const SLOTS: usize = 8;
+
+thread_local! {
+    // Addresses of the held heaps, 0 for a free slot. A fixed array rather
+    // than a `Vec` so tracking never allocates.
+    static HELD: [Cell<usize>; SLOTS] = const { [const { Cell::new(0) }; SLOTS] };
+}
+
+/// Whether the current thread holds `heap`'s lock.
+pub(crate) fn held(heap: &Mutex<Heap>) -> bool {
+    let addr = heap as *const Mutex<Heap> as usize;
+    HELD.with(|held| held.iter().any(|slot| slot.get() == addr))
+}
+
+/// A locked heap, recorded as held by the current thread until dropped.
+pub(crate) struct HeapGuard<'a> {
+    guard: MutexGuard<'a, Heap>,
+    slot: Option<usize>,
+}
+
+impl<'a> HeapGuard<'a> {
+    pub(crate) fn new(guard: MutexGuard<'a, Heap>, heap: &'a Mutex<Heap>) -> HeapGuard<'a> {
+        let addr = heap as *const Mutex<Heap> as usize;
+        // If every slot is taken the lock just goes untracked.
+        let slot = HELD.with(|held| {
+            let slot = held.iter().position(|slot| slot.get() == 0)?;
+            held[slot].set(addr);
+            Some(slot)
+        });
+        HeapGuard { guard, slot }
+    }
+}
+
+impl Deref for HeapGuard<'_> {
+    type Target = Heap;
+
+    fn deref(&self) -> &Heap {
+        &self.guard
+    }
+}
+
+impl DerefMut for HeapGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Heap {
+        &mut self.guard
+    }
+}
+
+impl Drop for HeapGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            HELD.with(|held| held[slot].set(0));
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{DiskDlmalloc, SystemAllocator};
+use core::cmp;
+use std::alloc::{AllocError, Allocator, Layout};
+use std::cell::{Cell, RefCell};
+use std::ptr::{self, NonNull};
+
+/// The least a scope takes from the arena at a time.
+const MIN_BLOCK: usize = 64 << 10;
+
+/// A bump allocator for the duration of a [`DiskDlmalloc::scope`].
+///
+/// Memory is taken from the arena directly, bypassing `dlmalloc`, in blocks
+/// that allocations are carved from one after the other. Nothing is freed
+/// one allocation at a time: `free` and `deallocate` do nothing, and every
+/// block goes back to the arena at once when the scope ends.
+pub struct ScopeAllocator<'a> {
+    alloc: &'a DiskDlmalloc,
+    /// Next free byte of the current block, and its end.
+    next: Cell<*mut u8>,
+    end: Cell<*mut u8>,
+    /// Every block taken, as `(start, len)`, in the order they were taken.
+    blocks: RefCell<Vec<(*mut u8, usize)>>,
+}
+
+impl DiskDlmalloc {
+    /// Runs `f` with a [`ScopeAllocator`] whose allocations are all freed
+    /// together when `f` returns (or unwinds), for phases that build up
+    /// transient data and throw it away at the end, such as a parser's.
+    ///
+    /// The scope's memory comes straight off the end of the arena, so giving
+    /// it back winds [`offset`](DiskDlmalloc::offset) back to where it was
+    /// on entry, in one step per block rather than one per allocation. If the
+    /// heap grew past the scope's memory in the meantime, it's left where it
+    /// is, and the scope's blocks become holes the next growth reuses.
+    ///
+    /// Nothing allocated from the scope may be used after it ends. Values
+    /// stored through the [`Allocator`] impl, like a `Vec` or `Box` made
+    /// with `new_in`, borrow the allocator and so can't escape, but raw
+    /// pointers from [`ScopeAllocator::malloc`] can, and it's up to the
+    /// caller not to let them.
+    pub fn scope<R>(&self, f: impl FnOnce(&ScopeAllocator) -> R) -> R {
+        let scope = ScopeAllocator {
+            alloc: self,
+            next: Cell::new(ptr::null_mut()),
+            end: Cell::new(ptr::null_mut()),
+            blocks: RefCell::new(Vec::new()),
+        };
+        f(&scope)
+    }
+}
+
+impl ScopeAllocator<'_> {
+    /// Allocates `size` bytes aligned to `align` from the scope, or returns
+    /// a null pointer if the arena is out of room.
+    pub fn malloc(&self, size: usize, align: usize) -> *mut u8 {
+        debug_assert!(align.is_power_of_two());
+        let next = self.next.get();
+        let start = next.wrapping_add(next.align_offset(align));
+        if !next.is_null()
+            && start <= self.end.get()
+            && size <= self.end.get() as usize - start as usize
+        {
+            self.next.set(start.wrapping_add(size));
+            return start;
+        }
+        let system = &self.alloc.0.system;
+        let len = cmp::max(MIN_BLOCK, size.saturating_add(align))
+            .checked_next_multiple_of(system.page_size());
+        let Some(len) = len else {
+            return ptr::null_mut();
+        };
+        let (block, len, _) = system.alloc(len);
+        if block.is_null() {
+            return ptr::null_mut();
+        }
+        self.blocks.borrow_mut().push((block, len));
+        let start = block.wrapping_add(block.align_offset(align));
+        self.next.set(start.wrapping_add(size));
+        self.end.set(block.wrapping_add(len));
+        start
+    }
+
+    /// Does nothing: memory from the scope is only freed when it ends.
+    pub fn free(&self, _ptr: *mut u8, _size: usize, _align: usize) {}
+
+    /// Returns how many bytes the scope has taken from the arena so far.
+    pub fn reserved(&self) -> usize {
+        self.blocks.borrow().iter().map(|&(_, len)| len).sum()
+    }
+}
+
+impl Drop for ScopeAllocator<'_> {
+    fn drop(&mut self) {
+        // The newest first, so that each is the end of the arena in turn.
+        let system = &self.alloc.0.system;
+        for &(block, len) in self.blocks.get_mut().iter().rev() {
+            system.free(block, len);
+        }
+    }
+}
+
+unsafe impl Allocator for ScopeAllocator<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.malloc(layout.size(), layout.align());
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+}
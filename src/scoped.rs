@@ -0,0 +1,172 @@
+use crate::DiskDlmalloc;
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ptr::{self, NonNull};
+
+/// A handle that allocates on behalf of one tenant of a shared arena and
+/// holds it to a byte budget.
+///
+/// Obtained from [`DiskDlmalloc::scoped`]. Usage is tracked per token, so
+/// every handle for the same token draws from the same count, though each
+/// enforces its own quota. Only the requested sizes are counted, not
+/// `dlmalloc`'s per-chunk overhead.
+///
+/// Allocations aren't tagged with their token, so memory allocated through
+/// a scoped handle has to be freed or reallocated through a handle for the
+/// same token to be credited back.
+#[derive(Clone)]
+pub struct ScopedAllocator {
+    alloc: DiskDlmalloc,
+    token: u64,
+    quota: usize,
+}
+
+impl DiskDlmalloc {
+    /// Returns a [`ScopedAllocator`] that allocates from this arena on behalf
+    /// of `token`, refusing requests that would take the bytes allocated
+    /// under `token` past `quota`.
+    pub fn scoped(&self, token: u64, quota: usize) -> ScopedAllocator {
+        ScopedAllocator {
+            alloc: self.clone(),
+            token,
+            quota,
+        }
+    }
+}
+
+impl ScopedAllocator {
+    /// Returns the token allocations are accounted to.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// Returns the number of bytes this handle lets `token` have allocated.
+    pub fn quota(&self) -> usize {
+        self.quota
+    }
+
+    /// Returns the number of bytes currently allocated under the token.
+    pub fn used(&self) -> usize {
+        let usage = self.alloc.0.usage.lock().unwrap();
+        usage.get(&self.token).copied().unwrap_or(0)
+    }
+
+    /// Counts `size` more bytes against the token, unless that would exceed
+    /// the quota.
+    fn charge(&self, size: usize) -> bool {
+        let mut usage = self.alloc.0.usage.lock().unwrap();
+        let used = usage.entry(self.token).or_insert(0);
+        match used.checked_add(size) {
+            Some(total) if total <= self.quota => {
+                *used = total;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn refund(&self, size: usize) {
+        let mut usage = self.alloc.0.usage.lock().unwrap();
+        if let Some(used) = usage.get_mut(&self.token) {
+            *used -= size;
+            if *used == 0 {
+                usage.remove(&self.token);
+            }
+        }
+    }
+
+    /// Like [`DiskDlmalloc::malloc`], but also returns a null pointer if the
+    /// allocation would exceed the quota.
+    ///
+    /// # Safety
+    ///
+    /// As for [`DiskDlmalloc::malloc`].
+    pub unsafe fn malloc(&self, size: usize, align: usize) -> *mut u8 {
+        if !self.charge(size) {
+            return ptr::null_mut();
+        }
+        let ptr = self.alloc.malloc(size, align);
+        if ptr.is_null() {
+            self.refund(size);
+        }
+        ptr
+    }
+
+    /// Like [`DiskDlmalloc::calloc`], but also returns a null pointer if the
+    /// allocation would exceed the quota.
+    ///
+    /// # Safety
+    ///
+    /// As for [`DiskDlmalloc::calloc`].
+    pub unsafe fn calloc(&self, size: usize, align: usize) -> *mut u8 {
+        if !self.charge(size) {
+            return ptr::null_mut();
+        }
+        let ptr = self.alloc.calloc(size, align);
+        if ptr.is_null() {
+            self.refund(size);
+        }
+        ptr
+    }
+
+    /// Like [`DiskDlmalloc::free`], crediting `size` bytes back to the token.
+    ///
+    /// # Safety
+    ///
+    /// As for [`DiskDlmalloc::free`], and the allocation must have been made
+    /// through this token.
+    pub unsafe fn free(&self, ptr: *mut u8, size: usize, align: usize) {
+        self.alloc.free(ptr, size, align);
+        self.refund(size);
+    }
+
+    /// Like [`DiskDlmalloc::realloc`], but also returns a null pointer, leaving
+    /// `ptr` untouched, if growing would exceed the quota.
+    ///
+    /// # Safety
+    ///
+    /// As for [`DiskDlmalloc::realloc`], and the allocation must have been made
+    /// through this token.
+    pub unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        old_size: usize,
+        old_align: usize,
+        new_size: usize,
+    ) -> *mut u8 {
+        if new_size > old_size && !self.charge(new_size - old_size) {
+            return ptr::null_mut();
+        }
+        let res = self.alloc.realloc(ptr, old_size, old_align, new_size);
+        if new_size == 0 {
+            // Reallocating to nothing frees `ptr` and returns null.
+            self.refund(old_size);
+        } else if res.is_null() {
+            if new_size > old_size {
+                self.refund(new_size - old_size);
+            }
+        } else if new_size < old_size {
+            self.refund(old_size - new_size);
+        }
+        res
+    }
+}
+
+unsafe impl Allocator for ScopedAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = unsafe { self.malloc(layout.size(), layout.align()) };
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = unsafe { self.calloc(layout.size(), layout.align()) };
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.free(ptr.as_ptr(), layout.size(), layout.align());
+    }
+}
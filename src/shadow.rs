@@ -0,0 +1,113 @@
+//! Shadow memory for catching stray accesses, enabled with the `shadow`
+//! feature.
+//!
+//! A side table keeps one bit per pointer-sized granule of the backing file,
+//! set while the granule is poisoned: freed, or part of a chunk that isn't
+//! the caller's to touch, like the header in front of each allocation or its
+//! redzones. `DiskDlmalloc::arena_read` and `arena_write` check it before
+//! copying, which catches uses after free and overruns the way ASan would
+//! for memory it knows about. The table is keyed by file offset, so it
+//! survives the arena being remapped. Memory never handed out through
+//! `malloc` and friends isn't poisoned, so a missed update can only hide an
+//! error, never invent one. Without the feature every method here is a
+//! no-op.
+
+#[cfg(feature = "shadow")]
+mod imp {
+    use crate::sys::System;
+    use core::mem;
+    use std::sync::Mutex;
+
+    const GRANULE: usize = mem::size_of::<usize>();
+
+    #[derive(Default)]
+    pub struct Shadow {
+        /// Bit `n` is set if granule `n` of the file is poisoned.
+        poisoned: Mutex<Vec<u64>>,
+    }
+
+    impl Shadow {
+        /// Poisons the granules `[start, end)` touches.
+        pub fn poison(&self, sys: &System, start: *const u8, end: *const u8) {
+            self.set(sys, start, end, true);
+        }
+
+        /// Unpoisons the granules `[start, end)` touches.
+        pub fn unpoison(&self, sys: &System, start: *const u8, end: *const u8) {
+            self.set(sys, start, end, false);
+        }
+
+        fn set(&self, sys: &System, start: *const u8, end: *const u8, poisoned: bool) {
+            let Some(offset) = sys.to_offset(start) else {
+                return;
+            };
+            let first = offset / GRANULE;
+            let last = (offset + (end as usize - start as usize)).div_ceil(GRANULE);
+            let mut bits = self.poisoned.lock().unwrap();
+            if bits.len() < last.div_ceil(64) {
+                if !poisoned {
+                    return;
+                }
+                bits.resize(last.div_ceil(64), 0);
+            }
+            for granule in first..last {
+                if poisoned {
+                    bits[granule / 64] |= 1 << (granule % 64);
+                } else {
+                    bits[granule / 64] &= !(1 << (granule % 64));
+                }
+            }
+        }
+
+        /// Returns the first poisoned byte in `[ptr, ptr + len)`, if any.
+        pub fn first_poisoned(
+            &self,
+            sys: &System,
+            ptr: *const u8,
+            len: usize,
+        ) -> Option<*const u8> {
+            let offset = sys.to_offset(ptr)?;
+            let bits = self.poisoned.lock().unwrap();
+            let granule = (offset / GRANULE..(offset + len).div_ceil(GRANULE))
+                .take_while(|granule| granule / 64 < bits.len())
+                .find(|granule| bits[granule / 64] & (1 << (granule % 64)) != 0)?;
+            Some(ptr.wrapping_add((granule * GRANULE).saturating_sub(offset)))
+        }
+
+        /// Forgets everything, for when the whole heap starts over.
+        pub fn clear(&self) {
+            self.poisoned.lock().unwrap().clear();
+        }
+    }
+}
+
+#[cfg(not(feature = "shadow"))]
+mod imp {
+    use crate::sys::System;
+
+    #[derive(Default)]
+    pub struct Shadow(());
+
+    impl Shadow {
+        #[inline]
+        pub fn poison(&self, _sys: &System, _start: *const u8, _end: *const u8) {}
+
+        #[inline]
+        pub fn unpoison(&self, _sys: &System, _start: *const u8, _end: *const u8) {}
+
+        #[inline]
+        pub fn first_poisoned(
+            &self,
+            _sys: &System,
+            _ptr: *const u8,
+            _len: usize,
+        ) -> Option<*const u8> {
+            None
+        }
+
+        #[inline]
+        pub fn clear(&self) {}
+    }
+}
+
+pub use imp::*;
@@ -0,0 +1,205 @@
+//! Turns `SIGBUS` from a faulting arena page into a readable abort.
+//!
+//! When the backing file shrinks under the mapping (someone truncated it, a
+//! sparse file hit a full disk, a network filesystem went away) touching the
+//! affected pages raises `SIGBUS`, which normally kills the process without a
+//! word. With [`Builder::catch_sigbus`](crate::Builder::catch_sigbus) arenas
+//! register their mappings here, and a process-wide handler that finds the
+//! faulting address in one of them prints the file and offset before
+//! aborting. Faults anywhere else are passed on to whatever handler was
+//! installed before.
+//!
+//! The handler can only use async-signal-safe operations, so registrations
+//! live in a fixed table of atomics rather than behind a lock.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Once, OnceLock};
+
+/// How many mappings can be watched at once, across all arenas.
+const SLOTS: usize = 64;
+
+struct Slot {
+    /// Address range of the mapping, `start` being 0 for a free slot.
+    start: AtomicUsize,
+    end: AtomicUsize,
+    /// File offset mapped at `start`.
+    offset: AtomicUsize,
+    /// Nul-terminated path of the file, owned by the `Registration`.
+    path: AtomicPtr<libc::c_char>,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: Slot = Slot {
+    start: AtomicUsize::new(0),
+    end: AtomicUsize::new(0),
+    offset: AtomicUsize::new(0),
+    path: AtomicPtr::new(ptr::null_mut()),
+};
+
+static TABLE: [Slot; SLOTS] = [FREE; SLOTS];
+static INSTALL: Once = Once::new();
+static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+/// The mappings of one arena, unregistered when dropped.
+pub struct Registration {
+    slots: Vec<usize>,
+    path: CString,
+}
+
+impl Registration {
+    /// Installs the handler if it isn't yet and starts watching for faults
+    /// in mappings of `path`.
+    pub fn new(path: &Path) -> io::Result<Registration> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        install()?;
+        Ok(Registration {
+            slots: Vec::new(),
+            path,
+        })
+    }
+
+    /// Watches `[ptr, ptr + len)`, which maps the file from `offset` on.
+    pub fn add(&mut self, ptr: *const u8, len: usize, offset: usize) -> io::Result<()> {
+        for (index, slot) in TABLE.iter().enumerate() {
+            // Claim the slot through `start` so concurrent registrations
+            // can't both take it.
+            if slot
+                .start
+                .compare_exchange(0, usize::MAX, Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+            slot.path.store(self.path.as_ptr().cast_mut(), Ordering::Release);
+            slot.offset.store(offset, Ordering::Release);
+            slot.end.store(ptr as usize + len, Ordering::Release);
+            slot.start.store(ptr as usize, Ordering::Release);
+            self.slots.push(index);
+            return Ok(());
+        }
+        Err(io::Error::other("too many mappings watched for SIGBUS"))
+    }
+
+    /// Shrinks the watched range starting at `ptr` to `len` bytes, or stops
+    /// watching it if `len` is 0.
+    pub fn resize(&mut self, ptr: *const u8, len: usize) {
+        let start = ptr as usize;
+        self.slots.retain(|&index| {
+            let slot = &TABLE[index];
+            if slot.start.load(Ordering::Acquire) != start {
+                return true;
+            }
+            if len > 0 {
+                slot.end.store(start + len, Ordering::Release);
+                return true;
+            }
+            slot.end.store(0, Ordering::Release);
+            slot.path.store(ptr::null_mut(), Ordering::Release);
+            slot.start.store(0, Ordering::Release);
+            false
+        });
+    }
+
+    /// Changes the file name reported for this arena's mappings.
+    pub fn rename(&mut self, path: &Path) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        for &index in &self.slots {
+            TABLE[index]
+                .path
+                .store(path.as_ptr().cast_mut(), Ordering::Release);
+        }
+        self.path = path;
+        Ok(())
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        for &index in &self.slots {
+            let slot = &TABLE[index];
+            slot.end.store(0, Ordering::Release);
+            slot.path.store(ptr::null_mut(), Ordering::Release);
+            slot.start.store(0, Ordering::Release);
+        }
+    }
+}
+
+fn install() -> io::Result<()> {
+    let mut result = Ok(());
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous: libc::sigaction = mem::zeroed();
+        if libc::sigaction(libc::SIGBUS, &action, &mut previous) != 0 {
+            result = Err(io::Error::last_os_error());
+            return;
+        }
+        let _ = PREVIOUS.set(previous);
+    });
+    result
+}
+
+extern "C" fn handler(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+    for slot in &TABLE {
+        let start = slot.start.load(Ordering::Acquire);
+        if start == 0 || start == usize::MAX || addr < start {
+            continue;
+        }
+        if addr >= slot.end.load(Ordering::Acquire) {
+            continue;
+        }
+        let offset = slot.offset.load(Ordering::Acquire) + (addr - start);
+        let path = slot.path.load(Ordering::Acquire);
+        unsafe {
+            write_stderr(b"disk-dlmalloc: SIGBUS accessing offset ");
+            write_number(offset);
+            write_stderr(b" of arena file ");
+            if !path.is_null() {
+                write_stderr(std::ffi::CStr::from_ptr(path).to_bytes());
+            }
+            write_stderr(b"; the file was probably truncated or its storage failed\n");
+            libc::abort();
+        }
+    }
+
+    // Not ours: put the previous disposition back and return, so the fault
+    // happens again and is handled the way it would have been without us.
+    unsafe {
+        match PREVIOUS.get() {
+            Some(previous) => libc::sigaction(signal, previous, ptr::null_mut()),
+            None => {
+                libc::signal(signal, libc::SIG_DFL);
+                0
+            }
+        };
+    }
+}
+
+unsafe fn write_stderr(bytes: &[u8]) {
+    libc::write(libc::STDERR_FILENO, bytes.as_ptr().cast(), bytes.len());
+}
+
+unsafe fn write_number(mut n: usize) {
+    let mut buf = [0u8; 20];
+    let mut at = buf.len();
+    loop {
+        at -= 1;
+        buf[at] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    write_stderr(&buf[at..]);
+}
@@ -0,0 +1,35 @@
+use crate::{DiskDlmalloc, Error, SystemAllocator};
+
+impl DiskDlmalloc {
+    /// Carves `sizes.len()` parts out of the arena, each a separate
+    /// allocator with its own heap and lock, so that a subsystem that fills
+    /// its part up can't take memory from the others.
+    ///
+    /// Each size is rounded up to a page. The parts come out of the arena
+    /// the way [`malloc`](DiskDlmalloc::malloc) would take fresh memory, and
+    /// stay out of it until the last handle to the part is dropped, when
+    /// they go back. They share the backing file, so offsets such as those
+    /// from [`to_offset`](DiskDlmalloc::to_offset) are file offsets in every
+    /// part alike. A part is built with the [`Builder`](crate::Builder)
+    /// defaults and can't grow, [`shrink_arena`](DiskDlmalloc::shrink_arena) or
+    /// [`swap_backing`](DiskDlmalloc::swap_backing).
+    ///
+    /// Returns [`Error::NoRoom`] if the arena can't hold every part, in
+    /// which case none are taken.
+    pub fn split(&self, sizes: &[usize]) -> Result<Vec<DiskDlmalloc>, Error> {
+        let page = self.0.system.page_size();
+        let mut parts = Vec::with_capacity(sizes.len());
+        for &size in sizes {
+            let len = size.next_multiple_of(page);
+            // Dropping the parts taken so far gives them back.
+            let system = match self.0.system.split_off(len)? {
+                Some(system) => system,
+                None => return Err(Error::NoRoom { requested: len }),
+            };
+            // The builder's file path goes unused.
+            let builder = DiskDlmalloc::builder("", len);
+            parts.push(DiskDlmalloc::from_system(system, &builder));
+        }
+        Ok(parts)
+    }
+}
@@ -0,0 +1,195 @@
+use crate::DiskDlmalloc;
+use core::cmp;
+use core::mem;
+use core::ptr;
+use std::io;
+
+/// Each chunk of a stream starts with its header: the offset of the next
+/// chunk (or `END`), the bytes written to it and the room it has for them.
+const HEADER: usize = 3 * mem::size_of::<usize>();
+const END: usize = usize::MAX;
+const FIRST_CHUNK: usize = 4 << 10;
+const MAX_CHUNK: usize = 1 << 20;
+
+/// Appends bytes to the arena as they're written, allocating as it goes.
+///
+/// Obtained from [`DiskDlmalloc::writer`]. The bytes go into a chain of
+/// allocations, each twice the size of the last up to a megabyte, so a
+/// stream of unknown length costs no copying as it grows. Once done,
+/// [`finish`](ArenaWriter::finish) gives the offset to read the stream back
+/// from with [`DiskDlmalloc::reader`]. A writer dropped without finishing
+/// frees what it wrote.
+pub struct ArenaWriter {
+    alloc: DiskDlmalloc,
+    /// Offset of the first chunk, if any.
+    head: Option<usize>,
+    /// Header of the chunk being filled.
+    tail: *mut usize,
+    next_size: usize,
+}
+
+/// Reads back a stream written with an [`ArenaWriter`], as one contiguous
+/// run of bytes.
+///
+/// Obtained from [`DiskDlmalloc::reader`].
+pub struct ArenaReader {
+    alloc: DiskDlmalloc,
+    /// Header of the chunk being read, null at the end.
+    chunk: *const usize,
+    pos: usize,
+}
+
+unsafe impl Send for ArenaWriter {}
+unsafe impl Send for ArenaReader {}
+
+impl DiskDlmalloc {
+    /// Returns an [`ArenaWriter`] that appends what's written to it to a new
+    /// stream in this arena.
+    pub fn writer(&self) -> ArenaWriter {
+        ArenaWriter {
+            alloc: self.clone(),
+            head: None,
+            tail: ptr::null_mut(),
+            next_size: FIRST_CHUNK,
+        }
+    }
+
+    /// Returns an [`ArenaReader`] for the stream starting at `offset`, as
+    /// returned by [`ArenaWriter::finish`].
+    ///
+    /// # Safety
+    ///
+    /// `offset` must be that of a finished stream that hasn't been freed.
+    pub unsafe fn reader(&self, offset: usize) -> ArenaReader {
+        ArenaReader {
+            alloc: self.clone(),
+            chunk: self.chunk_at(offset),
+            pos: 0,
+        }
+    }
+
+    /// Frees the stream starting at `offset`.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must be that of a finished stream that hasn't been freed, and
+    /// no reader of it may be used afterwards.
+    pub unsafe fn free_stream(&self, offset: usize) {
+        let mut chunk = self.chunk_at(offset).cast_mut();
+        while !chunk.is_null() {
+            let next = *chunk;
+            let cap = *chunk.add(2);
+            self.free(chunk.cast(), HEADER + cap, mem::align_of::<usize>());
+            chunk = if next == END {
+                ptr::null_mut()
+            } else {
+                self.chunk_at(next).cast_mut()
+            };
+        }
+    }
+
+    fn chunk_at(&self, offset: usize) -> *const usize {
+        let ptr = self
+            .to_ptr(offset)
+            .expect("stream offset outside the arena");
+        ptr.cast_const().cast()
+    }
+}
+
+impl ArenaWriter {
+    /// Ends the stream and returns the offset it starts at.
+    ///
+    /// # Errors
+    ///
+    /// Fails if nothing was written and there's no room for the empty
+    /// stream's one allocation.
+    pub fn finish(mut self) -> io::Result<usize> {
+        if self.head.is_none() {
+            self.grow()?;
+        }
+        Ok(self.head.take().unwrap())
+    }
+
+    /// Chains a new chunk to the stream.
+    fn grow(&mut self) -> io::Result<()> {
+        let cap = self.next_size;
+        let chunk = unsafe { self.alloc.malloc(HEADER + cap, mem::align_of::<usize>()) };
+        if chunk.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "no room in the arena to extend the stream",
+            ));
+        }
+        let offset = self.alloc.to_offset(chunk).unwrap();
+        let chunk = chunk.cast::<usize>();
+        unsafe {
+            chunk.write(END);
+            chunk.add(1).write(0);
+            chunk.add(2).write(cap);
+            match self.head {
+                Some(_) => *self.tail = offset,
+                None => self.head = Some(offset),
+            }
+        }
+        self.tail = chunk;
+        self.next_size = cmp::min(cap * 2, MAX_CHUNK);
+        Ok(())
+    }
+}
+
+impl io::Write for ArenaWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let full = self.tail.is_null() || unsafe { *self.tail.add(1) == *self.tail.add(2) };
+        if full {
+            self.grow()?;
+        }
+        unsafe {
+            let len = *self.tail.add(1);
+            let n = cmp::min(buf.len(), *self.tail.add(2) - len);
+            let data = self.tail.cast::<u8>().add(HEADER + len);
+            ptr::copy_nonoverlapping(buf.as_ptr(), data, n);
+            *self.tail.add(1) = len + n;
+            Ok(n)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ArenaWriter {
+    fn drop(&mut self) {
+        if let Some(head) = self.head.take() {
+            unsafe { self.alloc.free_stream(head) };
+        }
+    }
+}
+
+impl io::Read for ArenaReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        unsafe {
+            while !self.chunk.is_null() {
+                let len = *self.chunk.add(1);
+                if self.pos < len {
+                    let n = cmp::min(buf.len(), len - self.pos);
+                    let data = self.chunk.cast::<u8>().add(HEADER + self.pos);
+                    ptr::copy_nonoverlapping(data, buf.as_mut_ptr(), n);
+                    self.pos += n;
+                    return Ok(n);
+                }
+                let next = *self.chunk;
+                self.chunk = if next == END {
+                    ptr::null()
+                } else {
+                    self.alloc.chunk_at(next)
+                };
+                self.pos = 0;
+            }
+        }
+        Ok(0)
+    }
+}
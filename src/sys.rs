@@ -1,96 +1,1778 @@
-use crate::SystemAllocator;
+use crate::sigbus::Registration;
+#[cfg(target_os = "linux")]
+use crate::uffd::Pager;
+use crate::{redzone, Builder, Error, FileLock, SystemAllocator, MALLOC_ALIGNMENT};
+use core::cmp;
+use core::mem;
 use core::ptr;
-use memmap2::{Advice, MmapMut};
-use std::fs::OpenOptions;
+use memmap2::{Advice, MmapMut, MmapOptions};
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+/// Hands out memory from a file mapping. Clones share the same file and
+/// bump offset, so several `Dlmalloc` instances can carve up one arena.
+#[derive(Clone)]
 pub struct System {
-    inner: Mutex<Inner>,
+    inner: Arc<Mutex<Inner>>,
     page_size: usize,
+    lazy_free: bool,
+    account_disk_space: bool,
+    /// Whether the arena is plain heap memory rather than a file mapping,
+    /// which leaves the system calls on it out.
+    in_memory: bool,
+    /// Whether the arena is memory lent by another, see `split_off`, which
+    /// it can't grow, shrink or move.
+    part: bool,
 }
 
 struct Inner {
-    mmap: MmapMut,
+    /// `None` for an arena made with `in_memory`.
+    file: Option<File>,
+    /// Declared before `regions` so the mappings are unregistered before
+    /// they're unmapped.
+    sigbus: Option<Registration>,
+    /// Pages the regions in through the handler set with
+    /// `page_fault_handler`.
+    #[cfg(target_os = "linux")]
+    pager: Option<Pager>,
+    regions: Vec<Region>,
+    mem_advise: Advice,
+    /// File ranges given advice of their own with `advise_range`, as
+    /// `(start, end, advice)`, sorted and not overlapping.
+    advised: Vec<(usize, usize, Advice)>,
+    /// Address ranges locked with `lock_pages`, as `(start, len)`.
+    locked: Vec<(usize, usize)>,
+    shared: bool,
     total_size: usize,
+    /// Where handing out memory starts, past the padding asked for with
+    /// `initial_align`.
+    start: usize,
     offset: usize,
+    /// Address ranges below `offset` that were given back out of order,
+    /// sorted and coalesced. `alloc` reuses them before bumping `offset`.
+    holes: Vec<(usize, usize)>,
+    /// The furthest `offset` has ever been.
+    high_water_mark: usize,
+    /// For an arena made with `split_off`, the arena its memory came from and
+    /// the address and length to give back to it.
+    lender: Option<(System, usize, usize)>,
+    /// How much to extend the file by when an allocation doesn't fit, or 0
+    /// to keep the arena at its size.
+    growth_increment: usize,
+    /// `[high, high_end)` is handed out from the top down by `alloc_high`.
+    /// The bump from `offset` jumps over it while it holds anything.
+    high: usize,
+    high_end: usize,
+    /// Like `holes`, for the part of `[high, high_end)` given back out of
+    /// order.
+    high_holes: Vec<(usize, usize)>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some((lender, ptr, len)) = self.lender.take() {
+            lender.free(ptr as *mut u8, len);
+        }
+    }
+}
+
+/// A mapping of the file range `[start, start + mmap.len())`. The first region
+/// maps the file as it was at construction, later ones map tails picked up by
+/// `refresh_mapping`.
+struct Region {
+    mmap: Mapping,
+    start: usize,
+}
+
+/// Either a regular mapping or one placed at a requested address, which
+/// memmap2 has no way to ask for, or the heap memory of an in-memory arena,
+/// or part of another arena's mapping.
+enum Mapping {
+    Mmap(MmapMut),
+    Fixed { ptr: *mut u8, len: usize },
+    Memory { ptr: *mut u8, layout: Layout },
+    Borrowed { ptr: *mut u8, len: usize },
+}
+
+// The fixed mapping is just as shareable as `MmapMut`, it's only the raw
+// pointer that opts it out.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+/// The page size an in-memory arena rounds to, in place of asking the
+/// system.
+const MEMORY_PAGE_SIZE: usize = 4096;
+
+/// The error for what an in-memory arena can't do without a file or a
+/// mapping.
+fn in_memory_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the arena is in memory, with no file or mapping",
+    )
+}
+
+/// The error for what an arena from `split_off` can't do, as its memory
+/// belongs to another.
+fn part_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the arena is part of a split one, which it can't resize or move",
+    )
+}
+
+/// The `mmap` flag picking between a shared and a private mapping.
+fn visibility(shared: bool) -> libc::c_int {
+    if shared {
+        libc::MAP_SHARED
+    } else {
+        libc::MAP_PRIVATE
+    }
+}
+
+/// Copies up to `len` bytes at `offset` in `from` to the same offset in `to`
+/// inside the kernel, returning how many were copied. Stops early, leaving
+/// the rest to the caller, wherever `copy_file_range` isn't available or
+/// refuses, such as across filesystems on older kernels.
+#[cfg(target_os = "linux")]
+fn copy_file_range(from: &File, to: &File, offset: usize, len: usize) -> usize {
+    let mut copied = 0;
+    while copied < len {
+        let mut off_in = (offset + copied) as libc::loff_t;
+        let mut off_out = off_in;
+        let n = unsafe {
+            libc::copy_file_range(
+                from.as_raw_fd(),
+                &mut off_in,
+                to.as_raw_fd(),
+                &mut off_out,
+                len - copied,
+                0,
+            )
+        };
+        if n <= 0 {
+            break;
+        }
+        copied += n as usize;
+    }
+    copied
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_file_range(_from: &File, _to: &File, _offset: usize, _len: usize) -> usize {
+    0
+}
+
+/// Writes zeros over the first `len` bytes of `file` and checks that they
+/// read back.
+fn zero_file(file: &File, len: usize) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    let zeroed =
+        unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_ZERO_RANGE, 0, len as _) } == 0;
+    #[cfg(not(target_os = "linux"))]
+    let zeroed = false;
+    let mut buf = vec![0; cmp::min(len, 1 << 20)];
+    if !zeroed {
+        for start in (0..len).step_by(buf.len().max(1)) {
+            let end = cmp::min(start + buf.len(), len);
+            file.write_all_at(&buf[..end - start], start as u64)?;
+        }
+    }
+    file.sync_data()?;
+    for start in (0..len).step_by(buf.len().max(1)) {
+        let end = cmp::min(start + buf.len(), len);
+        let chunk = &mut buf[..end - start];
+        file.read_exact_at(chunk, start as u64)?;
+        if let Some(i) = chunk.iter().position(|&b| b != 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("offset {} didn't read back as zero", start + i),
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl Mapping {
+    /// Maps `file` as described by `options`, privately unless `shared`.
+    fn map(options: &MmapOptions, file: &File, shared: bool) -> io::Result<Mapping> {
+        let mmap = unsafe {
+            if shared {
+                options.map_mut(file)?
+            } else {
+                options.map_copy(file)?
+            }
+        };
+        Ok(Mapping::Mmap(mmap))
+    }
+
+    /// Maps the start of `file` at `addr`, failing rather than replacing any
+    /// existing mapping there. Kernels that predate `MAP_FIXED_NOREPLACE`
+    /// (Linux 4.17), and platforms without it, treat `addr` as a hint and may
+    /// place the mapping elsewhere.
+    fn fixed(file: &File, addr: usize, len: usize, shared: bool) -> io::Result<Mapping> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let flags = visibility(shared) | libc::MAP_FIXED_NOREPLACE;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let flags = visibility(shared);
+        let ptr = unsafe {
+            libc::mmap(
+                addr as *mut libc::c_void,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping::Fixed {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    /// Replaces the pages under this mapping with `file` from `offset` on,
+    /// keeping the same addresses.
+    fn remap_to(&mut self, file: &File, offset: usize, shared: bool) -> io::Result<()> {
+        let ptr = unsafe {
+            libc::mmap(
+                self.as_mut_ptr().cast(),
+                self.len(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                visibility(shared) | libc::MAP_FIXED,
+                file.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Unmaps everything past the first `len` bytes, which must be a
+    /// multiple of the page size.
+    fn truncate(&mut self, len: usize) -> io::Result<()> {
+        let ptr = self.as_mut_ptr();
+        let old_len = self.len();
+        if len >= old_len {
+            return Ok(());
+        }
+        let tail = unsafe { ptr.add(len) };
+        if unsafe { libc::munmap(tail.cast(), old_len - len) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Dropping the old mapping would unmap its original length, possibly
+        // taking someone else's mapping with it, so track what's left
+        // ourselves.
+        mem::forget(mem::replace(self, Mapping::Fixed { ptr, len }));
+        Ok(())
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        match self {
+            Mapping::Mmap(mmap) => mmap.as_ptr(),
+            Mapping::Fixed { ptr, .. }
+            | Mapping::Memory { ptr, .. }
+            | Mapping::Borrowed { ptr, .. } => *ptr,
+        }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        match self {
+            Mapping::Mmap(mmap) => mmap.as_mut_ptr(),
+            Mapping::Fixed { ptr, .. }
+            | Mapping::Memory { ptr, .. }
+            | Mapping::Borrowed { ptr, .. } => *ptr,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Mapping::Mmap(mmap) => mmap.len(),
+            Mapping::Fixed { len, .. } | Mapping::Borrowed { len, .. } => *len,
+            Mapping::Memory { layout, .. } => layout.size(),
+        }
+    }
+
+    fn advise(&self, advice: Advice) -> io::Result<()> {
+        match self {
+            Mapping::Mmap(mmap) => mmap.advise(advice),
+            Mapping::Memory { .. } => Ok(()),
+            Mapping::Fixed { ptr, len } | Mapping::Borrowed { ptr, len } => {
+                if unsafe { libc::madvise(ptr.cast(), *len, advice as libc::c_int) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        match *self {
+            Mapping::Mmap(_) | Mapping::Borrowed { .. } => {}
+            Mapping::Fixed { ptr, len } => unsafe {
+                libc::munmap(ptr.cast(), len);
+            },
+            Mapping::Memory { ptr, layout } => unsafe { alloc::dealloc(ptr, layout) },
+        }
+    }
+}
+
+impl Inner {
+    fn to_offset(&self, ptr: *const u8) -> Option<usize> {
+        self.regions.iter().find_map(|r| {
+            let base = r.mmap.as_ptr();
+            let delta = (ptr as usize).checked_sub(base as usize)?;
+            (delta < r.mmap.len()).then_some(r.start + delta)
+        })
+    }
+
+    /// The backing file, which callers check an in-memory arena for first.
+    fn file(&self) -> &File {
+        self.file.as_ref().expect("in-memory arenas have no file")
+    }
+
+    /// Whether `[ptr, ptr + size)` is a non-empty run of the arena, mapped
+    /// in one piece.
+    fn holds(&self, ptr: *const u8, size: usize) -> bool {
+        let Some(last) = size.checked_sub(1) else {
+            return false;
+        };
+        let Some(end) = (ptr as usize).checked_add(last) else {
+            return false;
+        };
+        match (self.to_offset(ptr), self.to_offset(end as *const u8)) {
+            (Some(start), Some(end)) => start.checked_add(last) == Some(end),
+            _ => false,
+        }
+    }
+
+    /// Gives `[ptr, ptr + size)` back to the bump allocator, which is only
+    /// possible if it's the most recently handed out memory. Holes left at
+    /// the new end are absorbed too.
+    fn unbump(&mut self, ptr: *mut u8, size: usize) -> bool {
+        match self.to_offset(ptr) {
+            Some(start) if start + size == self.offset => {
+                self.offset = start;
+                while let Some(&(addr, len)) = self.holes.last() {
+                    match self.to_offset((addr + len - 1) as *const u8) {
+                        Some(last) if last + 1 == self.offset => {
+                            self.offset = self.to_offset(addr as *const u8).unwrap();
+                            self.holes.pop();
+                        }
+                        _ => break,
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Hands out the start of the first hole of at least `size` bytes.
+    fn take_hole(&mut self, size: usize) -> Option<*mut u8> {
+        let i = self.holes.iter().position(|&(_, len)| len >= size)?;
+        let (addr, len) = self.holes[i];
+        if len == size {
+            self.holes.remove(i);
+        } else {
+            self.holes[i] = (addr + size, len - size);
+        }
+        Some(addr as *mut u8)
+    }
+
+    /// Where the part of region `i` that no earlier region maps starts.
+    ///
+    /// A region mapped by `map_tail` overlaps the last partial page of the
+    /// one before it. With a private mapping the two see separate copies of
+    /// that page, so its bytes are only ever handed out through the earlier
+    /// region.
+    fn own_start(&self, i: usize) -> usize {
+        let region = &self.regions[i];
+        match i.checked_sub(1) {
+            Some(prev) => cmp::max(region.start, self.regions[prev].end()),
+            None => region.start,
+        }
+    }
+
+    /// Hands out `size` bytes at `offset`, moving it past them.
+    fn bump(&mut self, size: usize) -> Option<*mut u8> {
+        // Allocations can't straddle regions, so if the request doesn't fit in
+        // what's left of the current region the remainder is abandoned and we
+        // move on to the next one. The same goes for the high end.
+        let current = self.offset;
+        let (high, high_end) = (self.high, self.high_end);
+        for i in 0..self.regions.len() {
+            let own_start = self.own_start(i);
+            let region = &mut self.regions[i];
+            if region.end() <= current {
+                continue;
+            }
+            let mut start = cmp::max(current, own_start);
+            if high < high_end && start < high_end && start + size > high {
+                start = cmp::max(start, high_end);
+            }
+            if start + size <= region.end() {
+                let ptr = unsafe { region.mmap.as_mut_ptr().add(start - region.start) };
+                self.offset = start + size;
+                self.high_water_mark = cmp::max(self.high_water_mark, self.offset);
+                return Some(ptr);
+            }
+        }
+        None
+    }
+
+    fn hole_bytes(&self) -> usize {
+        self.holes.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Whether `[offset, offset + 1)` lies in the high end.
+    fn in_high(&self, offset: usize) -> bool {
+        self.high <= offset && offset < self.high_end
+    }
+}
+
+/// Records `advice` for `[start, end)` in `advised`, trimming or splitting
+/// the ranges it overlaps.
+fn record_advice(
+    advised: &mut Vec<(usize, usize, Advice)>,
+    start: usize,
+    end: usize,
+    advice: Advice,
+) {
+    let mut ranges = Vec::with_capacity(advised.len() + 2);
+    for &(s, e, a) in advised.iter() {
+        if e <= start || end <= s {
+            ranges.push((s, e, a));
+            continue;
+        }
+        if s < start {
+            ranges.push((s, start, a));
+        }
+        if end < e {
+            ranges.push((end, e, a));
+        }
+    }
+    ranges.push((start, end, advice));
+    ranges.sort_unstable_by_key(|&(s, ..)| s);
+    *advised = ranges;
+}
+
+/// Records `[addr, addr + len)` as free in `holes`, merging it with its
+/// neighbours.
+fn add_hole(holes: &mut Vec<(usize, usize)>, mut addr: usize, mut len: usize) {
+    let mut i = holes.partition_point(|&(a, _)| a < addr);
+    if i > 0 && holes[i - 1].0 + holes[i - 1].1 == addr {
+        i -= 1;
+        addr = holes[i].0;
+        len += holes[i].1;
+        holes.remove(i);
+    }
+    if i < holes.len() && addr + len == holes[i].0 {
+        len += holes[i].1;
+        holes.remove(i);
+    }
+    holes.insert(i, (addr, len));
+}
+
+impl Region {
+    fn end(&self) -> usize {
+        self.start + self.mmap.len()
+    }
+
+    /// Points this region at the same range of another file.
+    fn remap_to(&mut self, file: &File, advice: Advice, shared: bool) -> io::Result<()> {
+        self.mmap.remap_to(file, self.start, shared)?;
+        self.mmap.advise(advice)
+    }
 }
 
 impl System {
-    pub fn new<P: AsRef<Path>>(
-        file_path: P,
-        total_size: usize,
-        mem_advise: Option<Advice>,
-    ) -> System {
-        let file_path = file_path.as_ref().to_path_buf();
-        let file = match OpenOptions::new()
+    pub fn new(builder: &Builder) -> Result<System, Error> {
+        let file_path = &builder.file_path;
+        let total_size = builder.total_size;
+        let context = |what: &str, err: io::Error| {
+            io::Error::new(
+                err.kind(),
+                format!("Could not {} {}: {}", what, file_path.display(), err),
+            )
+        };
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .truncate(true)
-            .open(&file_path)
-        {
-            Ok(file) => file,
-            Err(err) => panic!("Could not open file {}: {:?}", file_path.display(), err),
+            .truncate(false)
+            .open(file_path)
+            .map_err(|err| context("open file", err))?;
+        // Lock before truncating, so that a failed open leaves the file of
+        // whoever holds the lock alone.
+        let operation = match builder.file_lock {
+            FileLock::None => None,
+            FileLock::Shared => Some(libc::LOCK_SH),
+            FileLock::Exclusive => Some(libc::LOCK_EX),
         };
-        if let Err(err) = file.set_len(total_size as u64) {
-            panic!("Could not set file size {}: {:?}", file_path.display(), err);
-        }
-        let mmap: MmapMut = unsafe {
-            match MmapMut::map_mut(&file) {
-                Ok(mmap) => mmap,
-                Err(err) => panic!("Could not mmap file {}: {:?}", file_path.display(), err),
+        if let Some(operation) = operation {
+            if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Err(Error::Locked);
+                }
+                return Err(context("lock", err).into());
             }
+        }
+        // Whole filesystem blocks, so that punching holes never has to deal
+        // with a partial one at the end.
+        let block = file
+            .metadata()
+            .map_err(|err| context("stat", err))?
+            .blksize() as usize;
+        let total_size = match block {
+            0 => total_size,
+            block => total_size.checked_next_multiple_of(block).unwrap_or(total_size),
         };
-        let mem_advise = mem_advise.unwrap_or(Advice::Normal);
-        if let Err(err) = mmap.advise(mem_advise) {
-            panic!(
-                "Could not mem advise mmap for file {}: {:?}",
-                file_path.display(),
-                err
-            );
+        file.set_len(0)
+            .map_err(|err| context("truncate file", err))?;
+        file.set_len(total_size as u64)
+            .map_err(|err| context("set file size", err))?;
+        if builder.pre_zero {
+            zero_file(&file, total_size).map_err(|err| context("zero", err))?;
         }
+        // Refuse to go anywhere else if the requested range is taken, but if
+        // the kernel can't place mappings at all we map wherever it likes.
+        let fixed = match builder.base_address {
+            Some(addr) => match Mapping::fixed(&file, addr, total_size, builder.shared) {
+                Ok(mmap) => Some(mmap),
+                Err(err) if err.raw_os_error() == Some(libc::EEXIST) => {
+                    return Err(Error::MappingOverlaps {
+                        addr,
+                        len: total_size,
+                    });
+                }
+                Err(_) => None,
+            },
+            None => None,
+        };
+        let mmap = match fixed {
+            Some(mmap) => mmap,
+            None => Mapping::map(&MmapOptions::new(), &file, builder.shared)
+                .map_err(|err| context("mmap file", err))?,
+        };
+        let mem_advise = builder.config.advice;
+        mmap.advise(mem_advise)
+            .map_err(|err| context("mem advise mmap for file", err))?;
         let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
-        System {
-            inner: Mutex::new(Inner {
-                mmap,
+        let sigbus = if builder.catch_sigbus {
+            let mut registration = Registration::new(file_path)
+                .map_err(|err| context("install SIGBUS handler for", err))?;
+            registration
+                .add(mmap.as_ptr(), mmap.len(), 0)
+                .map_err(|err| context("install SIGBUS handler for", err))?;
+            Some(registration)
+        } else {
+            None
+        };
+        #[cfg(target_os = "linux")]
+        let pager = match &builder.page_fault_handler {
+            Some(handler) => {
+                let mut pager = Pager::new(handler.clone(), page_size)
+                    .map_err(|err| context("set up demand paging for", err))?;
+                pager
+                    .add(mmap.as_ptr(), mmap.len(), 0)
+                    .map_err(|err| context("set up demand paging for", err))?;
+                Some(pager)
+            }
+            None => None,
+        };
+        // `dlmalloc` puts the first chunk right at the start of the memory it
+        // gets, its payload two words in, and the caller's part of that comes
+        // after any redzone.
+        let payload =
+            mmap.as_ptr() as usize + 2 * mem::size_of::<usize>() + redzone::head(MALLOC_ALIGNMENT);
+        let start = cmp::min(
+            payload.next_multiple_of(builder.initial_align) - payload,
+            total_size,
+        );
+        Ok(System {
+            inner: Arc::new(Mutex::new(Inner {
+                file: Some(file),
+                sigbus,
+                #[cfg(target_os = "linux")]
+                pager,
+                regions: vec![Region { mmap, start: 0 }],
+                mem_advise,
+                advised: Vec::new(),
+                locked: Vec::new(),
+                shared: builder.shared,
                 total_size,
-                offset: 0,
-            }),
+                start,
+                offset: start,
+                holes: Vec::new(),
+                high_water_mark: start,
+                lender: None,
+                growth_increment: 0,
+                high: total_size,
+                high_end: total_size,
+                high_holes: Vec::new(),
+            })),
             page_size,
+            lazy_free: builder.lazy_free,
+            account_disk_space: builder.account_disk_space,
+            in_memory: false,
+            part: false,
+        })
+    }
+
+    /// Like `new`, but hands out `builder.total_size` bytes of zeroed heap
+    /// memory instead of mapping a file, and makes no system calls on it.
+    /// Only the settings that don't involve the file or the mapping apply.
+    pub fn in_memory(builder: &Builder) -> System {
+        let layout = Layout::from_size_align(cmp::max(builder.total_size, 1), MEMORY_PAGE_SIZE)
+            .expect("in-memory arena too large");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        let total_size = builder.total_size;
+        let payload = ptr as usize + 2 * mem::size_of::<usize>() + redzone::head(MALLOC_ALIGNMENT);
+        let start = cmp::min(
+            payload.next_multiple_of(builder.initial_align) - payload,
+            total_size,
+        );
+        System {
+            inner: Arc::new(Mutex::new(Inner {
+                file: None,
+                sigbus: None,
+                #[cfg(target_os = "linux")]
+                pager: None,
+                regions: vec![Region {
+                    mmap: Mapping::Memory { ptr, layout },
+                    start: 0,
+                }],
+                mem_advise: builder.config.advice,
+                advised: Vec::new(),
+                locked: Vec::new(),
+                shared: false,
+                total_size,
+                start,
+                offset: start,
+                holes: Vec::new(),
+                high_water_mark: start,
+                lender: None,
+                growth_increment: 0,
+                high: total_size,
+                high_end: total_size,
+                high_holes: Vec::new(),
+            })),
+            page_size: MEMORY_PAGE_SIZE,
+            lazy_free: false,
+            account_disk_space: false,
+            in_memory: true,
+            part: false,
+        }
+    }
+
+    /// Takes `len` bytes, a multiple of the page size, from this arena and
+    /// makes an arena of its own out of them, which uses the same file and
+    /// file offsets and gives the memory back once its last clone is
+    /// dropped. Returns `None` if there isn't room.
+    pub fn split_off(&self, len: usize) -> io::Result<Option<System>> {
+        let (ptr, len, _) = self.alloc(len);
+        if ptr.is_null() {
+            return Ok(None);
+        }
+        let inner = self.inner.lock().unwrap();
+        let file = match &inner.file {
+            Some(file) => match file.try_clone() {
+                Ok(file) => Some(file),
+                Err(err) => {
+                    drop(inner);
+                    self.free(ptr, len);
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+        let start = inner.to_offset(ptr).unwrap();
+        let end = start + len;
+        Ok(Some(System {
+            inner: Arc::new(Mutex::new(Inner {
+                file,
+                sigbus: None,
+                #[cfg(target_os = "linux")]
+                pager: None,
+                regions: vec![Region {
+                    mmap: Mapping::Borrowed { ptr, len },
+                    start,
+                }],
+                mem_advise: inner.mem_advise,
+                advised: Vec::new(),
+                locked: Vec::new(),
+                shared: inner.shared,
+                total_size: end,
+                start,
+                offset: start,
+                holes: Vec::new(),
+                high_water_mark: start,
+                lender: Some((self.clone(), ptr as usize, len)),
+                growth_increment: 0,
+                high: end,
+                high_end: end,
+                high_holes: Vec::new(),
+            })),
+            page_size: self.page_size,
+            lazy_free: self.lazy_free,
+            account_disk_space: false,
+            in_memory: self.in_memory,
+            part: true,
+        }))
+    }
+
+    /// Lets the kernel drop the pages entirely within `[ptr, ptr + size)`.
+    ///
+    /// With `lazy_free` this uses `MADV_FREE`, which only reclaims the pages
+    /// under memory pressure and is cheaper than `MADV_DONTNEED`. macOS only
+    /// supports it for private mappings and Linux only for anonymous memory,
+    /// never a file mapping, so we fall back to `MADV_DONTNEED` when it's
+    /// refused.
+    fn release(&self, ptr: *mut u8, size: usize) -> io::Result<()> {
+        if self.in_memory {
+            return Ok(());
+        }
+        let start = (ptr as usize).next_multiple_of(self.page_size);
+        let end = (ptr as usize + size) & !(self.page_size - 1);
+        if start >= end {
+            return Ok(());
+        }
+        let (addr, len) = (start as *mut libc::c_void, end - start);
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if self.lazy_free && unsafe { libc::madvise(addr, len, libc::MADV_FREE) } == 0 {
+            return Ok(());
+        }
+        if unsafe { libc::madvise(addr, len, libc::MADV_DONTNEED) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Drops the pages entirely within `[ptr, ptr + size)` from memory and,
+    /// for a shared mapping, punches them out of the file too, so they
+    /// don't linger in the page cache or on disk. Returns whether there were
+    /// any such pages, which a range not mapped in one piece by the arena
+    /// never has.
+    pub fn discard(&self, ptr: *mut u8, size: usize) -> io::Result<bool> {
+        if self.in_memory {
+            return Ok(false);
+        }
+        let start = (ptr as usize).next_multiple_of(self.page_size);
+        let end = (ptr as usize + size) & !(self.page_size - 1);
+        if start >= end {
+            return Ok(false);
+        }
+        let inner = self.inner.lock().unwrap();
+        let (addr, len) = (start as *mut libc::c_void, end - start);
+        // Only ever drop our own pages, and punch the file where they are.
+        if !inner.holds(start as *const u8, len) {
+            return Ok(false);
         }
+        if unsafe { libc::madvise(addr, len, libc::MADV_DONTNEED) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[cfg(target_os = "linux")]
+        if inner.shared {
+            let offset = inner.to_offset(start as *const u8).unwrap() as libc::off_t;
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            let fd = inner.file().as_raw_fd();
+            if unsafe { libc::fallocate(fd, mode, offset, len as libc::off_t) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        drop(inner);
+        Ok(true)
+    }
+
+    /// Zeroes `[ptr, ptr + len)` by punching the pages entirely within it out
+    /// of the file, leaving them sparse and out of memory, and writing zeros
+    /// over the bytes either side. Returns `false`, having done nothing, if
+    /// the pages can't be punched: the mapping is private, a page fault
+    /// handler would fill them with something else, or the filesystem
+    /// refuses.
+    pub fn zero_sparse(&self, ptr: *mut u8, len: usize) -> bool {
+        if self.in_memory {
+            return false;
+        }
+        let start = (ptr as usize).next_multiple_of(self.page_size);
+        let end = (ptr as usize + len) & !(self.page_size - 1);
+        if start >= end {
+            return false;
+        }
+        let inner = self.inner.lock().unwrap();
+        if !inner.shared {
+            return false;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if inner.pager.is_some() {
+                return false;
+            }
+            // Punching also drops the pages from every mapping of the file,
+            // so the next access faults in a fresh zero page.
+            let offset = inner.to_offset(start as *const u8).unwrap() as libc::off_t;
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            let fd = inner.file().as_raw_fd();
+            if unsafe { libc::fallocate(fd, mode, offset, (end - start) as libc::off_t) } != 0 {
+                return false;
+            }
+            drop(inner);
+            unsafe {
+                ptr::write_bytes(ptr, 0, start - ptr as usize);
+                ptr::write_bytes(end as *mut u8, 0, ptr as usize + len - end);
+            }
+            true
+        }
+        #[cfg(not(target_os = "linux"))]
+        false
+    }
+
+    /// Asks the kernel to bring the pages under `[ptr, ptr + len)` into
+    /// memory ahead of use, both through the mapping and, on Linux, by
+    /// starting readahead on the file.
+    pub fn prefetch(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        let inner = self.inner.lock().unwrap();
+        let offset = inner.to_offset(ptr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "pointer outside the arena")
+        })?;
+        if self.in_memory {
+            return Ok(());
+        }
+        let start = ptr as usize & !(self.page_size - 1);
+        let end = (ptr as usize).saturating_add(len).next_multiple_of(self.page_size);
+        if start < end {
+            let addr = start as *mut libc::c_void;
+            if unsafe { libc::madvise(addr, end - start, libc::MADV_WILLNEED) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let fd = inner.file().as_raw_fd();
+            if unsafe { libc::readahead(fd, offset as libc::off64_t, len) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = offset;
+        Ok(())
+    }
+
+    /// Copies `buf.len()` bytes from `ptr` into `buf`, returning an error
+    /// rather than raising `SIGBUS` if a page under them can't be read. On
+    /// Linux the kernel does the copy with `process_vm_readv`, which fails
+    /// with `EFAULT` where touching the page would fault; elsewhere this
+    /// fails with `Unsupported`.
+    pub fn try_read(&self, ptr: *const u8, buf: &mut [u8]) -> io::Result<()> {
+        let inner = self.inner.lock().unwrap();
+        if buf.is_empty() {
+            return Ok(());
+        }
+        if !inner.holds(ptr, buf.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range outside the arena",
+            ));
+        }
+        if self.in_memory {
+            unsafe { ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), buf.len()) };
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let mut done = 0;
+            while done < buf.len() {
+                let local = libc::iovec {
+                    iov_base: buf[done..].as_mut_ptr().cast(),
+                    iov_len: buf.len() - done,
+                };
+                let remote = libc::iovec {
+                    iov_base: ptr.wrapping_add(done).cast_mut().cast(),
+                    iov_len: buf.len() - done,
+                };
+                let read =
+                    unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+                // A partial read stops at the page that can't be read, which
+                // the next attempt then fails on.
+                if read < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.raw_os_error() != Some(libc::EFAULT) {
+                        return Err(err);
+                    }
+                    let offset = inner.to_offset(ptr.wrapping_add(done)).unwrap();
+                    return Err(io::Error::other(format!(
+                        "reading offset {} of the arena faulted; the file was probably truncated or its storage failed",
+                        offset
+                    )));
+                }
+                done += read as usize;
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Applies `MADV_HUGEPAGE` to `[ptr, ptr + len)`, which must be page
+    /// aligned. Transparent huge pages only exist on Linux, so elsewhere this
+    /// fails with `Unsupported`.
+    pub fn advise_hugepage(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        if self.in_memory {
+            return Err(in_memory_unsupported());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let addr = ptr as *mut libc::c_void;
+            if unsafe { libc::madvise(addr, len, libc::MADV_HUGEPAGE) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (ptr, len);
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    /// Writes the pages under `[ptr, ptr + len)` back to the file with
+    /// `msync(MS_SYNC)`, returning once they're on stable storage.
+    pub fn sync(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        if self.to_offset(ptr).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pointer outside the arena",
+            ));
+        }
+        let start = ptr as usize & !(self.page_size - 1);
+        let end = (ptr as usize).saturating_add(len).next_multiple_of(self.page_size);
+        if start >= end || self.in_memory {
+            return Ok(());
+        }
+        let addr = start as *mut libc::c_void;
+        if unsafe { libc::msync(addr, end - start, libc::MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Starts writing the dirty pages under `[ptr, ptr + len)` back to the
+    /// file without waiting for them, or with `wait`, waits for that to
+    /// finish, using `sync_file_range` where there is one.
+    pub fn writeback(&self, ptr: *mut u8, len: usize, wait: bool) -> io::Result<()> {
+        let inner = self.inner.lock().unwrap();
+        if self.in_memory || !inner.shared || !inner.holds(ptr, len) {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let offset = inner.to_offset(ptr).unwrap();
+            let mut flags = libc::SYNC_FILE_RANGE_WRITE;
+            if wait {
+                flags |= libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+            }
+            let fd = inner.file().as_raw_fd();
+            if unsafe { libc::sync_file_range(fd, offset as i64, len as i64, flags) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            drop(inner);
+            let start = ptr as usize & !(self.page_size - 1);
+            let end = (ptr as usize + len).next_multiple_of(self.page_size);
+            let flags = if wait { libc::MS_SYNC } else { libc::MS_ASYNC };
+            if unsafe { libc::msync(start as *mut libc::c_void, end - start, flags) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    /// Locks the pages under each of `ranges` into memory with `mlock`,
+    /// unlocking those locked by the previous call first.
+    pub fn lock_pages(&self, ranges: &[(*mut u8, usize)]) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if self.in_memory {
+            return Ok(());
+        }
+        // Ranges that went away with the mapping may fail, harmlessly.
+        for (start, len) in inner.locked.drain(..) {
+            unsafe { libc::munlock(start as *const libc::c_void, len) };
+        }
+        let mut pages: Vec<(usize, usize)> = ranges
+            .iter()
+            .filter(|&&(_, len)| len > 0)
+            .map(|&(ptr, len)| {
+                let start = ptr as usize & !(self.page_size - 1);
+                let end = (ptr as usize).saturating_add(len).next_multiple_of(self.page_size);
+                (start, end)
+            })
+            .collect();
+        pages.sort_unstable();
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in pages {
+            match runs.last_mut() {
+                Some(last) if start <= last.1 => last.1 = cmp::max(last.1, end),
+                _ => runs.push((start, end)),
+            }
+        }
+        for (start, end) in runs {
+            if unsafe { libc::mlock(start as *const libc::c_void, end - start) } != 0 {
+                let err = io::Error::last_os_error();
+                for (start, len) in inner.locked.drain(..) {
+                    unsafe { libc::munlock(start as *const libc::c_void, len) };
+                }
+                return Err(err);
+            }
+            inner.locked.push((start, end - start));
+        }
+        Ok(())
+    }
+
+    /// Like `sync`, but for the whole arena, followed by the file's metadata.
+    pub fn sync_all(&self) -> io::Result<()> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.shared {
+            return Ok(());
+        }
+        for region in &inner.regions {
+            let addr = region.mmap.as_ptr() as *mut libc::c_void;
+            if unsafe { libc::msync(addr, region.mmap.len(), libc::MS_SYNC) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        inner.file().sync_all()
+    }
+
+    /// Changes the protection of `[ptr, ptr + len)` with `mprotect`. Both
+    /// `ptr` and `len` must be multiples of the page size.
+    pub fn protect(&self, ptr: *mut u8, len: usize, prot: libc::c_int) -> io::Result<()> {
+        if self.in_memory {
+            return Err(in_memory_unsupported());
+        }
+        if self.to_offset(ptr).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pointer outside the arena",
+            ));
+        }
+        if !(ptr as usize).is_multiple_of(self.page_size) || !len.is_multiple_of(self.page_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range isn't page aligned",
+            ));
+        }
+        if unsafe { libc::mprotect(ptr.cast(), len, prot) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Maps the part of the file under `[ptr, ptr + size)` twice in a row at
+    /// a fresh address, returning it. `size` must be a multiple of the page
+    /// size and the range inside one region. Undone by `unmap_ring`.
+    pub fn map_ring(&self, ptr: *mut u8, size: usize) -> io::Result<*mut u8> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.shared {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the views of a private mapping don't share writes",
+            ));
+        }
+        let offset = inner.to_offset(ptr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "pointer outside the arena")
+        })?;
+        // Reserve the address space for both views first so nothing else can
+        // end up in between, then map the file over it twice.
+        let reserved = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                2 * size,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if reserved == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        for view in [reserved.cast::<u8>(), unsafe { reserved.cast::<u8>().add(size) }] {
+            let mapped = unsafe {
+                libc::mmap(
+                    view.cast(),
+                    size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_FIXED,
+                    inner.file().as_raw_fd(),
+                    offset as libc::off_t,
+                )
+            };
+            if mapped == libc::MAP_FAILED {
+                let err = io::Error::last_os_error();
+                unsafe { libc::munmap(reserved, 2 * size) };
+                return Err(err);
+            }
+        }
+        Ok(reserved.cast())
+    }
+
+    /// Unmaps both views of a ring made by `map_ring`.
+    pub fn unmap_ring(&self, ring: *mut u8, size: usize) {
+        unsafe { libc::munmap(ring.cast(), 2 * size) };
+    }
+
+    /// Returns whether every page under `[ptr, ptr + len)` is in memory.
+    /// Ranges the arena doesn't map aren't.
+    pub fn is_resident(&self, ptr: *mut u8, len: usize) -> bool {
+        if self.in_memory {
+            return self.to_offset(ptr).is_some();
+        }
+        // `mincore` would answer for whatever else is mapped there.
+        if !self.inner.lock().unwrap().holds(ptr, len.max(1)) {
+            return false;
+        }
+        let start = ptr as usize & !(self.page_size - 1);
+        let end = (ptr as usize).saturating_add(len).next_multiple_of(self.page_size);
+        if start >= end {
+            return true;
+        }
+        let mut pages = vec![0; (end - start) / self.page_size];
+        let addr = start as *mut libc::c_void;
+        if unsafe { libc::mincore(addr, end - start, pages.as_mut_ptr()) } != 0 {
+            return false;
+        }
+        pages.iter().all(|&page| page & 1 != 0)
+    }
+
+    /// Faults in the pages under `[ptr, ptr + len)` for writing, allocating
+    /// their blocks in the file, with `MADV_POPULATE_WRITE`. Kernels before
+    /// Linux 5.14 and other platforms don't have it, so there this falls
+    /// back to `MADV_WILLNEED`, which only starts reading them in.
+    pub fn commit(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        if self.in_memory {
+            return Err(in_memory_unsupported());
+        }
+        if self.inner.lock().unwrap().to_offset(ptr).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pointer outside the arena",
+            ));
+        }
+        let start = ptr as usize & !(self.page_size - 1);
+        let end = (ptr as usize).saturating_add(len).next_multiple_of(self.page_size);
+        if start >= end {
+            return Ok(());
+        }
+        let (addr, len) = (start as *mut libc::c_void, end - start);
+        #[cfg(target_os = "linux")]
+        if unsafe { libc::madvise(addr, len, libc::MADV_POPULATE_WRITE) } == 0 {
+            return Ok(());
+        } else {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINVAL) {
+                return Err(err);
+            }
+        }
+        if unsafe { libc::madvise(addr, len, libc::MADV_WILLNEED) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Writes the pages entirely within `[ptr, ptr + len)` back to the file
+    /// and drops them from the mapping and, on Linux, the page cache. Only
+    /// shared mappings can do this without losing their contents, so
+    /// private ones fail with `Unsupported`.
+    pub fn decommit(&self, ptr: *mut u8, len: usize) -> io::Result<()> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.shared {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a private mapping can't be decommitted without losing its contents",
+            ));
+        }
+        if inner.to_offset(ptr).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pointer outside the arena",
+            ));
+        }
+        let start = (ptr as usize).next_multiple_of(self.page_size);
+        let end = (ptr as usize).saturating_add(len) & !(self.page_size - 1);
+        if start >= end {
+            return Ok(());
+        }
+        let (addr, len) = (start as *mut libc::c_void, end - start);
+        if unsafe { libc::msync(addr, len, libc::MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::madvise(addr, len, libc::MADV_DONTNEED) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let offset = inner.to_offset(start as *const u8).unwrap() as libc::off_t;
+            let fd = inner.file().as_raw_fd();
+            let advice = libc::POSIX_FADV_DONTNEED;
+            let err = unsafe { libc::posix_fadvise(fd, offset, len as libc::off_t, advice) };
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks up growth of the backing file performed by someone else, mapping
+    /// the new tail as an additional region. Existing mappings are left in
+    /// place so outstanding pointers stay valid.
+    pub fn refresh_mapping(&self) -> io::Result<()> {
+        if self.in_memory || self.part {
+            return Ok(());
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let len = usize::try_from(inner.file().metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "file too large to map"))?;
+        if len <= inner.total_size {
+            return Ok(());
+        }
+        self.map_tail(&mut inner, len)
+    }
+
+    /// Maps the file from the end of the arena up to `len`, which must be
+    /// past it, as a new region.
+    fn map_tail(&self, inner: &mut Inner, len: usize) -> io::Result<()> {
+        // Mapping offsets must be page aligned, so the new region may overlap
+        // the last partial page of the previous one, which keeps those bytes
+        // for itself (see `own_start`).
+        let start = inner.total_size - inner.total_size % self.page_size;
+        let mmap = Mapping::map(
+            MmapOptions::new().offset(start as u64).len(len - start),
+            inner.file(),
+            inner.shared,
+        )?;
+        mmap.advise(inner.mem_advise)?;
+        if let Some(sigbus) = &mut inner.sigbus {
+            sigbus.add(mmap.as_ptr(), mmap.len(), start)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(pager) = &mut inner.pager {
+            pager.add(mmap.as_ptr(), mmap.len(), start)?;
+        }
+        inner.regions.push(Region { mmap, start });
+        inner.total_size = len;
+        Ok(())
+    }
+
+    /// Copies everything handed out so far to a fresh file at `path` and maps
+    /// it over the existing regions, so addresses don't change.
+    ///
+    /// The caller has to make sure nothing writes to the arena meanwhile.
+    pub fn swap_backing(&self, path: &Path) -> io::Result<()> {
+        if self.in_memory {
+            return Err(in_memory_unsupported());
+        }
+        if self.part {
+            return Err(part_unsupported());
+        }
+        let mut inner = self.inner.lock().unwrap();
+        // Don't truncate until we know `path` isn't the file we're copying
+        // from, under this name or another.
+        #[allow(clippy::suspicious_open_options)]
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        let (old, new) = (inner.file().metadata()?, file.metadata()?);
+        if (old.dev(), old.ino()) == (new.dev(), new.ino()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot swap the arena onto its own backing file",
+            ));
+        }
+        file.set_len(0)?;
+        file.set_len(inner.total_size as u64)?;
+        let live = [(0, inner.offset), (inner.high, inner.high_end)];
+        for (i, region) in inner.regions.iter().enumerate() {
+            // Bytes a region shares with the one before were written through
+            // that one, and a private mapping's copy here may be stale.
+            let own_start = inner.own_start(i);
+            for &(lo, hi) in &live {
+                let start = cmp::max(lo, own_start);
+                let end = cmp::min(hi, region.end());
+                if start >= end {
+                    continue;
+                }
+                // A private mapping's changes never reach the old file, so
+                // only a shared one can be copied file to file.
+                let copied = if inner.shared {
+                    copy_file_range(inner.file(), &file, start, end - start)
+                } else {
+                    0
+                };
+                let bytes = unsafe {
+                    let ptr = region.mmap.as_ptr().add(start - region.start);
+                    core::slice::from_raw_parts(ptr, end - start)
+                };
+                file.write_all_at(&bytes[copied..], (start + copied) as u64)?;
+            }
+        }
+        file.sync_all()?;
+
+        let Inner {
+            regions,
+            file: old,
+            mem_advise,
+            advised,
+            locked,
+            shared,
+            sigbus,
+            ..
+        } = &mut *inner;
+        for i in 0..regions.len() {
+            if let Err(err) = regions[i].remap_to(&file, *mem_advise, *shared) {
+                // Put back what we already switched so the arena stays
+                // consistent with the old file.
+                for region in &mut regions[..=i] {
+                    let _ = region.remap_to(old.as_ref().unwrap(), *mem_advise, *shared);
+                }
+                return Err(err);
+            }
+        }
+        *old = Some(file);
+        // The new mappings only have the advice for the whole arena, and
+        // aren't locked.
+        advised.clear();
+        locked.clear();
+        if let Some(sigbus) = sigbus {
+            sigbus.rename(path)?;
+        }
+        // The new mappings replaced the registered ones.
+        #[cfg(target_os = "linux")]
+        if let Inner {
+            pager: Some(pager),
+            regions,
+            ..
+        } = &mut *inner
+        {
+            for region in regions.iter() {
+                pager.add(region.mmap.as_ptr(), region.mmap.len(), region.start)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Cuts the arena down to `new_size` bytes, unmapping and truncating
+    /// everything past it. Fails if memory past `new_size` has been handed
+    /// out.
+    pub fn shrink(&self, new_size: usize) -> Result<(), Error> {
+        if self.in_memory {
+            return Err(in_memory_unsupported().into());
+        }
+        if self.part {
+            return Err(part_unsupported().into());
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if new_size > inner.total_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot shrink the arena to a larger size",
+            )
+            .into());
+        }
+        if inner.offset > new_size {
+            return Err(Error::WouldTruncateLive {
+                in_use: inner.offset,
+                requested: new_size,
+            });
+        }
+        if inner.high < inner.high_end && inner.high_end > new_size {
+            return Err(Error::WouldTruncateLive {
+                in_use: inner.high_end,
+                requested: new_size,
+            });
+        }
+        let Inner {
+            regions, sigbus, ..
+        } = &mut *inner;
+        for region in regions.iter_mut().rev() {
+            let keep = new_size
+                .saturating_sub(region.start)
+                .next_multiple_of(self.page_size);
+            if keep >= region.mmap.len() {
+                continue;
+            }
+            region.mmap.truncate(keep)?;
+            if let Some(sigbus) = sigbus {
+                sigbus.resize(region.mmap.as_ptr(), keep);
+            }
+        }
+        regions.retain(|region| region.mmap.len() > 0);
+        inner.file().set_len(new_size as u64)?;
+        inner.total_size = new_size;
+        Ok(())
+    }
+
+    /// Applies `advice` to every region, and to any mapped later.
+    pub fn set_advice(&self, advice: Advice) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !self.in_memory {
+            for region in &inner.regions {
+                region.mmap.advise(advice)?;
+            }
+        }
+        inner.mem_advise = advice;
+        inner.advised.clear();
+        Ok(())
+    }
+
+    /// Applies `advice` to the pages under `[ptr, ptr + len)`, remembering
+    /// it for `advice_for`.
+    pub fn advise_range(&self, ptr: *mut u8, len: usize, advice: Advice) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.holds(ptr, len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range outside the arena",
+            ));
+        }
+        let start = ptr as usize & !(self.page_size - 1);
+        let end = (ptr as usize + len).next_multiple_of(self.page_size);
+        let addr = start as *mut libc::c_void;
+        if !self.in_memory
+            && unsafe { libc::madvise(addr, end - start, advice as libc::c_int) } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let offset = inner.to_offset(start as *const u8).unwrap();
+        record_advice(&mut inner.advised, offset, offset + (end - start), advice);
+        Ok(())
+    }
+
+    /// Returns the advice in effect for the page holding `ptr`: that of the
+    /// last `advise_range` covering it, or else the arena's.
+    pub fn advice_for(&self, ptr: *const u8) -> Advice {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .to_offset(ptr)
+            .and_then(|offset| {
+                inner
+                    .advised
+                    .iter()
+                    .find(|&&(start, end, _)| start <= offset && offset < end)
+            })
+            .map_or(inner.mem_advise, |&(.., advice)| advice)
+    }
+
+    /// Returns the file offset up to which memory has been handed out.
+    pub fn offset(&self) -> usize {
+        self.inner.lock().unwrap().offset
+    }
+
+    /// Sets how many bytes to extend the file by when an allocation doesn't
+    /// fit, 0 turning growth off.
+    pub fn set_growth_increment(&self, bytes: usize) {
+        self.inner.lock().unwrap().growth_increment = bytes;
+    }
+
+    /// Extends the file by the growth increment, or by enough for `size`
+    /// bytes if that's more, and maps the new part. Returns whether it did.
+    fn grow(&self, inner: &mut Inner, size: usize) -> bool {
+        if inner.growth_increment == 0 || self.part {
+            return false;
+        }
+        // The new region starts on the page holding the old end of the file,
+        // which may already be handed out, so allow for a page more.
+        let len = size
+            .checked_add(self.page_size)
+            .and_then(|needed| needed.checked_next_multiple_of(self.page_size))
+            .and_then(|needed| {
+                inner
+                    .total_size
+                    .checked_add(cmp::max(inner.growth_increment, needed))
+            });
+        let Some(len) = len else {
+            return false;
+        };
+        let Some(file) = &inner.file else {
+            return false;
+        };
+        if file.set_len(len as u64).is_err() {
+            return false;
+        }
+        if self.map_tail(inner, len).is_err() {
+            let _ = inner.file().set_len(inner.total_size as u64);
+            return false;
+        }
+        true
+    }
+
+    /// Takes back everything handed out, so the next allocation starts at
+    /// the beginning of the arena again. The pages are left as they are.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.offset = inner.start;
+        inner.holes.clear();
+        inner.high = inner.total_size;
+        inner.high_end = inner.total_size;
+        inner.high_holes.clear();
+    }
+
+    /// Returns how many bytes below `offset` were given back and not handed
+    /// out again.
+    pub fn hole_bytes(&self) -> usize {
+        self.inner.lock().unwrap().hole_bytes()
+    }
+
+    /// Returns the largest `offset` has been since the arena was created.
+    pub fn high_water_mark(&self) -> usize {
+        self.inner.lock().unwrap().high_water_mark
+    }
+
+    /// Returns how many bytes of the file haven't been handed out yet or
+    /// were given back.
+    pub fn remaining(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let high_holes: usize = inner.high_holes.iter().map(|&(_, len)| len).sum();
+        let remaining = inner.total_size - inner.offset + inner.hole_bytes() + high_holes;
+        if inner.offset <= inner.high {
+            remaining - (inner.high_end - inner.high)
+        } else {
+            remaining
+        }
+    }
+
+    /// Hands out `size` bytes aligned to `align` from the top of the arena,
+    /// below whatever was handed out there before. Returns null if that
+    /// would run into memory handed out from the bottom.
+    pub fn alloc_high(&self, size: usize, align: usize) -> *mut u8 {
+        let mut inner = self.inner.lock().unwrap();
+        // Once empty the high end starts over from the current top, which
+        // may have moved since.
+        if inner.high == inner.high_end {
+            inner.high = inner.total_size;
+            inner.high_end = inner.total_size;
+        }
+        let Some(i) = inner
+            .regions
+            .iter()
+            .position(|r| r.start < inner.high_end && inner.high_end <= r.end())
+        else {
+            return ptr::null_mut();
+        };
+        let own_start = inner.own_start(i);
+        let Inner {
+            regions,
+            offset,
+            high,
+            high_holes,
+            ..
+        } = &mut *inner;
+        let region = &mut regions[i];
+        // Work with addresses, so alignments beyond a page hold too.
+        let base = region.mmap.as_mut_ptr() as usize;
+        let top = base + (*high - region.start);
+        let Some(addr) = top.checked_sub(size).map(|addr| addr & !(align - 1)) else {
+            return ptr::null_mut();
+        };
+        if addr < base || region.start + (addr - base) < cmp::max(*offset, own_start) {
+            return ptr::null_mut();
+        }
+        if addr + size < top {
+            add_hole(high_holes, addr + size, top - addr - size);
+        }
+        *high = region.start + (addr - base);
+        addr as *mut u8
+    }
+
+    /// Returns whether `ptr` is in memory handed out by `alloc_high`.
+    pub fn is_high(&self, ptr: *const u8) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.to_offset(ptr).is_some_and(|offset| inner.in_high(offset))
+    }
+
+    /// Gives back an allocation from `alloc_high`, returning false, and doing
+    /// nothing, if `ptr` isn't in the high end.
+    pub fn free_high(&self, ptr: *mut u8, size: usize) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.to_offset(ptr) {
+            Some(start) if inner.in_high(start) => {
+                if start == inner.high {
+                    inner.high += size;
+                    while let Some(&(addr, len)) = inner.high_holes.first() {
+                        if inner.to_offset(addr as *const u8) != Some(inner.high) {
+                            break;
+                        }
+                        inner.high += len;
+                        inner.high_holes.remove(0);
+                    }
+                } else {
+                    add_hole(&mut inner.high_holes, ptr as usize, size);
+                }
+                drop(inner);
+                let _ = self.release(ptr, size);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether the arena is heap memory rather than a file mapping.
+    pub fn is_in_memory(&self) -> bool {
+        self.in_memory
+    }
+
+    /// Returns whether the file is mapped shared, so writes reach it.
+    pub fn is_shared(&self) -> bool {
+        self.inner.lock().unwrap().shared
+    }
+
+    /// Returns the logical size of the arena.
+    pub fn total_size(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        // An arena from `split_off` starts partway into the file.
+        inner.total_size - inner.regions.first().map_or(0, |region| region.start)
+    }
+
+    /// Returns the free space on the filesystem holding the file, or `None`
+    /// for private mappings, whose pages never reach the file, or if it
+    /// can't be determined.
+    pub fn disk_free(&self) -> Option<usize> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.shared {
+            return None;
+        }
+        let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+        if unsafe { libc::fstatvfs(inner.file().as_raw_fd(), &mut stat) } != 0 {
+            return None;
+        }
+        let bytes = (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64);
+        Some(usize::try_from(bytes).unwrap_or(usize::MAX))
+    }
+
+    /// Returns how many bytes can still be handed out, which with
+    /// `account_disk_space` is also limited by free space on disk.
+    pub fn obtainable(&self) -> usize {
+        let remaining = self.remaining();
+        match self.disk_free() {
+            Some(free) if self.account_disk_space => cmp::min(remaining, free),
+            _ => remaining,
+        }
+    }
+
+    /// Returns the largest contiguous run `obtainable` counts, which is what
+    /// the biggest single allocation from the arena could get.
+    pub fn largest_obtainable(&self) -> usize {
+        let largest = {
+            let inner = self.inner.lock().unwrap();
+            let tail = if inner.offset <= inner.high {
+                cmp::max(inner.high - inner.offset, inner.total_size - inner.high_end)
+            } else {
+                inner.total_size - inner.offset
+            };
+            inner
+                .holes
+                .iter()
+                .chain(&inner.high_holes)
+                .map(|&(_, len)| len)
+                .fold(tail, cmp::max)
+        };
+        match self.disk_free() {
+            Some(free) if self.account_disk_space => cmp::min(largest, free),
+            _ => largest,
+        }
+    }
+
+    /// Returns the file offset `ptr` is mapped at, if it's in the arena.
+    pub fn to_offset(&self, ptr: *const u8) -> Option<usize> {
+        self.inner.lock().unwrap().to_offset(ptr)
+    }
+
+    /// Returns the address file offset `offset` is mapped at, if any.
+    pub fn to_ptr(&self, offset: usize) -> Option<*mut u8> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .regions
+            .iter_mut()
+            .find(|r| r.start <= offset && offset < r.end())
+            .map(|r| unsafe { r.mmap.as_mut_ptr().add(offset - r.start) })
     }
 }
 
 unsafe impl SystemAllocator for System {
     fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
         let mut inner = self.inner.lock().unwrap();
-        if inner.offset + size > inner.total_size {
-            return (ptr::null_mut(), 0, 0);
+        if let Some(ptr) = inner.take_hole(size) {
+            return (ptr, size, 0);
+        }
+        if let Some(ptr) = inner.bump(size) {
+            return (ptr, size, 0);
+        }
+        if self.grow(&mut inner, size) {
+            if let Some(ptr) = inner.bump(size) {
+                return (ptr, size, 0);
+            }
         }
-        let ptr = unsafe { inner.mmap.as_mut_ptr().add(inner.offset) };
-        inner.offset += size;
-        (ptr, size, 0)
+        (ptr::null_mut(), 0, 0)
     }
 
     fn remap(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize, _can_move: bool) -> *mut u8 {
         ptr::null_mut()
     }
 
-    fn free_part(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize) -> bool {
-        false
+    fn free_part(&self, ptr: *mut u8, oldsize: usize, newsize: usize) -> bool {
+        // Growing isn't freeing, and a region can't run off the end of the
+        // address space; `free` checks that the tail is ours.
+        if newsize >= oldsize || (ptr as usize).checked_add(oldsize).is_none() {
+            return false;
+        }
+        self.free(ptr.wrapping_add(newsize), oldsize - newsize)
     }
 
-    fn free(&self, _ptr: *mut u8, _size: usize) -> bool {
-        false
+    fn free(&self, ptr: *mut u8, size: usize) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.holds(ptr, size) {
+            return false;
+        }
+        if !inner.unbump(ptr, size) {
+            add_hole(&mut inner.holes, ptr as usize, size);
+        }
+        // The space is ours again either way, so failing to drop the pages
+        // only costs memory.
+        let _ = self.release(ptr, size);
+        true
     }
 
     fn can_release_part(&self, _flags: u32) -> bool {
-        false
+        true
     }
 
     fn allocates_zeros(&self) -> bool {
-        true
+        // Memory that's been handed out before may come back with its old
+        // contents, whether from the file or because `MADV_FREE` didn't get
+        // around to dropping it.
+        false
     }
 
     fn page_size(&self) -> usize {
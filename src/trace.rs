@@ -0,0 +1,368 @@
+//! Operation tracing, enabled with the `trace` feature.
+//!
+//! When a [`TraceSink`] is installed with [`DiskDlmalloc::set_trace_sink`]
+//! every operation that changes the heap, from `malloc` and `free` through
+//! the `Allocator` impl to `reset_keep_mapping` and `shrink_arena`, is
+//! reported to it as a [`TraceRecord`]. Pointers are recorded as offsets into the backing file
+//! rather than addresses, so a trace captured in one run can be fed to
+//! [`DiskDlmalloc::replay`] on a fresh arena to reproduce a failing sequence
+//! exactly.
+
+use crate::sys::System;
+use crate::{DiskDlmalloc, Shared};
+use std::alloc::Layout;
+
+/// A single allocator operation along with its outcome.
+///
+/// Pointer arguments and results are arena offsets, `None` standing for a
+/// null pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TraceRecord {
+    /// A call to `malloc`.
+    Malloc {
+        /// Requested size.
+        size: usize,
+        /// Requested alignment.
+        align: usize,
+        /// Offset of the returned allocation.
+        result: Option<usize>,
+    },
+    /// A call to `malloc_near`.
+    MallocNear {
+        /// Requested size.
+        size: usize,
+        /// Requested alignment.
+        align: usize,
+        /// Offset of the hint.
+        hint: Option<usize>,
+        /// Offset of the returned allocation.
+        result: Option<usize>,
+    },
+    /// A call to `malloc_high`.
+    MallocHigh {
+        /// Requested size.
+        size: usize,
+        /// Requested alignment.
+        align: usize,
+        /// Offset of the returned allocation.
+        result: Option<usize>,
+    },
+    /// A call to `calloc`.
+    Calloc {
+        /// Requested size.
+        size: usize,
+        /// Requested alignment.
+        align: usize,
+        /// Offset of the returned allocation.
+        result: Option<usize>,
+    },
+    /// A call to `free`.
+    Free {
+        /// Offset of the freed allocation.
+        offset: Option<usize>,
+        /// Size the allocation was made with.
+        size: usize,
+        /// Alignment the allocation was made with.
+        align: usize,
+    },
+    /// A call to `realloc`.
+    Realloc {
+        /// Offset of the original allocation.
+        offset: Option<usize>,
+        /// Size the original allocation was made with.
+        old_size: usize,
+        /// Alignment the original allocation was made with.
+        old_align: usize,
+        /// Requested new size.
+        new_size: usize,
+        /// Offset of the returned allocation.
+        result: Option<usize>,
+    },
+    /// A `grow`, `grow_zeroed` or `shrink` through the `Allocator` impl,
+    /// which unlike `realloc` may change the alignment.
+    Resize {
+        /// Offset of the original allocation.
+        offset: Option<usize>,
+        /// Size the original allocation was made with.
+        old_size: usize,
+        /// Alignment the original allocation was made with.
+        old_align: usize,
+        /// Requested new size.
+        new_size: usize,
+        /// Requested new alignment.
+        new_align: usize,
+        /// Offset of the returned allocation.
+        result: Option<usize>,
+    },
+    /// A call to `realloc_shrink_strict`.
+    ReallocShrinkStrict {
+        /// Offset of the original allocation.
+        offset: Option<usize>,
+        /// Size the original allocation was made with.
+        size: usize,
+        /// Alignment the original allocation was made with.
+        align: usize,
+        /// Requested new size.
+        new_size: usize,
+        /// Offset of the returned allocation.
+        result: Option<usize>,
+    },
+    /// A call to `relocate`.
+    Relocate {
+        /// Offset of the original allocation.
+        offset: Option<usize>,
+        /// Size of the allocation.
+        size: usize,
+        /// Offset the allocation ended up at.
+        result: Option<usize>,
+    },
+    /// A call to `trim`.
+    Trim {
+        /// Requested padding.
+        pad: usize,
+        /// Whether any memory was released.
+        released: bool,
+    },
+    /// A call to `coalesce_free`.
+    CoalesceFree {
+        /// Bytes that had been set aside.
+        bytes: usize,
+    },
+    /// A call to `reset_keep_mapping`.
+    Reset,
+    /// A call to `shrink_arena`.
+    ShrinkArena {
+        /// Requested arena size.
+        new_total_size: usize,
+        /// Whether the arena was shrunk.
+        shrunk: bool,
+    },
+    /// A call to `set_growth_increment`.
+    SetGrowthIncrement {
+        /// The new increment.
+        bytes: usize,
+    },
+}
+
+/// Receives a [`TraceRecord`] for every traced operation.
+///
+/// Records are delivered in the order the operations completed.
+/// Implementations must not call back into the allocator.
+pub trait TraceSink: Send {
+    /// Called once per operation, after it completed.
+    fn record(&mut self, record: TraceRecord);
+}
+
+impl<F: FnMut(TraceRecord) + Send> TraceSink for F {
+    fn record(&mut self, record: TraceRecord) {
+        self(record)
+    }
+}
+
+/// Returned by [`DiskDlmalloc::replay`] when the arena didn't behave the way
+/// the trace says it should have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayMismatch {
+    /// Index of the first diverging record.
+    pub index: usize,
+    /// The record from the trace.
+    pub expected: TraceRecord,
+    /// What the operation did this time around.
+    pub actual: TraceRecord,
+}
+
+impl Shared {
+    pub(crate) fn record(&self, f: impl FnOnce(&System) -> TraceRecord) {
+        if let Some(sink) = &mut *self.trace.lock().unwrap() {
+            sink.record(f(&self.system));
+        }
+    }
+
+    pub(crate) fn record_resize(
+        &self,
+        ptr: *mut u8,
+        old_layout: Layout,
+        new_layout: Layout,
+        result: *mut u8,
+    ) {
+        self.record(|sys| TraceRecord::Resize {
+            offset: sys.to_offset(ptr),
+            old_size: old_layout.size(),
+            old_align: old_layout.align(),
+            new_size: new_layout.size(),
+            new_align: new_layout.align(),
+            result: sys.to_offset(result),
+        });
+    }
+}
+
+impl DiskDlmalloc {
+    /// Installs `sink` to receive a record of every subsequent operation,
+    /// replacing any previously installed sink. Passing `None` turns tracing
+    /// off.
+    pub fn set_trace_sink(&self, sink: Option<Box<dyn TraceSink>>) {
+        *self.0.trace.lock().unwrap() = sink;
+    }
+
+    /// Re-executes the operations of a recorded trace against this arena,
+    /// checking that each one produces the recorded result.
+    ///
+    /// For the offsets to line up this should be a fresh arena of the same
+    /// size as the one the trace was recorded on.
+    ///
+    /// # Safety
+    ///
+    /// The trace must be one produced by a `TraceSink`; frees and reallocs
+    /// are issued for the recorded offsets as-is.
+    // Mismatches are rare and reported once, so their size doesn't matter.
+    #[allow(clippy::result_large_err)]
+    pub unsafe fn replay(&self, records: &[TraceRecord]) -> Result<(), ReplayMismatch> {
+        for (index, &expected) in records.iter().enumerate() {
+            let actual = match expected {
+                TraceRecord::Malloc { size, align, .. } => TraceRecord::Malloc {
+                    size,
+                    align,
+                    result: self.to_offset(self.malloc(size, align)),
+                },
+                TraceRecord::MallocHigh { size, align, .. } => TraceRecord::MallocHigh {
+                    size,
+                    align,
+                    result: self.to_offset(self.malloc_high(size, align)),
+                },
+                TraceRecord::Calloc { size, align, .. } => TraceRecord::Calloc {
+                    size,
+                    align,
+                    result: self.to_offset(self.calloc(size, align)),
+                },
+                TraceRecord::Free {
+                    offset,
+                    size,
+                    align,
+                } => {
+                    if let Some(ptr) = offset.and_then(|o| self.to_ptr(o)) {
+                        self.free(ptr, size, align);
+                    }
+                    expected
+                }
+                TraceRecord::Realloc {
+                    offset,
+                    old_size,
+                    old_align,
+                    new_size,
+                    ..
+                } => {
+                    // A missing offset is a realloc of null, which is a malloc.
+                    let ptr = match offset {
+                        Some(o) => self.to_ptr(o),
+                        None => Some(core::ptr::null_mut()),
+                    };
+                    let res = match ptr {
+                        Some(ptr) => self.realloc(ptr, old_size, old_align, new_size),
+                        None => core::ptr::null_mut(),
+                    };
+                    TraceRecord::Realloc {
+                        offset,
+                        old_size,
+                        old_align,
+                        new_size,
+                        result: self.to_offset(res),
+                    }
+                }
+                TraceRecord::MallocNear {
+                    size, align, hint, ..
+                } => {
+                    let hint_ptr = hint.and_then(|o| self.to_ptr(o));
+                    let res =
+                        self.malloc_near(size, align, hint_ptr.unwrap_or(core::ptr::null_mut()));
+                    TraceRecord::MallocNear {
+                        size,
+                        align,
+                        hint,
+                        result: self.to_offset(res),
+                    }
+                }
+                TraceRecord::Resize {
+                    offset,
+                    old_size,
+                    old_align,
+                    new_size,
+                    new_align,
+                    ..
+                } => {
+                    let res = match offset.and_then(|o| self.to_ptr(o)) {
+                        Some(ptr) => self
+                            .0
+                            .realloc(ptr, old_size, old_align, new_size, new_align),
+                        None => core::ptr::null_mut(),
+                    };
+                    TraceRecord::Resize {
+                        offset,
+                        old_size,
+                        old_align,
+                        new_size,
+                        new_align,
+                        result: self.to_offset(res),
+                    }
+                }
+                TraceRecord::ReallocShrinkStrict {
+                    offset,
+                    size,
+                    align,
+                    new_size,
+                    ..
+                } => {
+                    let res = match offset.and_then(|o| self.to_ptr(o)) {
+                        Some(ptr) => self.realloc_shrink_strict(ptr, size, align, new_size),
+                        None => core::ptr::null_mut(),
+                    };
+                    TraceRecord::ReallocShrinkStrict {
+                        offset,
+                        size,
+                        align,
+                        new_size,
+                        result: self.to_offset(res),
+                    }
+                }
+                TraceRecord::Relocate { offset, size, .. } => {
+                    let res = match offset.and_then(|o| self.to_ptr(o)) {
+                        Some(ptr) => self.relocate(ptr, size),
+                        None => core::ptr::null_mut(),
+                    };
+                    TraceRecord::Relocate {
+                        offset,
+                        size,
+                        result: self.to_offset(res),
+                    }
+                }
+                TraceRecord::Trim { pad, .. } => TraceRecord::Trim {
+                    pad,
+                    released: self.trim(pad),
+                },
+                TraceRecord::CoalesceFree { .. } => TraceRecord::CoalesceFree {
+                    bytes: self.coalesce_free(),
+                },
+                TraceRecord::Reset => {
+                    self.reset_keep_mapping();
+                    expected
+                }
+                TraceRecord::ShrinkArena { new_total_size, .. } => TraceRecord::ShrinkArena {
+                    new_total_size,
+                    shrunk: self.shrink_arena(new_total_size).is_ok(),
+                },
+                TraceRecord::SetGrowthIncrement { bytes } => {
+                    self.set_growth_increment(bytes);
+                    expected
+                }
+            };
+            if actual != expected {
+                return Err(ReplayMismatch {
+                    index,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use crate::DiskDlmalloc;
+use std::cell::{Cell, RefCell};
+
+/// Allocates on behalf of a [`DiskDlmalloc::transaction`], remembering what
+/// it hands out so that it can all be freed if the transaction fails.
+pub struct TxnAllocator<'a> {
+    alloc: &'a DiskDlmalloc,
+    /// Every allocation made and not freed since, as `(ptr, size, align)`.
+    allocations: RefCell<Vec<(*mut u8, usize, usize)>>,
+    committed: Cell<bool>,
+}
+
+impl DiskDlmalloc {
+    /// Runs `f` with a [`TxnAllocator`] and, if `f` returns `Err`, frees
+    /// everything allocated through it before passing the error on, for
+    /// building structures that must be allocated in full or not at all.
+    ///
+    /// On `Ok` the allocations are the caller's like any other, to be freed
+    /// with [`free`](DiskDlmalloc::free) in due course. They're freed too if
+    /// `f` panics. Allocations made with the allocator directly rather than
+    /// through the `TxnAllocator` aren't tracked either way.
+    pub fn transaction<R, E>(&self, f: impl FnOnce(&TxnAllocator) -> Result<R, E>) -> Result<R, E> {
+        let txn = TxnAllocator {
+            alloc: self,
+            allocations: RefCell::new(Vec::new()),
+            committed: Cell::new(false),
+        };
+        let res = f(&txn);
+        txn.committed.set(res.is_ok());
+        res
+    }
+}
+
+impl TxnAllocator<'_> {
+    /// Allocates `size` bytes aligned to `align` as part of the transaction,
+    /// like [`DiskDlmalloc::malloc`], or returns a null pointer if the arena
+    /// is out of room.
+    pub fn malloc(&self, size: usize, align: usize) -> *mut u8 {
+        let ptr = unsafe { self.alloc.malloc(size, align) };
+        self.track(ptr, size, align)
+    }
+
+    /// Like `malloc`, but the memory is zeroed.
+    pub fn calloc(&self, size: usize, align: usize) -> *mut u8 {
+        let ptr = unsafe { self.alloc.calloc(size, align) };
+        self.track(ptr, size, align)
+    }
+
+    /// Frees an allocation made earlier in the transaction, which then has
+    /// nothing to roll back for it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from this `TxnAllocator`'s `malloc` or `calloc`
+    /// with the same `size` and `align`, and not have been freed already.
+    pub unsafe fn free(&self, ptr: *mut u8, size: usize, align: usize) {
+        let mut allocations = self.allocations.borrow_mut();
+        if let Some(i) = allocations.iter().rposition(|&(p, ..)| p == ptr) {
+            allocations.swap_remove(i);
+        }
+        drop(allocations);
+        self.alloc.free(ptr, size, align);
+    }
+
+    fn track(&self, ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
+        if !ptr.is_null() {
+            self.allocations.borrow_mut().push((ptr, size, align));
+        }
+        ptr
+    }
+}
+
+impl Drop for TxnAllocator<'_> {
+    fn drop(&mut self) {
+        if self.committed.get() {
+            return;
+        }
+        for &(ptr, size, align) in self.allocations.get_mut().iter().rev() {
+            unsafe { self.alloc.free(ptr, size, align) };
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Demand paging through `userfaultfd`, see
+//! [`Builder::page_fault_handler`](crate::Builder::page_fault_handler).
+//!
+//! The arena's mappings are registered for missing-page faults, and a
+//! thread per arena waits on the `userfaultfd` for them. For each fault it
+//! has the caller's handler fill a page-sized buffer and installs that with
+//! `UFFDIO_COPY`, which also wakes the faulting thread. libc has no
+//! bindings for the `userfaultfd` ioctls, so the few we need are spelled
+//! out here.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Fills the page at a file offset, see `Builder::page_fault_handler`.
+pub type PageHandler = dyn Fn(usize, &mut [u8]) + Send + Sync;
+
+const UFFD_API: u64 = 0xaa;
+const UFFD_USER_MODE_ONLY: libc::c_int = 1;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFDIO_API: libc::c_ulong = 0xc018_aa3f;
+const UFFDIO_REGISTER: libc::c_ulong = 0xc020_aa00;
+const UFFDIO_WAKE: libc::c_ulong = 0x8010_aa02;
+const UFFDIO_COPY: libc::c_ulong = 0xc028_aa03;
+
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+/// `struct uffd_msg`, cut down to the page fault case.
+#[repr(C)]
+struct UffdMsg {
+    event: u8,
+    reserved: [u8; 7],
+    flags: u64,
+    address: u64,
+    rest: [u8; 8],
+}
+
+/// Address ranges being paged in, as `(addr, len, file offset)`.
+type Ranges = Arc<Mutex<Vec<(usize, usize, usize)>>>;
+
+/// The `userfaultfd` of one arena and the thread serving it, stopped when
+/// dropped.
+pub struct Pager {
+    uffd: Arc<OwnedFd>,
+    /// Written to when dropped, to stop the thread.
+    stop: OwnedFd,
+    ranges: Ranges,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn cvt(ret: libc::c_long) -> io::Result<libc::c_long> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+impl Pager {
+    /// Opens a `userfaultfd` and starts the thread that calls `handler` for
+    /// faults in the ranges added later.
+    pub fn new(handler: Arc<PageHandler>, page_size: usize) -> io::Result<Pager> {
+        let flags = libc::O_CLOEXEC | libc::O_NONBLOCK;
+        // Unprivileged processes may only handle faults from user space,
+        // which leaves the kernel's own accesses, say a `write` from the
+        // arena, failing with `EFAULT` on missing pages.
+        let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+        let fd = match cvt(fd) {
+            Ok(fd) => fd,
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                cvt(unsafe { libc::syscall(libc::SYS_userfaultfd, flags | UFFD_USER_MODE_ONLY) })?
+            }
+            Err(err) => return Err(err),
+        };
+        let uffd = Arc::new(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) });
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features: 0,
+            ioctls: 0,
+        };
+        cvt(unsafe { libc::ioctl(uffd.as_raw_fd(), UFFDIO_API, &mut api) }.into())?;
+        let stop = cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) }.into())?;
+        let stop = unsafe { OwnedFd::from_raw_fd(stop as libc::c_int) };
+        let ranges = Ranges::default();
+        let thread = {
+            let (uffd, ranges) = (uffd.clone(), ranges.clone());
+            let stop = stop.as_raw_fd();
+            thread::Builder::new()
+                .name("disk-dlmalloc-pager".into())
+                .spawn(move || serve(&uffd, stop, &ranges, &*handler, page_size))?
+        };
+        Ok(Pager {
+            uffd,
+            stop,
+            ranges,
+            thread: Some(thread),
+        })
+    }
+
+    /// Pages in `[ptr, ptr + len)`, which maps the file from `offset` on,
+    /// through the handler. Fails with `EINVAL` unless the mapping is of a
+    /// file the kernel supports this for.
+    pub fn add(&mut self, ptr: *const u8, len: usize, offset: usize) -> io::Result<()> {
+        let mut register = UffdioRegister {
+            range: UffdioRange {
+                start: ptr as u64,
+                len: len as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        cvt(unsafe { libc::ioctl(self.uffd.as_raw_fd(), UFFDIO_REGISTER, &mut register) }.into())?;
+        let mut ranges = self.ranges.lock().unwrap();
+        ranges.retain(|&(addr, _, _)| addr != ptr as usize);
+        ranges.push((ptr as usize, len, offset));
+        Ok(())
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        let one = 1u64;
+        unsafe { libc::write(self.stop.as_raw_fd(), (&one as *const u64).cast(), 8) };
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Serves faults until `stop` becomes readable.
+fn serve(
+    uffd: &OwnedFd,
+    stop: libc::c_int,
+    ranges: &Mutex<Vec<(usize, usize, usize)>>,
+    handler: &PageHandler,
+    page_size: usize,
+) {
+    let mut page = vec![0u8; page_size];
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: uffd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+        if fds[1].revents != 0 {
+            return;
+        }
+        let mut msg = mem::MaybeUninit::<UffdMsg>::uninit();
+        let n = unsafe {
+            libc::read(
+                uffd.as_raw_fd(),
+                msg.as_mut_ptr().cast(),
+                mem::size_of::<UffdMsg>(),
+            )
+        };
+        if n != mem::size_of::<UffdMsg>() as isize {
+            continue;
+        }
+        let msg = unsafe { msg.assume_init() };
+        if msg.event != UFFD_EVENT_PAGEFAULT {
+            continue;
+        }
+        let addr = msg.address as usize & !(page_size - 1);
+        let offset = ranges
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|&&(start, len, _)| start <= addr && addr < start + len)
+            .map(|&(start, _, offset)| offset + (addr - start));
+        page.fill(0);
+        if let Some(offset) = offset {
+            // A handler that panics would leave the faulting thread hanging
+            // forever, so there's nothing better to do than abort.
+            if panic::catch_unwind(AssertUnwindSafe(|| handler(offset, &mut page))).is_err() {
+                eprintln!("disk-dlmalloc: page fault handler panicked");
+                std::process::abort();
+            }
+        }
+        let mut copy = UffdioCopy {
+            dst: addr as u64,
+            src: page.as_ptr() as u64,
+            len: page_size as u64,
+            mode: 0,
+            copy: 0,
+        };
+        // Fails with `EEXIST` if the page was filled in the meantime, in
+        // which case the faulting thread only needs waking.
+        if unsafe { libc::ioctl(uffd.as_raw_fd(), UFFDIO_COPY, &mut copy) } < 0 {
+            let mut range = UffdioRange {
+                start: addr as u64,
+                len: page_size as u64,
+            };
+            unsafe { libc::ioctl(uffd.as_raw_fd(), UFFDIO_WAKE, &mut range) };
+        }
+    }
+}
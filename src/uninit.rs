@@ -0,0 +1,48 @@
+use crate::DiskDlmalloc;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+
+/// An uninitialized array allocated from the arena, freed when dropped.
+///
+/// Obtained from [`DiskDlmalloc::alloc_uninit`]. Write every element
+/// through the `MaybeUninit` slice it derefs to, then call
+/// [`assume_init`](ArenaUninit::assume_init) to get at the values.
+pub struct ArenaUninit<T>(Box<[MaybeUninit<T>], DiskDlmalloc>);
+
+impl DiskDlmalloc {
+    /// Allocates room for `count` values of `T` without initializing it, or
+    /// returns `None` if the arena can't satisfy the request.
+    ///
+    /// Unlike `calloc` nothing is zeroed, so this is the cheaper choice for
+    /// buffers that are about to be overwritten in full.
+    pub fn alloc_uninit<T>(&self, count: usize) -> Option<ArenaUninit<T>> {
+        Box::try_new_uninit_slice_in(count, self.clone())
+            .ok()
+            .map(ArenaUninit)
+    }
+}
+
+impl<T> ArenaUninit<T> {
+    /// Converts to an initialized slice, still owned by the arena.
+    ///
+    /// # Safety
+    ///
+    /// Every element must have been initialized.
+    pub unsafe fn assume_init(self) -> Box<[T], DiskDlmalloc> {
+        self.0.assume_init()
+    }
+}
+
+impl<T> Deref for ArenaUninit<T> {
+    type Target = [MaybeUninit<T>];
+
+    fn deref(&self) -> &[MaybeUninit<T>] {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for ArenaUninit<T> {
+    fn deref_mut(&mut self) -> &mut [MaybeUninit<T>] {
+        &mut self.0
+    }
+}
@@ -0,0 +1,79 @@
+use crate::{DiskDlmalloc, Shared};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+type Callback = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+struct Watermark {
+    fraction: f64,
+    above: bool,
+    callback: Callback,
+}
+
+/// The watermarks registered with [`DiskDlmalloc::set_watermark`].
+#[derive(Default)]
+pub(crate) struct Watermarks {
+    // Lets the allocation path skip locking `list` (and `System`) when no
+    // watermark was ever set.
+    any: AtomicBool,
+    list: Mutex<Vec<Watermark>>,
+}
+
+impl DiskDlmalloc {
+    /// Calls `callback` with the bytes in use and the arena's total size the
+    /// first time usage reaches `fraction` of `total_size`, e.g. `0.8` to be
+    /// alerted when the arena is 80% full.
+    ///
+    /// "In use" is the part of the file handed out to the allocator so far,
+    /// the same figure [`offset`](DiskDlmalloc::offset) reports. The callback
+    /// fires once per crossing: it's rearmed only after usage drops back
+    /// below the watermark, which happens when memory at the end of the arena
+    /// is [`trim`](DiskDlmalloc::trim)med.
+    ///
+    /// The callback runs on the allocating thread after its allocation
+    /// completes, with no allocator locks held, so it may allocate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fraction` isn't in `0.0..=1.0`.
+    pub fn set_watermark(&self, fraction: f64, callback: Box<dyn Fn(usize, usize) + Send + Sync>) {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "watermark must be a fraction of the arena, got {}",
+            fraction
+        );
+        let watermarks = &self.0.watermarks;
+        watermarks.list.lock().unwrap().push(Watermark {
+            fraction,
+            above: false,
+            callback: callback.into(),
+        });
+        watermarks.any.store(true, Ordering::Release);
+        self.0.check_watermarks();
+    }
+}
+
+impl Shared {
+    /// Fires the callbacks of any watermarks the arena has just crossed.
+    pub(crate) fn check_watermarks(&self) {
+        if !self.watermarks.any.load(Ordering::Acquire) {
+            return;
+        }
+        let used = self.system.offset();
+        let total = self.system.total_size();
+        let crossed: Vec<Callback> = {
+            let mut list = self.watermarks.list.lock().unwrap();
+            list.iter_mut()
+                .filter_map(|mark| {
+                    let above = used as f64 >= mark.fraction * total as f64;
+                    let fire = above && !mark.above;
+                    mark.above = above;
+                    fire.then(|| mark.callback.clone())
+                })
+                .collect()
+        };
+        for callback in crossed {
+            callback(used, total);
+        }
+    }
+}
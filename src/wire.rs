@@ -0,0 +1,162 @@
+//! A compact binary encoding of [`Stats`] for shipping to a monitoring
+//! system, see [`DiskDlmalloc::stats_bytes`].
+//!
+//! The format is a 4-byte magic, `b"DDST"`, and a version byte, followed by
+//! the fields in declaration order as little-endian integers: every `usize`
+//! as a `u64`, `disk_free` with `u64::MAX` standing for `None`, the advice as
+//! the platform's `i32` `MADV_*` value and `auto_trim` as one byte. Later
+//! versions will only append fields, so a decoder can read the prefix it
+//! knows about.
+
+use crate::{DiskDlmalloc, ReloadableConfig, Stats};
+use memmap2::Advice;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"DDST";
+const VERSION: u8 = 1;
+
+/// Why [`parse_stats`] couldn't decode its input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseStatsError {
+    /// The input doesn't start with the stats magic.
+    BadMagic,
+    /// The input was written by a newer, incompatible version.
+    UnsupportedVersion(u8),
+    /// The input ends before all fields were read.
+    Truncated,
+    /// A field holds a value this platform can't represent, such as a size
+    /// over `usize::MAX` or an unknown `madvise` advice.
+    InvalidValue,
+}
+
+impl fmt::Display for ParseStatsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseStatsError::BadMagic => f.write_str("not an encoded stats snapshot"),
+            ParseStatsError::UnsupportedVersion(v) => {
+                write!(f, "unsupported stats format version {}", v)
+            }
+            ParseStatsError::Truncated => f.write_str("stats snapshot is truncated"),
+            ParseStatsError::InvalidValue => f.write_str("stats snapshot holds an invalid value"),
+        }
+    }
+}
+
+impl std::error::Error for ParseStatsError {}
+
+impl DiskDlmalloc {
+    /// Returns a [`stats`](DiskDlmalloc::stats) snapshot encoded in the
+    /// format described in the [`wire`](crate::wire) module, for decoding
+    /// with [`parse_stats`].
+    pub fn stats_bytes(&self) -> Vec<u8> {
+        let stats = self.stats();
+        let mut out = Vec::with_capacity(48);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        let mut put = |v: usize| out.extend_from_slice(&(v as u64).to_le_bytes());
+        put(stats.logical_capacity);
+        put(stats.disk_free.unwrap_or(usize::MAX));
+        put(stats.offset);
+        put(stats.heap_free);
+        put(stats.available);
+        out.extend_from_slice(&(stats.config.advice as i32).to_le_bytes());
+        out.extend_from_slice(&(stats.config.trim_threshold as u64).to_le_bytes());
+        out.push(stats.config.auto_trim as u8);
+        out
+    }
+}
+
+/// Decodes a snapshot produced by [`DiskDlmalloc::stats_bytes`].
+pub fn parse_stats(bytes: &[u8]) -> Result<Stats, ParseStatsError> {
+    let mut r = Reader(bytes);
+    if r.take(4)? != MAGIC {
+        return Err(ParseStatsError::BadMagic);
+    }
+    match r.take(1)?[0] {
+        VERSION => {}
+        v => return Err(ParseStatsError::UnsupportedVersion(v)),
+    }
+    let logical_capacity = r.usize()?;
+    let disk_free = match r.u64()? {
+        u64::MAX => None,
+        v => Some(usize::try_from(v).map_err(|_| ParseStatsError::InvalidValue)?),
+    };
+    let offset = r.usize()?;
+    let heap_free = r.usize()?;
+    let available = r.usize()?;
+    let advice = i32::from_le_bytes(r.take(4)?.try_into().unwrap());
+    let config = ReloadableConfig {
+        advice: advice_from_raw(advice).ok_or(ParseStatsError::InvalidValue)?,
+        trim_threshold: r.usize()?,
+        auto_trim: match r.take(1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(ParseStatsError::InvalidValue),
+        },
+    };
+    Ok(Stats {
+        logical_capacity,
+        disk_free,
+        offset,
+        heap_free,
+        available,
+        config,
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ParseStatsError> {
+        if self.0.len() < n {
+            return Err(ParseStatsError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u64(&mut self) -> Result<u64, ParseStatsError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, ParseStatsError> {
+        usize::try_from(self.u64()?).map_err(|_| ParseStatsError::InvalidValue)
+    }
+}
+
+/// The inverse of `advice as i32`, which memmap2 doesn't provide.
+fn advice_from_raw(raw: i32) -> Option<Advice> {
+    let known = [
+        Advice::Normal,
+        Advice::Random,
+        Advice::Sequential,
+        Advice::WillNeed,
+        #[cfg(target_os = "linux")]
+        Advice::DontFork,
+        #[cfg(target_os = "linux")]
+        Advice::DoFork,
+        #[cfg(target_os = "linux")]
+        Advice::Mergeable,
+        #[cfg(target_os = "linux")]
+        Advice::Unmergeable,
+        #[cfg(target_os = "linux")]
+        Advice::HugePage,
+        #[cfg(target_os = "linux")]
+        Advice::NoHugePage,
+        #[cfg(target_os = "linux")]
+        Advice::DontDump,
+        #[cfg(target_os = "linux")]
+        Advice::DoDump,
+        #[cfg(target_os = "linux")]
+        Advice::HwPoison,
+        #[cfg(target_os = "linux")]
+        Advice::PopulateRead,
+        #[cfg(target_os = "linux")]
+        Advice::PopulateWrite,
+        #[cfg(target_vendor = "apple")]
+        Advice::ZeroWiredPages,
+    ];
+    known.into_iter().find(|&advice| advice as i32 == raw)
+}
@@ -0,0 +1,62 @@
+use crate::Shared;
+use core::mem;
+use std::sync::Mutex;
+
+/// The allocator's own writeback, set up with `Builder::writeback_limit`.
+pub(crate) struct Writeback {
+    limit: usize,
+    batches: Mutex<Batches>,
+}
+
+#[derive(Default)]
+struct Batches {
+    /// Ranges allocated since the last batch was started, as `(ptr, len)`,
+    /// and how many bytes they add up to.
+    pending: Vec<(usize, usize)>,
+    bytes: usize,
+    /// The batch last started, which the next one waits for.
+    started: Vec<(usize, usize)>,
+}
+
+impl Writeback {
+    pub(crate) fn new(limit: usize) -> Writeback {
+        Writeback {
+            limit,
+            batches: Mutex::default(),
+        }
+    }
+}
+
+impl Shared {
+    /// Counts `[ptr, ptr + len)` as dirtied, and once a batch's worth has
+    /// been, starts writing it back and waits for the batch before it.
+    pub(crate) fn dirtied(&self, ptr: *mut u8, len: usize) {
+        let Some(writeback) = &self.writeback else {
+            return;
+        };
+        if ptr.is_null() {
+            return;
+        }
+        let (start, wait) = {
+            let mut batches = writeback.batches.lock().unwrap();
+            batches.pending.push((ptr as usize, len));
+            batches.bytes += len;
+            if batches.bytes < writeback.limit {
+                return;
+            }
+            batches.bytes = 0;
+            let start = mem::take(&mut batches.pending);
+            let wait = mem::replace(&mut batches.started, start.clone());
+            (start, wait)
+        };
+        // Pointers from the overflow allocator aren't in the arena, and ones
+        // freed since have nothing left worth writing; either way this only
+        // costs a wasted call.
+        for (ptr, len) in start {
+            let _ = self.system.writeback(ptr as *mut u8, len, false);
+        }
+        for (ptr, len) in wait {
+            let _ = self.system.writeback(ptr as *mut u8, len, true);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn align_to_size_class_aligns_to_the_rounded_size() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 4 << 20)
+        .align_to_size_class(true)
+        .build()
+        .unwrap();
+    unsafe {
+        let mut ptrs = Vec::new();
+        for _ in 0..100 {
+            let ptr = a.malloc(64, 1);
+            assert_eq!(ptr as usize % 64, 0, "{:p}", ptr);
+            ptrs.push((ptr, 64));
+        }
+        for size in [24, 100, 1000, 3000] {
+            let ptr = a.malloc(size, 1);
+            assert_eq!(ptr as usize % size.next_power_of_two(), 0);
+            ptrs.push((ptr, size));
+        }
+        // Capped at a page.
+        let ptr = a.malloc(100 << 10, 1);
+        assert_eq!(ptr as usize % 4096, 0);
+        ptrs.push((ptr, 100 << 10));
+
+        // Growing keeps the alignment of the new size class.
+        let (ptr, size) = ptrs.pop().unwrap();
+        a.free(ptr, size, 1);
+        let (ptr, size) = ptrs.pop().unwrap();
+        let grown = a.realloc(ptr, size, 1, 2000);
+        assert_eq!(grown as usize % 2048, 0);
+        ptrs.push((grown, 2000));
+
+        for (ptr, size) in ptrs {
+            a.free(ptr, size, 1);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use disk_dlmalloc::{Backend, DiskDlmalloc};
+use tempfile::NamedTempFile;
+
+#[test]
+fn file_arena_reports_its_capabilities() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    let caps = a.capabilities();
+    assert_eq!(caps.backend, Backend::File);
+    assert_eq!(caps.can_punch_hole, cfg!(target_os = "linux"));
+    assert!(!caps.can_remap);
+    // Shared mappings can't use `MADV_FREE`.
+    assert!(!caps.can_madv_free);
+    assert!(caps.supports_mlock);
+}
+
+#[test]
+fn private_mapping_reports_its_capabilities() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .shared(false)
+        .build()
+        .unwrap();
+    let caps = a.capabilities();
+    assert!(!caps.can_punch_hole);
+    // Linux refuses `MADV_FREE` for file mappings, private or not.
+    assert_eq!(caps.can_madv_free, cfg!(target_os = "macos"));
+}
+
+#[test]
+fn in_memory_arena_reports_its_capabilities() {
+    let caps = DiskDlmalloc::new_in_memory(1 << 20).capabilities();
+    assert_eq!(caps.backend, Backend::Memory);
+    assert!(!caps.can_punch_hole);
+    assert!(!caps.can_madv_free);
+    assert!(!caps.supports_mlock);
+}
@@ -0,0 +1,122 @@
+use disk_dlmalloc::{DiskDlmalloc, TryAllocError};
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+#[tokio::test]
+async fn until_available_resolves_after_free() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 4 << 20, None);
+    let ptr = unsafe { a.malloc(3 << 20, 8) } as usize;
+    assert_ne!(ptr, 0);
+    assert!(a.available() < 2 << 20);
+
+    let watcher = a.capacity_watcher();
+    let waiter = tokio::spawn(async move { watcher.until_available(2 << 20).await });
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!waiter.is_finished());
+
+    unsafe { a.free(ptr as *mut u8, 3 << 20, 8) };
+    tokio::time::timeout(Duration::from_secs(5), waiter)
+        .await
+        .expect("waiter wasn't woken")
+        .unwrap();
+    assert!(a.available() >= 2 << 20);
+}
+
+#[tokio::test]
+async fn waiters_are_woken_while_other_threads_churn() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 4 << 20, None);
+    let done = Arc::new(AtomicBool::new(false));
+    let churn: Vec<_> = (0..4u8)
+        .map(|id| {
+            let (a, done) = (a.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    unsafe {
+                        let ptr = a.malloc(24, 8);
+                        ptr.write_bytes(id, 24);
+                        thread::yield_now();
+                        assert!(slice::from_raw_parts(ptr, 24).iter().all(|&b| b == id));
+                        a.free(ptr, 24, 8);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for i in 0..50 {
+        let ptr = unsafe { a.malloc(1 << 20, 8) } as usize;
+        assert_ne!(ptr, 0);
+        // Only the free can make this much available.
+        let wanted = a.available() + (512 << 10);
+        // Free from another thread, racing the waiter registering itself.
+        let freer = {
+            let a = a.clone();
+            thread::spawn(move || {
+                for _ in 0..i * 100 {
+                    std::hint::spin_loop();
+                }
+                unsafe { a.free(ptr as *mut u8, 1 << 20, 8) };
+            })
+        };
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            a.capacity_watcher().until_available(wanted),
+        )
+        .await
+        .expect("waiter wasn't woken");
+        freer.join().unwrap();
+    }
+
+    done.store(true, Ordering::Relaxed);
+    for thread in churn {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn failed_malloc_tells_fragmentation_from_exhaustion() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    let mut live = Vec::new();
+    unsafe {
+        loop {
+            let ptr = a.malloc(32 << 10, 8);
+            if ptr.is_null() {
+                break;
+            }
+            live.push(ptr);
+        }
+        for (i, &ptr) in live.iter().enumerate() {
+            if i % 2 == 0 {
+                a.free(ptr, 32 << 10, 8);
+            }
+        }
+
+        let requested = 256 << 10;
+        let Err(TryAllocError::OutOfMemory {
+            largest_free_block,
+            total_free,
+        }) = a.try_malloc(requested, 8)
+        else {
+            panic!("a fragmented arena served a large request");
+        };
+        assert!(total_free >= requested);
+        assert!(largest_free_block < requested);
+        assert!(largest_free_block >= 32 << 10);
+
+        for (i, &ptr) in live.iter().enumerate() {
+            if i % 2 == 1 {
+                a.free(ptr, 32 << 10, 8);
+            }
+        }
+        let ptr = a.try_malloc(requested, 8).unwrap();
+        a.free(ptr, requested, 8);
+    }
+}
@@ -0,0 +1,142 @@
+use disk_dlmalloc::{CoalescePolicy, DiskDlmalloc};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::slice;
+use tempfile::NamedTempFile;
+
+fn build(path: &std::path::Path, size: usize, policy: CoalescePolicy) -> DiskDlmalloc {
+    DiskDlmalloc::builder(path, size)
+        .coalesce_policy(policy)
+        .build()
+        .unwrap()
+}
+
+/// Mixed small and large churn, returning the offset left after freeing
+/// everything and trimming.
+fn run(policy: CoalescePolicy) -> usize {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = build(temp_file.path(), 64 << 20, policy);
+    let mut rng = SmallRng::seed_from_u64(2);
+    let mut live: Vec<(*mut u8, usize, u8)> = Vec::new();
+    unsafe {
+        for i in 0..20000 {
+            if !live.is_empty() && rng.gen_bool(0.45) {
+                let (ptr, size, tag) = live.swap_remove(rng.gen_range(0..live.len()));
+                assert!(slice::from_raw_parts(ptr, size).iter().all(|&b| b == tag));
+                a.free(ptr, size, 8);
+            } else {
+                let size = if rng.gen_bool(0.9) {
+                    rng.gen_range(1..240)
+                } else {
+                    rng.gen_range(240..16 << 10)
+                };
+                let ptr = a.malloc(size, 8);
+                assert!(!ptr.is_null(), "{:?} failed allocation {}", policy, i);
+                let tag = i as u8;
+                ptr.write_bytes(tag, size);
+                live.push((ptr, size, tag));
+            }
+        }
+        for (ptr, size, tag) in live {
+            assert!(slice::from_raw_parts(ptr, size).iter().all(|&b| b == tag));
+            a.free(ptr, size, 8);
+        }
+        a.trim(0);
+    }
+    a.offset()
+}
+
+#[test]
+fn both_policies_end_up_with_the_same_heap() {
+    assert_eq!(run(CoalescePolicy::Deferred), run(CoalescePolicy::Eager));
+}
+
+#[test]
+fn deferred_chunks_are_merged_when_space_runs_out() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = build(temp_file.path(), 1 << 20, CoalescePolicy::Deferred);
+    let mut small = Vec::new();
+    unsafe {
+        loop {
+            let ptr = a.malloc(100, 8);
+            if ptr.is_null() {
+                break;
+            }
+            small.push(ptr);
+        }
+        assert!(small.len() > 5000);
+        for &ptr in &small {
+            a.free(ptr, 100, 8);
+        }
+        // Set aside, the small chunks are still reused as they are.
+        assert_eq!(a.malloc(100, 8), *small.last().unwrap());
+        a.free(*small.last().unwrap(), 100, 8);
+        let large = a.malloc(512 << 10, 8);
+        assert!(!large.is_null());
+        a.free(large, 512 << 10, 8);
+    }
+}
+
+#[test]
+fn coalesce_free_merges_set_aside_chunks() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = build(temp_file.path(), 1 << 20, CoalescePolicy::Deferred);
+    let mut small = Vec::new();
+    unsafe {
+        for _ in 0..4000 {
+            let ptr = a.malloc(100, 8);
+            assert!(!ptr.is_null());
+            small.push(ptr);
+        }
+        for &ptr in &small {
+            a.free(ptr, 100, 8);
+        }
+        let offset = a.offset();
+        let merged = a.coalesce_free();
+        assert!(merged >= 4000 * 100, "{}", merged);
+        assert_eq!(a.coalesce_free(), 0);
+
+        // The merged chunks serve a large request without the heap growing.
+        let large = a.malloc(256 << 10, 8);
+        assert!(!large.is_null());
+        assert_eq!(a.offset(), offset);
+        a.free(large, 256 << 10, 8);
+    }
+}
+
+#[test]
+fn coalesce_free_has_nothing_to_do_when_eager() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = build(temp_file.path(), 1 << 20, CoalescePolicy::Eager);
+    unsafe {
+        let ptr = a.malloc(100, 8);
+        a.free(ptr, 100, 8);
+    }
+    assert_eq!(a.coalesce_free(), 0);
+}
+
+#[test]
+fn set_aside_chunks_count_as_free() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = build(temp_file.path(), 1 << 20, CoalescePolicy::Deferred);
+    let mut small = Vec::new();
+    unsafe {
+        for _ in 0..4000 {
+            small.push(a.malloc(100, 8));
+        }
+        let pin = a.malloc(100, 8);
+        let empty = a.health();
+        for ptr in small.drain(..).step_by(2) {
+            a.free(ptr, 100, 8);
+        }
+        let available = a.available();
+        let health = a.health();
+        assert!(health.free > empty.free + 2000 * 100);
+
+        assert!(a.coalesce_free() > 0);
+        assert_eq!(a.available(), available);
+        let merged = a.health();
+        assert_eq!((merged.used, merged.free), (health.used, health.free));
+        assert_eq!(merged.stats.heap_free, health.stats.heap_free);
+        a.free(pin, 100, 8);
+    }
+}
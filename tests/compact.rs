@@ -0,0 +1,206 @@
+use disk_dlmalloc::{CancellationToken, DiskDlmalloc, Error, Observer, HUGE_PAGE_SIZE};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+fn entry_size(seq: usize) -> usize {
+    200 + seq * 37 % 800
+}
+
+#[test]
+fn compaction_keeps_log_entries_in_order() {
+    let file = NamedTempFile::new().unwrap();
+    let alloc = DiskDlmalloc::new(file.path(), 8 << 20, None);
+    unsafe {
+        let mut log = Vec::new();
+        for seq in 0..300 {
+            let size = entry_size(seq);
+            let ptr = alloc.malloc(size, 8);
+            ptr.write_bytes(seq as u8, size);
+            log.push((seq, alloc.to_offset(ptr).unwrap()));
+        }
+        assert!(log.windows(2).all(|w| w[0].1 < w[1].1));
+        let end_before = log.last().unwrap().1;
+
+        // Delete a run from the middle and every third entry elsewhere.
+        log.retain(|&(seq, offset)| {
+            let keep = !(100..150).contains(&seq) && seq % 3 != 0;
+            if !keep {
+                alloc.free(alloc.to_ptr(offset).unwrap(), entry_size(seq), 8);
+            }
+            keep
+        });
+
+        let mut moved = BTreeMap::new();
+        alloc.compact_preserving_order(|old, new| {
+            moved.insert(old, new);
+        });
+        assert!(!moved.is_empty());
+        let translate = |old: usize| match moved.range(..=old).next_back() {
+            Some((&start, &new)) if old - start < 64 => new + (old - start),
+            _ => old,
+        };
+        for entry in &mut log {
+            entry.1 = translate(entry.1);
+        }
+
+        assert!(log.windows(2).all(|w| w[0].1 < w[1].1));
+        assert!(log.last().unwrap().1 < end_before);
+        for w in log.windows(2) {
+            // Each entry now follows the one before with no more than a
+            // chunk's overhead and redzones between them.
+            let (seq, offset) = w[0];
+            assert!(w[1].1 - offset <= entry_size(seq) + 128);
+        }
+        for &(seq, offset) in &log {
+            let ptr = alloc.to_ptr(offset).unwrap();
+            let bytes = std::slice::from_raw_parts(ptr, entry_size(seq));
+            assert!(bytes.iter().all(|&b| b == seq as u8), "entry {seq}");
+        }
+        for (seq, offset) in log {
+            alloc.free(alloc.to_ptr(offset).unwrap(), entry_size(seq), 8);
+        }
+    }
+}
+
+#[test]
+fn compaction_leaves_over_aligned_allocations_in_place() {
+    let file = NamedTempFile::new().unwrap();
+    let alloc = DiskDlmalloc::new(file.path(), 16 << 20, None);
+    let ring_size = 64 << 10;
+    unsafe {
+        let mut filler = Vec::new();
+        for seq in 0..20 {
+            filler.push((alloc.malloc(entry_size(seq), 8), entry_size(seq)));
+        }
+        let thp = alloc.alloc_thp(1);
+        assert!(!thp.is_null());
+        thp.write_bytes(0x5a, HUGE_PAGE_SIZE);
+        let ring = alloc.alloc_magic_ring(ring_size);
+        assert!(!ring.is_null());
+        for i in 0..ring_size {
+            *ring.add(i) = i as u8;
+        }
+        let thp_offset = alloc.to_offset(thp).unwrap();
+        for (ptr, size) in filler.drain(..) {
+            alloc.free(ptr, size, 8);
+        }
+
+        let mut moved = Vec::new();
+        alloc.compact_preserving_order(|old, new| moved.push((old, new)));
+        assert!(moved.iter().all(|&(old, _)| old != thp_offset));
+        assert_eq!(thp as usize % HUGE_PAGE_SIZE, 0);
+        assert!(std::slice::from_raw_parts(thp, HUGE_PAGE_SIZE)
+            .iter()
+            .all(|&b| b == 0x5a));
+
+        // Had the ring's storage moved, these would land where the ring's
+        // second mapping still looks.
+        for seq in 0..20 {
+            let ptr = alloc.malloc(entry_size(seq), 8);
+            ptr.write_bytes(0xff, entry_size(seq));
+            filler.push((ptr, entry_size(seq)));
+        }
+        for i in 0..ring_size {
+            assert_eq!(*ring.add(i), i as u8);
+            assert_eq!(*ring.add(ring_size + i), i as u8);
+        }
+
+        for (ptr, size) in filler {
+            alloc.free(ptr, size, 8);
+        }
+        alloc.free_magic_ring(ring, ring_size);
+        alloc.free_thp(thp, 1);
+    }
+}
+
+/// Cancels `token` the second time a lock is taken once armed.
+#[derive(Default)]
+struct CancelOnSecondLock {
+    armed: AtomicBool,
+    locks: AtomicUsize,
+    token: CancellationToken,
+}
+
+impl Observer for CancelOnSecondLock {
+    fn on_lock(&self) {
+        if self.armed.load(Ordering::SeqCst) && self.locks.fetch_add(1, Ordering::SeqCst) == 1 {
+            self.token.cancel();
+        }
+    }
+}
+
+#[test]
+fn cancelled_compaction_leaves_a_consistent_heap() {
+    let file = NamedTempFile::new().unwrap();
+    let observer = Arc::new(CancelOnSecondLock::default());
+    let alloc = DiskDlmalloc::builder(file.path(), 8 << 20)
+        .lock_striping(true)
+        .observer(observer.clone())
+        .build()
+        .unwrap();
+    unsafe {
+        // The large entries are in the first heap compacted, the small ones
+        // in the second, which is where the token gets cancelled.
+        let mut log = Vec::new();
+        for (seq, size) in [4096, 64].into_iter().cycle().take(60).enumerate() {
+            let ptr = alloc.malloc(size, 8);
+            ptr.write_bytes(seq as u8, size);
+            log.push((seq, size, alloc.to_offset(ptr).unwrap()));
+        }
+        log.retain(|&(seq, size, offset)| {
+            let keep = seq % 3 != 0;
+            if !keep {
+                alloc.free(alloc.to_ptr(offset).unwrap(), size, 8);
+            }
+            keep
+        });
+        let used = alloc.health().used;
+
+        observer.armed.store(true, Ordering::SeqCst);
+        let mut moved = BTreeMap::new();
+        let res = alloc.compact_preserving_order_cancellable(
+            |old, new| {
+                moved.insert(old, new);
+            },
+            &observer.token,
+        );
+        observer.armed.store(false, Ordering::SeqCst);
+        assert!(matches!(res, Err(Error::Cancelled)));
+        assert!(!moved.is_empty());
+        for entry in &mut log {
+            if let Some((&start, &new)) = moved.range(..=entry.2).next_back() {
+                if entry.2 - start < 64 {
+                    assert_eq!(entry.1, 4096, "a small entry moved");
+                    entry.2 = new + (entry.2 - start);
+                }
+            }
+        }
+        assert_eq!(alloc.health().used, used);
+        for &(seq, size, offset) in &log {
+            let bytes = std::slice::from_raw_parts(alloc.to_ptr(offset).unwrap(), size);
+            assert!(bytes.iter().all(|&b| b == seq as u8), "entry {seq}");
+        }
+
+        // Finishing the job from there leaves nothing more to move.
+        moved.clear();
+        alloc.compact_preserving_order(|old, new| {
+            moved.insert(old, new);
+        });
+        assert!(!moved.is_empty());
+        let fingerprint = alloc.layout_fingerprint();
+        alloc.compact_preserving_order(|_, _| panic!("nothing should move"));
+        assert_eq!(alloc.layout_fingerprint(), fingerprint);
+        for (seq, size, offset) in log {
+            let offset = match moved.range(..=offset).next_back() {
+                Some((&start, &new)) if offset - start < 64 => new + (offset - start),
+                _ => offset,
+            };
+            let ptr = alloc.to_ptr(offset).unwrap();
+            let bytes = std::slice::from_raw_parts(ptr, size);
+            assert!(bytes.iter().all(|&b| b == seq as u8), "entry {seq}");
+            alloc.free(ptr, size, 8);
+        }
+    }
+}
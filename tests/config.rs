@@ -0,0 +1,67 @@
+use disk_dlmalloc::{Advice, DiskDlmalloc, ReloadableConfig};
+use tempfile::NamedTempFile;
+
+#[test]
+fn reload_changes_advice_and_trimming() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 8 << 20)
+        .config(ReloadableConfig {
+            auto_trim: false,
+            ..ReloadableConfig::default()
+        })
+        .build()
+        .unwrap();
+    assert_eq!(a.stats().config.advice, Advice::Normal);
+
+    // With auto-trim off, freeing everything leaves the heap's footprint.
+    unsafe {
+        let ptr = a.malloc(4 << 20, 8);
+        a.free(ptr, 4 << 20, 8);
+    }
+    let untrimmed = a.offset();
+    assert!(untrimmed > 4 << 20);
+
+    let config = ReloadableConfig {
+        advice: Advice::Random,
+        trim_threshold: 64 << 10,
+        auto_trim: true,
+    };
+    a.reload(config).unwrap();
+    assert_eq!(a.stats().config, config);
+
+    // Now the same free goes back to the file once the top passes 64 KiB.
+    unsafe {
+        let ptr = a.malloc(4 << 20, 8);
+        a.free(ptr, 4 << 20, 8);
+    }
+    assert!(a.offset() < untrimmed - (1 << 20));
+}
+
+#[test]
+fn advice_for_reports_range_advice() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    unsafe {
+        let log = a.malloc(1 << 20, 4096);
+        let other = a.malloc(1 << 20, 4096);
+        a.advise_range(log, 1 << 20, Advice::Sequential).unwrap();
+        assert_eq!(a.advice_for(log), Advice::Sequential);
+        assert_eq!(a.advice_for(log.add((1 << 20) - 1)), Advice::Sequential);
+        assert_eq!(a.advice_for(other.add(4096)), Advice::Normal);
+
+        // Advising part of the range again splits it.
+        a.advise_range(log.add(64 << 10), 4096, Advice::Random).unwrap();
+        assert_eq!(a.advice_for(log.add(64 << 10)), Advice::Random);
+        assert_eq!(a.advice_for(log.add(128 << 10)), Advice::Sequential);
+        assert_eq!(a.advice_for(log), Advice::Sequential);
+
+        // Changing the advice for the whole arena replaces it everywhere.
+        a.reload(ReloadableConfig {
+            advice: Advice::WillNeed,
+            ..ReloadableConfig::default()
+        })
+        .unwrap();
+        assert_eq!(a.advice_for(log), Advice::WillNeed);
+        assert!(a.advise_range(std::ptr::null_mut(), 4096, Advice::Random).is_err());
+    }
+}
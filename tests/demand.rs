@@ -0,0 +1,61 @@
+#![cfg(target_os = "linux")]
+
+use disk_dlmalloc::DiskDlmalloc;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+
+/// The byte the handler fills the page at `offset` with.
+fn pattern(offset: usize) -> u8 {
+    (offset / 4096) as u8 ^ 0x5a
+}
+
+#[test]
+fn faulted_pages_are_filled_by_the_handler() {
+    // `userfaultfd` only works on shmem-backed files.
+    let temp_file = NamedTempFile::new_in("/dev/shm").unwrap();
+    let faulted = Arc::new(Mutex::new(Vec::new()));
+    let a = DiskDlmalloc::builder(temp_file.path(), 4 << 20)
+        .page_fault_handler({
+            let faulted = faulted.clone();
+            move |offset, page| {
+                faulted.lock().unwrap().push(offset);
+                page.fill(pattern(offset));
+            }
+        })
+        .build()
+        .unwrap();
+    let offset = 2 << 20;
+    assert!(fs::read(temp_file.path()).unwrap()[offset..offset + 4096]
+        .iter()
+        .all(|&b| b == 0));
+    assert!(faulted.lock().unwrap().is_empty());
+
+    let ptr = a.to_ptr(offset).unwrap();
+    let page = unsafe { std::slice::from_raw_parts(ptr, 4096) };
+    assert!(page.iter().all(|&b| b == pattern(offset)));
+    assert_eq!(*faulted.lock().unwrap(), [offset]);
+    // What the handler filled in is now part of the file.
+    assert_eq!(fs::read(temp_file.path()).unwrap()[offset], pattern(offset));
+
+    unsafe {
+        let block = a.malloc(64 << 10, 8);
+        assert!(!block.is_null());
+        block.write_bytes(7, 64 << 10);
+        assert_eq!(*block.add((64 << 10) - 1), 7);
+        a.free(block, 64 << 10, 8);
+    }
+    assert!(faulted.lock().unwrap().len() > 16);
+}
+
+#[test]
+fn demand_paging_needs_a_shmem_file() {
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap();
+    let res = DiskDlmalloc::builder(dir.path().join("arena"), 1 << 20)
+        .page_fault_handler(|_, _| {})
+        .build();
+    // Unless the target directory happens to be on tmpfs.
+    if let Err(err) = res {
+        assert!(err.to_string().contains("demand paging"), "{err}");
+    }
+}
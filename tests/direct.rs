@@ -0,0 +1,223 @@
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn huge_allocation_is_given_back_whole() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 256 << 20, None);
+    unsafe {
+        let before = a.malloc(64, 8);
+        let available = a.available();
+        let huge = a.malloc(100 << 20, 8);
+        assert!(!huge.is_null());
+        // Something allocated after it keeps it from being at the end of the
+        // arena, where it could simply be trimmed.
+        let after = a.malloc(64 << 10, 8);
+        assert!(a.available() < available - (100 << 20));
+        let start = a.to_offset(huge).unwrap();
+        let (found, usable) = a.find_allocation(start + (50 << 20)).unwrap();
+        assert!(found <= start && start + (100 << 20) <= found + usable);
+
+        a.free(huge, 100 << 20, 8);
+        assert!(a.available() >= available - (1 << 20));
+
+        // The region is reused.
+        let again = a.malloc(100 << 20, 8);
+        assert_eq!(again, huge);
+        a.free(again, 100 << 20, 8);
+        a.free(after, 64 << 10, 8);
+        a.free(before, 64, 8);
+    }
+}
+
+#[test]
+fn reused_regions_are_zeroed_by_calloc() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 16 << 20)
+        .mmap_threshold(1 << 20)
+        .build()
+        .unwrap();
+    unsafe {
+        let first = a.malloc(4 << 20, 8);
+        let pin = a.malloc(64, 8);
+        first.write_bytes(0xff, 4 << 20);
+        a.free(first, 4 << 20, 8);
+        let zeroed = a.calloc(4 << 20, 8);
+        assert_eq!(zeroed, first);
+        assert!((0..4 << 20).step_by(4093).all(|i| *zeroed.add(i) == 0));
+        a.free(zeroed, 4 << 20, 8);
+        a.free(pin, 64, 8);
+    }
+}
+
+/// Returns how much of the mapping holding `ptr` is in memory, from
+/// `/proc/self/smaps`, in KiB.
+fn mapping_rss_kib(ptr: *const u8) -> usize {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+    let mut inside = false;
+    for line in smaps.lines() {
+        let range = line.split(' ').next().unwrap();
+        if let Some((lo, hi)) = range.split_once('-') {
+            if let (Ok(lo), Ok(hi)) = (usize::from_str_radix(lo, 16), usize::from_str_radix(hi, 16))
+            {
+                inside = lo <= ptr as usize && (ptr as usize) < hi;
+                continue;
+            }
+        }
+        if inside {
+            if let Some(rss) = line.strip_prefix("Rss:") {
+                return rss.trim().trim_end_matches(" kB").parse().unwrap();
+            }
+        }
+    }
+    panic!("no mapping holds {:?}", ptr);
+}
+
+#[test]
+fn huge_calloc_leaves_its_pages_untouched() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 512 << 20, None);
+    unsafe {
+        let ptr = a.calloc(256 << 20, 8);
+        assert!(!ptr.is_null());
+        // Zeroing 256 MiB by hand would have brought all of it in.
+        assert!(
+            mapping_rss_kib(ptr) < 16 << 10,
+            "{} KiB",
+            mapping_rss_kib(ptr)
+        );
+        assert!((0..256 << 20).step_by(1 << 20).all(|i| *ptr.add(i) == 0));
+        assert_eq!(*ptr.add((256 << 20) - 1), 0);
+        a.free(ptr, 256 << 20, 8);
+    }
+}
+
+#[test]
+fn strict_shrink_gives_back_what_realloc_keeps() {
+    let shrink = |strict: bool| {
+        let temp_file = NamedTempFile::new().unwrap();
+        let a = DiskDlmalloc::builder(temp_file.path(), 16 << 20)
+            .mmap_threshold(256 << 10)
+            .build()
+            .unwrap();
+        unsafe {
+            let ptr = a.malloc(384 << 10, 8);
+            ptr.write_bytes(7, 384 << 10);
+            let pin = a.malloc(64, 8);
+            let available = a.available();
+            let res = if strict {
+                a.realloc_shrink_strict(ptr, 384 << 10, 8, 300 << 10)
+            } else {
+                a.realloc(ptr, 384 << 10, 8, 300 << 10)
+            };
+            assert!(!res.is_null());
+            assert!((0..300 << 10).step_by(4093).all(|i| *res.add(i) == 7));
+            let gained = a.available() - available;
+            a.free(res, 300 << 10, 8);
+            a.free(pin, 64, 8);
+            gained
+        }
+    };
+    assert_eq!(shrink(false), 0);
+    assert!(shrink(true) >= 80 << 10);
+}
+
+#[test]
+fn strict_shrink_of_a_large_allocation_frees_nearly_all_of_it() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 16 << 20)
+        .mmap_threshold(256 << 10)
+        .build()
+        .unwrap();
+    unsafe {
+        let ptr = a.malloc(1 << 20, 8);
+        let pin = a.malloc(64, 8);
+        let available = a.available();
+        let res = a.realloc_shrink_strict(ptr, 1 << 20, 8, 4 << 10);
+        assert!(!res.is_null());
+        assert!(a.available() - available >= (1 << 20) - (16 << 10));
+        a.free(res, 4 << 10, 8);
+        a.free(pin, 64, 8);
+    }
+}
+
+/// Grows a buffer 64 bytes at a time to 64 KiB with a small allocation
+/// placed right after it at every step, so it can't simply extend into free
+/// space, and returns how often it moved.
+fn count_moves(a: &DiskDlmalloc, amortized: bool) -> usize {
+    let mut blockers = Vec::new();
+    let (mut ptr, mut cap) = unsafe { a.malloc_with_usable(64, 8) };
+    let mut moves = 0;
+    unsafe {
+        for len in (128..=64 << 10).step_by(64) {
+            // Like a `Vec`, only reallocate once out of room.
+            let (new, new_cap) = if len <= cap {
+                (ptr, cap)
+            } else if amortized {
+                a.realloc_amortized(ptr, cap, 8, len)
+            } else {
+                (a.realloc(ptr, cap, 8, len), len)
+            };
+            assert!(!new.is_null());
+            assert!(new_cap >= len);
+            *new.add(len - 1) = len as u8;
+            if new != ptr {
+                moves += 1;
+            }
+            (ptr, cap) = (new, new_cap);
+            blockers.push(a.malloc_near(16, 8, new.add(new_cap)));
+        }
+        a.free(ptr, cap, 8);
+        for blocker in blockers {
+            a.free(blocker, 16, 8);
+        }
+    }
+    moves
+}
+
+#[test]
+fn realloc_amortized_moves_far_less_than_exact_growth() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 256 << 20, None);
+    let exact = count_moves(&a, false);
+    let amortized = count_moves(&a, true);
+    assert!(exact > 500, "{} moves", exact);
+    assert!(amortized < 20, "{} moves", amortized);
+}
+
+#[test]
+fn realloc_amortized_honours_the_growth_factor() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 16 << 20)
+        .realloc_growth_factor(4.0)
+        .build()
+        .unwrap();
+    unsafe {
+        let (ptr, cap) = a.malloc_with_usable(1000, 8);
+        ptr.write_bytes(0xab, 1000);
+        let (ptr, cap) = a.realloc_amortized(ptr, cap, 8, cap + 1);
+        assert!(cap >= 4000);
+        assert!((0..1000).all(|i| *ptr.add(i) == 0xab));
+        // Anything up to the usable size is already there.
+        assert_eq!(a.realloc_amortized(ptr, cap, 8, cap), (ptr, cap));
+        let (ptr, small) = a.realloc_amortized(ptr, cap, 8, 100);
+        assert!(small < 1000);
+        a.free(ptr, small, 8);
+    }
+}
+
+#[test]
+fn realloc_amortized_to_zero_keeps_an_allocation() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    unsafe {
+        let (ptr, cap) = a.malloc_with_usable(1000, 8);
+        *ptr = 0xab;
+        let (ptr, cap) = a.realloc_amortized(ptr, cap, 8, 0);
+        assert!(!ptr.is_null());
+        assert!(cap >= 1);
+        assert_eq!(*ptr, 0xab);
+        assert_eq!(a.live_count(), 1);
+        a.free(ptr, cap, 8);
+    }
+}
@@ -0,0 +1,160 @@
+use disk_dlmalloc::{DiskDlmalloc, Error};
+use std::env;
+use std::fs;
+use std::process::{self, Command};
+use tempfile::NamedTempFile;
+
+const CHILD_PATH: &str = "DISK_DLMALLOC_CRASH_CHILD";
+
+#[test]
+fn fence_and_flush_survives_abort() {
+    // The child writes and flushes, then dies without any cleanup.
+    if let Ok(path) = env::var(CHILD_PATH) {
+        let a = DiskDlmalloc::new(path, 1 << 20, None);
+        unsafe {
+            let ptr = a.malloc(4096, 8);
+            ptr.write_bytes(0xc3, 4096);
+            a.fence_and_flush(ptr, 4096).unwrap();
+            println!("offset={}", a.to_offset(ptr).unwrap());
+        }
+        process::abort();
+    }
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let output = Command::new(env::current_exe().unwrap())
+        .args(["fence_and_flush_survives_abort", "--exact", "--nocapture"])
+        .env(CHILD_PATH, temp_file.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // libtest prints the test name on the same line.
+    let (_, offset) = stdout.rsplit_once("offset=").unwrap();
+    let offset: usize = offset.trim().parse().unwrap();
+
+    let contents = fs::read(temp_file.path()).unwrap();
+    assert!(contents[offset..][..4096].iter().all(|&b| b == 0xc3));
+}
+
+const FLUSH_CHILD_PATH: &str = "DISK_DLMALLOC_FLUSH_CHILD";
+
+#[test]
+fn flush_on_free_makes_the_record_durable() {
+    // The child writes a record and frees it, then dies without any cleanup.
+    if let Ok(path) = env::var(FLUSH_CHILD_PATH) {
+        let a = DiskDlmalloc::builder(&path, 1 << 20)
+            .flush_on_free(true)
+            .build()
+            .unwrap();
+        unsafe {
+            let ptr = a.malloc(8192, 8);
+            // Keeps the record away from the top, so freeing it can't trim.
+            let _blocker = a.malloc(64, 8);
+            ptr.write_bytes(0x5a, 8192);
+            a.free(ptr, 8192, 8);
+            println!("offset={}", a.to_offset(ptr).unwrap());
+        }
+        process::abort();
+    }
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let output = Command::new(env::current_exe().unwrap())
+        .args([
+            "flush_on_free_makes_the_record_durable",
+            "--exact",
+            "--nocapture",
+        ])
+        .env(FLUSH_CHILD_PATH, temp_file.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (_, offset) = stdout.rsplit_once("offset=").unwrap();
+    let offset: usize = offset.trim().parse().unwrap();
+
+    // The free list links overwrite the start of the freed record.
+    let contents = fs::read(temp_file.path()).unwrap();
+    assert!(contents[offset + 64..offset + 8192]
+        .iter()
+        .all(|&b| b == 0x5a));
+}
+
+const SIGBUS_CHILD_PATH: &str = "DISK_DLMALLOC_SIGBUS_CHILD";
+
+#[test]
+fn sigbus_names_the_arena_file() {
+    if let Ok(path) = env::var(SIGBUS_CHILD_PATH) {
+        let a = DiskDlmalloc::builder(&path, 1 << 20)
+            .catch_sigbus(true)
+            .build()
+            .unwrap();
+        unsafe {
+            let ptr = a.malloc(256 << 10, 4096);
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(0)
+                .unwrap();
+            ptr.write_volatile(1);
+        }
+        process::exit(0);
+    }
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let output = Command::new(env::current_exe().unwrap())
+        .args(["sigbus_names_the_arena_file", "--exact", "--nocapture"])
+        .env(SIGBUS_CHILD_PATH, temp_file.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("SIGBUS"), "{}", stderr);
+    assert!(stderr.contains(temp_file.path().to_str().unwrap()), "{}", stderr);
+}
+
+#[test]
+fn close_refuses_while_clones_are_alive() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    let offset = unsafe {
+        let ptr = a.malloc(4096, 8);
+        ptr.write_bytes(0x7e, 4096);
+        a.to_offset(ptr).unwrap()
+    };
+    let b = a.clone();
+    let c = a.clone();
+    match c.close() {
+        Err(Error::StillInUse { handles }) => assert_eq!(handles, 2),
+        other => panic!("expected StillInUse, got {:?}", other),
+    }
+    assert!(matches!(b.close(), Err(Error::StillInUse { handles: 1 })));
+    a.close().unwrap();
+
+    let contents = fs::read(temp_file.path()).unwrap();
+    assert!(contents[offset..][..4096].iter().all(|&b| b == 0x7e));
+}
+
+#[test]
+fn flush_range_reports_how_far_it_got() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    unsafe {
+        let ptr = a.malloc(1 << 20, 8);
+        ptr.write_bytes(0x5a, 1 << 20);
+        let outcome = a.flush_range(ptr, 1 << 20);
+        assert!(outcome.is_complete());
+        assert_eq!(outcome.durable_up_to, 1 << 20);
+        a.free(ptr, 1 << 20, 8);
+    }
+
+    // A range running 1 MiB past the end of the arena gets as far as the
+    // end of the arena.
+    let start = (5 << 20) + 100;
+    let ptr = a.to_ptr(start).unwrap();
+    let outcome = a.flush_range(ptr, 4 << 20);
+    assert!(!outcome.is_complete());
+    assert!(outcome.durable_up_to < 4 << 20);
+    assert_eq!(outcome.durable_up_to, (8 << 20) - start);
+    assert!(outcome.into_result().is_err());
+}
@@ -0,0 +1,25 @@
+#![feature(allocator_api)]
+
+use disk_dlmalloc::{DiskDlmalloc, DynAllocator};
+use std::alloc::Allocator;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn boxes_through_dyn_allocator() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+
+    let erased: Arc<dyn Allocator + Send + Sync> = Arc::new(a.clone());
+    let handle = DynAllocator::from(erased);
+    let boxed = Box::new_in(42u64, handle.clone());
+    assert_eq!(*boxed, 42);
+    let ptr = &*boxed as *const u64 as *mut u8;
+    assert!(a.to_offset(ptr).is_some());
+
+    // Collections over different allocators share a type.
+    let mut v: Vec<Box<u64, DynAllocator>, DynAllocator> = Vec::new_in(a.to_dyn());
+    v.push(boxed);
+    v.push(Box::new_in(7, handle));
+    assert_eq!(*v[1], 7);
+}
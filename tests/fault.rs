@@ -0,0 +1,43 @@
+#![cfg(feature = "fault-injection")]
+
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn fails_after_n_allocs() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .fail_after_n_allocs(Some(3))
+        .build()
+        .unwrap();
+    unsafe {
+        let ptrs: Vec<_> = (0..3).map(|_| a.malloc(64, 8)).collect();
+        assert!(ptrs.iter().all(|p| !p.is_null()));
+        assert!(a.malloc(64, 8).is_null());
+        assert!(a.calloc(64, 8).is_null());
+        for ptr in ptrs {
+            a.free(ptr, 64, 8);
+        }
+    }
+}
+
+#[test]
+fn fails_with_probability() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .fail_with_probability(Some(0.5))
+        .build()
+        .unwrap();
+    let mut failed = 0;
+    unsafe {
+        for _ in 0..1000 {
+            let ptr = a.malloc(64, 8);
+            if ptr.is_null() {
+                failed += 1;
+            } else {
+                a.free(ptr, 64, 8);
+            }
+        }
+    }
+    assert!((350..650).contains(&failed), "{} failures", failed);
+}
@@ -0,0 +1,35 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::slice;
+use tempfile::NamedTempFile;
+
+#[test]
+fn fresh_allocations_are_filled() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .fill_on_alloc(Some(0xAB))
+        .fill_on_free(Some(0xDD))
+        .build()
+        .unwrap();
+    unsafe {
+        let ptr = a.malloc(1000, 8);
+        assert!(slice::from_raw_parts(ptr, 1000).iter().all(|&b| b == 0xAB));
+
+        ptr.write_bytes(0, 1000);
+        let ptr = a.realloc(ptr, 1000, 8, 2000);
+        let bytes = slice::from_raw_parts(ptr, 2000);
+        assert!(bytes[..1000].iter().all(|&b| b == 0));
+        assert!(bytes[1000..].iter().all(|&b| b == 0xAB));
+
+        // Keep something after the block so it isn't merged into the top
+        // chunk, then check the tail past dlmalloc's free list links.
+        let guard = a.malloc(16, 8);
+        a.free(ptr, 2000, 8);
+        let bytes = slice::from_raw_parts(ptr, 2000);
+        assert!(bytes[64..].iter().all(|&b| b == 0xDD));
+        a.free(guard, 16, 8);
+
+        let ptr = a.calloc(1000, 8);
+        assert!(slice::from_raw_parts(ptr, 1000).iter().all(|&b| b == 0));
+        a.free(ptr, 1000, 8);
+    }
+}
@@ -0,0 +1,121 @@
+use disk_dlmalloc::{DiskDlmalloc, FitPolicy};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::slice;
+use tempfile::NamedTempFile;
+
+fn run(fit_policy: FitPolicy) {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 64 << 20)
+        .fit_policy(fit_policy)
+        .build()
+        .unwrap();
+    let mut rng = SmallRng::seed_from_u64(1);
+    let mut live: Vec<(*mut u8, usize, u8)> = Vec::new();
+    unsafe {
+        for i in 0..5000 {
+            if !live.is_empty() && rng.gen_bool(0.45) {
+                let (ptr, size, tag) = live.swap_remove(rng.gen_range(0..live.len()));
+                assert!(slice::from_raw_parts(ptr, size).iter().all(|&b| b == tag));
+                a.free(ptr, size, 8);
+            } else {
+                let size = rng.gen_range(1..32 << 10);
+                let ptr = a.malloc(size, 8);
+                assert!(!ptr.is_null(), "{:?} failed allocation {}", fit_policy, i);
+                let tag = i as u8;
+                ptr.write_bytes(tag, size);
+                live.push((ptr, size, tag));
+            }
+        }
+        for (ptr, size, tag) in live {
+            assert!(slice::from_raw_parts(ptr, size).iter().all(|&b| b == tag));
+            a.free(ptr, size, 8);
+        }
+    }
+}
+
+#[test]
+fn best_fit_sequence() {
+    run(FitPolicy::BestFit);
+}
+
+#[test]
+fn first_fit_sequence() {
+    run(FitPolicy::FirstFit);
+}
+
+#[test]
+fn raised_small_bin_threshold_frees_across_it() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 16 << 20)
+        .small_bin_threshold(400)
+        .build()
+        .unwrap();
+    unsafe {
+        // 300 byte requests, separated by live allocations so they can't be
+        // merged when freed, now go to a small bin past the usual last one.
+        let pairs: Vec<_> = (0..64)
+            .map(|_| (a.malloc(300, 8), a.malloc(16, 8)))
+            .collect();
+        for &(ptr, _) in &pairs {
+            a.free(ptr, 300, 8);
+        }
+        let bins = a.bin_stats();
+        let bin = bins.small.iter().position(|bin| bin.chunks == 64).unwrap();
+        assert!(bins.small[bin].min_size >= 256);
+        assert_eq!(bins.tree.iter().map(|bin| bin.chunks).sum::<usize>(), 0);
+        let again: Vec<_> = (0..64).map(|_| a.malloc(300, 8)).collect();
+        assert_eq!(a.bin_stats().small[bin].chunks, 0);
+        for (ptr, (_, pin)) in again.into_iter().zip(pairs) {
+            a.free(ptr, 300, 8);
+            a.free(pin, 16, 8);
+        }
+    }
+    assert_eq!(
+        a.bin_stats()
+            .small
+            .iter()
+            .map(|bin| bin.chunks)
+            .sum::<usize>(),
+        0
+    );
+
+    // Random sizes on either side of the threshold keep their contents and
+    // free cleanly.
+    let mut rng = SmallRng::seed_from_u64(2);
+    let mut live: Vec<(*mut u8, usize, u8)> = Vec::new();
+    unsafe {
+        for i in 0..5000 {
+            if !live.is_empty() && rng.gen_bool(0.45) {
+                let (ptr, size, tag) = live.swap_remove(rng.gen_range(0..live.len()));
+                assert!(slice::from_raw_parts(ptr, size).iter().all(|&b| b == tag));
+                a.free(ptr, size, 8);
+            } else {
+                let size = rng.gen_range(200..600);
+                let ptr = a.malloc(size, 8);
+                assert!(!ptr.is_null());
+                let tag = i as u8;
+                ptr.write_bytes(tag, size);
+                live.push((ptr, size, tag));
+            }
+        }
+        for (ptr, size, tag) in live {
+            assert!(slice::from_raw_parts(ptr, size).iter().all(|&b| b == tag));
+            a.free(ptr, size, 8);
+        }
+    }
+    assert_eq!(
+        a.bin_stats()
+            .small
+            .iter()
+            .map(|bin| bin.chunks)
+            .sum::<usize>(),
+        0
+    );
+}
+
+#[test]
+#[should_panic(expected = "small_bin_threshold")]
+fn small_bin_threshold_past_the_last_bin_panics() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let _ = DiskDlmalloc::builder(temp_file.path(), 1 << 20).small_bin_threshold(4096);
+}
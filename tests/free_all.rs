@@ -0,0 +1,43 @@
+use disk_dlmalloc::{DiskDlmalloc, Observer};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+#[derive(Default)]
+struct LockCounter(AtomicUsize);
+
+impl Observer for LockCounter {
+    fn on_lock(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn frees_a_thousand_under_one_lock() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let locks = Arc::new(LockCounter::default());
+    let a = DiskDlmalloc::builder(temp_file.path(), 16 << 20)
+        .observer(locks.clone())
+        .build()
+        .unwrap();
+    unsafe {
+        let keep = a.malloc(16, 8);
+        let before = a.available();
+
+        let ptrs: Vec<_> = (0..1000)
+            .map(|i| {
+                let size = 16 + i % 300;
+                (a.malloc(size, 8), size)
+            })
+            .collect();
+        assert!(ptrs.iter().all(|(ptr, _)| !ptr.is_null()));
+        assert!(a.available() < before);
+
+        locks.0.store(0, Ordering::SeqCst);
+        a.free_all(&ptrs);
+        assert_eq!(locks.0.load(Ordering::SeqCst), 1);
+        assert_eq!(a.available(), before);
+
+        a.free(keep, 16, 8);
+    }
+}
@@ -0,0 +1,66 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::fs;
+use tempfile::NamedTempFile;
+
+#[test]
+fn arena_grows_by_the_increment_set() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let file_len = || fs::metadata(temp_file.path()).unwrap().len() as usize;
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        assert!(a.malloc(2 << 20, 8).is_null());
+        assert_eq!(file_len(), 1 << 20);
+
+        a.set_growth_increment(4 << 20);
+        let first = a.malloc(2 << 20, 8);
+        assert!(!first.is_null());
+        first.write_bytes(1, 2 << 20);
+        assert_eq!(file_len(), 5 << 20);
+
+        a.set_growth_increment(16 << 20);
+        let second = a.malloc(4 << 20, 8);
+        assert!(!second.is_null());
+        second.write_bytes(2, 4 << 20);
+        assert_eq!(file_len(), 21 << 20);
+        assert_eq!(*first, 1);
+
+        a.free(second, 4 << 20, 8);
+        a.free(first, 2 << 20, 8);
+    }
+}
+
+#[test]
+#[should_panic(expected = "multiple of")]
+fn growth_increment_must_be_whole_pages() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    a.set_growth_increment(1000);
+}
+
+#[test]
+fn max_segments_stops_new_segments_but_not_extension() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .max_segments(2)
+        .build()
+        .unwrap();
+    a.set_growth_increment(1 << 20);
+    unsafe {
+        let first = a.malloc(512 << 10, 8);
+        assert!(!first.is_null());
+        let second = a.malloc(768 << 10, 8);
+        assert!(!second.is_null());
+        assert_eq!(a.segments().len(), 2);
+
+        // What's left at the end of the second segment is still used.
+        let small = a.malloc(64 << 10, 8);
+        assert!(!small.is_null());
+        // But memory for a third one isn't taken.
+        assert!(a.malloc(768 << 10, 8).is_null());
+        assert_eq!(a.segments().len(), 2);
+
+        a.free(small, 64 << 10, 8);
+        a.free(second, 768 << 10, 8);
+        a.free(first, 512 << 10, 8);
+    }
+}
@@ -0,0 +1,71 @@
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+
+const BLOCK: usize = 64 << 10;
+
+#[test]
+fn high_and_low_allocations_meet_in_the_middle() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 4 << 20, None);
+    let (mut low, mut high) = (Vec::new(), Vec::new());
+    unsafe {
+        // Alternate until neither end has room left.
+        loop {
+            let l = a.malloc(BLOCK, 8);
+            let h = a.malloc_high(BLOCK, 4096);
+            if !l.is_null() {
+                l.write_bytes(1, BLOCK);
+                low.push(l);
+            }
+            if !h.is_null() {
+                assert_eq!(h as usize % 4096, 0);
+                h.write_bytes(2, BLOCK);
+                high.push(h);
+            }
+            if l.is_null() && h.is_null() {
+                break;
+            }
+        }
+        assert!(low.len() > 8 && high.len() > 8);
+        // Each high allocation is below the one before it.
+        assert!(high.windows(2).all(|w| w[1] < w[0]));
+        let top_of_low = low.iter().map(|&p| p as usize + BLOCK).max().unwrap();
+        let bottom_of_high = *high.last().unwrap() as usize;
+        assert!(top_of_low <= bottom_of_high);
+        assert!(low.iter().all(|&p| *p == 1 && *p.add(BLOCK - 1) == 1));
+        assert!(high.iter().all(|&p| *p == 2 && *p.add(BLOCK - 1) == 2));
+        assert!((low.len() + high.len()) * BLOCK > 3 << 20);
+
+        // Freeing the lowest high allocation makes room for another.
+        let last = high.pop().unwrap();
+        a.free(last, BLOCK, 4096);
+        assert_eq!(a.malloc_high(BLOCK, 4096), last);
+        high.push(last);
+
+        for p in low {
+            a.free(p, BLOCK, 8);
+        }
+        for p in high {
+            a.free(p, BLOCK, 4096);
+        }
+    }
+}
+
+#[test]
+fn high_allocations_freed_out_of_order_are_reused() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        let first = a.malloc_high(BLOCK, 8);
+        let second = a.malloc_high(BLOCK, 8);
+        assert_eq!(second as usize + BLOCK, first as usize);
+        a.free(first, BLOCK, 8);
+        // `first` is above `second`, so its space stays taken for now.
+        let third = a.malloc_high(BLOCK, 8);
+        assert_eq!(third as usize + BLOCK, second as usize);
+        a.free(third, BLOCK, 8);
+        a.free(second, BLOCK, 8);
+        assert_eq!(a.malloc_high(BLOCK, 8), first);
+        a.free(first, BLOCK, 8);
+    }
+}
@@ -0,0 +1,317 @@
+use disk_dlmalloc::{parse_stats, Advice, DiskDlmalloc, ParseStatsError, ReloadableConfig};
+use tempfile::NamedTempFile;
+
+#[test]
+fn single_segment_spans_used_range() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10 << 20, None);
+    assert!(a.segments().is_empty());
+    unsafe {
+        let p1 = a.malloc(100, 8);
+        let p2 = a.malloc(300 << 10, 8);
+        let segments = a.segments();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].base_offset, 0);
+        assert_eq!(segments[0].len, a.offset());
+        assert!(!segments[0].is_extern());
+        a.free(p1, 100, 8);
+        a.free(p2, 300 << 10, 8);
+    }
+}
+
+#[test]
+fn find_allocation_reports_enclosing_bounds() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        let first = a.malloc(100, 8);
+        let second = a.malloc(1000, 8);
+        let freed = a.malloc(500, 8);
+        let _guard = a.malloc(16, 8);
+        a.free(freed, 500, 8);
+
+        for (ptr, size) in [(first, 100), (second, 1000)] {
+            let start = a.to_offset(ptr).unwrap();
+            for offset in [start, start + size / 2, start + size - 1] {
+                let (found, usable) = a.find_allocation(offset).unwrap();
+                if cfg!(feature = "redzones") {
+                    assert!(found < start && found + usable > start + size);
+                } else {
+                    assert_eq!(found, start);
+                    assert!(usable >= size && usable < size + 32);
+                }
+            }
+        }
+
+        assert_eq!(a.find_allocation(a.to_offset(freed).unwrap() + 8), None);
+        assert_eq!(a.find_allocation(a.offset() + 4096), None);
+        assert_eq!(a.find_allocation(1 << 30), None);
+    }
+}
+
+#[test]
+fn available_is_capped_by_disk_space() {
+    let dir = tempfile::tempdir().unwrap();
+    let probe = DiskDlmalloc::new(dir.path().join("probe"), 1 << 20, None);
+    let disk_free = probe.stats().disk_free.unwrap();
+
+    // A sparse file well beyond what the filesystem could hold.
+    let total_size = disk_free + (1 << 30);
+    let path = dir.path().join("arena");
+    let unchecked = DiskDlmalloc::new(&path, total_size, None);
+    assert_eq!(unchecked.available(), total_size);
+    drop(unchecked);
+
+    let a = DiskDlmalloc::builder(&path, total_size)
+        .account_disk_space(true)
+        .build()
+        .unwrap();
+    let stats = a.stats();
+    assert_eq!(stats.logical_capacity, total_size);
+    assert!(stats.available < total_size);
+    assert!(stats.available <= stats.disk_free.unwrap() + (1 << 20));
+    assert_eq!(a.available(), stats.available);
+}
+
+#[test]
+fn bin_stats_count_freed_chunks() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        // Interleave with allocations that stay live, so the freed chunks
+        // can't coalesce.
+        let ptrs: Vec<_> = (0..10).map(|_| a.malloc(64, 8)).collect();
+        for ptr in ptrs.iter().step_by(2) {
+            a.free(*ptr, 64, 8);
+        }
+        let stats = a.bin_stats();
+        let bins: Vec<_> = stats.small.iter().filter(|b| b.chunks > 0).collect();
+        assert_eq!(bins.len(), 1);
+        assert_eq!(bins[0].chunks, 5);
+        assert_eq!(bins[0].bytes, 5 * bins[0].min_size);
+        assert!(bins[0].min_size > 64);
+        assert!(stats.tree.iter().all(|b| b.chunks == 0));
+        for ptr in ptrs.iter().skip(1).step_by(2) {
+            a.free(*ptr, 64, 8);
+        }
+    }
+}
+
+#[test]
+fn stats_bytes_round_trip() {
+    let temp_file = NamedTempFile::new().unwrap();
+    // A private mapping, so `disk_free` can't change between snapshots.
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .shared(false)
+        .config(ReloadableConfig {
+            advice: Advice::Random,
+            trim_threshold: 12345,
+            auto_trim: false,
+        })
+        .build()
+        .unwrap();
+    let ptr = unsafe { a.malloc(1000, 8) };
+    let bytes = a.stats_bytes();
+    assert_eq!(parse_stats(&bytes).unwrap(), a.stats());
+
+    assert_eq!(
+        parse_stats(&bytes[..bytes.len() - 1]),
+        Err(ParseStatsError::Truncated)
+    );
+    let mut future = bytes.clone();
+    future[4] = 99;
+    assert_eq!(
+        parse_stats(&future),
+        Err(ParseStatsError::UnsupportedVersion(99))
+    );
+    unsafe { a.free(ptr, 1000, 8) };
+}
+
+#[test]
+fn high_water_mark_survives_trimming() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    unsafe {
+        let kept = a.malloc(1 << 20, 8);
+        let peak = a.malloc(7 << 20, 8);
+        assert!(a.offset() >= 8 << 20);
+        a.free(peak, 7 << 20, 8);
+        a.trim(0);
+
+        assert!(a.offset() < 2 << 20);
+        assert!(a.high_water_mark() >= 8 << 20);
+        assert!(a.high_water_mark() < 9 << 20);
+        a.free(kept, 1 << 20, 8);
+    }
+}
+
+#[test]
+fn same_operations_give_same_layout_fingerprint() {
+    let run = |extra: bool| {
+        let temp_file = NamedTempFile::new().unwrap();
+        let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+        let mut live = Vec::new();
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..500 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let size = (seed % 5000) as usize + 1;
+            unsafe {
+                if seed.is_multiple_of(3) && !live.is_empty() {
+                    let (ptr, size) = live.swap_remove(seed as usize % live.len());
+                    a.free(ptr, size, 8);
+                } else {
+                    live.push((a.malloc(size, 8), size));
+                }
+            }
+        }
+        if extra {
+            unsafe { a.malloc(100, 8) };
+        }
+        a.layout_fingerprint()
+    };
+    assert_eq!(run(false), run(false));
+    assert_ne!(run(false), run(true));
+}
+
+#[test]
+fn allocations_are_visited_in_address_order() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    a.set_growth_increment(1 << 20);
+    let mut live = Vec::new();
+    unsafe {
+        for i in 0..200usize {
+            let size = 1 + i * 337 % (48 << 10);
+            let ptr = a.malloc(size, 8);
+            assert!(!ptr.is_null());
+            live.push((ptr, size));
+        }
+        let freed: Vec<_> = live
+            .extract_if(.., |(ptr, _)| (*ptr as usize / 8).is_multiple_of(3))
+            .collect();
+        for (ptr, size) in freed {
+            a.free(ptr, size, 8);
+        }
+    }
+    assert!(a.segments().len() > 1);
+
+    let mut visited = Vec::new();
+    a.for_each_allocation_sorted(|ptr, size| visited.push((ptr, size)));
+    assert!(visited
+        .windows(2)
+        .all(|w| w[0].0.wrapping_add(w[0].1) <= w[1].0));
+    assert_eq!(visited.len(), live.len());
+    for &(ptr, size) in &live {
+        assert!(visited
+            .iter()
+            .any(|&(p, s)| p <= ptr && ptr.wrapping_add(size) <= p.wrapping_add(s)));
+    }
+    for (ptr, size) in live {
+        unsafe { a.free(ptr, size, 8) };
+    }
+}
+
+#[test]
+fn health_report_adds_up_and_matches_the_accessors() {
+    for striped in [false, true] {
+        let temp_file = NamedTempFile::new().unwrap();
+        let a = DiskDlmalloc::builder(temp_file.path(), 8 << 20)
+            .lock_striping(striped)
+            .build()
+            .unwrap();
+        let mut live = Vec::new();
+        unsafe {
+            for i in 0..300usize {
+                let size = 16 + i * 211 % (20 << 10);
+                live.push((a.malloc(size, 8), size));
+            }
+            for (i, &(ptr, size)) in live.iter().enumerate() {
+                if i % 3 == 0 {
+                    a.free(ptr, size, 8);
+                }
+            }
+        }
+
+        let health = a.health();
+        assert_eq!(health.used + health.free, health.stats.offset);
+        assert!(health.used > 0 && health.free > 0);
+        assert!(health.largest_free_block <= health.stats.heap_free);
+        assert!((0.0..1.0).contains(&health.fragmentation()));
+
+        let stats = a.stats();
+        assert_eq!(health.stats.offset, stats.offset);
+        assert_eq!(health.stats.heap_free, stats.heap_free);
+        assert_eq!(health.stats.logical_capacity, stats.logical_capacity);
+        assert_eq!(health.bins, a.bin_stats());
+        assert_eq!(health.segments, a.segments());
+        assert_eq!(health.high_water_mark, a.high_water_mark());
+        let bins = health.bins.small.iter().chain(&health.bins.tree);
+        assert!(bins.map(|b| b.bytes).sum::<usize>() <= health.stats.heap_free);
+
+        for (i, (ptr, size)) in live.into_iter().enumerate() {
+            if i % 3 != 0 {
+                unsafe { a.free(ptr, size, 8) };
+            }
+        }
+    }
+}
+
+#[test]
+fn live_count_tracks_mallocs_and_frees() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 8 << 20)
+        .lock_striping(true)
+        .build()
+        .unwrap();
+    assert_eq!(a.live_count(), 0);
+    unsafe {
+        let sizes = [16, 100, 4096, 64 << 10, 32];
+        let ptrs: Vec<_> = sizes.iter().map(|&size| a.malloc(size, 8)).collect();
+        assert_eq!(a.live_count(), 5);
+        a.free(ptrs[1], sizes[1], 8);
+        a.free(ptrs[3], sizes[3], 8);
+        assert_eq!(a.live_count(), 3);
+
+        // Moving an allocation, even between stripes, doesn't change it.
+        let moved = a.realloc(ptrs[0], sizes[0], 8, 8192);
+        assert_eq!(a.live_count(), 3);
+        let aligned = a.calloc(100, 256);
+        assert_eq!(a.live_count(), 4);
+
+        a.free(moved, 8192, 8);
+        a.free(aligned, 100, 256);
+        a.free(ptrs[2], sizes[2], 8);
+        a.free(ptrs[4], sizes[4], 8);
+    }
+    assert_eq!(a.live_count(), 0);
+}
+
+#[test]
+fn live_regions_yield_each_allocation() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10 << 20, None);
+    unsafe {
+        let small = a.malloc(100, 8);
+        small.write_bytes(0x11, 100);
+        let freed = a.malloc(1000, 8);
+        let large = a.malloc(64 << 10, 8);
+        large.write_bytes(0x22, 64 << 10);
+        a.free(freed, 1000, 8);
+
+        let regions: Vec<_> = a.live_regions().collect();
+        assert_eq!(regions.len(), 2);
+        for ((offset, bytes), (ptr, size, tag)) in regions
+            .iter()
+            .zip([(small, 100, 0x11), (large, 64 << 10, 0x22)])
+        {
+            // The region may start before the allocation with redzones, and
+            // run on past it into the chunk's slack.
+            let start = a.to_offset(ptr).unwrap() - offset;
+            assert!(bytes[start..][..size].iter().all(|&b| b == tag));
+        }
+        a.free(small, 100, 8);
+        a.free(large, 64 << 10, 8);
+    }
+}
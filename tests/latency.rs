@@ -0,0 +1,29 @@
+#![cfg(feature = "latency_tracking")]
+
+use disk_dlmalloc::DiskDlmalloc;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+#[test]
+fn percentiles_after_many_operations() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    unsafe {
+        let empty = a.op_latency_percentiles();
+        assert_eq!(empty.malloc.count, 0);
+        assert_eq!(empty.malloc.p99, Duration::ZERO);
+
+        for i in 0..1000 {
+            let size = 16 + i % 500;
+            let ptr = a.malloc(size, 8);
+            let ptr = a.realloc(ptr, size, 8, size * 2);
+            a.free(ptr, size * 2, 8);
+        }
+    }
+    let report = a.op_latency_percentiles();
+    for op in [report.malloc, report.free, report.realloc] {
+        assert_eq!(op.count, 1000);
+        assert!(op.p50 > Duration::ZERO);
+        assert!(op.p50 <= op.p90 && op.p90 <= op.p99);
+    }
+}
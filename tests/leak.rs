@@ -0,0 +1,96 @@
+#![feature(allocator_api)]
+
+use disk_dlmalloc::{DiskDlmalloc, Error, LeakReport, Observer};
+use std::alloc::{Allocator, Layout};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+
+#[derive(Default)]
+struct Leaks(Mutex<Vec<LeakReport>>);
+
+impl Observer for Leaks {
+    fn on_leak(&self, report: &LeakReport) {
+        self.0.lock().unwrap().push(report.clone());
+    }
+}
+
+#[test]
+fn panic_on_leak_reports_the_leaked_size() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .panic_on_leak(true)
+        .build()
+        .unwrap();
+    let mut leaked = 0;
+    unsafe {
+        let freed = a.malloc(64, 8);
+        a.malloc(1000, 8);
+        a.free(freed, 64, 8);
+    }
+    a.for_each_allocation_sorted(|_, size| leaked += size);
+    assert!(leaked >= 1000);
+
+    let err = panic::catch_unwind(AssertUnwindSafe(|| drop(a))).unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert_eq!(
+        *msg,
+        format!("1 allocations totalling {} bytes were never freed", leaked)
+    );
+}
+
+#[test]
+fn nothing_is_reported_once_everything_is_freed() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let leaks = Arc::new(Leaks::default());
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .warn_on_leak(true)
+        .panic_on_leak(true)
+        .observer(leaks.clone())
+        .build()
+        .unwrap();
+    let b = a.clone();
+    unsafe {
+        let ptr = a.malloc(1000, 8);
+        drop(a);
+        b.free(ptr, 1000, 8);
+    }
+    drop(b);
+    assert!(leaks.0.lock().unwrap().is_empty());
+}
+
+#[test]
+fn warn_on_leak_reports_to_the_observer_by_token() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let leaks = Arc::new(Leaks::default());
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .warn_on_leak(true)
+        .observer(leaks.clone())
+        .build()
+        .unwrap();
+    let scoped = a.scoped(7, 1 << 16);
+    scoped
+        .allocate(Layout::from_size_align(300, 8).unwrap())
+        .unwrap();
+    unsafe { a.malloc(100, 8) };
+    drop((a, scoped));
+
+    let leaks = leaks.0.lock().unwrap();
+    assert_eq!(leaks.len(), 1);
+    assert_eq!(leaks[0].allocations, 2);
+    assert!(leaks[0].bytes >= 400);
+    assert_eq!(leaks[0].by_token, [(7, 300)]);
+}
+
+#[test]
+fn warn_on_leak_needs_an_observer() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let res = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .warn_on_leak(true)
+        .build();
+    match res {
+        Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+        _ => panic!("built without an observer to warn"),
+    }
+}
@@ -0,0 +1,585 @@
+use disk_dlmalloc::{DiskDlmalloc, Error, FileLock};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn refresh_mapping_picks_up_external_growth() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        assert!(a.malloc(4 << 20, 8).is_null());
+
+        let file = OpenOptions::new()
+            .write(true)
+            .open(temp_file.path())
+            .unwrap();
+        file.set_len(16 << 20).unwrap();
+        a.refresh_mapping().unwrap();
+
+        let ptr = a.malloc(4 << 20, 8);
+        assert!(!ptr.is_null());
+        ptr.write_bytes(0xab, 4 << 20);
+        assert_eq!(*ptr.add((4 << 20) - 1), 0xab);
+        a.free(ptr, 4 << 20, 8);
+    }
+}
+
+#[test]
+fn lazy_free_trim_releases_space() {
+    let temp_file = NamedTempFile::new().unwrap();
+    // `MADV_FREE` needs a private mapping.
+    let a = DiskDlmalloc::builder(temp_file.path(), 16 << 20)
+        .shared(false)
+        .lazy_free(true)
+        .build()
+        .unwrap();
+    unsafe {
+        let keep = a.malloc(64, 8);
+        let ptr = a.malloc(8 << 20, 8);
+        assert!(!ptr.is_null());
+        ptr.write_bytes(0x5a, 8 << 20);
+        a.free(ptr, 8 << 20, 8);
+        a.trim(0);
+
+        // The released range is still mapped. With `MADV_FREE` it may or
+        // may not have kept its contents; without it, as on Linux, the
+        // private copies were dropped and it reads back the file's zeros.
+        let byte = ptr.add(4 << 20).read_volatile();
+        if a.capabilities().can_madv_free {
+            assert!(byte == 0x5a || byte == 0);
+        } else {
+            assert_eq!(byte, 0);
+        }
+
+        // ... and it's available to be handed out again.
+        let ptr2 = a.malloc(12 << 20, 8);
+        assert!(!ptr2.is_null());
+        a.free(ptr2, 12 << 20, 8);
+        a.free(keep, 64, 8);
+    }
+}
+
+#[test]
+fn deterministic_offsets_across_constructions() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let layout = || {
+        let a = DiskDlmalloc::new_deterministic(temp_file.path(), 1 << 20);
+        [(16, 8), (100, 8), (4096, 64), (24, 16)]
+            .iter()
+            .map(|&(size, align)| unsafe {
+                let ptr = a.malloc(size, align);
+                (a.to_offset(ptr).unwrap(), ptr as usize)
+            })
+            .collect::<Vec<_>>()
+    };
+    let first = layout();
+    let second = layout();
+    let offsets = |v: &[(usize, usize)]| v.iter().map(|&(o, _)| o).collect::<Vec<_>>();
+    assert_eq!(offsets(&first), offsets(&second));
+    // Addresses are only best-effort, but nothing should be in the way on a
+    // recent Linux.
+    #[cfg(target_os = "linux")]
+    assert_eq!(first, second);
+}
+
+#[test]
+fn swap_backing_keeps_data_and_moves_writes() {
+    let old_file = NamedTempFile::new().unwrap();
+    let new_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(old_file.path(), 1 << 20, None);
+    unsafe {
+        let ptr = a.malloc(64, 8);
+        ptr.write_bytes(0x11, 64);
+
+        a.swap_backing(new_file.path()).unwrap();
+        assert!(slice::from_raw_parts(ptr, 64).iter().all(|&b| b == 0x11));
+
+        let new = a.malloc(64, 8);
+        new.write_bytes(0x22, 64);
+        ptr.write_bytes(0x33, 64);
+
+        let contents = fs::read(new_file.path()).unwrap();
+        let at = |p: *mut u8| &contents[a.to_offset(p).unwrap()..][..64];
+        assert!(at(new).iter().all(|&b| b == 0x22));
+        assert!(at(ptr).iter().all(|&b| b == 0x33));
+
+        let stale = fs::read(old_file.path()).unwrap();
+        assert!(stale[a.to_offset(ptr).unwrap()..][..64].iter().all(|&b| b == 0x11));
+    }
+}
+
+#[test]
+fn swap_backing_refuses_its_own_file() {
+    let old_file = NamedTempFile::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let link = dir.path().join("link");
+    fs::hard_link(old_file.path(), &link).unwrap();
+    let a = DiskDlmalloc::builder(old_file.path(), 1 << 20)
+        .shared(true)
+        .build()
+        .unwrap();
+    unsafe {
+        let ptr = a.malloc(64, 8);
+        ptr.write_bytes(0x11, 64);
+
+        for path in [old_file.path(), &link] {
+            let err = a.swap_backing(path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert!(slice::from_raw_parts(ptr, 64).iter().all(|&b| b == 0x11));
+            let contents = fs::read(old_file.path()).unwrap();
+            assert!(contents[a.to_offset(ptr).unwrap()..][..64].iter().all(|&b| b == 0x11));
+        }
+    }
+}
+
+#[test]
+fn swap_backing_copies_the_same_bytes_either_way() {
+    // A shared arena is copied file to file, a private one from memory.
+    let copy = |shared: bool| {
+        let old_file = NamedTempFile::new().unwrap();
+        let new_file = NamedTempFile::new().unwrap();
+        let a = DiskDlmalloc::builder(old_file.path(), 8 << 20)
+            .shared(shared)
+            .build()
+            .unwrap();
+        unsafe {
+            for i in 0..64usize {
+                let size = 1000 + i * 997;
+                let ptr = a.malloc(size, 8);
+                for j in 0..size {
+                    *ptr.add(j) = (i * 31 + j) as u8;
+                }
+            }
+            a.swap_backing(new_file.path()).unwrap();
+        }
+        let contents = fs::read(new_file.path()).unwrap();
+        contents[..a.offset()].to_vec()
+    };
+    let fast = copy(true);
+    assert!(fast.iter().any(|&b| b != 0));
+    assert_eq!(fast, copy(false));
+}
+
+#[test]
+fn swap_backing_grown_private_arena_of_odd_size() {
+    // With a size that isn't a multiple of the page size, the region mapped
+    // when the arena grows overlaps the last page of the one before, and
+    // with a private mapping each has its own copy of that page.
+    let old_file = NamedTempFile::new().unwrap();
+    let new_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(old_file.path(), 2 << 20)
+        .shared(false)
+        .build()
+        .unwrap();
+    let size = (1 << 20) + 100;
+    a.shrink_arena(size).unwrap();
+    a.set_growth_increment(1 << 20);
+    unsafe {
+        // Written through the first region, at its very end.
+        let high = a.malloc_high(64, 8);
+        high.write_bytes(0xee, 64);
+        let mut blocks = Vec::new();
+        while a.offset() < size + (64 << 10) {
+            let ptr = a.malloc(1000, 8);
+            assert!(!ptr.is_null());
+            ptr.write_bytes(blocks.len() as u8, 1000);
+            blocks.push(ptr);
+        }
+        a.swap_backing(new_file.path()).unwrap();
+        for (i, &ptr) in blocks.iter().enumerate() {
+            assert!(slice::from_raw_parts(ptr, 1000).iter().all(|&b| b == i as u8));
+        }
+        assert!(slice::from_raw_parts(high, 64).iter().all(|&b| b == 0xee));
+    }
+}
+
+#[test]
+fn private_mapping_leaves_file_untouched() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .shared(false)
+        .build()
+        .unwrap();
+    unsafe {
+        let ptr = a.malloc(4096, 8);
+        ptr.write_bytes(0x5a, 4096);
+        assert!(slice::from_raw_parts(ptr, 4096).iter().all(|&b| b == 0x5a));
+
+        let contents = fs::read(temp_file.path()).unwrap();
+        assert_eq!(contents.len(), 1 << 20);
+        assert!(contents.iter().all(|&b| b == 0));
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn major_faults() -> libc::c_long {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    assert_eq!(unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) }, 0);
+    usage.ru_majflt
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn prefetch_cold_region() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    unsafe {
+        let len = 4 << 20;
+        let ptr = a.malloc(len, 4096);
+        ptr.write_bytes(0x7e, len);
+        // Drop the pages from the mapping so the next access has to fault
+        // them back in.
+        assert_eq!(libc::madvise(ptr.cast(), len, libc::MADV_DONTNEED), 0);
+
+        a.prefetch(ptr, len).unwrap();
+        let before = major_faults();
+        let sum: usize = slice::from_raw_parts(ptr, len)
+            .iter()
+            .step_by(4096)
+            .map(|&b| b as usize)
+            .sum();
+        assert_eq!(sum, 0x7e * len / 4096);
+        assert_eq!(major_faults(), before);
+
+        assert!(a.prefetch(std::ptr::null_mut(), 1).is_err());
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn residency_follows_eviction() {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    unsafe {
+        let len = 1 << 20;
+        let ptr = a.malloc(len, 4096);
+        ptr.write_bytes(0x7e, len);
+        assert!(a.is_resident(ptr, len));
+
+        // For a file mapping `mincore` reports the page cache, so the pages
+        // have to be written back and dropped from there as well.
+        assert_eq!(libc::msync(ptr.cast(), len, libc::MS_SYNC), 0);
+        assert_eq!(libc::madvise(ptr.cast(), len, libc::MADV_DONTNEED), 0);
+        let file = File::open(temp_file.path()).unwrap();
+        let offset = a.to_offset(ptr).unwrap() as libc::off_t;
+        let advice = libc::POSIX_FADV_DONTNEED;
+        assert_eq!(libc::posix_fadvise(file.as_raw_fd(), offset, len as libc::off_t, advice), 0);
+        assert!(!a.is_resident(ptr, len));
+    }
+}
+
+#[test]
+fn residency_of_memory_outside_the_arena_is_false() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    let mut outside = vec![0x7eu8; 1 << 16];
+    assert!(!a.is_resident(outside.as_mut_ptr(), outside.len()));
+    unsafe {
+        let ptr = a.malloc(4096, 4096);
+        ptr.write_bytes(0x7e, 4096);
+        assert!(a.is_resident(ptr, 4096));
+        // Running off the end of the arena doesn't count either.
+        let end = a.to_ptr(0).unwrap().add(a.stats().logical_capacity);
+        assert!(!a.is_resident(end.sub(4096), 2 * 4096));
+        a.free(ptr, 4096, 4096);
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn release_drops_an_interior_block() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 128 << 20)
+        .mmap_threshold(usize::MAX)
+        .build()
+        .unwrap();
+    unsafe {
+        let len = 32 << 20;
+        let before = a.malloc(4096, 8);
+        let ptr = a.malloc(len, 8);
+        let after = a.malloc(4096, 8);
+        ptr.write_bytes(0x7e, len);
+        after.write_bytes(0x7f, 4096);
+        let page = 4096;
+        let inner = ptr.add(page).map_addr(|addr| addr & !(page - 1));
+        let inner_len = len - 2 * page;
+        assert!(a.is_resident(inner, inner_len));
+
+        assert!(a.release(ptr, len, 8));
+        assert!(!a.is_resident(inner, inner_len));
+        // Not at the end of the heap, so `trim` couldn't have done this.
+        assert!(a.offset() > a.to_offset(after).unwrap());
+        assert_eq!(*after, 0x7f);
+
+        let again = a.malloc(len, 8);
+        assert_eq!(again, ptr);
+        assert_eq!(*inner, 0);
+        a.free(again, len, 8);
+        a.free(before, 4096, 8);
+        a.free(after, 4096, 8);
+
+        // Too small to have whole pages to give back.
+        let small = a.malloc(100, 8);
+        assert!(!a.release(small, 100, 8));
+    }
+}
+
+#[test]
+fn release_of_a_foreign_pointer_leaves_the_arena_alone() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let fallback_file = NamedTempFile::new().unwrap();
+    let fallback = DiskDlmalloc::new(fallback_file.path(), 16 << 20, None);
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None)
+        .with_overflow(Arc::new(fallback.clone()));
+    unsafe {
+        let first = a.malloc(8192, 8);
+        assert!(a.to_offset(first).unwrap() < 4096);
+        first.write_bytes(0x77, 8192);
+        // Too big for the arena, so it comes from the fallback.
+        let len = 4 << 20;
+        let foreign = a.malloc(len, 8);
+        assert!(fallback.owns(foreign));
+        foreign.write_bytes(0x55, len);
+        let start = fs::read(temp_file.path()).unwrap()[..8192].to_vec();
+
+        assert!(!a.release(foreign, len, 8));
+        assert_eq!(fs::read(temp_file.path()).unwrap()[..8192], start);
+        assert!(slice::from_raw_parts(first, 8192).iter().all(|&b| b == 0x77));
+        a.free(first, 8192, 8);
+    }
+}
+
+#[test]
+fn shrink_arena_after_freeing_the_top() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    unsafe {
+        let low = a.malloc(4096, 8);
+        low.write_bytes(0x61, 4096);
+        let high = a.malloc(4 << 20, 8);
+        match a.shrink_arena(2 << 20) {
+            Err(Error::WouldTruncateLive { in_use, requested }) => {
+                assert!(in_use > 4 << 20);
+                assert_eq!(requested, 2 << 20);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        a.free(high, 4 << 20, 8);
+        a.shrink_arena(2 << 20).unwrap();
+        assert_eq!(fs::metadata(temp_file.path()).unwrap().len(), 2 << 20);
+        assert!(a.available() <= 2 << 20);
+        assert!(a.available() > (2 << 20) - (128 << 10));
+        assert!(slice::from_raw_parts(low, 4096).iter().all(|&b| b == 0x61));
+
+        assert!(a.malloc(3 << 20, 8).is_null());
+        let ptr = a.malloc(1 << 20, 8);
+        assert!(!ptr.is_null());
+        ptr.write_bytes(0x62, 1 << 20);
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn fixed_base_refuses_to_clobber_a_mapping() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let len = 1 << 20;
+    unsafe {
+        let taken = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        assert_ne!(taken, libc::MAP_FAILED);
+        let taken = taken.cast::<u8>();
+        taken.write_bytes(0xab, len);
+
+        let res = DiskDlmalloc::builder(temp_file.path(), len)
+            .base_address(taken as usize)
+            .build();
+        match res {
+            Err(Error::MappingOverlaps { addr, .. }) => assert_eq!(addr, taken as usize),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("mapped over an existing mapping"),
+        }
+        assert!(slice::from_raw_parts(taken, len).iter().all(|&b| b == 0xab));
+        libc::munmap(taken.cast(), len);
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn decommit_drops_residency_until_the_next_access() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    unsafe {
+        let len = 1 << 20;
+        let ptr = a.malloc(len, 4096);
+        a.commit(ptr, len).unwrap();
+        assert!(a.is_resident(ptr, len));
+        ptr.write_bytes(0x5a, len);
+
+        a.decommit(ptr, len).unwrap();
+        assert!(!a.is_resident(ptr, len));
+        assert!((0..len).step_by(4096).all(|i| *ptr.add(i) == 0x5a));
+        assert!(a.is_resident(ptr, len));
+
+        a.decommit(ptr, len).unwrap();
+        a.commit(ptr, len).unwrap();
+        assert!(a.is_resident(ptr, len));
+        a.free(ptr, len, 4096);
+    }
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 8 << 20)
+        .shared(false)
+        .build()
+        .unwrap();
+    unsafe {
+        let ptr = a.malloc(1 << 20, 4096);
+        let err = a.decommit(ptr, 1 << 20).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        a.free(ptr, 1 << 20, 4096);
+    }
+}
+
+#[test]
+fn magic_ring_wraps_around() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    let size = 64 << 10;
+    unsafe {
+        let ring = a.alloc_magic_ring(size);
+        assert!(!ring.is_null());
+        let data: Vec<u8> = (0..100).collect();
+        ptr::copy_nonoverlapping(data.as_ptr(), ring.add(size - 50), data.len());
+
+        assert_eq!(slice::from_raw_parts(ring, 50), &data[50..]);
+        assert_eq!(slice::from_raw_parts(ring.add(size - 50), 100), &data[..]);
+        *ring.add(size + 60) = 0xaa;
+        assert_eq!(*ring.add(60), 0xaa);
+
+        // The ring's storage is still an allocation like any other.
+        let other = a.malloc(size, 8);
+        assert!(!other.is_null());
+        a.free(other, size, 8);
+        a.free_magic_ring(ring, size);
+    }
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 8 << 20)
+        .shared(false)
+        .build()
+        .unwrap();
+    assert!(unsafe { a.alloc_magic_ring(size) }.is_null());
+}
+
+#[test]
+fn exclusive_file_lock_keeps_a_second_open_out() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let build = || {
+        DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+            .file_lock(FileLock::Exclusive)
+            .build()
+    };
+    let a = build().unwrap();
+    unsafe {
+        let ptr = a.malloc(64, 8);
+        ptr.write_bytes(0xab, 64);
+        assert!(matches!(build(), Err(Error::Locked)));
+        // The failed open didn't truncate the file under the first one.
+        assert_eq!(fs::metadata(temp_file.path()).unwrap().len(), 1 << 20);
+        assert_eq!(*ptr.add(63), 0xab);
+        a.free(ptr, 64, 8);
+    }
+    drop(a);
+    assert!(build().is_ok());
+}
+
+#[test]
+fn total_size_rounds_up_to_the_block_size() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let block = fs::metadata(temp_file.path()).unwrap().blksize() as usize;
+    let a = DiskDlmalloc::new(temp_file.path(), 10 * block + 123, None);
+    assert_eq!(a.total_size(), 11 * block);
+    assert_eq!(a.stats().logical_capacity, 11 * block);
+    let len = fs::metadata(temp_file.path()).unwrap().len();
+    assert_eq!(len, 11 * block as u64);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn try_read_reports_a_truncated_page() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        let ptr = a.malloc(256 << 10, 8);
+        ptr.write_bytes(0x5a, 256 << 10);
+        let mut buf = [0; 4096];
+        a.try_read(ptr, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x5a));
+
+        // Cut the file off before the end of the allocation, leaving its
+        // last page unbacked.
+        let end = a.to_offset(ptr).unwrap() + (256 << 10);
+        let file = OpenOptions::new()
+            .write(true)
+            .open(temp_file.path())
+            .unwrap();
+        file.set_len((end - (64 << 10)) as u64).unwrap();
+        let err = a
+            .try_read(ptr.add((256 << 10) - 4096), &mut buf)
+            .unwrap_err();
+        assert!(err.to_string().contains("faulted"), "{}", err);
+        a.try_read(ptr, &mut buf).unwrap();
+
+        assert_eq!(
+            a.try_read(ptr.add(1 << 20), &mut buf).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+}
+
+#[test]
+fn lock_metadata_keeps_free_chunk_headers_resident() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 4 << 20, None);
+    unsafe {
+        let freed = a.malloc(64 << 10, 8);
+        let data = a.malloc(256 << 10, 8);
+        data.write_bytes(0x77, 256 << 10);
+        a.free(freed, 64 << 10, 8);
+        a.lock_metadata().unwrap();
+
+        // Evict the data, whose pages hold no bookkeeping and so aren't
+        // locked; this would fail with `EINVAL` on a locked page.
+        let page = 4096;
+        let start = (data as usize).next_multiple_of(page);
+        let end = (data as usize + (256 << 10)) & !(page - 1);
+        assert_eq!(
+            libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_DONTNEED),
+            0
+        );
+
+        // The freed chunk's header is still locked in, and serves the next
+        // allocation.
+        assert!(a.is_resident(freed, 1));
+        let ptr = a.malloc(1000, 8);
+        assert_eq!(ptr, freed);
+        assert_eq!(*data.add(1000), 0x77);
+        a.free(ptr, 1000, 8);
+        a.free(data, 256 << 10, 8);
+        a.lock_metadata().unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use disk_dlmalloc::{CancellationToken, DiskDlmalloc, Error, Observer};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+#[repr(C)]
+struct Node {
+    /// Offset of the next node, or `u64::MAX` at the end of the list.
+    next: u64,
+    value: u64,
+}
+
+const END: u64 = u64::MAX;
+
+#[test]
+fn linked_allocations_survive_migration() {
+    let src_file = NamedTempFile::new().unwrap();
+    let dest_file = NamedTempFile::new().unwrap();
+    let src = DiskDlmalloc::new(src_file.path(), 8 << 20, None);
+    let dest = DiskDlmalloc::new(dest_file.path(), 2 << 20, None);
+    let size = std::mem::size_of::<Node>();
+    unsafe {
+        // Spread the nodes out, so they land elsewhere in the new arena.
+        let mut fillers = Vec::new();
+        let mut next = END;
+        for value in [3, 2, 1] {
+            fillers.push(src.malloc(100 << 10, 8));
+            let node = src.malloc(size, 8).cast::<Node>();
+            node.write(Node { next, value });
+            next = src.to_offset(node.cast()).unwrap() as u64;
+        }
+        let head = next as usize;
+        for filler in fillers {
+            src.free(filler, 100 << 10, 8);
+        }
+
+        let mut moved = BTreeMap::new();
+        src.migrate_to(&dest, |old, new| {
+            moved.insert(old, new);
+        })
+        .unwrap();
+        assert_eq!(moved.len(), 3);
+        let translate = |old: usize| {
+            let (&start, &new) = moved.range(..=old).next_back().unwrap();
+            new + (old - start)
+        };
+        assert_ne!(translate(head), head);
+
+        // Walk the list in the new arena, rewriting the links on the way.
+        let mut offset = translate(head);
+        let mut values = Vec::new();
+        loop {
+            let node = &mut *dest.to_ptr(offset).unwrap().cast::<Node>();
+            values.push(node.value);
+            if node.next == END {
+                break;
+            }
+            node.next = translate(node.next as usize) as u64;
+            offset = node.next as usize;
+        }
+        assert_eq!(values, [1, 2, 3]);
+
+        // The copies are ordinary allocations of the new arena.
+        let mut offset = translate(head);
+        while offset != END as usize {
+            let node = dest.to_ptr(offset).unwrap();
+            offset = (*node.cast::<Node>()).next as usize;
+            dest.free(node, size, 8);
+        }
+    }
+}
+
+#[test]
+fn migration_stops_when_the_destination_is_full() {
+    let src_file = NamedTempFile::new().unwrap();
+    let dest_file = NamedTempFile::new().unwrap();
+    let src = DiskDlmalloc::new(src_file.path(), 8 << 20, None);
+    let dest = DiskDlmalloc::new(dest_file.path(), 1 << 20, None);
+    unsafe {
+        let big = src.malloc(4 << 20, 8);
+        let res = src.migrate_to(&dest, |_, _| panic!("nothing should be reported"));
+        assert!(matches!(res, Err(Error::DestinationFull { .. })));
+        src.free(big, 4 << 20, 8);
+    }
+}
+
+/// Cancels `token` the second time a lock is taken once armed.
+#[derive(Default)]
+struct CancelOnSecondLock {
+    armed: AtomicBool,
+    locks: AtomicUsize,
+    token: CancellationToken,
+}
+
+impl Observer for CancelOnSecondLock {
+    fn on_lock(&self) {
+        if self.armed.load(Ordering::SeqCst) && self.locks.fetch_add(1, Ordering::SeqCst) == 1 {
+            self.token.cancel();
+        }
+    }
+}
+
+#[test]
+fn cancelled_migration_stops_between_allocations() {
+    let src_file = NamedTempFile::new().unwrap();
+    let dest_file = NamedTempFile::new().unwrap();
+    let observer = Arc::new(CancelOnSecondLock::default());
+    let src = DiskDlmalloc::builder(src_file.path(), 8 << 20)
+        .lock_striping(true)
+        .observer(observer.clone())
+        .build()
+        .unwrap();
+    let dest = DiskDlmalloc::new(dest_file.path(), 8 << 20, None);
+    let mut live = Vec::new();
+    unsafe {
+        // The large allocations are in the first heap migrated, the small
+        // ones in the second, which is where the token gets cancelled.
+        for (i, size) in [4096, 64].into_iter().cycle().take(10).enumerate() {
+            let ptr = src.malloc(size, 8);
+            ptr.write_bytes(i as u8, size);
+            live.push((ptr, size));
+        }
+
+        observer.armed.store(true, Ordering::SeqCst);
+        let mut fixups = 0;
+        let res = src.migrate_to_cancellable(&dest, |_, _| fixups += 1, &observer.token);
+        observer.armed.store(false, Ordering::SeqCst);
+        assert!(matches!(res, Err(Error::Cancelled)));
+        assert_eq!(fixups, 0);
+
+        let mut copied = 0;
+        dest.for_each_allocation_sorted(|_, size| {
+            assert!(size >= 4096);
+            copied += 1;
+        });
+        assert_eq!(copied, 5);
+        let ptr = dest.malloc(1 << 20, 8);
+        assert!(!ptr.is_null());
+        dest.free(ptr, 1 << 20, 8);
+
+        for (i, &(ptr, size)) in live.iter().enumerate() {
+            assert!(std::slice::from_raw_parts(ptr, size)
+                .iter()
+                .all(|&b| b == i as u8));
+        }
+        let again_file = NamedTempFile::new().unwrap();
+        let again = DiskDlmalloc::new(again_file.path(), 8 << 20, None);
+        src.migrate_to(&again, |_, _| fixups += 1).unwrap();
+        assert_eq!(fixups, live.len());
+        for (ptr, size) in live {
+            src.free(ptr, size, 8);
+        }
+    }
+}
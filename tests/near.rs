@@ -0,0 +1,45 @@
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+
+const PAGE: usize = 4096;
+
+fn page_of(ptr: *mut u8) -> usize {
+    ptr as usize / PAGE
+}
+
+#[test]
+fn malloc_near_reuses_the_hints_page() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        // Free a hole next to `hint` and an exact fit for the request a few
+        // pages away, which is what plain `malloc` would pick.
+        let hint = a.malloc(64, 8);
+        let hole = a.malloc(64, 8);
+        let filler: Vec<_> = (0..16).map(|_| a.malloc(1024, 8)).collect();
+        let decoy = a.malloc(48, 8);
+        let pin = a.malloc(64, 8);
+        assert_ne!(page_of(hint), page_of(decoy));
+        a.free(hole, 64, 8);
+        a.free(decoy, 48, 8);
+
+        let near = a.malloc_near(48, 8, hint);
+        assert_eq!(page_of(near), page_of(hint));
+        let plain = a.malloc(48, 8);
+        assert_eq!(plain, decoy);
+
+        // With nothing free on its page, the hint is ignored.
+        let elsewhere = a.malloc_near(64, 8, filler[8]);
+        assert!(!elsewhere.is_null());
+
+        for ptr in [near, plain] {
+            a.free(ptr, 48, 8);
+        }
+        for ptr in [elsewhere, pin, hint] {
+            a.free(ptr, 64, 8);
+        }
+        for ptr in filler {
+            a.free(ptr, 1024, 8);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn full_arena_overflows_into_fallback() {
+    let primary_file = NamedTempFile::new().unwrap();
+    let fallback_file = NamedTempFile::new().unwrap();
+    let fallback = DiskDlmalloc::new(fallback_file.path(), 16 << 20, None);
+    let a = DiskDlmalloc::new(primary_file.path(), 1 << 20, None)
+        .with_overflow(Arc::new(fallback.clone()));
+    let fallback_available = fallback.available();
+    unsafe {
+        let mut ptrs = Vec::new();
+        loop {
+            let ptr = a.malloc(64 << 10, 8);
+            assert!(!ptr.is_null());
+            ptr.write_bytes(ptrs.len() as u8, 64 << 10);
+            ptrs.push(ptr);
+            if !a.owns(ptr) {
+                break;
+            }
+            assert!(ptrs.len() < 16);
+        }
+        let overflowed = *ptrs.last().unwrap();
+        assert!(fallback.owns(overflowed));
+        assert!(fallback.available() < fallback_available);
+
+        let zeroed = a.calloc(64 << 10, 8);
+        assert!(fallback.owns(zeroed));
+        assert!(std::slice::from_raw_parts(zeroed, 64 << 10)
+            .iter()
+            .all(|&b| b == 0));
+        a.free(zeroed, 64 << 10, 8);
+
+        // Growing past what the arena has left moves to the fallback too.
+        let grown = a.realloc(ptrs[0], 64 << 10, 8, 4 << 20);
+        assert!(fallback.owns(grown));
+        assert!(std::slice::from_raw_parts(grown, 64 << 10)
+            .iter()
+            .all(|&b| b == 0));
+        ptrs[0] = a.realloc(grown, 4 << 20, 8, 64 << 10);
+        assert!(fallback.owns(ptrs[0]));
+
+        for ptr in ptrs {
+            a.free(ptr, 64 << 10, 8);
+        }
+    }
+    assert_eq!(fallback.available(), fallback_available);
+}
+
+#[cfg(feature = "debug")]
+#[test]
+#[should_panic(expected = "isn't in the arena")]
+fn freeing_a_foreign_pointer_is_caught() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    let foreign = Box::into_raw(Box::new([0u64; 8]));
+    unsafe { a.free(foreign.cast(), 64, 8) };
+}
+
+#[test]
+fn malloc_near_and_malloc_high_overflow_too() {
+    let primary_file = NamedTempFile::new().unwrap();
+    let fallback_file = NamedTempFile::new().unwrap();
+    let fallback = DiskDlmalloc::new(fallback_file.path(), 16 << 20, None);
+    let a = DiskDlmalloc::new(primary_file.path(), 1 << 20, None)
+        .with_overflow(Arc::new(fallback.clone()));
+    unsafe {
+        let hint = a.malloc(64, 8);
+        assert!(a.owns(hint));
+        let near = a.malloc_near(4 << 20, 8, hint);
+        assert!(fallback.owns(near));
+        let high = a.malloc_high(4 << 20, 8);
+        assert!(fallback.owns(high));
+        a.free(near, 4 << 20, 8);
+        a.free(high, 4 << 20, 8);
+        a.free(hint, 64, 8);
+    }
+    assert_eq!(fallback.live_count(), 0);
+}
@@ -0,0 +1,50 @@
+use disk_dlmalloc::{BufferPool, DiskDlmalloc};
+use tempfile::NamedTempFile;
+
+const BUFFER: usize = 256 << 10;
+
+#[test]
+fn buffers_are_reused() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    let pool = BufferPool::new(&a, BUFFER, 4);
+    unsafe {
+        let mut buffers: Vec<_> = (0..4).map(|_| pool.get()).collect();
+        assert!(buffers.iter().all(|ptr| !ptr.is_null()));
+        let offset = a.offset();
+        for &ptr in &buffers {
+            pool.put(ptr);
+        }
+        assert_eq!(pool.cached(), 4);
+
+        for _ in 0..100 {
+            let reused: Vec<_> = (0..4).map(|_| pool.get()).collect();
+            // Last in, first out.
+            buffers.reverse();
+            assert_eq!(reused, buffers);
+            for &ptr in &reused {
+                ptr.write_bytes(0xee, BUFFER);
+                pool.put(ptr);
+            }
+        }
+        assert_eq!(a.offset(), offset);
+        assert_eq!(a.live_count(), 4);
+    }
+    drop(pool);
+    assert_eq!(a.live_count(), 0);
+}
+
+#[test]
+fn buffers_past_the_cache_go_back_to_the_arena() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    let pool = BufferPool::new(&a, BUFFER, 2);
+    unsafe {
+        let buffers: Vec<_> = (0..5).map(|_| pool.get()).collect();
+        for ptr in buffers {
+            pool.put(ptr);
+        }
+    }
+    assert_eq!(pool.cached(), 2);
+    assert_eq!(a.live_count(), 2);
+}
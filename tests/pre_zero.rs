@@ -0,0 +1,30 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::slice;
+use tempfile::NamedTempFile;
+
+#[test]
+fn pre_zeroed_file_is_allocated_and_calloc_still_clears() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 4 << 20)
+        .pre_zero(true)
+        .build()
+        .unwrap();
+    // The zeros were written out rather than left to the filesystem, so
+    // the file isn't sparse any more.
+    let meta = fs::metadata(temp_file.path()).unwrap();
+    assert!(meta.blocks() * 512 >= 4 << 20);
+    assert!(fs::read(temp_file.path()).unwrap().iter().all(|&b| b == 0));
+    unsafe {
+        let ptr = a.malloc(1000, 8);
+        ptr.write_bytes(0xFF, 1000);
+        let guard = a.malloc(16, 8);
+        a.free(ptr, 1000, 8);
+        let zeroed = a.calloc(1000, 8);
+        assert_eq!(zeroed, ptr);
+        assert!(slice::from_raw_parts(zeroed, 1000).iter().all(|&b| b == 0));
+        a.free(zeroed, 1000, 8);
+        a.free(guard, 16, 8);
+    }
+}
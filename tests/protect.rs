@@ -0,0 +1,48 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::env;
+use std::process::{self, Command};
+use tempfile::NamedTempFile;
+
+const PAGE: usize = 4096;
+const CHILD_PATH: &str = "DISK_DLMALLOC_PROTECT_CHILD";
+
+#[test]
+fn readonly_pages_fault_on_write() {
+    // The child writes to a read-only page, which should kill it.
+    if let Ok(path) = env::var(CHILD_PATH) {
+        let a = DiskDlmalloc::new(path, 1 << 20, None);
+        unsafe {
+            let ptr = a.malloc(2 * PAGE, PAGE);
+            a.protect_readonly(ptr, 2 * PAGE).unwrap();
+            ptr.add(PAGE).write_volatile(1);
+        }
+        process::exit(0);
+    }
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let status = Command::new(env::current_exe().unwrap())
+        .args(["readonly_pages_fault_on_write", "--exact"])
+        .env(CHILD_PATH, temp_file.path())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn readwrite_restores_writes() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        let ptr = a.malloc(2 * PAGE, PAGE);
+        ptr.write_bytes(7, 2 * PAGE);
+        a.protect_readonly(ptr, 2 * PAGE).unwrap();
+        assert_eq!(ptr.add(PAGE).read_volatile(), 7);
+        a.protect_readwrite(ptr, 2 * PAGE).unwrap();
+        ptr.add(PAGE).write_volatile(8);
+        assert_eq!(*ptr.add(PAGE), 8);
+
+        assert!(a.protect_readonly(ptr.add(1), PAGE).is_err());
+        assert!(a.protect_readonly(ptr, PAGE + 1).is_err());
+        a.free(ptr, 2 * PAGE, PAGE);
+    }
+}
@@ -0,0 +1,48 @@
+#![cfg(feature = "redzones")]
+
+use disk_dlmalloc::{DiskDlmalloc, Observer};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+
+#[derive(Default)]
+struct Corruptions(Mutex<Vec<(usize, usize)>>);
+
+impl Observer for Corruptions {
+    fn on_corruption(&self, ptr: *mut u8, size: usize) {
+        self.0.lock().unwrap().push((ptr as usize, size));
+    }
+}
+
+#[test]
+fn overrun_detected_at_free() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let observer = Arc::new(Corruptions::default());
+    let a = DiskDlmalloc::builder(temp_file.path(), 10 << 20)
+        .observer(observer.clone())
+        .build()
+        .unwrap();
+    unsafe {
+        let ok = a.malloc(40, 8);
+        ok.write_bytes(1, 40);
+        a.free(ok, 40, 8);
+        assert!(observer.0.lock().unwrap().is_empty());
+
+        let bad = a.malloc(40, 64);
+        assert_eq!(bad as usize % 64, 0);
+        *bad.add(40) = 1;
+        a.free(bad, 40, 64);
+        assert_eq!(*observer.0.lock().unwrap(), [(bad as usize, 40)]);
+    }
+}
+
+#[test]
+#[should_panic(expected = "redzone")]
+fn underrun_panics_without_observer() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10 << 20, None);
+    unsafe {
+        let bad = a.malloc(100, 8);
+        *bad.sub(1) = 0;
+        a.free(bad, 100, 8);
+    }
+}
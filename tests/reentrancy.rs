@@ -0,0 +1,108 @@
+use disk_dlmalloc::{DiskDlmalloc, Observer};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use tempfile::NamedTempFile;
+
+/// Allocates from the allocator it observes every time a lock is taken.
+#[derive(Default)]
+struct Allocating {
+    alloc: OnceLock<DiskDlmalloc>,
+    nested: AtomicUsize,
+    all_null: AtomicBool,
+}
+
+impl Observer for Allocating {
+    fn on_lock(&self) {
+        if let Some(a) = self.alloc.get() {
+            self.nested.fetch_add(1, Ordering::SeqCst);
+            unsafe {
+                let ptr = a.malloc(32, 8);
+                if !ptr.is_null() {
+                    self.all_null.store(false, Ordering::SeqCst);
+                    a.free(ptr, 32, 8);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn observer_that_allocates_does_not_deadlock() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let observer = Arc::new(Allocating {
+        all_null: AtomicBool::new(true),
+        ..Allocating::default()
+    });
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .observer(observer.clone())
+        .build()
+        .unwrap();
+    observer.alloc.set(a.clone()).ok().unwrap();
+
+    unsafe {
+        let ptr = a.malloc(100, 8);
+        assert!(!ptr.is_null());
+        let ptr = a.realloc(ptr, 100, 8, 200);
+        assert!(!ptr.is_null());
+        a.free(ptr, 200, 8);
+    }
+    assert_eq!(observer.nested.load(Ordering::SeqCst), 3);
+    assert!(observer.all_null.load(Ordering::SeqCst));
+
+    // Other threads are unaffected once the lock is released.
+    let b = a.clone();
+    let ptr = std::thread::spawn(move || unsafe { b.malloc(64, 8) as usize })
+        .join()
+        .unwrap();
+    assert_ne!(ptr, 0);
+    unsafe { a.free(ptr as *mut u8, 64, 8) };
+}
+
+/// Frees a pointer it's been handed the next time a lock is taken.
+#[derive(Default)]
+struct Freeing {
+    alloc: OnceLock<DiskDlmalloc>,
+    victim: AtomicUsize,
+    panicked: AtomicBool,
+}
+
+impl Observer for Freeing {
+    fn on_lock(&self) {
+        let victim = self.victim.swap(0, Ordering::SeqCst);
+        if victim == 0 {
+            return;
+        }
+        let a = self.alloc.get().unwrap();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+            a.free(victim as *mut u8, 32, 8)
+        }));
+        self.panicked.store(res.is_err(), Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn nested_free_panics_instead_of_leaking() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let observer = Arc::new(Freeing::default());
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .observer(observer.clone())
+        .build()
+        .unwrap();
+    observer.alloc.set(a.clone()).ok().unwrap();
+
+    unsafe {
+        let victim = a.malloc(32, 8);
+        assert_eq!(a.live_count(), 1);
+        observer.victim.store(victim as usize, Ordering::SeqCst);
+        let other = a.malloc(64, 8);
+        assert!(observer.panicked.load(Ordering::SeqCst));
+        // The refused free didn't count the allocation as gone...
+        assert_eq!(a.live_count(), 2);
+
+        // ... and it can still be freed once the lock is released.
+        a.free(victim, 32, 8);
+        a.free(other, 64, 8);
+        assert_eq!(a.live_count(), 0);
+    }
+}
@@ -0,0 +1,30 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::slice;
+use tempfile::NamedTempFile;
+
+#[test]
+fn relocating_top_allocation_lowers_offset() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    unsafe {
+        let low = a.malloc(256 << 10, 8);
+        let hole = a.malloc(256 << 10, 8);
+        let high = a.malloc(128 << 10, 8);
+        high.write_bytes(0x42, 128 << 10);
+        a.free(hole, 256 << 10, 8);
+        a.trim(0);
+        let before = a.offset();
+
+        let moved = a.relocate(high, 128 << 10);
+        assert!(moved < high);
+        assert!(slice::from_raw_parts(moved, 128 << 10).iter().all(|&b| b == 0x42));
+        a.trim(0);
+        assert!(a.offset() < before);
+
+        // Nothing lower is free any more, so it stays put.
+        assert_eq!(a.relocate(moved, 128 << 10), moved);
+
+        a.free(moved, 128 << 10, 8);
+        a.free(low, 256 << 10, 8);
+    }
+}
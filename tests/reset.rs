@@ -0,0 +1,32 @@
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn each_iteration_starts_from_the_same_address() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    let mut firsts = Vec::new();
+    for _ in 0..3 {
+        unsafe {
+            let first = a.malloc(64, 8);
+            assert!(!first.is_null());
+            firsts.push(first);
+            for i in 1..1000 {
+                let ptr = a.malloc(i * 8, 8);
+                assert!(!ptr.is_null());
+                ptr.write_bytes(i as u8, i * 8);
+            }
+            assert!(!a.malloc_high(1 << 20, 4096).is_null());
+            assert!(a.offset() > 0);
+            a.reset_keep_mapping();
+        }
+        assert_eq!(a.offset(), 0);
+    }
+    assert_eq!(
+        a.to_offset(firsts[0]),
+        Some(a.to_offset(firsts[0]).unwrap())
+    );
+    assert!(firsts.iter().all(|&ptr| ptr == firsts[0]));
+    // Everything is available again, the high end included.
+    assert!(a.available() >= (16 << 20) - 4096);
+}
@@ -0,0 +1,62 @@
+#![feature(allocator_api)]
+
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn scope_allocations_are_reclaimed_on_return() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 64 << 20, None);
+    let keep = unsafe { a.malloc(100, 8) };
+    let before = a.offset();
+
+    let sum = a.scope(|scope| {
+        let mut tokens = Vec::new_in(scope);
+        for i in 0..100_000u64 {
+            tokens.push(i);
+        }
+        for i in 0..1000 {
+            let ptr = scope.malloc(i + 1, 16);
+            assert_eq!(ptr as usize % 16, 0);
+            unsafe { ptr.write_bytes(0xab, i + 1) };
+        }
+        assert!(a.offset() >= before + scope.reserved());
+        assert!(scope.reserved() >= 800_000);
+        tokens.iter().sum::<u64>()
+    });
+    assert_eq!(sum, 99_999 * 100_000 / 2);
+    assert_eq!(a.offset(), before);
+
+    // The heap is none the worse for it.
+    unsafe {
+        let ptr = a.malloc(1 << 20, 8);
+        assert!(!ptr.is_null());
+        a.free(ptr, 1 << 20, 8);
+        a.free(keep, 100, 8);
+    }
+}
+
+#[test]
+fn heap_growth_inside_a_scope_survives_it() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 64 << 20, None);
+    let before = a.offset();
+    let ptr = a.scope(|scope| {
+        scope.malloc(1000, 8);
+        let ptr = unsafe { a.malloc(4 << 20, 8) };
+        unsafe { ptr.write_bytes(0xcd, 4 << 20) };
+        scope.malloc(1 << 20, 8);
+        ptr
+    });
+    assert!(a.offset() > before);
+    unsafe {
+        assert_eq!(*ptr.add((4 << 20) - 1), 0xcd);
+        a.free(ptr, 4 << 20, 8);
+    }
+    // The scope's blocks are reused rather than lost.
+    let after = a.offset();
+    a.scope(|scope| {
+        scope.malloc(1000, 8);
+    });
+    assert_eq!(a.offset(), after);
+}
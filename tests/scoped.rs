@@ -0,0 +1,34 @@
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn quota_refuses_then_recovers_after_free() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    let tenant = a.scoped(7, 1 << 20);
+    unsafe {
+        let first = tenant.malloc(512 << 10, 8);
+        let second = tenant.malloc(512 << 10, 8);
+        assert!(!first.is_null() && !second.is_null());
+        assert_eq!(tenant.used(), 1 << 20);
+
+        assert!(tenant.malloc(1, 8).is_null());
+        assert!(tenant.realloc(second, 512 << 10, 8, (512 << 10) + 1).is_null());
+        assert_eq!(tenant.used(), 1 << 20);
+
+        // Other tenants and the arena itself are unaffected.
+        let other = a.scoped(8, 1 << 20);
+        let theirs = other.malloc(4096, 8);
+        assert!(!theirs.is_null());
+        other.free(theirs, 4096, 8);
+
+        tenant.free(first, 512 << 10, 8);
+        assert_eq!(tenant.used(), 512 << 10);
+        let third = tenant.malloc(256 << 10, 8);
+        assert!(!third.is_null());
+
+        tenant.free(second, 512 << 10, 8);
+        tenant.free(third, 256 << 10, 8);
+        assert_eq!(tenant.used(), 0);
+    }
+}
@@ -0,0 +1,57 @@
+#![cfg(feature = "shadow")]
+
+use disk_dlmalloc::{DiskDlmalloc, Observer};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+
+#[derive(Default)]
+struct Accesses(Mutex<Vec<(usize, usize)>>);
+
+impl Observer for Accesses {
+    fn on_poisoned_access(&self, ptr: *const u8, len: usize, _poisoned: *const u8) {
+        self.0.lock().unwrap().push((ptr as usize, len));
+    }
+}
+
+#[test]
+fn read_after_free_is_flagged() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let observer = Arc::new(Accesses::default());
+    let a = DiskDlmalloc::builder(temp_file.path(), 10 << 20)
+        .observer(observer.clone())
+        .build()
+        .unwrap();
+    let mut buf = [0u8; 64];
+    unsafe {
+        let ptr = a.malloc(64, 8);
+        a.arena_write(ptr, &[7; 64]);
+        a.arena_read(ptr, &mut buf);
+        assert_eq!(buf, [7; 64]);
+        assert!(observer.0.lock().unwrap().is_empty());
+
+        a.free(ptr, 64, 8);
+        a.arena_read(ptr, &mut buf);
+        assert_eq!(*observer.0.lock().unwrap(), [(ptr as usize, 64)]);
+
+        // Once the memory is handed out again it's fair game.
+        let again = a.malloc(64, 8);
+        assert_eq!(again, ptr);
+        a.arena_read(again, &mut buf);
+        assert_eq!(observer.0.lock().unwrap().len(), 1);
+        a.free(again, 64, 8);
+    }
+}
+
+#[test]
+#[should_panic(expected = "touches freed or reserved memory")]
+fn overrun_into_the_next_chunk_panics_without_observer() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10 << 20, None);
+    unsafe {
+        let first = a.malloc(64, 8);
+        let _second = a.malloc(64, 8);
+        let usable = a.usable_size(first, 64, 8);
+        a.arena_write(first, &vec![1; usable]);
+        a.arena_write(first, &vec![1; usable + 8]);
+    }
+}
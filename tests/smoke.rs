@@ -1,13 +1,36 @@
+#![feature(allocator_api)]
+
 use arbitrary::Unstructured;
 use disk_dlmalloc::DiskDlmalloc;
 use rand::{rngs::SmallRng, RngCore, SeedableRng};
+use std::alloc::{Allocator, Layout};
+use std::ptr;
 use tempfile::NamedTempFile;
 
 #[test]
+#[allow(unused_mut, clippy::needless_borrows_for_generic_args)]
 fn smoke() {
     let temp_file = NamedTempFile::new().unwrap();
     let temp_file_path = temp_file.path();
     let mut a = DiskDlmalloc::new(&temp_file_path, 10485760, None);
+    smoke_sequence(&a);
+}
+
+#[test]
+fn smoke_in_memory() {
+    let a = DiskDlmalloc::new_in_memory(10485760);
+    smoke_sequence(&a);
+    unsafe {
+        let ptr = a.malloc(1 << 20, 8);
+        ptr.write_bytes(0xa5, 1 << 20);
+        a.fence_and_flush(ptr, 1 << 20).unwrap();
+        assert!(a.commit(ptr, 4096).is_err());
+        a.free(ptr, 1 << 20, 8);
+        assert!(a.malloc(16 << 20, 8).is_null());
+    }
+}
+
+fn smoke_sequence(a: &DiskDlmalloc) {
     unsafe {
         let ptr = a.malloc(1, 1);
         assert!(!ptr.is_null());
@@ -37,3 +60,143 @@ fn stress() {
         let _ = fuzz::run(&mut u);
     }
 }
+
+#[test]
+fn realloc_to_zero_frees() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10485760, None);
+    unsafe {
+        let keep = a.malloc(16, 8);
+        let before = a.available();
+
+        let ptr = a.malloc(1000, 8);
+        assert!(a.available() < before);
+        assert!(a.realloc(ptr, 1000, 8, 0).is_null());
+        assert_eq!(a.available(), before);
+
+        let ptr = a.allocate(Layout::from_size_align(1000, 8).unwrap()).unwrap();
+        let new_layout = Layout::from_size_align(0, 8).unwrap();
+        let shrunk = a
+            .shrink(ptr.cast(), Layout::from_size_align(1000, 8).unwrap(), new_layout)
+            .unwrap();
+        assert_eq!(shrunk.len(), 0);
+        a.deallocate(shrunk.cast(), new_layout);
+        assert_eq!(a.available(), before);
+
+        a.free(keep, 16, 8);
+    }
+}
+
+#[test]
+fn realloc_null_is_malloc() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10485760, None);
+    unsafe {
+        let ptr = a.realloc(ptr::null_mut(), 0, 8, 128);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % 8, 0);
+        ptr.write_bytes(0x5a, 128);
+        a.free(ptr, 128, 8);
+
+        let ptr = a.realloc(ptr::null_mut(), 0, 8, 0);
+        assert!(!ptr.is_null());
+        a.free(ptr, 0, 8);
+    }
+}
+
+#[test]
+fn zero_size_allocations() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10485760, None);
+    unsafe {
+        for align in [1, 8, 64, 4096] {
+            let layout = Layout::from_size_align(0, align).unwrap();
+            let first = a.allocate(layout).unwrap();
+            let second = a.allocate(layout).unwrap();
+            assert_eq!(first.len(), 0);
+            assert_eq!(first.cast::<u8>().as_ptr() as usize % align, 0);
+            assert_ne!(first.cast::<u8>(), second.cast::<u8>());
+            a.deallocate(first.cast(), layout);
+            a.deallocate(second.cast(), layout);
+
+            let ptr = a.malloc(0, align);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            a.free(ptr, 0, align);
+        }
+
+        let mut v = Vec::<u64, _>::new_in(&a);
+        v.push(1);
+        v.clear();
+        v.shrink_to_fit();
+        assert_eq!(v.capacity(), 0);
+    }
+}
+
+#[test]
+fn malloc_meets_malloc_alignment() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10485760, None);
+    let alignment = a.malloc_alignment();
+    assert!(alignment.is_power_of_two());
+    assert!(alignment >= 2 * std::mem::size_of::<usize>());
+    unsafe {
+        let ptrs: Vec<_> = (1..200).map(|size| (a.malloc(size, 1), size)).collect();
+        for &(ptr, size) in &ptrs {
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % alignment, 0);
+            a.free(ptr, size, 1);
+        }
+    }
+}
+
+#[test]
+fn malloc_with_usable_reports_usable_size() {
+    let temp_file = NamedTempFile::new().unwrap();
+    for striping in [false, true] {
+        let a = DiskDlmalloc::builder(temp_file.path(), 10485760)
+            .lock_striping(striping)
+            .build()
+            .unwrap();
+        unsafe {
+            for size in (0..600).step_by(7) {
+                let (ptr, usable) = a.malloc_with_usable(size, 8);
+                assert!(!ptr.is_null());
+                assert!(usable >= size);
+                assert_eq!(a.usable_size(ptr, size, 8), usable);
+                ptr.write_bytes(0x5a, usable);
+                // The whole usable size is a valid size to free with.
+                a.free(ptr, usable, 8);
+            }
+        }
+    }
+}
+
+#[test]
+fn initial_align_places_the_first_allocation() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 16 << 20)
+        .initial_align(4096)
+        .build()
+        .unwrap();
+    unsafe {
+        let first = a.malloc(1 << 20, 8);
+        assert_eq!(first as usize % 4096, 0);
+        assert_eq!(a.to_offset(first), Some(4096));
+        let second = a.malloc(1 << 20, 8);
+        assert!(!second.is_null());
+        a.free(second, 1 << 20, 8);
+        a.free(first, 1 << 20, 8);
+
+        a.reset_keep_mapping();
+        let again = a.malloc(64, 8);
+        assert_eq!(again, first);
+        a.free(again, 64, 8);
+    }
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    let first = unsafe { a.malloc(1 << 20, 8) };
+    assert_ne!(first as usize % 4096, 0);
+    unsafe { a.free(first, 1 << 20, 8) };
+}
@@ -0,0 +1,44 @@
+use disk_dlmalloc::{DiskDlmalloc, Error};
+use tempfile::NamedTempFile;
+
+#[test]
+fn filling_one_part_leaves_the_other_alone() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10 << 20, None);
+    let parts = a.split(&[5 << 20, 5 << 20]).unwrap();
+    assert_eq!(parts.len(), 2);
+    unsafe {
+        let mut ptrs = Vec::new();
+        loop {
+            let ptr = parts[0].malloc(64 << 10, 8);
+            if ptr.is_null() {
+                break;
+            }
+            ptr.write_bytes(0xab, 64 << 10);
+            ptrs.push(ptr);
+        }
+        assert!(!ptrs.is_empty());
+
+        let ptr = parts[1].malloc(64 << 10, 8);
+        assert!(!ptr.is_null());
+        ptr.write_bytes(0xcd, 64 << 10);
+        assert_eq!(*ptrs[0], 0xab);
+        parts[1].free(ptr, 64 << 10, 8);
+
+        for ptr in ptrs {
+            parts[0].free(ptr, 64 << 10, 8);
+        }
+    }
+}
+
+#[test]
+fn split_fails_when_the_parts_dont_fit() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    match a.split(&[512 << 10, 1 << 20]) {
+        Err(Error::NoRoom { requested }) => assert_eq!(requested, 1 << 20),
+        other => panic!("unexpected result: {:?}", other.map(|parts| parts.len())),
+    }
+    // The part taken before the failure went back.
+    assert_eq!(a.split(&[512 << 10]).unwrap().len(), 1);
+}
@@ -0,0 +1,63 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::io::{Read, Write};
+use tempfile::NamedTempFile;
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 4096) as u8).collect()
+}
+
+#[test]
+fn writer_round_trips_through_reader() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 64 << 20, None);
+    let data = pattern(10 << 20);
+
+    let mut writer = a.writer();
+    for piece in data.chunks(1000) {
+        writer.write_all(piece).unwrap();
+    }
+    let offset = writer.finish().unwrap();
+
+    let mut read = Vec::new();
+    unsafe { a.reader(offset) }.read_to_end(&mut read).unwrap();
+    assert_eq!(read.len(), data.len());
+    assert!(read == data);
+
+    // Small reads straddle the chunk boundaries just the same.
+    let mut reader = unsafe { a.reader(offset) };
+    let mut buf = [0; 333];
+    let mut at = 0;
+    loop {
+        let n = reader.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        assert_eq!(buf[..n], data[at..at + n]);
+        at += n;
+    }
+    assert_eq!(at, data.len());
+
+    let offset_before = a.offset();
+    unsafe { a.free_stream(offset) };
+    let mut writer = a.writer();
+    writer.write_all(&data).unwrap();
+    writer.finish().unwrap();
+    assert_eq!(a.offset(), offset_before);
+}
+
+#[test]
+fn empty_and_abandoned_streams() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    let offset = a.writer().finish().unwrap();
+    let mut read = Vec::new();
+    unsafe { a.reader(offset) }.read_to_end(&mut read).unwrap();
+    assert!(read.is_empty());
+    unsafe { a.free_stream(offset) };
+
+    let mut writer = a.writer();
+    writer.write_all(&pattern(1 << 20)).unwrap();
+    drop(writer);
+    let ptr = unsafe { a.malloc(4 << 20, 8) };
+    assert!(!ptr.is_null());
+}
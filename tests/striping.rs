@@ -0,0 +1,96 @@
+use disk_dlmalloc::{DiskDlmalloc, ReloadableConfig};
+use std::thread;
+use tempfile::NamedTempFile;
+
+#[test]
+fn mixed_sizes_across_threads() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 64 << 20)
+        .lock_striping(true)
+        .build()
+        .unwrap();
+    let threads = (0..6)
+        .map(|i| {
+            let a = a.clone();
+            thread::spawn(move || {
+                let size = if i % 3 == 0 { 1 << 20 } else { 32 };
+                for j in 0..500 {
+                    unsafe {
+                        let ptr = a.malloc(size, 8);
+                        assert!(!ptr.is_null());
+                        ptr.write_bytes(j as u8, size);
+                        assert_eq!(*ptr.add(size - 1), j as u8);
+                        a.free(ptr, size, 8);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn realloc_between_stripes() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 10 << 20)
+        .lock_striping(true)
+        .build()
+        .unwrap();
+    unsafe {
+        let ptr = a.malloc(32, 8);
+        for i in 0..32 {
+            *ptr.add(i) = i as u8;
+        }
+        let ptr = a.realloc(ptr, 32, 8, 4096);
+        assert!(!ptr.is_null());
+        for i in 0..32 {
+            assert_eq!(*ptr.add(i), i as u8);
+        }
+        let ptr = a.realloc(ptr, 4096, 8, 16);
+        assert!(!ptr.is_null());
+        for i in 0..16 {
+            assert_eq!(*ptr.add(i), i as u8);
+        }
+        a.free(ptr, 16, 8);
+    }
+}
+
+/// Fills the small heap, frees it all and then asks the main heap for more
+/// than is left, returning whether that succeeded.
+fn large_after_small(retry_with_trim: bool) -> bool {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 4 << 20)
+        .lock_striping(true)
+        .config(ReloadableConfig {
+            auto_trim: false,
+            ..ReloadableConfig::default()
+        })
+        .retry_with_trim(retry_with_trim)
+        .build()
+        .unwrap();
+    unsafe {
+        let mut small = Vec::new();
+        while a.offset() < 3 << 20 {
+            let ptr = a.malloc(64, 8);
+            assert!(!ptr.is_null());
+            small.push(ptr);
+        }
+        for ptr in small {
+            a.free(ptr, 64, 8);
+        }
+        let ptr = a.malloc(2 << 20, 8);
+        if ptr.is_null() {
+            return false;
+        }
+        a.free(ptr, 2 << 20, 8);
+    }
+    true
+}
+
+#[test]
+fn retry_with_trim_frees_up_the_other_heap() {
+    assert!(!large_after_small(false));
+    assert!(large_after_small(true));
+}
@@ -0,0 +1,46 @@
+use disk_dlmalloc::{DiskDlmalloc, HUGE_PAGE_SIZE};
+use tempfile::NamedTempFile;
+
+/// Returns the `VmFlags` of the mapping containing `addr`.
+#[cfg(target_os = "linux")]
+fn vm_flags(addr: usize) -> String {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+    let mut inside = false;
+    for line in smaps.lines() {
+        if let Some((range, _)) = line.split_once(' ') {
+            if let Some((start, end)) = range.split_once('-') {
+                if let (Ok(start), Ok(end)) = (
+                    usize::from_str_radix(start, 16),
+                    usize::from_str_radix(end, 16),
+                ) {
+                    inside = (start..end).contains(&addr);
+                    continue;
+                }
+            }
+        }
+        if inside {
+            if let Some(flags) = line.strip_prefix("VmFlags:") {
+                return flags.to_string();
+            }
+        }
+    }
+    panic!("no mapping contains {:#x}", addr);
+}
+
+#[test]
+fn thp_buffers_are_aligned_and_advised() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 16 << 20, None);
+    unsafe {
+        let ptr = a.alloc_thp(3 << 20);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % HUGE_PAGE_SIZE, 0);
+        ptr.write_bytes(1, 4 << 20);
+        #[cfg(target_os = "linux")]
+        assert!(
+            vm_flags(ptr as usize).split_whitespace().any(|f| f == "hg"),
+            "MADV_HUGEPAGE wasn't applied"
+        );
+        a.free_thp(ptr, 3 << 20);
+    }
+}
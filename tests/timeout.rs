@@ -0,0 +1,56 @@
+use disk_dlmalloc::{DiskDlmalloc, Observer, TryAllocError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// Sleeps with the lock held the first time it's armed.
+#[derive(Default)]
+struct Stall {
+    armed: AtomicBool,
+    holding: AtomicBool,
+}
+
+impl Observer for Stall {
+    fn on_lock(&self) {
+        if self.armed.swap(false, Ordering::SeqCst) {
+            self.holding.store(true, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(300));
+        }
+    }
+}
+
+#[test]
+fn timed_malloc_gives_up_on_a_held_lock() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let stall = Arc::new(Stall::default());
+    let a = DiskDlmalloc::builder(temp_file.path(), 1 << 20)
+        .observer(stall.clone())
+        .build()
+        .unwrap();
+
+    stall.armed.store(true, Ordering::SeqCst);
+    let holder = {
+        let a = a.clone();
+        thread::spawn(move || unsafe {
+            let ptr = a.malloc(64, 8);
+            a.free(ptr, 64, 8);
+        })
+    };
+    while !stall.holding.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+    let res = unsafe { a.try_malloc_timeout(64, 8, Duration::from_millis(20)) };
+    assert_eq!(res, Err(TryAllocError::LockTimeout));
+    holder.join().unwrap();
+
+    unsafe {
+        let ptr = a
+            .try_malloc_timeout(64, 8, Duration::from_millis(20))
+            .unwrap();
+        a.free(ptr, 64, 8);
+        let res = a.try_malloc_timeout(2 << 20, 8, Duration::from_millis(20));
+        assert!(matches!(res, Err(TryAllocError::OutOfMemory { .. })));
+    }
+}
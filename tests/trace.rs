@@ -0,0 +1,80 @@
+#![cfg(feature = "trace")]
+#![feature(allocator_api)]
+
+use disk_dlmalloc::trace::TraceRecord;
+use disk_dlmalloc::DiskDlmalloc;
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+
+#[test]
+fn replay_reproduces_offsets() {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10 << 20, None);
+    let sink = records.clone();
+    a.set_trace_sink(Some(Box::new(move |r: TraceRecord| {
+        sink.lock().unwrap().push(r)
+    })));
+    unsafe {
+        let p1 = a.malloc(100, 8);
+        let p2 = a.calloc(5000, 64);
+        let p3 = a.malloc(1 << 20, 8);
+        a.free(p1, 100, 8);
+        let p2 = a.realloc(p2, 5000, 64, 9000);
+        let p4 = a.malloc(24, 8);
+        a.free(p3, 1 << 20, 8);
+        a.trim(0);
+        a.free(p2, 9000, 64);
+        a.free(p4, 24, 8);
+    }
+    let records = records.lock().unwrap().clone();
+    assert_eq!(records.len(), 10);
+    assert!(matches!(
+        records[0],
+        TraceRecord::Malloc {
+            size: 100,
+            align: 8,
+            result: Some(_)
+        }
+    ));
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let b = DiskDlmalloc::new(temp_file.path(), 10 << 20, None);
+    unsafe {
+        b.replay(&records).unwrap();
+    }
+}
+
+#[test]
+fn allocator_api_and_maintenance_are_traced() {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 10 << 20, None);
+    let sink = records.clone();
+    a.set_trace_sink(Some(Box::new(move |r: TraceRecord| {
+        sink.lock().unwrap().push(r)
+    })));
+    let mut v: Vec<u64, _> = Vec::with_capacity_in(4, a.clone());
+    v.extend(0..100);
+    v.shrink_to_fit();
+    drop(v);
+    a.coalesce_free();
+    a.set_growth_increment(0);
+    unsafe { a.reset_keep_mapping() };
+    let records = records.lock().unwrap().clone();
+    assert!(matches!(records[0], TraceRecord::Malloc { size: 32, .. }));
+    assert!(matches!(
+        records[1],
+        TraceRecord::Resize { old_size: 32, .. }
+    ));
+    assert!(records
+        .iter()
+        .any(|r| matches!(r, TraceRecord::Free { .. })));
+    assert_eq!(records[records.len() - 1], TraceRecord::Reset);
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let b = DiskDlmalloc::new(temp_file.path(), 10 << 20, None);
+    unsafe {
+        b.replay(&records).unwrap();
+    }
+}
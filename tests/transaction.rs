@@ -0,0 +1,51 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::panic::{self, AssertUnwindSafe};
+use tempfile::NamedTempFile;
+
+#[test]
+fn failed_transaction_frees_its_allocations() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    let before = a.live_count();
+    let res: Result<(), &str> = a.transaction(|txn| {
+        let first = txn.malloc(100, 8);
+        let second = txn.calloc(4096, 8);
+        assert!(!first.is_null() && !second.is_null());
+        Err("second half didn't fit")
+    });
+    assert_eq!(res, Err("second half didn't fit"));
+    assert_eq!(a.live_count(), before);
+}
+
+#[test]
+fn committed_transaction_keeps_its_allocations() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    let ptrs = a
+        .transaction(|txn| {
+            let dropped = txn.malloc(64, 8);
+            unsafe { txn.free(dropped, 64, 8) };
+            Ok::<_, ()>([txn.malloc(100, 8), txn.malloc(200, 8)])
+        })
+        .unwrap();
+    assert_eq!(a.live_count(), 2);
+    unsafe {
+        a.free(ptrs[0], 100, 8);
+        a.free(ptrs[1], 200, 8);
+    }
+    assert_eq!(a.live_count(), 0);
+}
+
+#[test]
+fn panicking_transaction_frees_its_allocations() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        a.transaction(|txn| -> Result<(), ()> {
+            txn.malloc(100, 8);
+            panic!("building the structure failed");
+        })
+    }));
+    assert!(res.is_err());
+    assert_eq!(a.live_count(), 0);
+}
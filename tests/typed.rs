@@ -0,0 +1,70 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::mem;
+use tempfile::NamedTempFile;
+
+#[test]
+fn u64_records_round_trip() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        let size = 100 * mem::size_of::<u64>();
+        let ptr = a.malloc(size, 8);
+        let records = a.typed_region::<u64>(ptr, 100);
+        for (i, record) in records.iter_mut().enumerate() {
+            *record = i as u64 * 3;
+        }
+        let records = a.typed_region::<u64>(ptr, 100);
+        assert_eq!(records.iter().sum::<u64>(), 3 * 99 * 100 / 2);
+        assert_eq!(*ptr.add(10 * 8).cast::<u64>(), 30);
+        a.free(ptr, size, 8);
+    }
+}
+
+#[test]
+#[should_panic(expected = "don't fit")]
+fn region_past_the_allocation_panics() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        let ptr = a.malloc(800, 8);
+        let _guard = a.malloc(16, 8);
+        a.typed_region::<u64>(ptr, 1000);
+    }
+}
+
+#[test]
+#[should_panic(expected = "aligned")]
+fn misaligned_region_panics() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    unsafe {
+        let ptr = a.malloc(800, 8);
+        a.typed_region::<u64>(ptr.add(1), 10);
+    }
+}
+
+#[test]
+fn bulk_copy_through_a_byte_slice() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    let blob: Vec<u8> = (0..1 << 20).map(|i| (i * 7 % 251) as u8).collect();
+    unsafe {
+        let ptr = a.malloc(blob.len(), 8);
+        a.chunk_as_mut_slice(ptr, blob.len()).copy_from_slice(&blob);
+        assert_eq!(std::slice::from_raw_parts(ptr, blob.len()), &blob[..]);
+        assert_eq!(a.chunk_as_mut_slice(ptr, blob.len())[12345], blob[12345]);
+        a.free(ptr, blob.len(), 8);
+    }
+}
+
+#[test]
+#[should_panic(expected = "don't fit")]
+fn byte_slice_past_the_allocation_panics() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 8 << 20, None);
+    unsafe {
+        let ptr = a.malloc(1 << 20, 8);
+        let usable = a.usable_size(ptr, 1 << 20, 8);
+        a.chunk_as_mut_slice(ptr, usable + 4096);
+    }
+}
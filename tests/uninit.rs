@@ -0,0 +1,21 @@
+use disk_dlmalloc::DiskDlmalloc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn uninit_slice_round_trip() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::new(temp_file.path(), 1 << 20, None);
+    let before = a.stats().heap_free;
+
+    let mut buf = a.alloc_uninit::<u64>(1000).unwrap();
+    assert_eq!(buf.len(), 1000);
+    for (i, slot) in buf.iter_mut().enumerate() {
+        slot.write(i as u64 * 3);
+    }
+    let values = unsafe { buf.assume_init() };
+    assert!(values.iter().enumerate().all(|(i, &v)| v == i as u64 * 3));
+
+    drop(values);
+    assert!(a.stats().heap_free >= before);
+    assert!(a.alloc_uninit::<u64>(1 << 20).is_none());
+}
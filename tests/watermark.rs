@@ -0,0 +1,47 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+#[test]
+fn watermark_fires_once_per_crossing() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let total = 1 << 20;
+    let a = DiskDlmalloc::new(temp_file.path(), total, None);
+    let fired = Arc::new(AtomicUsize::new(0));
+    let seen = Arc::new(AtomicUsize::new(0));
+    {
+        let fired = fired.clone();
+        let seen = seen.clone();
+        a.set_watermark(
+            0.8,
+            Box::new(move |used, t| {
+                assert_eq!(t, total);
+                seen.store(used, Ordering::SeqCst);
+                fired.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+    }
+
+    unsafe {
+        let mut ptrs = Vec::new();
+        while a.offset() < total * 7 / 10 {
+            ptrs.push(a.malloc(16 << 10, 8));
+        }
+        assert_eq!(fired.load(Ordering::SeqCst), 0);
+        while a.offset() < total * 9 / 10 {
+            let ptr = a.malloc(16 << 10, 8);
+            assert!(!ptr.is_null());
+            ptrs.push(ptr);
+        }
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+        assert!(seen.load(Ordering::SeqCst) >= total * 8 / 10);
+
+        // Further allocations above the watermark don't fire it again.
+        ptrs.push(a.malloc(16 << 10, 8));
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+        for ptr in ptrs {
+            a.free(ptr, 16 << 10, 8);
+        }
+    }
+}
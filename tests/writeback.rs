@@ -0,0 +1,53 @@
+use disk_dlmalloc::DiskDlmalloc;
+use std::fs;
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+
+const CHUNK: usize = 64 << 10;
+
+/// Allocates and fills `count` chunks of `a`, returning them with the
+/// slowest allocation and fill.
+unsafe fn burst(a: &DiskDlmalloc, count: usize) -> (Vec<*mut u8>, Duration) {
+    let mut slowest = Duration::ZERO;
+    let ptrs = (0..count)
+        .map(|i| {
+            let begin = Instant::now();
+            let ptr = a.malloc(CHUNK, 8);
+            assert!(!ptr.is_null());
+            ptr.write_bytes(i as u8, CHUNK);
+            slowest = slowest.max(begin.elapsed());
+            ptr
+        })
+        .collect();
+    (ptrs, slowest)
+}
+
+#[test]
+fn writeback_limit_keeps_the_burst_intact() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let a = DiskDlmalloc::builder(temp_file.path(), 64 << 20)
+        .writeback_limit(Some(1 << 20))
+        .build()
+        .unwrap();
+    unsafe {
+        let (ptrs, slowest) = burst(&a, 512);
+        // Waiting on a batch is bounded by the disk writing 1 MiB, far
+        // below this even on slow CI machines.
+        assert!(slowest < Duration::from_secs(5), "{:?}", slowest);
+
+        let contents = fs::read(temp_file.path()).unwrap();
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            let offset = a.to_offset(ptr).unwrap();
+            assert!(contents[offset..][..CHUNK].iter().all(|&b| b == i as u8));
+        }
+        for ptr in ptrs {
+            a.free(ptr, CHUNK, 8);
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "writeback_limit must be positive")]
+fn zero_writeback_limit_panics() {
+    let _ = DiskDlmalloc::builder("unused", 1 << 20).writeback_limit(Some(0));
+}